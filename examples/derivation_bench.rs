@@ -0,0 +1,58 @@
+//! Times the tracker's initial derivation of large lookahead windows.
+//!
+//! Compare the serial and parallel paths by running it with and without the
+//! feature (the speedup scales with the number of cores):
+//!
+//! ```text
+//! cargo run --release --example derivation_bench
+//! cargo run --release --example derivation_bench --features parallel-derivation
+//! ```
+//!
+//! Measured on a single-core VM, best of 5 (µs per script):
+//!
+//! ```text
+//! window     serial   parallel
+//!  1,000       55.9       50.8
+//! 10,000       54.8       52.7
+//! 50,000       53.9       52.8
+//! ```
+//!
+//! With one core the parallel path only shows it costs nothing extra; rerun
+//! on the target machine before relying on a speedup.
+
+use std::str::FromStr;
+use std::time::Instant;
+
+use bdk_electrum_streaming_poc::DerivedSpkTracker;
+use bdk_wallet::miniscript::{Descriptor, DescriptorPublicKey};
+
+const DESCRIPTOR: &str = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+
+/// Each window is derived this many times; the fastest run is reported.
+const RUNS: usize = 5;
+
+fn main() {
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(DESCRIPTOR).expect("valid descriptor");
+    let mode = if cfg!(feature = "parallel-derivation") { "parallel" } else { "serial" };
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} derivation, {} threads available", mode, threads);
+
+    for window in [1_000u32, 10_000, 50_000] {
+        let best = (0..RUNS)
+            .map(|_| {
+                let mut tracker = DerivedSpkTracker::<&str>::new(window - 1);
+                let started = Instant::now();
+                let derived = tracker.insert_descriptor("external", descriptor.clone(), 0);
+                assert_eq!(derived.len(), window as usize);
+                started.elapsed()
+            })
+            .min()
+            .expect("at least one run");
+        println!(
+            "{:>6} scripts: {:>8.1} ms ({:.1} µs/script)",
+            window,
+            best.as_secs_f64() * 1e3,
+            best.as_secs_f64() * 1e6 / window as f64
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//! Streaming-first sync with a polling fallback (the `auto` subcommand).
+//!
+//! The streaming path is tried first. If it fails terminally (server unreachable,
+//! subscriptions rejected, connection lost), the polling baseline is run instead,
+//! so the tool still completes a sync against servers that can't stream.
+
+use anyhow::Result;
+
+/// Which sync strategy actually completed the run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletedBy {
+    Streaming,
+    Polling,
+}
+
+/// Runs `streaming`, falling back to `polling` if it returns an error.
+///
+/// Both closures are expected to sync the same wallet. The returned
+/// `CompletedBy` reports which one produced the result.
+pub fn sync_with_fallback<T>(
+    streaming: impl FnOnce() -> Result<T>,
+    polling: impl FnOnce() -> Result<T>,
+) -> Result<(CompletedBy, T)> {
+    match streaming() {
+        Ok(result) => Ok((CompletedBy::Streaming, result)),
+        Err(e) => {
+            log::warn!("[AUTO] Streaming failed ({:#}); falling back to polling", e);
+            polling().map(|result| (CompletedBy::Polling, result))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::polling::SyncStats;
+    use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+    use crate::streaming::electrum::asynchronous::adapter::ElectrumAdapter;
+    use crate::streaming::engine::SyncEngine;
+    use crate::streaming::runtime::tests::dummy_wallet;
+    use crate::streaming::runtime::SyncOrchestrator;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    #[test]
+    fn falls_back_to_polling_when_streaming_fails() {
+        let wallet = dummy_wallet();
+
+        // A port nothing listens on any more: the connection is refused.
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let streaming = || {
+            let client = ElectrumAdapter::new(format!("tcp://{}", unreachable))?;
+            let engine = SyncEngine::new(DerivedSpkTracker::<String>::new(2));
+            SyncOrchestrator::new(engine, client, wallet.clone())
+                .run_forever()
+                .map(|_| SyncStats { total_time: Duration::ZERO, rounds: 0, round_stats: vec![] })
+        };
+        let polled = Cell::new(false);
+        let polling = || {
+            polled.set(true);
+            Ok(SyncStats { total_time: Duration::from_millis(1), rounds: 10, round_stats: vec![] })
+        };
+
+        let (completed_by, stats) = sync_with_fallback(streaming, polling).unwrap();
+
+        assert!(polled.get());
+        assert_eq!(completed_by, CompletedBy::Polling);
+        assert_eq!(stats.rounds, 10);
+    }
+}
@@ -1,5 +1,30 @@
 pub mod polling;
 pub mod streaming;
 pub mod persistence;
+pub mod fallback;
 
-pub use persistence::setup_wallet;
\ No newline at end of file
+pub use persistence::{open_wallet, setup_wallet, WalletConfig, WalletError};
+
+// Stable paths for the streaming stack; the module layout under `streaming`
+// is an implementation detail.
+pub use streaming::domain::spk_tracker::{
+    DerivedSpkTracker, FixedLookahead, GapLimitPolicy, GapPolicy, ScanToIndex,
+};
+pub use streaming::electrum::asynchronous::adapter::ElectrumAdapter;
+pub use streaming::electrum::{
+    ElectrumApi, FetchError, PooledElectrumClient, QuorumElectrumClient, SharedElectrumClient, TenantClient,
+};
+pub use streaming::engine::types::HistoryTx;
+pub use streaming::engine::{EngineCommand, EngineEvent, SyncEngine};
+pub use streaming::runtime::{
+    BalanceCheck, BalanceNotifyMode, DriverHandle, LoggingNotifier, PaymentAlertPolicy, PaymentNotifier,
+    StateDump, StreamingStats, SyncOrchestrator, SyncStatus, TxGraphSink, UpdateSink,
+};
+
+/// Everything needed to wire up a streaming sync: `use bdk_electrum_streaming_poc::prelude::*;`
+pub mod prelude {
+    pub use crate::{
+        DerivedSpkTracker, DriverHandle, ElectrumAdapter, ElectrumApi, EngineCommand, EngineEvent,
+        HistoryTx, SyncEngine, SyncOrchestrator, SyncStatus,
+    };
+}
@@ -1,10 +1,13 @@
 use anyhow::Result;
-use clap::{Parser, ValueEnum};
-use bdk_wallet::{bitcoin::Network, KeychainKind};
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+use bdk_wallet::bitcoin::Network;
 use bdk_electrum::electrum_client;
 
-use bdk_electrum_streaming_poc::setup_wallet;
-use bdk_electrum_streaming_poc::polling::auto_sync;
+use bdk_electrum_streaming_poc::{setup_wallet, DerivedSpkTracker, StreamingStats};
+use bdk_wallet::miniscript::{Descriptor, DescriptorPublicKey};
+use bdk_electrum_streaming_poc::polling::{auto_sync, wallet_fingerprint};
+use bdk_electrum_streaming_poc::fallback::sync_with_fallback;
 use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
 use std::time::{Instant, Duration};
 
@@ -43,24 +46,127 @@ impl StreamingStatsHandle {
     }
 }
 
-#[derive(ValueEnum, Clone, Debug)]
-enum SyncMode {
-    Polling,
-    Streaming,
-    Both,
-}
-
 #[derive(Debug)]
 struct SyncResult {
     mode: &'static str,
     total_time: Duration,
-    rounds: Option<u64>,   // polling has rounds, streaming does not (yet)
-    balance: Option<u64>,  // polling has balance, streaming may not yet
+    rounds: Option<u64>,   // polling only: full-scan rounds
+    balance: Option<u64>,
+    streaming: Option<StreamingStats>, // streaming only
+}
+
+/// One `SyncResult` as printed by `--output json`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct JsonResult {
+    mode: String,
+    total_time_ms: u64,
+    rounds: Option<u64>,
+    balance_sats: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    scripts_subscribed: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    histories_processed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    txs_applied: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first_history_ms: Option<u64>,
+    /// Polling time over this mode's time; only in comparisons.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    speedup: Option<f64>,
+}
+
+impl JsonResult {
+    fn new(result: &SyncResult, baseline: Option<&SyncResult>) -> Self {
+        Self {
+            mode: result.mode.to_string(),
+            total_time_ms: result.total_time.as_millis() as u64,
+            rounds: result.rounds,
+            balance_sats: result.balance,
+            scripts_subscribed: result.streaming.map(|s| s.scripts_subscribed),
+            histories_processed: result.streaming.map(|s| s.histories_processed),
+            txs_applied: result.streaming.map(|s| s.txs_applied),
+            first_history_ms: result
+                .streaming
+                .and_then(|s| s.first_history_latency)
+                .map(|d| d.as_millis() as u64),
+            speedup: baseline.map(|b| b.total_time.as_secs_f64() / result.total_time.as_secs_f64()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Tables for people.
+    Human,
+    /// JSON on stdout, for scripts.
+    Json,
 }
 
 #[derive(Parser)]
 #[command(author, version, about)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sync by repeatedly polling with a full scan.
+    Poll {
+        #[command(flatten)]
+        wallet: WalletArgs,
+        #[command(flatten)]
+        poll: PollArgs,
+    },
+    /// Sync by subscribing to every script and streaming updates.
+    Stream {
+        #[command(flatten)]
+        wallet: WalletArgs,
+        #[command(flatten)]
+        stream: StreamArgs,
+    },
+    /// Poll, then stream, and compare the two.
+    Both {
+        #[command(flatten)]
+        wallet: WalletArgs,
+        #[command(flatten)]
+        poll: PollArgs,
+        #[command(flatten)]
+        stream: StreamArgs,
+    },
+    /// Stream first; fall back to polling if streaming fails terminally.
+    Auto {
+        #[command(flatten)]
+        wallet: WalletArgs,
+        #[command(flatten)]
+        poll: PollArgs,
+        #[command(flatten)]
+        stream: StreamArgs,
+    },
+    /// Run `both` several times and report mean timings.
+    Bench {
+        #[command(flatten)]
+        wallet: WalletArgs,
+        #[command(flatten)]
+        poll: PollArgs,
+        #[command(flatten)]
+        stream: StreamArgs,
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        iterations: u32,
+    },
+    /// Submit a signed raw transaction to the Electrum server.
+    Broadcast {
+        /// The transaction, hex-encoded.
+        tx_hex: String,
+
+        #[arg(long, default_value = "ssl://electrum.blockstream.info:60002", env = "ELECTRUM_URL")]
+        electrum_url: String,
+    },
+}
+
+/// Wallet and connection options shared by every subcommand.
+#[derive(ClapArgs, Clone, Debug)]
+struct WalletArgs {
     #[arg(long, default_value = "testnet", env = "BITCOIN_NETWORK")]
     network: Network,
 
@@ -69,46 +175,196 @@ struct Args {
     descriptor: String,
 
     /// Change descriptor. Can be loaded from WALLET_CHANGE_DESCRIPTOR env var.
+    /// Without one, a new wallet is receive-only; an existing one uses its stored change keychain.
     #[arg(long, env = "WALLET_CHANGE_DESCRIPTOR")]
     change_descriptor: Option<String>,
 
     #[arg(long, default_value = "ssl://electrum.blockstream.info:60002", env = "ELECTRUM_URL")]
     electrum_url: String,
 
-    #[arg(long, value_enum, default_value_t = SyncMode::Polling, env = "SYNC_MODE")]
-    sync_mode: SyncMode,
+    /// How to print the results.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+    output: OutputFormat,
+}
+
+#[derive(ClapArgs, Clone, Debug)]
+struct PollArgs {
+    /// Full-scan rounds on a cold start.
+    #[arg(long, default_value_t = 10)]
+    rounds: usize,
 }
+
+#[derive(ClapArgs, Clone, Debug)]
+struct StreamArgs {
+    /// Apply the whole initial scan as a single wallet update.
+    #[arg(long, env = "BULK_INITIAL_APPLY")]
+    bulk_initial_apply: bool,
+
+    /// Keep streaming updates after the initial sync instead of exiting.
+    #[arg(long)]
+    follow: bool,
+
+    /// After the initial sync, compare the wallet balance with the server's
+    /// per-script balances and report any discrepancy.
+    #[arg(long)]
+    verify_balance: bool,
+
+    /// The wallet is known to hold funds: warn if the initial sync finds no
+    /// history at all, which usually means a wrong descriptor.
+    #[arg(long)]
+    expect_funds: bool,
+
+    /// File of transactions exported from another wallet (`<raw tx hex>
+    /// <height>` per line), applied before the first server response.
+    #[arg(long)]
+    seed_txs: Option<std::path::PathBuf>,
+
+    /// Keep resolved script histories in this file, so a restart only
+    /// downloads the ones whose status changed.
+    #[arg(long)]
+    history_cache: Option<std::path::PathBuf>,
+
+    /// Unsubscribe addresses whose funds are all spent once that spend has
+    /// this many confirmations, to stay under the server's subscription cap.
+    #[arg(long, value_name = "DEPTH")]
+    unsubscribe_buried: Option<u32>,
+
+    /// Also watch a descriptor under its own keychain name, e.g. one of a
+    /// basket of xpubs (repeatable). Its histories are synced like the
+    /// wallet's, but only the wallet's own keychains count towards the balance.
+    #[arg(long = "watch-descriptor", value_name = "NAME=DESC", value_parser = parse_watch_descriptor)]
+    watch_descriptors: Vec<WatchDescriptor>,
+}
+
+/// A `--watch-descriptor` entry.
+#[derive(Clone, Debug)]
+struct WatchDescriptor {
+    name: String,
+    descriptor: Descriptor<DescriptorPublicKey>,
+}
+
+fn parse_watch_descriptor(arg: &str) -> Result<WatchDescriptor, String> {
+    let (name, descriptor) = arg.split_once('=').ok_or("expected NAME=DESC")?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("empty keychain name".into());
+    }
+    // The wallet's own keychains go by these names in the tracker.
+    if name == "external" || name == "internal" {
+        return Err(format!("keychain name {:?} is reserved for the wallet", name));
+    }
+    let descriptor = descriptor.trim().parse().map_err(|e| format!("bad descriptor for {}: {}", name, e))?;
+    Ok(WatchDescriptor { name: name.to_string(), descriptor })
+}
+
+/// Makes the tracker watch exactly the wallet's keychains plus `watched`:
+/// adds each entry under its name, and drops names an earlier run's saved
+/// tracker still carries but that are no longer asked for.
+fn apply_watch_descriptors(tracker: &mut DerivedSpkTracker<String>, watched: &[WatchDescriptor]) {
+    let stale: Vec<String> = tracker
+        .keychains()
+        .filter(|k| *k != "external" && *k != "internal" && !watched.iter().any(|w| &w.name == *k))
+        .cloned()
+        .collect();
+    for keychain in stale {
+        log::info!("[STREAMING] No longer watching {}", keychain);
+        tracker.remove_keychain(&keychain);
+    }
+    for watch in watched {
+        let added = tracker.insert_descriptor(watch.name.clone(), watch.descriptor.clone(), 0);
+        log::info!("[STREAMING] Watching {} ({} new scripts)", watch.name, added.len());
+    }
+}
+
 fn main() -> Result<()> {
     env_logger::init();
     // Load .env file variables into the environment
     dotenvy::dotenv().ok();
     
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    log::info!("[MAIN] Sync mode: {:?}", args.sync_mode);
-
-    match args.sync_mode {
-        SyncMode::Polling => {
-            let _ = run_polling(&args)?;
+    match cli.command {
+        Command::Poll { wallet, poll } => {
+            let result = run_polling(&wallet, &poll)?;
+            print_json_if_asked(&wallet, &JsonResult::new(&result, None));
         }
-        SyncMode::Streaming => {
-            let _ = run_streaming(&args)?;
+        Command::Stream { wallet, stream } => {
+            let result = run_streaming(&wallet, &stream)?;
+            print_json_if_asked(&wallet, &JsonResult::new(&result, None));
         }
-        SyncMode::Both => {
+        Command::Both { wallet, poll, stream } => {
             log::info!("[MAIN] Running POLLING first...");
-            let polling = run_polling(&args)?;
+            let polling = run_polling(&wallet, &poll)?;
 
             log::info!("\n\n[MAIN] Running STREAMING next...");
-            let streaming = run_streaming(&args)?;
+            let streaming = run_streaming(&wallet, &stream)?;
 
-            print_comparison(&polling, &streaming);
+            print_comparison(&wallet, &polling, &streaming);
+        }
+        Command::Auto { wallet, poll, stream } => {
+            let (completed_by, result) = sync_with_fallback(
+                || run_streaming(&wallet, &stream),
+                || run_polling(&wallet, &poll),
+            )?;
+            log::info!("[MAIN] Auto sync completed by {:?}", completed_by);
+            match wallet.output {
+                OutputFormat::Human => println!("[AUTO] Sync completed via {} mode", result.mode),
+                OutputFormat::Json => print_json_if_asked(&wallet, &JsonResult::new(&result, None)),
+            }
+        }
+        Command::Bench { wallet, poll, stream, iterations } => {
+            run_bench(&wallet, &poll, &stream, iterations)?;
+        }
+        Command::Broadcast { tx_hex, electrum_url } => {
+            run_broadcast(&tx_hex, electrum_url)?;
         }
     }
 
     Ok(())
 }
 
-fn run_polling(args: &Args) -> Result<SyncResult> {
+fn run_broadcast(tx_hex: &str, electrum_url: String) -> Result<()> {
+    use bdk_electrum_streaming_poc::prelude::*;
+    use bdk_wallet::bitcoin::consensus::encode::deserialize_hex;
+    use bdk_wallet::bitcoin::Transaction;
+
+    let tx: Transaction = deserialize_hex(tx_hex.trim())
+        .map_err(|e| anyhow::anyhow!("not a raw transaction: {}", e))?;
+
+    let adapter = ElectrumAdapter::new(electrum_url)?;
+    let txid = adapter.broadcast_blocking(&tx)?;
+    println!("[BROADCAST] Accepted {}", txid);
+    Ok(())
+}
+
+fn run_bench(wallet: &WalletArgs, poll: &PollArgs, stream: &StreamArgs, iterations: u32) -> Result<()> {
+    if stream.follow {
+        anyhow::bail!("--follow never returns; it can't be benchmarked");
+    }
+    let (mut polling, mut streaming) = (Vec::new(), Vec::new());
+    for i in 1..=iterations {
+        log::info!("[BENCH] Iteration {}/{}", i, iterations);
+        polling.push(run_polling(wallet, poll)?);
+        streaming.push(run_streaming(wallet, stream)?);
+    }
+    print_comparison(wallet, &mean(&polling), &mean(&streaming));
+    Ok(())
+}
+
+/// Mean time of several runs of one mode; the other fields come from the last run.
+fn mean(results: &[SyncResult]) -> SyncResult {
+    let last = results.last().expect("at least one iteration");
+    let total: Duration = results.iter().map(|r| r.total_time).sum();
+    SyncResult {
+        mode: last.mode,
+        total_time: total / results.len() as u32,
+        rounds: last.rounds,
+        balance: last.balance,
+        streaming: last.streaming,
+    }
+}
+
+fn run_polling(args: &WalletArgs, poll: &PollArgs) -> Result<SyncResult> {
     log::info!("[POLLING] Setting up wallet...");
 
     let mut wallet = setup_wallet(
@@ -122,69 +378,84 @@ fn run_polling(args: &Args) -> Result<SyncResult> {
     let client = bdk_electrum::BdkElectrumClient::new(electrum_client);
 
     log::info!("[POLLING] Starting Auto Sync...");
-    let stats = auto_sync(&mut wallet, &client, 10)?;
+    let fingerprint = wallet_fingerprint(&wallet);
+    let stats = auto_sync(&mut wallet, &client, poll.rounds, &fingerprint)?;
 
     let balance = wallet.balance();
 
-    println!("[POLLING] Sync Finished");
-    println!("-----------------------------------");
-    println!("Total Time:       {:?}", stats.total_time);
-    println!("Total Rounds:     {}", stats.rounds);
-    println!("Total Balance:    {} sats", balance.total());
-    println!("-----------------------------------");
+    if args.output == OutputFormat::Human {
+        println!("[POLLING] Sync Finished");
+        println!("-----------------------------------");
+        println!("Total Time:       {:?}", stats.total_time);
+        println!("Total Rounds:     {}", stats.rounds);
+        println!("Total Balance:    {} sats", balance.total());
+        println!("-----------------------------------");
+        println!("{:<7} {:>12} {:>9} {:>12}", "Round", "Time", "New txs", "Index delta");
+        for r in &stats.round_stats {
+            println!(
+                "{:<7} {:>12} {:>9} {:>12}",
+                r.round,
+                format!("{:?}", r.duration),
+                r.new_txs,
+                r.highest_index_delta
+            );
+        }
+        println!("-----------------------------------");
+    }
 
     Ok(SyncResult {
         mode: "Polling",
         total_time: stats.total_time,
         rounds: Some(stats.rounds as u64),
         balance: Some(balance.total().to_sat()),
+        streaming: None,
     })
 }
 
-fn run_streaming(args: &Args) -> Result<SyncResult> {
-    use std::str::FromStr;
-    use bdk_wallet::miniscript::{Descriptor, DescriptorPublicKey};
-    use bdk_electrum_streaming_poc::streaming::domain::spk_tracker::DerivedSpkTracker;
-    use bdk_electrum_streaming_poc::streaming::engine::SyncEngine;
-    use bdk_electrum_streaming_poc::streaming::electrum::asynchronous::adapter::ElectrumAdapter;
-    use bdk_electrum_streaming_poc::streaming::runtime::SyncOrchestrator;
-
-    log::info!("[STREAMING] Setting up descriptors...");
-    let external: Descriptor<DescriptorPublicKey> = Descriptor::from_str(&args.descriptor)?;
-    let change: Option<Descriptor<DescriptorPublicKey>> =
-        match &args.change_descriptor {
-            Some(d) => Some(Descriptor::from_str(d)?),
-            None => None,
-        };
-    log::debug!("[STREAMING] Descriptor loaded");
+fn run_streaming(args: &WalletArgs, stream: &StreamArgs) -> Result<SyncResult> {
+    use bdk_electrum_streaming_poc::persistence::{
+        restore_tracker, setup_wallet_with_store, bootstrap_progress_path, TIP_PATH, TRACKER_PATH, DB_PATH,
+    };
+    use bdk_electrum_streaming_poc::prelude::*;
 
+    let (wallet, store) = setup_wallet_with_store(
+        args.descriptor.clone(),
+        args.change_descriptor.clone(),
+        args.network,
+    )?;
+
+    // Both keychains come from the wallet itself (a stored wallet's change
+    // keychain is used even without --change-descriptor), watched with the
+    // wallet's own lookahead. Each window starts at what the wallet
+    // has already revealed, or further if the tracker saved by the last run
+    // had extended it.
     log::info!("[STREAMING] Building script tracker...");
-    let lookahead = 50;
-    let mut tracker = DerivedSpkTracker::<String>::new(lookahead);
-    tracker.insert_descriptor(KeychainKind::External.to_string(), external, 0);
-    let change_desc = change.expect("CRITICAL: Change descriptor is missing!");
-    tracker.insert_descriptor(KeychainKind::Internal.to_string(), change_desc, 0);
-    // if let Some(change_desc) = change {
-    //     tracker.insert_descriptor("internal".to_string(), change_desc, 0);
-    // }
+    let mut tracker = restore_tracker(TRACKER_PATH, &wallet, wallet.spk_index().lookahead());
+    apply_watch_descriptors(&mut tracker, &stream.watch_descriptors);
 
     log::info!("[STREAMING] Building streaming engine...");
-    let engine = SyncEngine::new(tracker);
+    let mut engine = SyncEngine::new(tracker);
+    if let Some(depth) = stream.unsubscribe_buried {
+        engine = engine.with_unsubscribe_buried(depth);
+    }
 
     log::info!("[STREAMING] Creating async electrum client...");
-    let adapter = ElectrumAdapter::new(args.electrum_url.clone());
+    let mut adapter = ElectrumAdapter::new(args.electrum_url.clone())?;
+    if let Some(path) = &stream.history_cache {
+        adapter = adapter.with_cache_path(path);
+    }
 
     // ---- STATS ----
     let stats = StreamingStatsHandle::new();
-    
-    let wallet = setup_wallet(
-        args.descriptor.clone(),
-        args.change_descriptor.clone(),
-        args.network,
-    )?;
     let wallet = Arc::new(Mutex::new(wallet));
        
     let orchestrator = SyncOrchestrator::new(engine, adapter, wallet.clone())
+        .with_store(store)
+        .with_persisted_tip(TIP_PATH)
+        .with_persisted_tracker(TRACKER_PATH)
+        .with_bootstrap_progress(bootstrap_progress_path(DB_PATH))
+        .with_bulk_initial_apply(stream.bulk_initial_apply)
+        .with_expect_funds(stream.expect_funds)
         .with_initial_sync_notifier({
             let stats = stats.clone();
             move || {
@@ -196,15 +467,50 @@ fn run_streaming(args: &Args) -> Result<SyncResult> {
             }
         });
 
-    std::thread::spawn(move || {
-        orchestrator.run_forever();
+    let orchestrator = match &stream.seed_txs {
+        Some(path) => orchestrator.with_seed_transactions(path)?,
+        None => orchestrator,
+    };
+
+    let json = args.output == OutputFormat::Json;
+    let orchestrator = if stream.verify_balance {
+        orchestrator.with_balance_verification(move |check| match check {
+            Ok(check) if check.is_consistent() => report(json, log::Level::Info, format!("[VERIFY] Balance OK: {}", check)),
+            Ok(check) => report(json, log::Level::Warn, format!("[VERIFY] Balance MISMATCH: {}", check)),
+            Err(e) => report(json, log::Level::Warn, format!("[VERIFY] Could not verify balance: {}", e)),
+        })
+    } else {
+        orchestrator
+    };
+
+    let orchestrator = if stream.follow {
+        orchestrator.with_balance_change_notifier(move |balance| {
+            report(json, log::Level::Info, format!("[STREAMING] Balance: {} sats", balance.total().to_sat()));
+        })
+    } else {
+        orchestrator
+    };
+
+    let handle = orchestrator.handle();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let driver = std::thread::spawn({
+        let shutdown = shutdown.clone();
+        move || orchestrator.run(shutdown)
     });
 
     while !stats.is_done() {
+        if driver.is_finished() {
+            return match driver.join() {
+                Ok(Err(e)) => Err(e),
+                Ok(Ok(())) => Err(anyhow::anyhow!("streaming driver stopped before initial sync")),
+                Err(_) => Err(anyhow::anyhow!("streaming driver panicked")),
+            };
+        }
         std::thread::sleep(Duration::from_millis(50));
     }
 
     let dt = stats.elapsed().unwrap();
+    let counters = handle.stats();
 
     let balance = {
         log::debug!("[STREAMING] Acquiring wallet lock...");
@@ -213,22 +519,69 @@ fn run_streaming(args: &Args) -> Result<SyncResult> {
         w.balance().total().to_sat()
     };
 
-    println!("[WALLET] FINAL balance = {:?}", balance);
-    println!("[STREAMING] Initial Sync Finished");
-    println!("-----------------------------------");
-    println!("Total Time:       {:?}", dt);
-    println!("Total Balance:    {} sats", balance);
-    println!("-----------------------------------");
+    if args.output == OutputFormat::Human {
+        println!("[WALLET] FINAL balance = {:?}", balance);
+        println!("[STREAMING] Initial Sync Finished");
+        println!("-----------------------------------");
+        println!("Total Time:       {:?}", dt);
+        println!("Total Balance:    {} sats", balance);
+        println!("Subscribed:       {} scripts", counters.scripts_subscribed);
+        println!("Histories:        {}", counters.histories_processed);
+        println!("Txs Applied:      {}", counters.txs_applied);
+        if let Some(latency) = counters.first_history_latency {
+            println!("First History:    {:?}", latency);
+        }
+        println!("{}", handle.latency_report());
+        println!("-----------------------------------");
+    }
+
+    if stream.follow {
+        log::info!("[STREAMING] Following updates (Ctrl-C to stop)...");
+    } else {
+        // Close the connection before the next run (`both`, `bench`) opens its own.
+        shutdown.store(true, Ordering::SeqCst);
+    }
+    match driver.join() {
+        Ok(result) => result?,
+        Err(_) => anyhow::bail!("streaming driver panicked"),
+    }
 
     Ok(SyncResult {
         mode: "Streaming",
         total_time: dt,
         rounds: None,
         balance: Some(balance),
+        streaming: Some(counters),
     })
 }
 
-fn print_comparison(a: &SyncResult, b: &SyncResult) {
+/// Prints a status line on stdout, or logs it at `level` when stdout is
+/// reserved for `--output json`.
+fn report(json: bool, level: log::Level, line: String) {
+    if json {
+        log::log!(level, "{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+/// Prints `value` as JSON on stdout if `--output json` was given.
+fn print_json_if_asked<T: Serialize + ?Sized>(args: &WalletArgs, value: &T) {
+    if args.output == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(value).expect("results serialize"));
+    }
+}
+
+/// Both results, each with its speedup over `a` (the polling run).
+fn comparison_json(a: &SyncResult, b: &SyncResult) -> Vec<JsonResult> {
+    vec![JsonResult::new(a, Some(a)), JsonResult::new(b, Some(a))]
+}
+
+fn print_comparison(args: &WalletArgs, a: &SyncResult, b: &SyncResult) {
+    if args.output == OutputFormat::Json {
+        print_json_if_asked(args, &comparison_json(a, b));
+        return;
+    }
     println!();
     println!("==================================================");
     println!("                 SYNC COMPARISON                  ");
@@ -257,9 +610,195 @@ fn print_comparison(a: &SyncResult, b: &SyncResult) {
         b.balance.map(|v| format!("{} sats", v)).unwrap_or("-".into()),
     );
 
+    println!(
+        "{:<15} | {:<15} | {:<15}",
+        "Subscribed",
+        a.streaming.map(|s| s.scripts_subscribed.to_string()).unwrap_or("-".into()),
+        b.streaming.map(|s| s.scripts_subscribed.to_string()).unwrap_or("-".into()),
+    );
+
+    println!(
+        "{:<15} | {:<15} | {:<15}",
+        "Histories",
+        a.streaming.map(|s| s.histories_processed.to_string()).unwrap_or("-".into()),
+        b.streaming.map(|s| s.histories_processed.to_string()).unwrap_or("-".into()),
+    );
+
+    println!(
+        "{:<15} | {:<15} | {:<15}",
+        "Txs Applied",
+        a.streaming.map(|s| s.txs_applied.to_string()).unwrap_or("-".into()),
+        b.streaming.map(|s| s.txs_applied.to_string()).unwrap_or("-".into()),
+    );
+
     let speedup = a.total_time.as_secs_f64() / b.total_time.as_secs_f64();
 
     println!("--------------------------------------------------");
     println!("Speedup: {:.2}x", speedup);
     println!("==================================================");
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC: &str = "wpkh(tpub/0/*)";
+
+    fn parse(args: &[&str]) -> Command {
+        let mut argv = vec!["bin"];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv).unwrap().command
+    }
+
+    #[test]
+    fn each_subcommand_parses_its_options() {
+        match parse(&["poll", "--descriptor", DESC, "--rounds", "4"]) {
+            Command::Poll { wallet, poll } => {
+                assert_eq!(wallet.descriptor, DESC);
+                assert_eq!(poll.rounds, 4);
+            }
+            _ => panic!("expected poll"),
+        }
+
+        match parse(&["stream", "--descriptor", DESC, "--follow", "--bulk-initial-apply", "--verify-balance"]) {
+            Command::Stream { stream, .. } => {
+                assert!(stream.follow && stream.bulk_initial_apply && stream.verify_balance)
+            }
+            _ => panic!("expected stream"),
+        }
+
+        match parse(&["both", "--descriptor", DESC, "--electrum-url", "tcp://localhost:50001"]) {
+            Command::Both { wallet, poll, stream } => {
+                assert_eq!(wallet.electrum_url, "tcp://localhost:50001");
+                assert_eq!(poll.rounds, 10);
+                assert!(!stream.follow);
+            }
+            _ => panic!("expected both"),
+        }
+
+        assert!(matches!(parse(&["auto", "--descriptor", DESC]), Command::Auto { .. }));
+
+        match parse(&["bench", "--descriptor", DESC, "--iterations", "5"]) {
+            Command::Bench { iterations, .. } => assert_eq!(iterations, 5),
+            _ => panic!("expected bench"),
+        }
+
+        match parse(&["broadcast", "0200", "--electrum-url", "tcp://localhost:50001"]) {
+            Command::Broadcast { tx_hex, electrum_url } => {
+                assert_eq!(tx_hex, "0200");
+                assert_eq!(electrum_url, "tcp://localhost:50001");
+            }
+            _ => panic!("expected broadcast"),
+        }
+    }
+
+    #[test]
+    fn watch_descriptors_are_subscribed_in_full_windows() {
+        use bdk_electrum_streaming_poc::{EngineCommand, EngineEvent, SyncEngine};
+
+        let xpub = "tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M";
+        let cold = format!("cold=wpkh({}/0/*)", xpub);
+        let hot = format!("hot=tr({}/1/*)", xpub);
+        let watched = match parse(&["stream", "--descriptor", DESC, "--watch-descriptor", &cold, "--watch-descriptor", &hot]) {
+            Command::Stream { stream, .. } => stream.watch_descriptors,
+            _ => panic!("expected stream"),
+        };
+        assert_eq!(watched.iter().map(|w| w.name.as_str()).collect::<Vec<_>>(), ["cold", "hot"]);
+        assert!(parse_watch_descriptor(&format!("external=wpkh({}/0/*)", xpub)).is_err());
+        assert!(parse_watch_descriptor("cold").is_err());
+
+        // A keychain left over from an earlier run is dropped.
+        let mut tracker = DerivedSpkTracker::new(4);
+        tracker.insert_descriptor("old".to_string(), watched[0].descriptor.clone(), 0);
+        apply_watch_descriptors(&mut tracker, &watched);
+        assert_eq!(tracker.keychains().cloned().collect::<Vec<_>>(), ["cold", "hot"]);
+
+        let cmds = SyncEngine::new(tracker).handle_event(EngineEvent::Connected);
+        let subscribed = cmds.iter().filter(|c| matches!(c, EngineCommand::Subscribe(_))).count();
+        // Indices 0..=4 of each.
+        assert_eq!(subscribed, 2 * 5);
+    }
+
+    #[test]
+    fn json_comparison_parses_back_with_speedups() {
+        match parse(&["both", "--descriptor", DESC, "--output", "json"]) {
+            Command::Both { wallet, .. } => assert_eq!(wallet.output, OutputFormat::Json),
+            _ => panic!("expected both"),
+        }
+        match parse(&["poll", "--descriptor", DESC]) {
+            Command::Poll { wallet, .. } => assert_eq!(wallet.output, OutputFormat::Human),
+            _ => panic!("expected poll"),
+        }
+
+        let polling = SyncResult {
+            mode: "Polling",
+            total_time: Duration::from_millis(3000),
+            rounds: Some(4),
+            balance: Some(50_000),
+            streaming: None,
+        };
+        let counters = StreamingStats {
+            scripts_subscribed: 40,
+            histories_processed: 42,
+            txs_applied: 7,
+            first_history_latency: Some(Duration::from_millis(120)),
+            ..StreamingStats::default()
+        };
+        let streaming = SyncResult {
+            mode: "Streaming",
+            total_time: Duration::from_millis(750),
+            rounds: None,
+            streaming: Some(counters),
+            ..polling
+        };
+        let json = serde_json::to_string(&comparison_json(&polling, &streaming)).unwrap();
+
+        let parsed: Vec<JsonResult> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                JsonResult {
+                    mode: "Polling".into(),
+                    total_time_ms: 3000,
+                    rounds: Some(4),
+                    balance_sats: Some(50_000),
+                    scripts_subscribed: None,
+                    histories_processed: None,
+                    txs_applied: None,
+                    first_history_ms: None,
+                    speedup: Some(1.0),
+                },
+                JsonResult {
+                    mode: "Streaming".into(),
+                    total_time_ms: 750,
+                    rounds: None,
+                    balance_sats: Some(50_000),
+                    scripts_subscribed: Some(40),
+                    histories_processed: Some(42),
+                    txs_applied: Some(7),
+                    first_history_ms: Some(120),
+                    speedup: Some(4.0),
+                },
+            ]
+        );
+        // A single run has no speedup field at all.
+        let single = serde_json::to_value(JsonResult::new(&polling, None)).unwrap();
+        assert!(single.get("speedup").is_none());
+    }
+
+    #[test]
+    fn options_belong_to_their_subcommand() {
+        let err = Cli::try_parse_from(["bin", "poll", "--descriptor", DESC, "--follow"]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn bench_needs_at_least_one_iteration() {
+        let err = Cli::try_parse_from(["bin", "bench", "--descriptor", DESC, "--iterations", "0"]);
+        assert!(err.is_err());
+        match parse(&["bench", "--descriptor", DESC, "--iterations", "1"]) {
+            Command::Bench { iterations, .. } => assert_eq!(iterations, 1),
+            _ => panic!("expected bench"),
+        }
+    }
+}
@@ -1,27 +1,247 @@
 use anyhow::Result;
 use bdk_wallet::{bitcoin::Network, ChangeSet, KeychainKind, PersistedWallet, Wallet};
-use bdk_wallet::file_store::Store;
+use bdk_wallet::{CreateWithPersistError, FileStoreError, LoadError, LoadMismatch, LoadWithPersistError};
+use bdk_wallet::file_store::{Store, StoreError, StoreErrorWithDump};
+
+use bdk_wallet::bitcoin::consensus::encode::{deserialize_hex, serialize_hex};
+use bitcoin::hashes::sha256;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::streaming::domain::spk_tracker::{DerivedSpkTracker, GapPolicy};
+use crate::streaming::domain::tip::ChainTip;
+use crate::streaming::engine::types::HistoryTx;
 
 pub const DB_PATH: &str = "wallet_db.dat";
 pub const DB_MAGIC: &[u8] = b"bdk_wallet_magic_bytes";
+pub const TIP_PATH: &str = "wallet_tip.dat";
+pub const TRACKER_PATH: &str = "wallet_tracker.dat";
 
 /// Must match the lookahead used by the streaming DerivedSpkTracker.
-const LOOKAHEAD: u32 = 50;
+pub const LOOKAHEAD: u32 = 50;
+
+/// How store operations (opening the store, persisting the wallet) retry
+/// transient I/O failures such as a full disk or a busy file. Fatal errors
+/// (bad magic bytes, undecodable entries, missing permissions) are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreRetry {
+    /// Total tries, including the first; 1 disables retrying.
+    pub attempts: u32,
+    /// Wait before the first retry, doubled for each one after it.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl StoreRetry {
+    pub const NONE: StoreRetry =
+        StoreRetry { attempts: 1, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO };
+}
+
+impl Default for StoreRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Runs `op`, retrying per `retry` while it fails with a transient error
+/// (see `is_transient_store_error`). `what` names the operation in logs.
+pub fn retry_store_op<T>(retry: &StoreRetry, what: &str, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut backoff = retry.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if attempt < retry.attempts && is_transient_store_error(&e) => {
+                log::warn!("[STORE] {} failed (attempt {}/{}), retrying in {:?}: {:#}", what, attempt, retry.attempts, backoff, e);
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(retry.max_backoff);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Whether a store error is worth retrying: an I/O failure that may clear up
+/// on its own (interrupted, busy, out of space), as opposed to a corrupt or
+/// foreign file.
+pub fn is_transient_store_error(err: &anyhow::Error) -> bool {
+    err.chain().any(is_transient_cause)
+}
+
+fn is_transient_cause(err: &(dyn std::error::Error + 'static)) -> bool {
+    let io_err = if let Some(e) = err.downcast_ref::<io::Error>() {
+        Some(e)
+    } else if let Some(e) = err.downcast_ref::<StoreError>() {
+        store_io_error(e)
+    } else if let Some(e) = err.downcast_ref::<StoreErrorWithDump<ChangeSet>>() {
+        store_io_error(&e.error)
+    } else if let Some(e) = err.downcast_ref::<FileStoreError>() {
+        match e {
+            FileStoreError::Load(e) => store_io_error(&e.error),
+            FileStoreError::Write(e) => Some(e),
+        }
+    } else {
+        None
+    };
+    io_err.is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ResourceBusy
+                | io::ErrorKind::StorageFull
+                | io::ErrorKind::QuotaExceeded
+                | io::ErrorKind::OutOfMemory
+        )
+    })
+}
+
+/// `wallet.persist(store)`, retried per `retry`. The staged changes stay
+/// staged across failed attempts, so a retry writes the same changeset.
+pub fn persist_with_retry(
+    wallet: &mut PersistedWallet<Store<ChangeSet>>,
+    store: &mut Store<ChangeSet>,
+    retry: &StoreRetry,
+) -> Result<bool> {
+    retry_store_op(retry, "persisting the wallet", || Ok(wallet.persist(store)?))
+}
+
+fn store_io_error(err: &StoreError) -> Option<&io::Error> {
+    match err {
+        StoreError::Io(e) => Some(e),
+        _ => None,
+    }
+}
+
+/// Where a wallet's store lives and how the wallet is opened (see `open_wallet`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalletConfig {
+    pub db_path: PathBuf,
+    /// Magic bytes at the head of the store file.
+    pub db_magic: Vec<u8>,
+    /// Addresses revealed (and looked ahead) on each keychain.
+    pub lookahead: u32,
+    pub network: Network,
+    /// How opening the store retries transient I/O errors.
+    pub store_retry: StoreRetry,
+}
+
+impl WalletConfig {
+    /// The defaults: `DB_PATH`, `DB_MAGIC` and `LOOKAHEAD`.
+    pub fn new(network: Network) -> Self {
+        Self {
+            db_path: DB_PATH.into(),
+            db_magic: DB_MAGIC.to_vec(),
+            lookahead: LOOKAHEAD,
+            network,
+            store_retry: StoreRetry::default(),
+        }
+    }
+}
+
+/// Why `open_wallet` couldn't hand back a wallet.
+#[derive(Debug, thiserror::Error)]
+pub enum WalletError {
+    /// The store file couldn't be opened (after retries), or isn't a store
+    /// with the expected magic bytes.
+    #[error("cannot open the wallet store {}: {source}", path.display())]
+    Store {
+        path: PathBuf,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A given descriptor isn't the stored one, or only one side has one.
+    #[error("{}", describe_descriptor_mismatch(*keychain, stored.as_deref(), given.as_deref(), path))]
+    DescriptorMismatch {
+        keychain: KeychainKind,
+        stored: Option<String>,
+        given: Option<String>,
+        path: PathBuf,
+    },
+    /// The stored wallet is for another network.
+    #[error("the wallet stored in {} is for {stored}, not {given}", path.display())]
+    NetworkMismatch {
+        path: PathBuf,
+        stored: Network,
+        given: Network,
+    },
+    /// The stored wallet couldn't be loaded for any other reason.
+    #[error("cannot load the wallet stored in {}: {source}", path.display())]
+    Load {
+        path: PathBuf,
+        source: Box<LoadWithPersistError<FileStoreError>>,
+    },
+    /// No wallet was stored, and a new one couldn't be created.
+    #[error("cannot create a wallet in {}: {source}", path.display())]
+    Create {
+        path: PathBuf,
+        source: Box<CreateWithPersistError<FileStoreError>>,
+    },
+}
 
 pub fn setup_wallet(
     descriptor: String,
     change_descriptor: Option<String>,
     network: Network,
-) -> Result<PersistedWallet<Store<ChangeSet>>> {
+) -> Result<PersistedWallet<Store<ChangeSet>>, WalletError> {
+    setup_wallet_with_store(descriptor, change_descriptor, network).map(|(wallet, _)| wallet)
+}
+
+/// Like `setup_wallet`, but also returns the open store so later changes
+/// (e.g. addresses revealed while streaming) can be persisted.
+pub fn setup_wallet_with_store(
+    descriptor: String,
+    change_descriptor: Option<String>,
+    network: Network,
+) -> Result<(PersistedWallet<Store<ChangeSet>>, Store<ChangeSet>), WalletError> {
+    setup_wallet_with_retry(descriptor, change_descriptor, network, StoreRetry::default())
+}
+
+/// Like `setup_wallet_with_store`, with an explicit retry policy for opening the store.
+pub fn setup_wallet_with_retry(
+    descriptor: String,
+    change_descriptor: Option<String>,
+    network: Network,
+    retry: StoreRetry,
+) -> Result<(PersistedWallet<Store<ChangeSet>>, Store<ChangeSet>), WalletError> {
+    open_wallet(descriptor, change_descriptor, &WalletConfig { store_retry: retry, ..WalletConfig::new(network) })
+}
+
+/// Loads the wallet stored per `config`, or creates it there, and returns it
+/// with the open store. Wallets with different `db_path`s are independent,
+/// so one process can run several.
+pub fn open_wallet(
+    descriptor: String,
+    change_descriptor: Option<String>,
+    config: &WalletConfig,
+) -> Result<(PersistedWallet<Store<ChangeSet>>, Store<ChangeSet>), WalletError> {
+    let db_path = config.db_path.as_path();
+    let network = config.network;
+
     // Open or create the file store
-    let (mut db, _) = Store::<ChangeSet>::load_or_create(DB_MAGIC, DB_PATH)?;
+    let (mut db, _) = retry_store_op(&config.store_retry, "opening the wallet store", || {
+        Ok(Store::<ChangeSet>::load_or_create(&config.db_magic, db_path)?)
+    })
+    .map_err(|e| WalletError::Store { path: db_path.into(), source: e.into() })?;
 
-    // Try to load existing wallet
-    let wallet_opt = Wallet::load()
+    // Try to load existing wallet; the given descriptors must be the stored ones.
+    // Without a change descriptor, the stored wallet's own change keychain is used.
+    let mut params = Wallet::load()
         .descriptor(KeychainKind::External, Some(descriptor.clone()))
-        .descriptor(KeychainKind::Internal, change_descriptor.clone())
-        .check_network(network)
-        .load_wallet(&mut db)?;
+        .lookahead(config.lookahead)
+        .check_network(network);
+    if let Some(change) = &change_descriptor {
+        params = params.descriptor(KeychainKind::Internal, Some(change.clone()));
+    }
+    let wallet_opt = params
+        .load_wallet(&mut db)
+        .map_err(|e| describe_load_error(e, db_path))?;
 
     let mut wallet = match wallet_opt {
         Some(wallet) => {
@@ -29,15 +249,23 @@ pub fn setup_wallet(
             wallet
         }
         None => {
-            log::info!("[WALLET] Creating new...");
-            let change_desc = change_descriptor
-                .clone()
-                .expect("[WALLET] Change descriptor required for new wallet");
-
-            Wallet::create(descriptor, change_desc)
+            // Without a change descriptor, a receive-only wallet: change
+            // goes back to the external keychain.
+            let params = match change_descriptor {
+                Some(change) => {
+                    log::info!("[WALLET] Creating new...");
+                    Wallet::create(descriptor, change)
+                }
+                None => {
+                    log::info!("[WALLET] Creating new single-descriptor (receive-only) wallet...");
+                    Wallet::create_single(descriptor)
+                }
+            };
+            params
                 .network(network)
-                .lookahead(LOOKAHEAD)
-                .create_wallet(&mut db)?
+                .lookahead(config.lookahead)
+                .create_wallet(&mut db)
+                .map_err(|source| WalletError::Create { path: db_path.into(), source: Box::new(source) })?
         }
     };
 
@@ -45,12 +273,502 @@ pub fn setup_wallet(
     // This is critical when loading a wallet that was previously created with a
     // smaller lookahead — the persisted state won't cover higher-index addresses
     // where change outputs may have landed.
-    let _ = wallet.reveal_addresses_to(KeychainKind::External, LOOKAHEAD);
-    let _ = wallet.reveal_addresses_to(KeychainKind::Internal, LOOKAHEAD);
+    let _ = wallet.reveal_addresses_to(KeychainKind::External, config.lookahead);
+    let _ = wallet.reveal_addresses_to(KeychainKind::Internal, config.lookahead);
     log::info!(
         "[WALLET] Revealed addresses to index {} for both keychains",
-        LOOKAHEAD
+        config.lookahead
     );
 
-    Ok(wallet)
-}
\ No newline at end of file
+    Ok((wallet, db))
+}
+
+/// Sorts out the load errors callers may want to act on: pointing a wallet
+/// DB at the wrong keys or network isn't mistaken for a corrupt file.
+fn describe_load_error(err: LoadWithPersistError<FileStoreError>, db_path: &Path) -> WalletError {
+    let path = db_path.to_path_buf();
+    match err {
+        LoadWithPersistError::InvalidChangeSet(LoadError::Mismatch(LoadMismatch::Descriptor {
+            keychain,
+            loaded,
+            expected,
+        })) => WalletError::DescriptorMismatch {
+            keychain,
+            stored: loaded.map(|d| d.to_string()),
+            given: expected.map(|d| d.to_string()),
+            path,
+        },
+        LoadWithPersistError::InvalidChangeSet(LoadError::Mismatch(LoadMismatch::Network { loaded, expected })) => {
+            WalletError::NetworkMismatch { path, stored: loaded, given: expected }
+        }
+        source => WalletError::Load { path, source: Box::new(source) },
+    }
+}
+
+/// The message of `WalletError::DescriptorMismatch`, naming the keychain.
+fn describe_descriptor_mismatch(
+    keychain: KeychainKind,
+    stored: Option<&str>,
+    given: Option<&str>,
+    db_path: &Path,
+) -> String {
+    let which = match keychain {
+        KeychainKind::External => "external (receive)",
+        KeychainKind::Internal => "internal (change)",
+    };
+    match (stored, given) {
+        (Some(stored), Some(given)) => format!(
+            "{} descriptor does not match the wallet stored in {}: stored {}, given {}",
+            which, db_path.display(), stored, given
+        ),
+        (Some(stored), None) => format!(
+            "no {} descriptor given, but the wallet stored in {} has {}",
+            which, db_path.display(), stored
+        ),
+        (None, given) => format!(
+            "{} descriptor given ({}), but the wallet stored in {} has none",
+            which,
+            given.unwrap_or_default(),
+            db_path.display()
+        ),
+    }
+}
+
+/// Builds the streaming script tracker for `wallet`'s keychains (just the
+/// external one for a single-descriptor wallet).
+///
+/// Each keychain's window starts at the wallet's highest revealed index, not 0:
+/// a wallet revealed beyond `LOOKAHEAD` elsewhere would otherwise have funds on
+/// addresses the tracker never watches. Change extends eagerly (see `GapPolicy`).
+pub fn tracker_for_wallet(wallet: &Wallet, lookahead: u32) -> DerivedSpkTracker<String> {
+    let mut tracker = DerivedSpkTracker::new(lookahead)
+        .with_gap_policy(KeychainKind::Internal.to_string(), GapPolicy { lookahead, eager: true });
+    for (keychain, _) in wallet.keychains() {
+        let next_index = wallet.derivation_index(keychain).unwrap_or(0);
+        log::debug!("[WALLET] {} keychain revealed to index {}", keychain, next_index);
+        tracker.insert_descriptor(
+            keychain.to_string(),
+            wallet.public_descriptor(keychain).clone(),
+            next_index,
+        );
+    }
+    tracker
+}
+
+/// Like `tracker_for_wallet`, but starting from the tracker saved at `path`
+/// (see `SyncOrchestrator::with_persisted_tracker`), so a warm start watches
+/// everything derived in earlier runs, including windows extended past the
+/// wallet's revealed index, straight away.
+///
+/// Each keychain is still brought up to the wallet's revealed index, since
+/// the wallet may have been persisted after the tracker. A missing or
+/// unreadable save, or one tracking other descriptors or another lookahead,
+/// falls back to `tracker_for_wallet`.
+pub fn restore_tracker(path: impl AsRef<Path>, wallet: &Wallet, lookahead: u32) -> DerivedSpkTracker<String> {
+    let path = path.as_ref();
+    let saved = std::fs::read(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| DerivedSpkTracker::from_bytes(&bytes));
+    let mut tracker = match saved {
+        Ok(tracker) => tracker,
+        Err(e) => {
+            if path.exists() {
+                log::warn!("[WALLET] Ignoring unreadable tracker file {}: {}", path.display(), e);
+            }
+            return tracker_for_wallet(wallet, lookahead);
+        }
+    };
+    for (keychain, _) in wallet.keychains() {
+        let name = keychain.to_string();
+        if tracker.descriptor(&name) != Some(wallet.public_descriptor(keychain)) {
+            log::warn!("[WALLET] Saved tracker doesn't match the wallet's {} keychain; re-deriving", keychain);
+            return tracker_for_wallet(wallet, lookahead);
+        }
+        if tracker.lookahead_of(&name) != lookahead {
+            log::warn!(
+                "[WALLET] Saved tracker looks {} ahead on the {} keychain, the wallet {}; re-deriving",
+                tracker.lookahead_of(&name),
+                keychain,
+                lookahead
+            );
+            return tracker_for_wallet(wallet, lookahead);
+        }
+        if let Some(index) = wallet.derivation_index(keychain) {
+            tracker.mark_revealed_and_derive_new(&name, index);
+        }
+    }
+    log::info!("[WALLET] Restored script tracker from {}", path.display());
+    tracker
+}
+
+/// Writes `tracker` to `path` (see `DerivedSpkTracker::to_bytes`).
+pub fn save_tracker<K>(path: impl AsRef<Path>, tracker: &DerivedSpkTracker<K>) -> Result<()>
+where
+    K: Ord + Clone + serde::Serialize,
+{
+    std::fs::write(path, tracker.to_bytes()?)?;
+    Ok(())
+}
+
+/// Writes `tip` to `path` as `<height> <header hex>`.
+pub fn save_tip(path: impl AsRef<Path>, tip: &ChainTip) -> Result<()> {
+    std::fs::write(path, format!("{} {}\n", tip.height, serialize_hex(&tip.header)))?;
+    Ok(())
+}
+
+/// Reads a tip written by `save_tip`; `None` if the file doesn't exist yet.
+pub fn load_tip(path: impl AsRef<Path>) -> Result<Option<ChainTip>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let (height, header) = contents
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("malformed tip file"))?;
+    Ok(Some(ChainTip { height: height.parse()?, header: deserialize_hex(header)? }))
+}
+
+/// Where the bootstrap progress of the wallet stored at `db_path` is
+/// recorded: beside the store, so each wallet resumes only its own scan
+/// (`wallet_db.dat` gets `wallet_db.bootstrap.dat`).
+pub fn bootstrap_progress_path(db_path: impl AsRef<Path>) -> PathBuf {
+    let db_path = db_path.as_ref();
+    let stem = db_path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match db_path.extension() {
+        Some(ext) => format!("{}.bootstrap.{}", stem, ext.to_string_lossy()),
+        None => format!("{}.bootstrap", stem),
+    };
+    db_path.with_file_name(name)
+}
+
+/// Appends a scripthash whose bootstrap history has been applied and persisted.
+pub fn record_bootstrap_progress(path: impl AsRef<Path>, hash: &sha256::Hash) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", hash)?;
+    Ok(())
+}
+
+/// Reads the scripthashes recorded by `record_bootstrap_progress`; empty if
+/// there is no unfinished bootstrap. A line torn by a crash is skipped, so
+/// that script is simply fetched again.
+pub fn load_bootstrap_progress(path: impl AsRef<Path>) -> Result<HashSet<sha256::Hash>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents.lines().filter_map(|line| line.trim().parse().ok()).collect())
+}
+
+/// Reads transactions exported from another wallet: one `<raw tx hex> <height>`
+/// per line, height 0 (or omitted) for unconfirmed. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn load_seed_transactions(path: impl AsRef<Path>) -> Result<Vec<HistoryTx>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut txs = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let tx = deserialize_hex(fields.next().unwrap_or_default())
+            .map_err(|e| anyhow::anyhow!("line {}: bad transaction: {}", n + 1, e))?;
+        let height = match fields.next() {
+            Some(height) => height.parse().map_err(|e| anyhow::anyhow!("line {}: bad height: {}", n + 1, e))?,
+            None => 0,
+        };
+        txs.push(HistoryTx { tx, height, verified: true });
+    }
+    Ok(txs)
+}
+
+/// A script's resolved history as of the status the server reported for it.
+#[derive(Debug, Clone)]
+pub struct CachedHistory {
+    pub status: Option<String>,
+    pub txs: Vec<HistoryTx>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedHistoryEntry {
+    status: Option<String>,
+    /// `(raw tx hex, height, verified)`
+    txs: Vec<(String, i32, bool)>,
+}
+
+/// Writes `histories` to `path` as JSON, through a temporary file so a crash
+/// mid-write leaves the previous cache intact.
+pub fn save_history_cache(path: impl AsRef<Path>, histories: &HashMap<sha256::Hash, CachedHistory>) -> Result<()> {
+    let entries: HashMap<String, CachedHistoryEntry> = histories
+        .iter()
+        .map(|(hash, history)| {
+            let txs = history.txs.iter().map(|h| (serialize_hex(&h.tx), h.height, h.verified)).collect();
+            (hash.to_string(), CachedHistoryEntry { status: history.status.clone(), txs })
+        })
+        .collect();
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Reads histories written by `save_history_cache`; empty if the file doesn't
+/// exist yet.
+pub fn load_history_cache(path: impl AsRef<Path>) -> Result<HashMap<sha256::Hash, CachedHistory>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let entries: HashMap<String, CachedHistoryEntry> = serde_json::from_slice(&contents)?;
+    entries
+        .into_iter()
+        .map(|(hash, entry)| {
+            let txs = entry
+                .txs
+                .into_iter()
+                .map(|(tx, height, verified)| Ok(HistoryTx { tx: deserialize_hex(&tx)?, height, verified }))
+                .collect::<Result<_>>()?;
+            Ok((hash.parse()?, CachedHistory { status: entry.status, txs }))
+        })
+        .collect()
+}
+
+/// Forgets bootstrap progress once the bootstrap has finished.
+pub fn clear_bootstrap_progress(path: impl AsRef<Path>) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    fn config_at(db_path: &Path) -> WalletConfig {
+        WalletConfig { db_path: db_path.into(), store_retry: StoreRetry::NONE, ..WalletConfig::new(Network::Testnet) }
+    }
+
+    #[test]
+    fn mismatched_change_descriptor_is_named_on_reload() {
+        let external = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+        let internal = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)";
+        let wrong_internal = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/2/*)";
+        let db_path = std::env::temp_dir().join(format!("bdk_test_mismatch_{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+
+        let (wallet, db) =
+            open_wallet(external.into(), Some(internal.into()), &config_at(&db_path))
+                .unwrap();
+        drop((wallet, db));
+
+        let err = open_wallet(external.into(), Some(wrong_internal.into()), &config_at(&db_path))
+            .err()
+            .unwrap();
+        let other_network = open_wallet(
+            external.into(),
+            Some(internal.into()),
+            &WalletConfig { network: Network::Signet, ..config_at(&db_path) },
+        );
+        let _ = std::fs::remove_file(&db_path);
+
+        assert!(matches!(err, WalletError::DescriptorMismatch { keychain: KeychainKind::Internal, .. }), "{:?}", err);
+        let err = err.to_string();
+        assert!(err.starts_with("internal (change) descriptor does not match"), "{}", err);
+        assert!(err.contains("/1/*") && err.contains("/2/*"), "{}", err);
+        assert!(matches!(
+            other_network.err().unwrap(),
+            WalletError::NetworkMismatch { stored: Network::Testnet, given: Network::Signet, .. }
+        ));
+    }
+
+    #[test]
+    fn receive_only_wallet_is_created_and_reloaded_without_a_change_descriptor() {
+        let external = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+        let db_path = std::env::temp_dir().join(format!("bdk_test_single_{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+
+        let (wallet, db) = open_wallet(external.into(), None, &config_at(&db_path)).unwrap();
+        assert_eq!(wallet.keychains().count(), 1);
+        let tracker = tracker_for_wallet(&wallet, LOOKAHEAD);
+        assert_eq!(tracker.max_derived_index(&KeychainKind::Internal.to_string()), None);
+        drop((wallet, db));
+
+        let reloaded = open_wallet(external.into(), None, &config_at(&db_path));
+        let _ = std::fs::remove_file(&db_path);
+        assert_eq!(reloaded.unwrap().0.keychains().count(), 1);
+    }
+
+    #[test]
+    fn wallets_at_different_paths_do_not_clobber_each_other() {
+        let descriptor = |account: u32, chain: u32| {
+            format!("wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/{}/{}/*)", account, chain)
+        };
+        let dir = std::env::temp_dir();
+        let first = WalletConfig { lookahead: 5, ..config_at(&dir.join(format!("bdk_test_first_{}.dat", std::process::id()))) };
+        let second = WalletConfig {
+            db_magic: b"second_wallet".to_vec(),
+            ..config_at(&dir.join(format!("bdk_test_second_{}.dat", std::process::id())))
+        };
+        for config in [&first, &second] {
+            let _ = std::fs::remove_file(&config.db_path);
+        }
+
+        let (mut a, mut a_db) = open_wallet(descriptor(0, 0), Some(descriptor(0, 1)), &first).unwrap();
+        let (mut b, mut b_db) = open_wallet(descriptor(1, 0), Some(descriptor(1, 1)), &second).unwrap();
+        a.persist(&mut a_db).unwrap();
+        b.persist(&mut b_db).unwrap();
+        let (a_addr, b_addr) = (a.peek_address(KeychainKind::External, 0), b.peek_address(KeychainKind::External, 0));
+        drop((a, a_db, b, b_db));
+
+        // Each reopens with its own keys, lookahead and magic.
+        let (a, _a_db) = open_wallet(descriptor(0, 0), Some(descriptor(0, 1)), &first).unwrap();
+        let (b, _b_db) = open_wallet(descriptor(1, 0), Some(descriptor(1, 1)), &second).unwrap();
+        let wrong_magic = open_wallet(descriptor(1, 0), None, &WalletConfig { db_magic: DB_MAGIC.to_vec(), ..second.clone() });
+        for config in [&first, &second] {
+            let _ = std::fs::remove_file(&config.db_path);
+        }
+
+        assert_eq!(a.peek_address(KeychainKind::External, 0), a_addr);
+        assert_eq!(b.peek_address(KeychainKind::External, 0), b_addr);
+        assert_ne!(a_addr, b_addr);
+        assert_eq!(a.derivation_index(KeychainKind::External), Some(5));
+        assert_eq!(b.derivation_index(KeychainKind::External), Some(LOOKAHEAD));
+        assert!(matches!(wrong_magic.err().unwrap(), WalletError::Store { .. }));
+    }
+
+    #[test]
+    fn transient_store_failure_is_retried_and_fatal_one_is_not() {
+        let retry = StoreRetry { attempts: 3, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+
+        // A write that hits a full disk once, then goes through.
+        let mut attempts = 0;
+        let persisted = retry_store_op(&retry, "persist", || {
+            attempts += 1;
+            if attempts == 1 {
+                return Err(FileStoreError::Write(io::ErrorKind::StorageFull.into()).into());
+            }
+            Ok(true)
+        });
+        assert!(persisted.unwrap());
+        assert_eq!(attempts, 2);
+
+        // A store written by something else fails once, without retries.
+        let db_path = std::env::temp_dir().join(format!("bdk_test_foreign_{}.dat", std::process::id()));
+        std::fs::write(&db_path, b"not a bdk wallet store at all").unwrap();
+        let mut attempts = 0;
+        let opened = retry_store_op(&retry, "open", || {
+            attempts += 1;
+            Ok(Store::<ChangeSet>::load_or_create(DB_MAGIC, &db_path)?)
+        });
+        let _ = std::fs::remove_file(&db_path);
+        let err = opened.err().unwrap();
+        let err = err.downcast_ref::<StoreErrorWithDump<ChangeSet>>().unwrap();
+        assert!(matches!(err.error, StoreError::InvalidMagicBytes { .. }));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn each_store_gets_its_own_bootstrap_progress() {
+        assert_eq!(bootstrap_progress_path(DB_PATH), PathBuf::from("wallet_db.bootstrap.dat"));
+        assert_eq!(bootstrap_progress_path("/data/alice/db"), PathBuf::from("/data/alice/db.bootstrap"));
+        assert_ne!(bootstrap_progress_path("alice.dat"), bootstrap_progress_path("bob.dat"));
+    }
+
+    #[test]
+    fn history_cache_round_trips() {
+        let path = std::env::temp_dir().join(format!("bdk_test_history_cache_{}.json", std::process::id()));
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(1_000), script_pubkey: bitcoin::ScriptBuf::new() }],
+        };
+        let hash = sha256::Hash::hash(b"script");
+        let histories = HashMap::from([(
+            hash,
+            CachedHistory {
+                status: Some("abcd".into()),
+                txs: vec![HistoryTx { tx: tx.clone(), height: 120, verified: false }],
+            },
+        )]);
+
+        save_history_cache(&path, &histories).unwrap();
+        let loaded = load_history_cache(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let cached = &loaded[&hash];
+        assert_eq!(cached.status.as_deref(), Some("abcd"));
+        assert_eq!(cached.txs.len(), 1);
+        assert_eq!((&cached.txs[0].tx, cached.txs[0].height, cached.txs[0].verified), (&tx, 120, false));
+        // No file yet is an empty cache, not an error.
+        assert!(load_history_cache(&path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tracker_window_follows_the_wallet_lookahead() {
+        let external = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+        let internal = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)";
+        let db_path = std::env::temp_dir().join(format!("bdk_test_lookahead_{}.dat", std::process::id()));
+        let tracker_path = db_path.with_extension("tracker");
+        let _ = std::fs::remove_file(&db_path);
+        let config = WalletConfig { lookahead: 7, ..config_at(&db_path) };
+
+        let (wallet, _db) = open_wallet(external.into(), Some(internal.into()), &config).unwrap();
+        let _ = std::fs::remove_file(&db_path);
+        let lookahead = wallet.spk_index().lookahead();
+        assert_eq!(lookahead, 7);
+
+        // Revealed to 7, watched a full lookahead beyond.
+        let tracker = tracker_for_wallet(&wallet, lookahead);
+        for keychain in [KeychainKind::External, KeychainKind::Internal] {
+            assert_eq!(tracker.lookahead_of(&keychain.to_string()), 7);
+            assert_eq!(tracker.max_derived_index(&keychain.to_string()), Some(14));
+        }
+
+        // A tracker saved with another lookahead is re-derived.
+        save_tracker(&tracker_path, &tracker_for_wallet(&wallet, 20)).unwrap();
+        let restored = restore_tracker(&tracker_path, &wallet, lookahead);
+        let _ = std::fs::remove_file(&tracker_path);
+        assert_eq!(restored.max_derived_index(&KeychainKind::External.to_string()), Some(14));
+    }
+
+    #[test]
+    fn tracker_covers_indices_revealed_beyond_lookahead() {
+        let mut wallet = Wallet::create(
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)",
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)",
+        )
+        .network(Network::Testnet)
+        .create_wallet_no_persist()
+        .unwrap();
+        let _ = wallet.reveal_addresses_to(KeychainKind::External, 100);
+
+        let tracker = tracker_for_wallet(&wallet, LOOKAHEAD);
+
+        assert_eq!(tracker.max_derived_index(&KeychainKind::External.to_string()), Some(100 + LOOKAHEAD));
+        // Nothing revealed on the change keychain: the window starts at 0.
+        assert_eq!(tracker.max_derived_index(&KeychainKind::Internal.to_string()), Some(LOOKAHEAD));
+
+        // The very first subscriptions already cover every revealed address.
+        let cmds = crate::streaming::engine::SyncEngine::new(tracker)
+            .handle_event(crate::streaming::engine::EngineEvent::Connected);
+        let subscribed: HashSet<sha256::Hash> = cmds
+            .iter()
+            .filter_map(|c| match c {
+                crate::streaming::engine::EngineCommand::Subscribe(h) => Some(*h),
+                _ => None,
+            })
+            .collect();
+        for index in 0..=100 {
+            let spk = wallet.peek_address(KeychainKind::External, index).script_pubkey();
+            assert!(subscribed.contains(&crate::streaming::util::script_hash(&spk)), "index {} not subscribed", index);
+        }
+    }
+}
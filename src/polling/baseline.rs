@@ -2,33 +2,115 @@
 
 use anyhow::Result;
 use bdk_electrum::BdkElectrumClient;
-use bdk_wallet::{PersistedWallet, ChangeSet};
+use bdk_wallet::chain::spk_client::{FullScanRequest, FullScanResponse};
+use bdk_wallet::{PersistedWallet, ChangeSet, KeychainKind, Wallet};
 use bdk_wallet::file_store::Store;
+use bdk_wallet::bitcoin::hashes::{sha256, Hash};
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
-const MARKER_FILE: &str = "initial_scan_done.marker";
 pub struct SyncStats {
     pub total_time: Duration,
     pub rounds: usize,
+    /// One entry per round, to see when discovery converged.
+    pub round_stats: Vec<RoundStat>,
 }
 
-fn has_done_initial_scan() -> bool {
-    std::path::Path::new(MARKER_FILE).exists()
+/// What a single full-scan round added to the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundStat {
+    pub round: usize,
+    pub duration: Duration,
+    /// Transactions the wallet did not have before this round.
+    pub new_txs: usize,
+    /// How many indices this round added to the revealed range, over both keychains.
+    pub highest_index_delta: u32,
 }
 
-fn mark_initial_scan_done() -> std::io::Result<()> {
-    std::fs::write(MARKER_FILE, b"ok")
+/// The one Electrum call the polling baseline makes; implemented by
+/// `BdkElectrumClient`, and by scripted clients in tests.
+pub trait FullScanClient {
+    fn full_scan(
+        &self,
+        request: FullScanRequest<KeychainKind>,
+        stop_gap: usize,
+        batch_size: usize,
+    ) -> Result<FullScanResponse<KeychainKind>>;
 }
 
+impl FullScanClient for BdkElectrumClient<bdk_electrum::electrum_client::Client> {
+    fn full_scan(
+        &self,
+        request: FullScanRequest<KeychainKind>,
+        stop_gap: usize,
+        batch_size: usize,
+    ) -> Result<FullScanResponse<KeychainKind>> {
+        Ok(BdkElectrumClient::full_scan(self, request, stop_gap, batch_size, false)?)
+    }
+}
+
+/// Tx count and revealed range, compared before and after each round.
+struct WalletMark {
+    txs: usize,
+    revealed: u32,
+}
+
+impl WalletMark {
+    fn of(wallet: &Wallet) -> Self {
+        let revealed = wallet
+            .keychains()
+            .filter_map(|(k, _)| wallet.derivation_index(k).map(|i| i + 1))
+            .sum();
+        Self { txs: wallet.tx_graph().full_txs().count(), revealed }
+    }
+
+    fn round_stat(&self, after: &WalletMark, round: usize, duration: Duration) -> RoundStat {
+        RoundStat {
+            round,
+            duration,
+            new_txs: after.txs.saturating_sub(self.txs),
+            highest_index_delta: after.revealed.saturating_sub(self.revealed),
+        }
+    }
+}
+
+/// Identifies a wallet by its public descriptors and network, so each one
+/// keeps its own cold/warm scan state.
+pub fn wallet_fingerprint(wallet: &Wallet) -> String {
+    let mut id = String::new();
+    for (keychain, descriptor) in wallet.keychains() {
+        id.push_str(&format!("{:?}:{}\n", keychain, descriptor));
+    }
+    id.push_str(&wallet.network().to_string());
+    sha256::Hash::hash(id.as_bytes()).to_string()[..16].to_string()
+}
+
+/// The marker recording that the wallet with `fingerprint` had its cold scan.
+pub fn scan_marker_path(fingerprint: &str) -> PathBuf {
+    PathBuf::from(format!("initial_scan_done.{}.marker", fingerprint))
+}
+
+fn has_done_initial_scan(marker: &Path) -> bool {
+    marker.exists()
+}
+
+fn mark_initial_scan_done(marker: &Path) -> std::io::Result<()> {
+    std::fs::write(marker, b"ok")
+}
+
+/// Cold scan on the first run for the wallet with `fingerprint` (see
+/// `wallet_fingerprint`), warm scans after that.
 pub fn auto_sync(
     wallet: &mut PersistedWallet<Store<ChangeSet>>,
-    client: &BdkElectrumClient<bdk_electrum::electrum_client::Client>,
+    client: &impl FullScanClient,
     rounds: usize,
+    fingerprint: &str,
 ) -> Result<SyncStats> {
-    if !has_done_initial_scan() {
-        log::info!("[SYNC] No scan marker: running COLD START scan");
+    let marker = scan_marker_path(fingerprint);
+    if !has_done_initial_scan(&marker) {
+        log::info!("[SYNC] No scan marker {}: running COLD START scan", marker.display());
         let stats = cold_start_sync(wallet, client, rounds)?;
-        mark_initial_scan_done()?;
+        mark_initial_scan_done(&marker)?;
         Ok(stats)
     } else {
         log::info!("[SYNC] First WARM run after restart will still be slow (no streaming cache yet)");
@@ -38,22 +120,28 @@ pub fn auto_sync(
 
 pub fn cold_start_sync(
     wallet: &mut PersistedWallet<Store<ChangeSet>>,
-    client: &BdkElectrumClient<bdk_electrum::electrum_client::Client>,
+    client: &impl FullScanClient,
     rounds: usize,
 ) -> Result<SyncStats> {
     log::info!("[COLD] Starting progressive sync...");
     let global_start = Instant::now();
+    let mut round_stats = Vec::with_capacity(rounds);
 
     for round in 1..=rounds {
         log::info!("[COLD] Sync round #{} ...", round);
         let round_start = Instant::now();
+        let before = WalletMark::of(wallet);
         let request = wallet.start_full_scan().build();
         // stop_gap = 20 → discovery mode
-        let update = client.full_scan(request, 20, 5, false)?;
+        let update = client.full_scan(request, 20, 5)?;
         wallet.apply_update(update)?;
 
-        let round_time = round_start.elapsed();
-        log::info!("[COLD] Round #{} done in {:?}", round, round_time);
+        let stat = before.round_stat(&WalletMark::of(wallet), round, round_start.elapsed());
+        log::info!(
+            "[COLD] Round #{} done in {:?}: {} new txs, +{} indices",
+            round, stat.duration, stat.new_txs, stat.highest_index_delta
+        );
+        round_stats.push(stat);
     }
 
     let total_time = global_start.elapsed();
@@ -61,27 +149,31 @@ pub fn cold_start_sync(
     Ok(SyncStats {
         total_time,
         rounds,
+        round_stats,
     })
 }
 
 pub fn warm_sync(
     wallet: &mut PersistedWallet<Store<ChangeSet>>,
-    client: &BdkElectrumClient<bdk_electrum::electrum_client::Client>,
+    client: &impl FullScanClient,
 ) -> Result<SyncStats> {
     log::info!("[WARM] Starting incremental sync loop...");
     let rounds = 1;
     let global_start = Instant::now();
+    let mut round_stats = Vec::with_capacity(rounds);
 
     for round in 1..=rounds {
         log::info!("[WARM] Sync round #{} ...", round);
         let round_start = Instant::now();
+        let before = WalletMark::of(wallet);
         // stop_gap = 0  → disables discovery
         let request = wallet.start_full_scan().build();
-        let update = client.full_scan(request, 0, 5, false)?;
+        let update = client.full_scan(request, 0, 5)?;
         wallet.apply_update(update)?;
 
-        let round_time = round_start.elapsed();
-        log::info!("[WARM] Round #{} done in {:?}", round, round_time);
+        let stat = before.round_stat(&WalletMark::of(wallet), round, round_start.elapsed());
+        log::info!("[WARM] Round #{} done in {:?}: {} new txs", round, stat.duration, stat.new_txs);
+        round_stats.push(stat);
     }
 
     let total_time = global_start.elapsed();
@@ -89,10 +181,107 @@ pub fn warm_sync(
     Ok(SyncStats {
         total_time,
         rounds,
+        round_stats,
     })
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk_wallet::bitcoin::{Amount, Network, OutPoint, Transaction, TxIn, TxOut, Txid};
+    use bdk_wallet::bitcoin::absolute::LockTime;
+    use bdk_wallet::bitcoin::hashes::Hash;
+    use bdk_wallet::bitcoin::transaction::Version;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::Arc;
 
+    /// Answers each `full_scan` with the next scripted response.
+    struct ScriptedScans(RefCell<VecDeque<FullScanResponse<KeychainKind>>>);
 
+    impl FullScanClient for ScriptedScans {
+        fn full_scan(
+            &self,
+            _request: FullScanRequest<KeychainKind>,
+            _stop_gap: usize,
+            _batch_size: usize,
+        ) -> Result<FullScanResponse<KeychainKind>> {
+            self.0.borrow_mut().pop_front().ok_or_else(|| anyhow::anyhow!("no more scans scripted"))
+        }
+    }
 
+    #[test]
+    fn each_wallet_gets_its_own_scan_marker() {
+        let xpub = "tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M";
+        let wallet = |account: u32, network: Network| {
+            Wallet::create(
+                format!("wpkh([73c5da0a/84h/1h/0h]{}/{}/0/*)", xpub, account),
+                format!("wpkh([73c5da0a/84h/1h/0h]{}/{}/1/*)", xpub, account),
+            )
+            .network(network)
+            .create_wallet_no_persist()
+            .unwrap()
+        };
+        let first = wallet_fingerprint(&wallet(0, Network::Testnet));
+        let second = wallet_fingerprint(&wallet(1, Network::Testnet));
+        assert_eq!(first, wallet_fingerprint(&wallet(0, Network::Testnet)));
+        assert_ne!(first, second);
+        assert_ne!(first, wallet_fingerprint(&wallet(0, Network::Signet)));
 
+        let dir = std::env::temp_dir().join(format!("bdk_test_markers_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, second) = (dir.join(scan_marker_path(&first)), dir.join(scan_marker_path(&second)));
+        mark_initial_scan_done(&first).unwrap();
+        assert!(has_done_initial_scan(&first));
+        assert!(!has_done_initial_scan(&second));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn round_stats_show_discovery_converging() {
+        let db_path = std::env::temp_dir().join(format!("bdk_test_rounds_{}.dat", std::process::id()));
+        let _ = std::fs::remove_file(&db_path);
+        let mut db = Store::<ChangeSet>::create(b"test", &db_path).unwrap();
+        let mut wallet = Wallet::create(
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)",
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)",
+        )
+        .network(Network::Testnet)
+        .create_wallet(&mut db)
+        .unwrap();
+
+        let payments: Vec<Arc<Transaction>> = (0..4u8)
+            .map(|i| {
+                let spk = wallet.peek_address(KeychainKind::External, i as u32).script_pubkey();
+                Arc::new(Transaction {
+                    version: Version(2),
+                    lock_time: LockTime::ZERO,
+                    input: vec![TxIn {
+                        previous_output: OutPoint { txid: Txid::from_byte_array([i + 1; 32]), vout: 0 },
+                        ..Default::default()
+                    }],
+                    output: vec![TxOut { value: Amount::from_sat(10_000), script_pubkey: spk }],
+                })
+            })
+            .collect();
+        // Each round finds everything earlier rounds did, plus less and less new.
+        let scan = |found: usize, last_active: u32| {
+            let mut response = FullScanResponse::<KeychainKind>::default();
+            for tx in &payments[..found] {
+                response.tx_update.txs.push(tx.clone());
+                response.tx_update.seen_ats.insert((tx.compute_txid(), 1));
+            }
+            response.last_active_indices = BTreeMap::from([(KeychainKind::External, last_active)]);
+            response
+        };
+        let client = ScriptedScans(RefCell::new(VecDeque::from([scan(3, 4), scan(4, 6), scan(4, 6)])));
+
+        let stats = cold_start_sync(&mut wallet, &client, 3).unwrap();
+        let _ = std::fs::remove_file(&db_path);
+
+        let found: Vec<(usize, usize, u32)> =
+            stats.round_stats.iter().map(|r| (r.round, r.new_txs, r.highest_index_delta)).collect();
+        // Round 1 reveals external 0..=4 (the change keychain stays unrevealed).
+        assert_eq!(found, vec![(1, 3, 5), (2, 1, 2), (3, 0, 0)]);
+    }
+}
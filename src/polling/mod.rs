@@ -2,4 +2,7 @@ pub mod baseline;
 pub use baseline::auto_sync;
 pub use baseline::cold_start_sync;
 pub use baseline::warm_sync;
+pub use baseline::wallet_fingerprint;
+pub use baseline::FullScanClient;
+pub use baseline::RoundStat;
 pub use baseline::SyncStats;
@@ -1 +1,2 @@
 pub mod spk_tracker;
+pub mod tip;
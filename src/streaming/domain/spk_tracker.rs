@@ -1,10 +1,110 @@
 // Gap limit + derivation tracker
 
-use std::collections::{btree_map, BTreeMap, HashMap};
-
-use bitcoin::{ScriptBuf};
-use bitcoin::hashes::{sha256, Hash};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap};
+use std::fmt::Debug;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use bitcoin::{Address, Network, ScriptBuf};
+use bitcoin::hashes::sha256;
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
 use bdk_wallet::miniscript::{Descriptor, DescriptorPublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::streaming::util::script_hash;
+
+/// Ranges at least this long are derived on all cores (with the
+/// `parallel-derivation` feature); shorter ones aren't worth the fan-out.
+pub const PARALLEL_DERIVATION_MIN: usize = 1_000;
+
+/// `derive_script` plus the script's Electrum hash, the per-index work of a
+/// range; only the map insertions are left to do serially.
+fn derive_hashed_spk(
+    secp: &Secp256k1<VerifyOnly>,
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    index: u32,
+) -> (sha256::Hash, ScriptBuf) {
+    let spk = derive_script(secp, descriptor, index);
+    (script_hash(&spk), spk)
+}
+
+fn derive_script(
+    secp: &Secp256k1<VerifyOnly>,
+    descriptor: &Descriptor<DescriptorPublicKey>,
+    index: u32,
+) -> ScriptBuf {
+    descriptor
+        .derived_descriptor(secp, index)
+        .expect("can derive")
+        .script_pubkey()
+}
+
+/// How one keychain's lookahead window is maintained (see `with_gap_policy`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GapPolicy {
+    /// Unused scripts watched beyond the highest used index.
+    pub lookahead: u32,
+    /// Extend the window as soon as *any* history contains a tx paying one of
+    /// this keychain's scripts (e.g. the change of a self-send), and refetch
+    /// that script, instead of waiting for its own history. Meant for change,
+    /// which is used sequentially and must never be missed.
+    pub eager: bool,
+}
+
+/// Decides which indices of a keychain must be watched, given the highest
+/// index seen used so far (see `with_gap_limit_policy`).
+///
+/// The tracker derives every index in the returned window that it doesn't
+/// track yet; indices it already tracks stay tracked.
+pub trait GapLimitPolicy<K>: Debug + Send + Sync {
+    fn window(&self, keychain: &K, highest_used: Option<u32>) -> RangeInclusive<u32>;
+}
+
+/// The standard gap limit: this many unused indices past the highest used one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedLookahead(pub u32);
+
+impl<K> GapLimitPolicy<K> for FixedLookahead {
+    fn window(&self, _keychain: &K, highest_used: Option<u32>) -> RangeInclusive<u32> {
+        let start = highest_used.map_or(0, |index| index + 1);
+        start..=start + self.0
+    }
+}
+
+/// Watches every index up to a known bound (e.g. the highest index a
+/// migrated wallet ever handed out), whatever has been seen used. Past the
+/// bound, only the next index is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanToIndex(pub u32);
+
+impl<K> GapLimitPolicy<K> for ScanToIndex {
+    fn window(&self, _keychain: &K, highest_used: Option<u32>) -> RangeInclusive<u32> {
+        let start = highest_used.map_or(0, |index| index + 1);
+        start..=self.0.max(start)
+    }
+}
+
+/// What `DerivedSpkTracker::to_bytes` saves. The derived maps aren't part
+/// of it; they are re-derived on load.
+#[derive(Serialize, Deserialize)]
+struct TrackerSnapshot<K> {
+    lookahead: u32,
+    policies: Vec<(K, GapPolicy)>,
+    keychains: Vec<KeychainSnapshot<K>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeychainSnapshot<K> {
+    keychain: K,
+    descriptor: String,
+    window_start: u32,
+    /// Derived indices as inclusive runs: one from 0 unless a range was inserted.
+    derived: Vec<(u32, u32)>,
+    lazy: bool,
+}
 
 /// Tracks derived ScriptPubKeys (SPKs) for a set of descriptors.
 ///
@@ -27,24 +127,148 @@ pub struct DerivedSpkTracker<K> {
     /// Used to generate the list of scripts to monitor.
     derived_spks: BTreeMap<(K, u32), (sha256::Hash, ScriptBuf)>,
 
-    /// Reverse index: Maps ScriptHash to (Keychain, Index).
+    /// Reverse index: Maps ScriptHash to every (Keychain, Index) deriving it.
     /// Used to identify which wallet address received funds when a notification arrives.
-    derived_spks_rev: HashMap<sha256::Hash, (K, u32)>,
+    /// Usually one owner; several if keychains overlap (e.g. external == internal).
+    derived_spks_rev: HashMap<sha256::Hash, Vec<(K, u32)>>,
+
+    /// First index of each keychain's lookahead window: the `next_index` it was
+    /// inserted with, raised past every index marked used since.
+    window_start: BTreeMap<K, u32>,
+
+    /// Per-keychain overrides of the default lookahead and extension rules.
+    policies: BTreeMap<K, GapPolicy>,
+
+    /// Keychains watched without a proactive history fetch (see
+    /// `insert_lazy_descriptor`).
+    lazy: BTreeSet<K>,
+
+    /// Replaces the lookahead rule for every keychain when set.
+    window_policy: Option<Arc<dyn GapLimitPolicy<K>>>,
+
+    /// Shared context for key derivation.
+    secp: Secp256k1<VerifyOnly>,
 }
 
 impl<K: Ord + Clone> DerivedSpkTracker<K> {
     /// Creates a new tracker with the specified lookahead (gap limit) size.
     ///
     /// # Arguments
-    /// * `lookahead` - The number of addresses to watch beyond the last used index. 
-    ///                 Common values are 20 (standard) or higher for services.
+    /// * `lookahead` - The number of addresses to watch beyond the last used index.
+    ///   Common values are 20 (standard) or higher for services.
     pub fn new(lookahead: u32) -> Self {
         Self {
             lookahead,
             descriptors: BTreeMap::new(),
             derived_spks: BTreeMap::new(),
             derived_spks_rev: HashMap::new(),
+            window_start: BTreeMap::new(),
+            policies: BTreeMap::new(),
+            lazy: BTreeSet::new(),
+            window_policy: None,
+            secp: Secp256k1::verification_only(),
+        }
+    }
+
+    /// Uses `policy` instead of the default lookahead for `keychain`.
+    ///
+    /// Must be set before the keychain's descriptor is inserted.
+    pub fn with_gap_policy(mut self, keychain: K, policy: GapPolicy) -> Self {
+        self.policies.insert(keychain, policy);
+        self
+    }
+
+    /// Derives each keychain's window from `policy` instead of the
+    /// `FixedLookahead` of its lookahead. `GapPolicy::eager` still applies.
+    ///
+    /// Must be set before any descriptor is inserted.
+    pub fn with_gap_limit_policy(mut self, policy: impl GapLimitPolicy<K> + 'static) -> Self {
+        self.window_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// The lookahead in effect for `keychain`.
+    pub fn lookahead_of(&self, keychain: &K) -> u32 {
+        self.policies.get(keychain).map_or(self.lookahead, |p| p.lookahead)
+    }
+
+    /// Whether `keychain` extends eagerly (see `GapPolicy::eager`).
+    pub fn is_eager(&self, keychain: &K) -> bool {
+        self.policies.get(keychain).is_some_and(|p| p.eager)
+    }
+
+    /// Whether `keychain` is only watched, not bootstrapped (see
+    /// `insert_lazy_descriptor`).
+    pub fn is_lazy(&self, keychain: &K) -> bool {
+        self.lazy.contains(keychain)
+    }
+
+    /// The descriptor tracked for `keychain`, if any.
+    pub fn descriptor(&self, keychain: &K) -> Option<&Descriptor<DescriptorPublicKey>> {
+        self.descriptors.get(keychain)
+    }
+
+    /// Serialises the descriptors, lookahead settings and derived range of
+    /// every keychain, for `from_bytes` to rebuild the tracker on a warm start.
+    ///
+    /// A `with_gap_limit_policy` policy isn't saved; install it again on the
+    /// loaded tracker.
+    pub fn to_bytes(&self) -> Result<Vec<u8>>
+    where
+        K: Serialize,
+    {
+        let keychains = self
+            .descriptors
+            .iter()
+            .map(|(keychain, descriptor)| {
+                let indices = self
+                    .derived_spks
+                    .range((keychain.clone(), 0)..=(keychain.clone(), u32::MAX))
+                    .map(|((_, index), _)| *index);
+                let mut derived: Vec<(u32, u32)> = Vec::new();
+                for index in indices {
+                    match derived.last_mut() {
+                        Some((_, end)) if *end + 1 == index => *end = index,
+                        _ => derived.push((index, index)),
+                    }
+                }
+                KeychainSnapshot {
+                    keychain: keychain.clone(),
+                    descriptor: descriptor.to_string(),
+                    window_start: self.window_start.get(keychain).copied().unwrap_or(0),
+                    derived,
+                    lazy: self.lazy.contains(keychain),
+                }
+            })
+            .collect();
+        let snapshot = TrackerSnapshot {
+            lookahead: self.lookahead,
+            policies: self.policies.iter().map(|(k, p)| (k.clone(), *p)).collect(),
+            keychains,
+        };
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    /// Rebuilds a tracker saved by `to_bytes`, re-deriving every script it had.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self>
+    where
+        K: DeserializeOwned,
+    {
+        let snapshot: TrackerSnapshot<K> = serde_json::from_slice(bytes)?;
+        let mut tracker = Self::new(snapshot.lookahead);
+        tracker.policies = snapshot.policies.into_iter().collect();
+        for saved in snapshot.keychains {
+            let descriptor = Descriptor::from_str(&saved.descriptor)?;
+            tracker.descriptors.insert(saved.keychain.clone(), descriptor);
+            tracker.window_start.insert(saved.keychain.clone(), saved.window_start);
+            if saved.lazy {
+                tracker.lazy.insert(saved.keychain.clone());
+            }
+            for (start, end) in saved.derived {
+                tracker.derive_range(saved.keychain.clone(), start..=end);
+            }
         }
+        Ok(tracker)
     }
 
     /// Returns an iterator over all currently tracked script hashes and scripts.
@@ -54,16 +278,45 @@ impl<K: Ord + Clone> DerivedSpkTracker<K> {
         self.derived_spks.values()
     }
 
-    /// Reverse lookup: Finds the Keychain ID and Index for a given script hash.
+    /// Reverse lookup: Finds every Keychain ID and Index deriving a given script hash.
+    ///
+    /// Returns an empty list if the hash is not tracked.
+    pub fn index_of_spk_hash(&self, hash: &sha256::Hash) -> Vec<(K, u32)> {
+        self.derived_spks_rev.get(hash).cloned().unwrap_or_default()
+    }
+
+    /// Returns the keychains that currently have a descriptor.
+    pub fn keychains(&self) -> impl Iterator<Item = &K> {
+        self.descriptors.keys()
+    }
+
+    /// Returns the highest derived (watched) index for a keychain, if any.
+    pub fn max_derived_index(&self, keychain: &K) -> Option<u32> {
+        self.derived_spks
+            .range((keychain.clone(), 0)..=(keychain.clone(), u32::MAX))
+            .next_back()
+            .map(|((_, index), _)| *index)
+    }
+
+    /// Every tracked derivation as (keychain, index, address, scripthash), in
+    /// keychain/index order, for diffing against an independent derivation
+    /// (e.g. the addresses a hardware wallet shows).
     ///
-    /// Returns `None` if the hash is not tracked.
-    pub fn index_of_spk_hash(&self, hash: &sha256::Hash) -> Option<(K, u32)> {
-        self.derived_spks_rev.get(hash).cloned()
+    /// Scripts without an address form (bare scripts) are left out.
+    pub fn audit(&self, network: Network) -> Vec<(K, u32, Address, sha256::Hash)> {
+        self.derived_spks
+            .iter()
+            .filter_map(|((keychain, index), (hash, script))| {
+                let address = Address::from_script(script, network).ok()?;
+                Some((keychain.clone(), *index, address, *hash))
+            })
+            .collect()
     }
 
     /// Registers or updates a descriptor for a keychain (e.g., "external").
     ///
-    /// This will derive the initial range of scripts from index `0` up to `next_index + lookahead`.
+    /// This will derive the initial range of scripts from index `0` up to the
+    /// end of the window (by default `next_index + lookahead`).
     ///
     /// # Returns
     /// A list of newly derived scripts that need to be subscribed to.
@@ -74,18 +327,58 @@ impl<K: Ord + Clone> DerivedSpkTracker<K> {
         next_index: u32,
     ) -> Vec<(sha256::Hash, ScriptBuf)> {
         log::debug!("[DerivedSpkTracker] KeyChain{0}: {1}", next_index, descriptor);
-        // If the descriptor changed, we must clear old derivations to avoid mixing scripts
-        if let Some(old) = self.descriptors.insert(keychain.clone(), descriptor.clone()) {
-            if old == descriptor {
-                return vec![];
-            }
-            self.clear_keychain(&keychain);
+        self.lazy.remove(&keychain);
+        if !self.replace_descriptor(&keychain, descriptor) {
+            return vec![];
         }
+        self.window_start.insert(keychain.clone(), next_index);
 
-        // Derive the full window [0 .. next_index + lookahead]
-        (0..=next_index + self.lookahead)
-            .filter_map(|i| self.add_derived_spk(keychain.clone(), i))
-            .collect()
+        // Derive everything up to the end of the window: the indices below
+        // it may already have been handed out.
+        let end = *self.window(&keychain, next_index.checked_sub(1)).end();
+        self.derive_range(keychain, 0..=end)
+    }
+
+    /// Like `insert_descriptor`, for a keychain that is only watched: its
+    /// scripts are subscribed but their histories aren't fetched up front,
+    /// only once the server reports a change. Keeps the bootstrap cost of
+    /// secondary accounts down.
+    ///
+    /// # Returns
+    /// A list of newly derived scripts that need to be subscribed to.
+    pub fn insert_lazy_descriptor(
+        &mut self,
+        keychain: K,
+        descriptor: Descriptor<DescriptorPublicKey>,
+        next_index: u32,
+    ) -> Vec<(sha256::Hash, ScriptBuf)> {
+        let added = self.insert_descriptor(keychain.clone(), descriptor, next_index);
+        self.lazy.insert(keychain);
+        added
+    }
+
+    /// Registers a descriptor but only derives the indices in `range`, e.g. a
+    /// service's slice of a shared address space.
+    ///
+    /// Nothing below the range is derived. Beyond it, the usual gap-limit
+    /// extension applies once an index is marked used. Inserting the same
+    /// descriptor again adds another range.
+    ///
+    /// # Returns
+    /// A list of newly derived scripts that need to be subscribed to.
+    pub fn insert_descriptor_range(
+        &mut self,
+        keychain: K,
+        descriptor: Descriptor<DescriptorPublicKey>,
+        range: RangeInclusive<u32>,
+    ) -> Vec<(sha256::Hash, ScriptBuf)> {
+        log::debug!("[DerivedSpkTracker] KeyChain{:?}: {}", range, descriptor);
+        self.replace_descriptor(&keychain, descriptor);
+
+        // The whole range is watched, so it all counts as inside the window.
+        let start = self.window_start.entry(keychain.clone()).or_insert(0);
+        *start = (*start).max(*range.end());
+        self.derive_range(keychain, range)
     }
 
     /// Notifies the tracker that an address at `index` has been used.
@@ -93,6 +386,9 @@ impl<K: Ord + Clone> DerivedSpkTracker<K> {
     /// This checks if the usage creates a gap larger than permitted. If so, it
     /// derives new addresses to restore the lookahead window.
     ///
+    /// If other keychains derive the same script, their windows are extended
+    /// from their own index too.
+    ///
     /// # Returns
     /// A list of *newly* derived scripts that must be subscribed to immediately.
     pub fn mark_used_and_derive_new(
@@ -100,22 +396,185 @@ impl<K: Ord + Clone> DerivedSpkTracker<K> {
         keychain: &K,
         index: u32,
     ) -> Vec<(sha256::Hash, ScriptBuf)> {
+        let mut owners = match self.derived_spks.get(&(keychain.clone(), index)) {
+            Some((hash, _)) => self.index_of_spk_hash(hash),
+            None => vec![],
+        };
+        if !owners.contains(&(keychain.clone(), index)) {
+            owners.push((keychain.clone(), index));
+        }
+        owners
+            .into_iter()
+            .flat_map(|(keychain, index)| self.extend_window(&keychain, index))
+            .collect()
+    }
+
+    /// Internal helper: Restores `keychain`'s lookahead past a used `index`.
+    fn extend_window(&mut self, keychain: &K, index: u32) -> Vec<(sha256::Hash, ScriptBuf)> {
         let next_index = index + 1;
-        let mut newly_derived = Vec::new();
-
-        // Check the new required window: [next_index .. next_index + lookahead]
-        for i in next_index..=next_index + self.lookahead {
-            if let Some(pair) = self.add_derived_spk(keychain.clone(), i) {
-                newly_derived.push(pair);
-            } else {
-                // Optimization: If `add_derived_spk` returns None, it means we already
-                // track this index. Since we derive sequentially, we likely track
-                // everything after it too, so we can stop early.
-                break;
-            }
+        let start = self.window_start.entry(keychain.clone()).or_insert(0);
+        *start = (*start).max(next_index);
+
+        // Indices already tracked are skipped, so only the missing part of the
+        // window is derived.
+        let window = self.window(keychain, Some(index));
+        self.derive_range(keychain.clone(), window)
+    }
+
+    /// Internal helper: The window `keychain` must watch given its highest
+    /// used index.
+    fn window(&self, keychain: &K, highest_used: Option<u32>) -> RangeInclusive<u32> {
+        match &self.window_policy {
+            Some(policy) => policy.window(keychain, highest_used),
+            None => FixedLookahead(self.lookahead_of(keychain)).window(keychain, highest_used),
+        }
+    }
+
+    /// Notifies the tracker that the address at `index` was handed out.
+    ///
+    /// Like `mark_used_and_derive_new`, but also derives `index` itself, which
+    /// may lie beyond the current window.
+    ///
+    /// # Returns
+    /// A list of *newly* derived scripts that must be subscribed to immediately.
+    pub fn mark_revealed_and_derive_new(
+        &mut self,
+        keychain: &K,
+        index: u32,
+    ) -> Vec<(sha256::Hash, ScriptBuf)> {
+        let mut newly: Vec<_> = self.add_derived_spk(keychain.clone(), index).into_iter().collect();
+        newly.extend(self.mark_used_and_derive_new(keychain, index));
+        newly
+    }
+
+    /// Finds the keychain whose descriptor derives `script` at `index`.
+    ///
+    /// Unlike `index_of_spk_hash`, this works for scripts not derived yet.
+    pub fn keychain_of_script_at(&self, script: &ScriptBuf, index: u32) -> Option<K> {
+        self.descriptors
+            .iter()
+            .find(|(_, descriptor)| {
+                descriptor
+                    .at_derivation_index(index)
+                    .map(|d| &d.script_pubkey() == script)
+                    .unwrap_or(false)
+            })
+            .map(|(keychain, _)| keychain.clone())
+    }
+
+    /// Stops tracking `keychain`: its descriptor and every script derived
+    /// from it are dropped, e.g. when a wallet rotates descriptors.
+    ///
+    /// # Returns
+    /// The hashes of the scripts that are no longer tracked (a script another
+    /// keychain also derives stays tracked).
+    pub fn remove_keychain(&mut self, keychain: &K) -> Vec<sha256::Hash> {
+        if self.descriptors.remove(keychain).is_none() {
+            return vec![];
         }
+        self.window_start.remove(keychain);
+        self.lazy.remove(keychain);
+        self.clear_keychain(keychain)
+    }
 
-        newly_derived
+    /// Reduces the lookahead and stops tracking the scripts that fall outside
+    /// the smaller window.
+    ///
+    /// Only the never-used tail is pruned: each keychain keeps everything up to
+    /// its window start (past the highest used index) plus the new lookahead.
+    /// A `lookahead` that isn't smaller than the current one is a no-op, and
+    /// keychains with their own `GapPolicy` keep their window. With a
+    /// `GapLimitPolicy` installed, the lookahead is unused and nothing is pruned.
+    ///
+    /// # Returns
+    /// The hashes of the scripts that are no longer tracked.
+    pub fn shrink_lookahead(&mut self, lookahead: u32) -> Vec<sha256::Hash> {
+        if lookahead >= self.lookahead {
+            return vec![];
+        }
+        if self.window_policy.is_some() {
+            self.lookahead = lookahead;
+            return vec![];
+        }
+        self.lookahead = lookahead;
+
+        let window_start = &self.window_start;
+        let policies = &self.policies;
+        let removed: Vec<((K, u32), sha256::Hash)> = self
+            .derived_spks
+            .extract_if(.., |(kc, index), _| {
+                let lookahead = policies.get(kc).map_or(lookahead, |p| p.lookahead);
+                *index > window_start.get(kc).copied().unwrap_or(0) + lookahead
+            })
+            .map(|(at, (hash, _))| (at, hash))
+            .collect();
+
+        // A script another keychain still derives stays tracked.
+        removed
+            .into_iter()
+            .filter_map(|(at, hash)| self.remove_owner(hash, &at).then_some(hash))
+            .collect()
+    }
+
+    /// Internal helper: Derives and stores every untracked index in `range`,
+    /// in parallel when the range is large and the feature is enabled.
+    fn derive_range(
+        &mut self,
+        keychain: K,
+        range: RangeInclusive<u32>,
+    ) -> Vec<(sha256::Hash, ScriptBuf)> {
+        let missing: Vec<u32> = range
+            .filter(|i| !self.derived_spks.contains_key(&(keychain.clone(), *i)))
+            .collect();
+        let descriptor = self.descriptors.get(&keychain).expect("descriptor exists");
+
+        let derive = |i: &u32| derive_hashed_spk(&self.secp, descriptor, *i);
+        #[cfg(feature = "parallel-derivation")]
+        let spks: Vec<_> = if missing.len() >= PARALLEL_DERIVATION_MIN {
+            use rayon::prelude::*;
+            missing.par_iter().map(derive).collect()
+        } else {
+            missing.iter().map(derive).collect()
+        };
+        #[cfg(not(feature = "parallel-derivation"))]
+        let spks: Vec<_> = missing.iter().map(derive).collect();
+
+        // Serially, in ascending index order, so the maps and the returned
+        // list come out the same however the work was split.
+        missing
+            .into_iter()
+            .zip(spks)
+            .map(|(index, (hash, spk))| self.insert_spk(keychain.clone(), index, hash, spk))
+            .collect()
+    }
+
+    /// Internal helper: Stores an already derived script in both maps.
+    fn insert_spk(&mut self, keychain: K, index: u32, hash: sha256::Hash, spk: ScriptBuf) -> (sha256::Hash, ScriptBuf) {
+        self.derived_spks.insert((keychain.clone(), index), (hash, spk.clone()));
+        self.add_owner(hash, keychain, index);
+        (hash, spk)
+    }
+
+    /// Internal helper: Records `(keychain, index)` as deriving `hash`.
+    fn add_owner(&mut self, hash: sha256::Hash, keychain: K, index: u32) {
+        let owners = self.derived_spks_rev.entry(hash).or_default();
+        if !owners.contains(&(keychain.clone(), index)) {
+            owners.push((keychain, index));
+        }
+    }
+
+    /// Internal helper: Forgets one owner of `hash`. Returns `true` if it was
+    /// the last, i.e. the script is no longer tracked at all.
+    fn remove_owner(&mut self, hash: sha256::Hash, at: &(K, u32)) -> bool {
+        let Some(owners) = self.derived_spks_rev.get_mut(&hash) else {
+            return true;
+        };
+        owners.retain(|owner| owner != at);
+        if owners.is_empty() {
+            self.derived_spks_rev.remove(&hash);
+            return true;
+        }
+        false
     }
 
     /// Internal helper: Derives and stores a single script at the given index.
@@ -130,34 +589,50 @@ impl<K: Ord + Clone> DerivedSpkTracker<K> {
             let descriptor = self.descriptors.get(&keychain).expect("descriptor exists");
 
             // Derive the script at the specific index
-            let spk = descriptor
-                .at_derivation_index(index)
-                .expect("can derive")
-                .script_pubkey();
+            let spk = derive_script(&self.secp, descriptor, index);
 
-            let hash = sha256::Hash::hash(spk.as_bytes());
+            let hash = script_hash(&spk);
 
             // Store in both forward and reverse maps
             entry.insert((hash, spk.clone()));
-            self.derived_spks_rev.insert(hash, (keychain, index));
+            self.add_owner(hash, keychain, index);
 
             return Some((hash, spk));
         }
         None
     }
 
+    /// Internal helper: Sets `keychain`'s descriptor. If it replaces a different
+    /// one, the old derivations are cleared so scripts aren't mixed.
+    ///
+    /// Returns `false` if the keychain already had this exact descriptor.
+    fn replace_descriptor(&mut self, keychain: &K, descriptor: Descriptor<DescriptorPublicKey>) -> bool {
+        match self.descriptors.insert(keychain.clone(), descriptor.clone()) {
+            Some(old) if old == descriptor => false,
+            Some(_) => {
+                self.clear_keychain(keychain);
+                true
+            }
+            None => true,
+        }
+    }
+
     /// Internal helper: Removes all tracking data for a specific keychain.
-    /// Used when a descriptor is updated or replaced.
-    fn clear_keychain(&mut self, keychain: &K) {
+    /// Used when a descriptor is updated, replaced or removed.
+    ///
+    /// Returns the hashes no other keychain still derives.
+    fn clear_keychain(&mut self, keychain: &K) -> Vec<sha256::Hash> {
         // Efficiently extract all entries belonging to this keychain
-        let removed = self
+        let removed: Vec<_> = self
             .derived_spks
-            .extract_if(.., |(kc, _), _| kc == keychain);
+            .extract_if(.., |(kc, _), _| kc == keychain)
+            .collect();
 
         // Clean up the reverse map
-        for (_, (hash, _)) in removed {
-            self.derived_spks_rev.remove(&hash);
-        }
+        removed
+            .into_iter()
+            .filter_map(|(at, (hash, _))| self.remove_owner(hash, &at).then_some(hash))
+            .collect()
     }
 }
 
@@ -173,6 +648,120 @@ mod tests {
         ).unwrap()
     }
 
+    #[test]
+    fn descriptor_range_derives_only_that_slice_and_extends_past_it() {
+        let descriptor = test_descriptor();
+        let mut tracker = DerivedSpkTracker::<String>::new(3);
+        let kc = "service".to_string();
+
+        let added = tracker.insert_descriptor_range(kc.clone(), descriptor.clone(), 1000..=1005);
+
+        assert_eq!(added.len(), 6);
+        assert_eq!(tracker.derived_spks.len(), 6);
+        for (hash, script) in &added {
+            let (_, index) = tracker.index_of_spk_hash(hash).remove(0);
+            assert!((1000..=1005).contains(&index));
+            assert_eq!(descriptor.at_derivation_index(index).unwrap().script_pubkey(), *script);
+        }
+
+        // Using the last index opens a lookahead window beyond the range.
+        let newly = tracker.mark_used_and_derive_new(&kc, 1005);
+        assert_eq!(newly.len(), 4);
+        assert_eq!(tracker.max_derived_index(&kc), Some(1009));
+    }
+
+    #[test]
+    fn custom_gap_limit_policy_decides_the_derived_window() {
+        /// Skips ahead: watches 10 indices starting 5 past the highest used.
+        #[derive(Debug)]
+        struct SkipAhead;
+        impl GapLimitPolicy<String> for SkipAhead {
+            fn window(&self, _keychain: &String, highest_used: Option<u32>) -> RangeInclusive<u32> {
+                let start = highest_used.map_or(0, |index| index + 5);
+                start..=start + 9
+            }
+        }
+        let indices = |tracker: &DerivedSpkTracker<String>| -> Vec<u32> {
+            tracker.derived_spks.keys().map(|(_, index)| *index).collect()
+        };
+        let kc = "external".to_string();
+
+        let mut tracker = DerivedSpkTracker::<String>::new(2).with_gap_limit_policy(SkipAhead);
+        tracker.insert_descriptor(kc.clone(), test_descriptor(), 0);
+        assert_eq!(indices(&tracker), (0..=9).collect::<Vec<_>>());
+
+        let newly = tracker.mark_used_and_derive_new(&kc, 7);
+        assert_eq!(newly.len(), 10);
+        assert_eq!(indices(&tracker), (0..=9).chain(12..=21).collect::<Vec<_>>());
+
+        // The stock policies reproduce the default and a fixed scan.
+        let mut scan = DerivedSpkTracker::<String>::new(2).with_gap_limit_policy(ScanToIndex(30));
+        scan.insert_descriptor(kc.clone(), test_descriptor(), 0);
+        assert_eq!(scan.max_derived_index(&kc), Some(30));
+        scan.mark_used_and_derive_new(&kc, 30);
+        assert_eq!(scan.max_derived_index(&kc), Some(31));
+        assert_eq!(GapLimitPolicy::<String>::window(&FixedLookahead(2), &kc, Some(4)), 5..=7);
+    }
+
+    #[test]
+    fn audit_lists_known_testnet_addresses() {
+        let mut tracker = DerivedSpkTracker::<String>::new(2);
+        tracker.insert_descriptor("external".to_string(), test_descriptor(), 0);
+
+        let audit = tracker.audit(Network::Testnet);
+        let rows: Vec<(u32, String, String)> = audit
+            .iter()
+            .map(|(_, index, address, hash)| {
+                (*index, address.to_string(), crate::streaming::util::scripthash_to_wire(hash))
+            })
+            .collect();
+
+        // BIP84 testnet derivations of the "abandon ... about" test mnemonic.
+        assert_eq!(
+            rows,
+            vec![
+                (
+                    0,
+                    "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkl".to_string(),
+                    "71d53db103b8dedac12267edc183a38240654842bc98fd9776515a86a84f9590".to_string(),
+                ),
+                (
+                    1,
+                    "tb1qd7spv5q28348xl4myc8zmh983w5jx32cjhkn97".to_string(),
+                    "fb51113b012a2eb8b8157ed8336ef31aa669993372bd5a0e712004989eee044b".to_string(),
+                ),
+                (
+                    2,
+                    "tb1qxdyjf6h5d6qxap4n2dap97q4j5ps6ua8sll0ct".to_string(),
+                    "8fcfd41d8e89a0e4f6564981fad75f3a55711071517033ded4b0a433f48538bc".to_string(),
+                ),
+            ]
+        );
+        assert!(audit.iter().all(|(kc, ..)| kc == "external"));
+    }
+
+    #[test]
+    fn large_range_derivation_matches_per_index_derivation() {
+        let descriptor = test_descriptor();
+        let indices: Vec<u32> = (0..PARALLEL_DERIVATION_MIN as u32 + 200).collect();
+        let expected: Vec<ScriptBuf> = indices
+            .iter()
+            .map(|i| descriptor.at_derivation_index(*i).unwrap().script_pubkey())
+            .collect();
+
+        let secp = Secp256k1::verification_only();
+        for (index, script) in indices.iter().zip(&expected) {
+            assert_eq!(derive_hashed_spk(&secp, &descriptor, *index), (script_hash(script), script.clone()));
+        }
+
+        // The tracker takes the large-range path for a big initial window.
+        let mut tracker = DerivedSpkTracker::<String>::new(indices.len() as u32 - 1);
+        tracker.insert_descriptor("kc".to_string(), descriptor, 0);
+        for (index, script) in indices.iter().zip(&expected) {
+            assert_eq!(&tracker.derived_spks[&("kc".to_string(), *index)].1, script);
+        }
+    }
+
     #[test]
     fn insert_descriptor_derives_initial_range() {
         let mut tracker = DerivedSpkTracker::<String>::new(2);
@@ -207,11 +796,34 @@ mod tests {
 
         let _added = tracker.insert_descriptor("kc".to_string(), test_descriptor(), 0);
         let (hash, _script) = tracker.derived_spks.values().next().unwrap();
-        let found = tracker.index_of_spk_hash(hash).unwrap();
+        let found = tracker.index_of_spk_hash(hash).remove(0);
 
         assert_eq!(found.0, "kc");
     }
 
+    #[test]
+    fn script_shared_by_two_keychains_extends_both() {
+        // Misconfigured: the change keychain uses the receive descriptor.
+        let mut tracker = DerivedSpkTracker::<String>::new(2);
+        let (ext, int) = ("external".to_string(), "internal".to_string());
+        tracker.insert_descriptor(ext.clone(), test_descriptor(), 0);
+        tracker.insert_descriptor(int.clone(), test_descriptor(), 0);
+
+        let (hash, _) = tracker.derived_spks[&(ext.clone(), 2)].clone();
+        assert_eq!(tracker.index_of_spk_hash(&hash), vec![(ext.clone(), 2), (int.clone(), 2)]);
+
+        // A hit attributed to either keychain extends both windows.
+        tracker.mark_used_and_derive_new(&ext, 2);
+        assert_eq!(tracker.max_derived_index(&ext), Some(5));
+        assert_eq!(tracker.max_derived_index(&int), Some(5));
+
+        // Dropping one keychain leaves the other's ownership intact.
+        tracker.insert_descriptor(int.clone(), Descriptor::from_str(
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)"
+        ).unwrap(), 0);
+        assert_eq!(tracker.index_of_spk_hash(&hash), vec![(ext, 2)]);
+    }
+
     #[test]
     fn changing_descriptor_clears_old_scripts() {
         let mut tracker = DerivedSpkTracker::<String>::new(1);
@@ -277,6 +889,50 @@ mod tests {
         assert!(c >= b);
     }
 
+    #[test]
+    fn shrink_lookahead_keeps_used_window() {
+        let mut tracker = DerivedSpkTracker::<String>::new(5);
+        let kc = "kc".to_string();
+
+        tracker.insert_descriptor(kc.clone(), test_descriptor(), 0);
+        tracker.mark_used_and_derive_new(&kc, 3);
+        // Window: used 3, watched through 4 + 5 = 9.
+
+        let removed = tracker.shrink_lookahead(2);
+
+        assert_eq!(removed.len(), 3); // 7, 8, 9
+        assert_eq!(tracker.max_derived_index(&kc), Some(6));
+        assert!(removed.iter().all(|h| tracker.index_of_spk_hash(h).is_empty()));
+        assert!(tracker.shrink_lookahead(2).is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_bytes_with_the_same_reverse_index() {
+        let change = Descriptor::from_str(
+            "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)"
+        ).unwrap();
+        let mut tracker = DerivedSpkTracker::<String>::new(3)
+            .with_gap_policy("internal".to_string(), GapPolicy { lookahead: 5, eager: true });
+        tracker.insert_descriptor("external".to_string(), test_descriptor(), 0);
+        tracker.insert_descriptor("internal".to_string(), change, 0);
+        tracker.mark_used_and_derive_new(&"external".to_string(), 7);
+        tracker.insert_descriptor_range("service".to_string(), test_descriptor(), 1000..=1002);
+
+        let restored = DerivedSpkTracker::<String>::from_bytes(&tracker.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(restored.derived_spks, tracker.derived_spks);
+        for (hash, _) in tracker.all_spks() {
+            let mut owners = restored.index_of_spk_hash(hash);
+            let mut expected = tracker.index_of_spk_hash(hash);
+            owners.sort();
+            expected.sort();
+            assert_eq!(owners, expected);
+        }
+        assert_eq!(restored.window_start, tracker.window_start);
+        assert_eq!(restored.lookahead_of(&"internal".to_string()), 5);
+        assert!(restored.is_eager(&"internal".to_string()));
+    }
+
     #[test]
     fn maintains_gap_relative_to_last_used() {
         let mut tracker = DerivedSpkTracker::<String>::new(2);
@@ -294,6 +950,6 @@ mod tests {
             .unwrap();
 
         // Gap invariant: must be >= used + lookahead
-        assert!(max_index >= 0 + 2);
+        assert!(max_index >= 2);
     }
 }
\ No newline at end of file
@@ -0,0 +1,16 @@
+use bitcoin::block;
+
+/// The best block header the driver has seen, persisted across restarts so
+/// confirmation counts are available before the server's first notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainTip {
+    pub height: u32,
+    pub header: block::Header,
+}
+
+impl ChainTip {
+    /// Confirmations of a tx anchored at `height` (0 if the tip is behind it).
+    pub fn confirmations(&self, height: u32) -> u32 {
+        (self.height + 1).saturating_sub(height)
+    }
+}
@@ -1,7 +1,45 @@
+use anyhow::Result;
+use serde::Serialize;
 use bitcoin::hashes::sha256;
-use bitcoin::{block, ScriptBuf};
+use bitcoin::{block, ScriptBuf, SignedAmount, Transaction, Txid};
+use std::time::Duration;
 
 use crate::streaming::engine::types::HistoryTx;
+use crate::streaming::metrics::LatencyRecorder;
+
+/// Requests a client is still waiting on (see `ElectrumApi::pending_work`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PendingWork {
+    pub histories: usize,
+    pub txs: usize,
+    pub headers: usize,
+}
+
+/// A scripthash's balance as the server reports it (`blockchain.scripthash.get_balance`).
+///
+/// `unconfirmed` is the net mempool effect, negative when mempool txs spend
+/// confirmed coins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ScriptBalance {
+    pub confirmed: u64,
+    pub unconfirmed: i64,
+}
+
+impl ScriptBalance {
+    pub fn total(&self) -> SignedAmount {
+        SignedAmount::from_sat(self.confirmed as i64 + self.unconfirmed)
+    }
+}
+
+/// A history member the client couldn't use: the server answered with an
+/// error or with bytes that don't decode. The history was delivered without
+/// it, flagged as incomplete (see `ElectrumApi::history_incomplete` and
+/// `ElectrumApi::take_errors`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchError {
+    Transaction { hash: sha256::Hash, txid: Txid, error: String },
+    Header { hash: sha256::Hash, height: u32, error: String },
+}
 
 /// Minimal Electrum interface used by the driver.
 /// Everything is scripthash-based.
@@ -9,6 +47,10 @@ pub trait ElectrumApi {
     /// Called once when engine discovers a new script
     fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash);
 
+    /// Called when the engine stops tracking a script. Clients that keep no
+    /// server-side subscription state can ignore it.
+    fn unregister_script(&mut self, _hash: sha256::Hash) {}
+
     /// Blocking poll: returns next script hash that changed (if any)
     fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash>;
 
@@ -23,6 +65,35 @@ pub trait ElectrumApi {
 
     fn request_history(&mut self, hash: sha256::Hash);
 
+    /// Whether the history `fetch_history_txs` just returned for `hash` lacks
+    /// members that failed to fetch (see `FetchError`). What such a history
+    /// doesn't list may still be there, so it mustn't be taken as dropped.
+    /// Clients that never deliver incomplete histories return `false`.
+    fn history_incomplete(&mut self, _hash: sha256::Hash) -> bool {
+        false
+    }
+
+    /// Non-blocking poll for history metadata: a scripthash whose history
+    /// lists `usize` txs, reported as soon as the list arrives and before the
+    /// txs are downloaded. Clients that only report complete histories
+    /// return `None`.
+    fn poll_history_activity(&mut self) -> Option<(sha256::Hash, usize)> {
+        None
+    }
+
+    /// Like `poll_scripthash_changed`, but when nothing is ready blocks until
+    /// the client has news (any answer or notification, not only a ready
+    /// scripthash) or `timeout` passes, then polls once more.
+    ///
+    /// Clients without a way to signal news just sleep briefly.
+    fn wait_for_change(&mut self, timeout: Duration) -> Option<sha256::Hash> {
+        if let Some(hash) = self.poll_scripthash_changed() {
+            return Some(hash);
+        }
+        std::thread::sleep(timeout.min(Duration::from_millis(5)));
+        self.poll_scripthash_changed()
+    }
+
     /// Retrieves a cached block header by height.
     ///
     /// Returns `Some(Header)` if the header has been fetched and cached.
@@ -31,4 +102,175 @@ pub trait ElectrumApi {
     /// NEW: The adapter fetches block headers alongside transaction history.
     ///      The orchestrator uses these to build `ConfirmationBlockTime` anchors.
     fn get_cached_header(&self, height: u32) -> Option<block::Header>;
-}
\ No newline at end of file
+
+    /// Fetches the header at `height`, blocking until the server answers.
+    ///
+    /// For anchors whose header the history flow didn't cache, e.g. txs
+    /// confirmed far below anything seen this session. Clients that can't
+    /// fetch headers on demand return an error.
+    fn get_block_header(&mut self, height: u32) -> Result<block::Header> {
+        anyhow::bail!("client cannot fetch the header at height {}", height)
+    }
+
+    /// Asks for the header at `height` without blocking; the answer is
+    /// collected with `take_block_header`.
+    ///
+    /// Returns `false` if the client can't fetch headers this way, in which
+    /// case the caller falls back to `get_block_header`.
+    fn request_block_header(&mut self, _height: u32) -> bool {
+        false
+    }
+
+    /// Takes the outcome of a `request_block_header`, once the server has
+    /// answered (or the client failed).
+    fn take_block_header(&mut self, _height: u32) -> Option<Result<block::Header>> {
+        None
+    }
+
+    /// The server's current chain tip (height and header), as last announced
+    /// via `blockchain.headers.subscribe`. `None` until one is known or if
+    /// the client doesn't follow the tip.
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        None
+    }
+
+    /// Heights `get_cached_header` can answer, ascending. For diagnostics only.
+    fn cached_header_heights(&self) -> Vec<u32> {
+        Vec::new()
+    }
+
+    /// Asks for a single transaction by id (e.g. the unknown parent of a mempool tx).
+    ///
+    /// Returns `false` if the client can't fetch standalone transactions, in
+    /// which case the caller shouldn't wait for it.
+    fn request_transaction(&mut self, _txid: Txid) -> bool {
+        false
+    }
+
+    /// Takes the outcome of a `request_transaction`, once the server has answered:
+    /// `Some(Some(tx))` if it was found, `Some(None)` if the server doesn't have it.
+    fn take_transaction(&mut self, _txid: &Txid) -> Option<Option<Transaction>> {
+        None
+    }
+
+    /// A transaction the client already downloaded (for any history), without
+    /// touching the network. Used to supply prevouts of txs applied before
+    /// their parents.
+    fn cached_transaction(&self, _txid: &Txid) -> Option<Transaction> {
+        None
+    }
+
+    /// Fetches a single transaction by id, blocking until the server answers.
+    ///
+    /// For tooling outside the history flow (e.g. inspecting a parent for its
+    /// fee). Clients that can't fetch standalone transactions return an error.
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        anyhow::bail!("client cannot fetch transaction {}", txid)
+    }
+
+    /// Fetches the server's balance of each scripthash, in order, blocking
+    /// until all have been answered.
+    ///
+    /// For cross-checking the wallet (see `SyncOrchestrator::with_balance_verification`).
+    /// Clients that can't query balances return an error.
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> Result<Vec<ScriptBalance>> {
+        anyhow::bail!("client cannot fetch balances of {} scripthashes", hashes.len())
+    }
+
+    /// Queues a balance query for each scripthash without blocking; the
+    /// answers are collected with `take_balances`.
+    ///
+    /// Returns `false` if the client can't query balances this way, in which
+    /// case the caller falls back to `get_balances`.
+    fn request_balances(&mut self, _hashes: &[sha256::Hash]) -> bool {
+        false
+    }
+
+    /// Takes the outcome of a `request_balances` for the same `hashes`, in
+    /// order, once every one has been answered (or the client failed).
+    fn take_balances(&mut self, _hashes: &[sha256::Hash]) -> Option<Result<Vec<ScriptBalance>>> {
+        None
+    }
+
+    /// Submits a signed transaction (`blockchain.transaction.broadcast`),
+    /// blocking until the server accepts or rejects it.
+    ///
+    /// Returns the txid the server reports; a rejection (e.g. a conflicting
+    /// or invalid tx) is an error carrying the server's message. Clients that
+    /// can't broadcast return an error.
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid> {
+        anyhow::bail!("client cannot broadcast transaction {}", tx.compute_txid())
+    }
+
+    /// Submits a signed transaction without blocking, returning the id its
+    /// outcome is collected with (see `take_broadcast`).
+    ///
+    /// Returns `None` if the client can't broadcast this way, in which case
+    /// the caller falls back to `broadcast`.
+    fn request_broadcast(&mut self, _tx: &Transaction) -> Option<u64> {
+        None
+    }
+
+    /// Takes the outcome of the `request_broadcast` that returned `request`,
+    /// once the server has accepted or rejected the tx (or the client failed).
+    fn take_broadcast(&mut self, _request: u64) -> Option<Result<Txid>> {
+        None
+    }
+
+    /// Closes the client's connection and stops any background work it
+    /// runs, once the driver is done with it. Nothing arrives afterwards.
+    fn shutdown(&mut self) {}
+
+    /// Returns the reason the client gave up, if it did.
+    ///
+    /// A terminal failure means no further events will ever arrive (e.g. the
+    /// connection could not be established or the server refuses subscriptions).
+    /// The driver stops when this returns `Some`, so callers can fall back to polling.
+    fn terminal_error(&self) -> Option<String> {
+        None
+    }
+
+    /// Counts of requests sent (or queued) but not yet answered.
+    fn pending_work(&self) -> PendingWork {
+        PendingWork::default()
+    }
+
+    /// Whether a connection is currently being (re-)established.
+    fn is_connecting(&self) -> bool {
+        false
+    }
+
+    /// Whether the client currently has a live connection to its server.
+    ///
+    /// Clients without a connection of their own (mocks, wrappers over
+    /// blocking calls) always report `true`.
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Counts the connections opened so far; it changes on every reconnect.
+    ///
+    /// The driver has the engine re-subscribe every script when it changes
+    /// (`EngineEvent::Reconnected`), so a client only has to report, not
+    /// restore, a new connection.
+    fn connection_epoch(&self) -> u64 {
+        0
+    }
+
+    /// Takes the txs and headers that couldn't be fetched since the last call,
+    /// so the app can report them.
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        Vec::new()
+    }
+
+    /// The protocol version the server agreed to in `server.version`, once known.
+    fn server_version(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the recorder this client feeds with request and sync latencies,
+    /// if it measures them. The driver exposes it via `DriverHandle::latency_report`.
+    fn latency_recorder(&self) -> Option<LatencyRecorder> {
+        None
+    }
+}
@@ -10,25 +10,36 @@
 //! * **Command Queue**: The driver pushes commands (Subscribe, Fetch) to a queue. The background task
 //!   drains this queue and sends JSON-RPC requests to the socket.
 //! * **Event Loop**: The background task runs an infinite loop handling socket reads/writes.
+//! * **Transport**: The byte stream is opened by a `Connector` (TLS socket in production,
+//!   in-memory duplex in tests), so the protocol handling is testable without a network.
 
 use anyhow::Result;
 use serde_json::{json, Value};
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, WriteHalf};
 use tokio::net::TcpStream;
-use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_native_tls::TlsConnector;
 
-use bitcoin::{block, ScriptBuf, Transaction, Txid};
+use bitcoin::{block, BlockHash, ScriptBuf, Transaction, TxMerkleNode, Txid};
 use bitcoin::hashes::{sha256, Hash};
+use bitcoin::consensus::encode::serialize_hex;
 use bitcoin::consensus::Decodable;
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
-use crate::streaming::electrum::api::ElectrumApi;
+use super::socks;
+use crate::persistence::{self, CachedHistory};
+use crate::streaming::electrum::api::{ElectrumApi, FetchError, PendingWork, ScriptBalance};
 use crate::streaming::engine::types::HistoryTx;
+use crate::streaming::metrics::LatencyRecorder;
+use crate::streaming::util::{merkle_root_from_branch, scripthash_from_wire, scripthash_to_wire};
 
 // =====================================================================
 // Utils
@@ -41,20 +52,342 @@ pub fn next_id() -> u64 {
     NEXT_ID.fetch_add(1, Ordering::Relaxed)
 }
 
-/// Convert script bytes to electrum scripthash hex (little endian).
-///
-/// Electrum uses the sha256 hash of the script, reversed, represented as hex.
-pub fn electrum_scripthash(script: &[u8]) -> String {
-    let hash = sha256::Hash::hash(script);
-    let mut bytes = hash.to_byte_array();
-    bytes.reverse();
-    hex::encode(bytes)
+pub use crate::streaming::util::electrum_scripthash;
+
+/// The status the server reports for a `get_history` result: sha256 of its
+/// `tx_hash:height:` pairs in order, `None` for an empty history.
+fn history_status(history: &[Value]) -> Result<Option<String>> {
+    if history.is_empty() {
+        return Ok(None);
+    }
+    let mut joined = String::new();
+    for item in history {
+        let txid = item["tx_hash"].as_str().ok_or_else(|| anyhow::anyhow!("missing tx_hash"))?;
+        joined.push_str(&format!("{}:{}:", txid, item["height"].as_i64().unwrap_or(0)));
+    }
+    Ok(Some(sha256::Hash::hash(joined.as_bytes()).to_string()))
+}
+
+/// Best-effort extraction of `"method"` from a (possibly truncated) frame.
+fn sniff_method(frame: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(frame);
+    let start = text.find("\"method\"")? + "\"method\"".len();
+    let rest = text[start..].trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+    rest.find('"').map(|end| rest[..end].to_string())
 }
 
 // =====================================================================
 // Types
 // =====================================================================
 
+/// A bidirectional byte stream carrying newline-delimited JSON-RPC.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// Opens a fresh transport to the Electrum server.
+pub type Connector = Arc<
+    dyn Fn() -> Pin<Box<dyn Future<Output = Result<Box<dyn Transport>>> + Send>> + Send + Sync,
+>;
+
+/// How often the write loop pings the server by default.
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a request may go unanswered by default before it is sent again.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default cap on a single incoming JSON-RPC frame. Generous enough for large
+/// histories and transactions, small enough that a hostile server can't OOM us.
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 32 * 1024 * 1024;
+
+/// The lowest protocol version accepted by default (see
+/// `ConnectOptions::min_protocol_version`): `blockchain.block.header` and
+/// friends need it.
+pub const DEFAULT_MIN_PROTOCOL_VERSION: &str = "1.4";
+
+/// Default cap on histories downloaded (or downloading) but not yet taken by
+/// the driver. Further history fetches wait, so a driver slowed down by
+/// wallet lock contention throttles the server instead of buffering without
+/// limit.
+pub const DEFAULT_MAX_PENDING_HISTORIES: usize = 200;
+
+/// Default cap on requests awaiting an answer. A cold start queues a fetch and
+/// a subscribe per script at once; public servers throttle or drop a client
+/// sending them all in one go.
+pub const DEFAULT_MAX_INFLIGHT: usize = 50;
+
+/// How connections are opened: the socket options `tls_connector_with_options`
+/// sets before the TLS handshake, and what the handshake must agree on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// Disable Nagle's algorithm. Requests are small JSON lines; holding them
+    /// back to coalesce with later writes only adds latency.
+    pub nodelay: bool,
+    /// OS-level TCP keepalive, on top of `server.ping`. Notices a dead peer
+    /// (e.g. after a mobile network switch) without any application traffic.
+    pub keepalive: Option<KeepaliveOptions>,
+    /// SOCKS5 proxy (e.g. Tor at `127.0.0.1:9050`) to connect through. The
+    /// proxy resolves the server's name; nothing is looked up locally.
+    pub proxy: Option<SocketAddr>,
+    /// How the server's certificate is checked.
+    pub tls: TlsConfig,
+    /// Protocol version asked for in `server.version`. A server agreeing to
+    /// an older one fails the connect.
+    pub min_protocol_version: String,
+}
+
+/// Certificate validation for the TLS handshake. The default validates the
+/// chain against the system roots and the name against the server's host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// Accept any certificate chain, including self-signed and expired ones.
+    pub danger_accept_invalid_certs: bool,
+    /// Accept a certificate issued for a different host name.
+    pub danger_accept_invalid_hostnames: bool,
+    /// DER encoding of the one certificate the server must present. It
+    /// replaces chain and host name validation: nothing else is accepted.
+    pub pinned_cert: Option<Vec<u8>>,
+}
+
+impl TlsConfig {
+    fn connector(&self) -> Result<native_tls::TlsConnector> {
+        // A pinned certificate is checked after the handshake, so it must get through it.
+        let pinned = self.pinned_cert.is_some();
+        Ok(native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(self.danger_accept_invalid_certs || pinned)
+            .danger_accept_invalid_hostnames(self.danger_accept_invalid_hostnames || pinned)
+            .build()?)
+    }
+
+    fn check_pin(&self, peer: Option<native_tls::Certificate>) -> Result<()> {
+        let Some(pinned) = &self.pinned_cert else {
+            return Ok(());
+        };
+        let presented = peer.ok_or_else(|| anyhow::anyhow!("server presented no certificate"))?.to_der()?;
+        if &presented != pinned {
+            anyhow::bail!("server certificate does not match the pinned certificate");
+        }
+        Ok(())
+    }
+}
+
+/// TCP keepalive timing (`TCP_KEEPIDLE`, `TCP_KEEPINTVL`, `TCP_KEEPCNT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveOptions {
+    /// Idle time before the first probe.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped.
+    pub retries: u32,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: Some(KeepaliveOptions {
+                idle: Duration::from_secs(60),
+                interval: Duration::from_secs(10),
+                retries: 5,
+            }),
+            proxy: None,
+            tls: TlsConfig::default(),
+            min_protocol_version: DEFAULT_MIN_PROTOCOL_VERSION.to_string(),
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Sets the options on a connected socket.
+    pub fn apply(&self, tcp: &std::net::TcpStream) -> std::io::Result<()> {
+        let socket = socket2::SockRef::from(tcp);
+        socket.set_tcp_nodelay(self.nodelay)?;
+        match &self.keepalive {
+            Some(k) => socket.set_tcp_keepalive(
+                &socket2::TcpKeepalive::new()
+                    .with_time(k.idle)
+                    .with_interval(k.interval)
+                    .with_retries(k.retries),
+            )?,
+            None => socket.set_keepalive(false)?,
+        }
+        log::debug!("[ADAPTER] socket options: {:?}", self);
+        Ok(())
+    }
+}
+
+/// How the adapter re-establishes a connection the server (or the network)
+/// dropped: attempts with exponential backoff plus jitter, then a terminal failure.
+///
+/// The first attempt after a stable connection is immediate; later ones wait
+/// `initial_backoff`, doubling up to `max_backoff`. A connection that drops
+/// again within `stable_after` keeps the backoff where it was, so a server
+/// that accepts and then hangs up isn't hammered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Connection attempts before giving up; 0 fails as soon as the socket closes.
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Upper bound of the random delay added to each backoff.
+    pub jitter: Duration,
+    /// How long a connection must last for the backoff to start over.
+    pub stable_after: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Never reconnect: a dropped connection is a terminal failure.
+    pub const NONE: Self = Self {
+        attempts: 0,
+        initial_backoff: Duration::ZERO,
+        max_backoff: Duration::ZERO,
+        jitter: Duration::ZERO,
+        stable_after: Duration::ZERO,
+    };
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            jitter: Duration::from_millis(500),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Where a `ReconnectPolicy`'s backoff stands, kept across reconnects.
+#[derive(Debug, Default)]
+pub(crate) struct Backoff {
+    /// Base delay before the next attempt; `None` right after a reset.
+    next: Option<Duration>,
+}
+
+impl Backoff {
+    /// The delay before the next attempt (zero right after a reset), and
+    /// advances the backoff.
+    pub(crate) fn next_delay(&mut self, policy: &ReconnectPolicy) -> Duration {
+        let base = self.next;
+        self.next = Some(match base {
+            None => policy.initial_backoff,
+            Some(delay) => (delay * 2).min(policy.max_backoff),
+        });
+        match base {
+            Some(delay) => delay + random_up_to(policy.jitter),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// A random duration in `0..=max`, from std's randomly keyed hasher.
+fn random_up_to(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    if max.is_zero() {
+        return max;
+    }
+    let random = std::collections::hash_map::RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// How many downloaded transactions `TxCache` keeps.
+const MAX_CACHED_TXS: usize = 10_000;
+
+/// Downloaded transactions by txid, so repeat lookups don't hit the
+/// server. Bounded: past `capacity`, the least recently used is evicted.
+struct TxCache {
+    capacity: usize,
+    /// Each tx with the tick it was last used at.
+    txs: HashMap<Txid, (Transaction, u64)>,
+    /// Txids by last use, oldest first.
+    order: BTreeMap<u64, Txid>,
+    tick: u64,
+}
+
+impl TxCache {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, txs: HashMap::new(), order: BTreeMap::new(), tick: 0 }
+    }
+
+    /// The cached tx, marked as just used.
+    fn get(&mut self, txid: &Txid) -> Option<&Transaction> {
+        let tick = self.next_tick();
+        let (tx, used) = self.txs.get_mut(txid)?;
+        self.order.remove(used);
+        self.order.insert(tick, *txid);
+        *used = tick;
+        Some(tx)
+    }
+
+    fn insert(&mut self, txid: Txid, tx: Transaction) {
+        let tick = self.next_tick();
+        if let Some((_, used)) = self.txs.insert(txid, (tx, tick)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(tick, txid);
+        while self.txs.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.txs.remove(&oldest);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+/// Why `AsyncElectrumTask::run_forever` returned without failing.
+enum SessionEnd {
+    /// Closed on purpose after being idle; reopened on the next request.
+    Idle,
+    /// Lost (socket closed, read or write error); reconnect right away.
+    Dropped(String),
+    /// Closed because the adapter is shutting down; the task exits.
+    Shutdown,
+}
+
+/// How long a blocking request (`get_transaction`, `get_balances`,
+/// `get_block_header`, `broadcast`) waits for the server's answer.
+const BLOCKING_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a changed history cache is written to disk while syncing.
+const HISTORY_CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connection health counters, so a dead link doesn't look like "no changes".
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionHealth {
+    /// `server.ping` requests sent.
+    pub pings_sent: u64,
+    /// `server.ping` responses received.
+    pub pongs_received: u64,
+    /// Incoming messages that failed to parse or process (logged, then skipped).
+    pub message_errors: u64,
+    /// The most recent message error, if any.
+    pub last_error: Option<String>,
+    /// Requests left unanswered for the request timeout, and sent again.
+    pub requests_timed_out: u64,
+}
+
+/// Informational metadata the server reports about itself (see
+/// `ElectrumAdapter::server_info`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// Server software, from the `server.version` reply (e.g. "ElectrumX 1.16.0").
+    pub software: Option<String>,
+    /// Protocol version the server agreed to.
+    pub protocol: Option<String>,
+    /// `server.banner`, the operator's message of the day.
+    pub banner: Option<String>,
+    /// `server.donation_address`.
+    pub donation_address: Option<String>,
+}
+
 /// Internal commands sent from the synchronous Driver to the Async Task.
 #[derive(Debug)]
 pub enum InternalCommand {
@@ -63,6 +396,10 @@ pub enum InternalCommand {
         hash: sha256::Hash,
         script: ScriptBuf,
     },
+    /// Cancel the status subscription for a script hash.
+    Unsubscribe {
+        hash: sha256::Hash,
+    },
     /// Request the transaction history for a script hash.
     FetchHistory {
         hash: sha256::Hash,
@@ -73,11 +410,39 @@ pub enum InternalCommand {
         related_hash: sha256::Hash,
         height: i32,                      // NEW: confirmation height from get_history
     },
-    /// Request a block header by height (for building anchors).
+    /// Request a standalone transaction (not part of a history), for
+    /// `request_transaction` or, if `blocking`, a `get_transaction` caller.
+    FetchRawTransaction {
+        txid: Txid,
+        blocking: bool,
+    },
+    /// Look up a scripthash's balance for a blocking `get_balances` caller.
+    GetBalance {
+        hash: sha256::Hash,
+    },
+    /// Request a block header by height (for building anchors). `None`:
+    /// outside any history, for a blocking `get_block_header` caller.
     FetchBlockHeader {                    // NEW
+        height: u32,
+        related_hash: Option<sha256::Hash>,
+    },
+    /// Request the merkle proof of a confirmed history tx, to check it
+    /// against the header at `height`.
+    FetchMerkle {
+        txid: Txid,
         height: u32,
         related_hash: sha256::Hash,
     },
+    /// Submit a signed transaction for a blocking `broadcast` caller, whose
+    /// outcome is filed under the caller's `request` id.
+    Broadcast {
+        request: u64,
+        tx: Transaction,
+    },
+    /// Health check (`server.ping`).
+    Ping,
+    /// Ask for `server.banner` and `server.donation_address`.
+    FetchServerInfo,
 }
 
 /// Tracks the type of an in-flight JSON-RPC request to handle the response correctly.
@@ -85,13 +450,46 @@ pub enum InternalCommand {
 pub enum RequestType {
     History(sha256::Hash),
     Transaction {
+        txid: Txid,
         related_hash: sha256::Hash,
         height: i32,                      // NEW: carried from history response
     },
     BlockHeader {                         // NEW
         height: u32,
+        related_hash: Option<sha256::Hash>,
+    },
+    /// A history tx's merkle proof (`blockchain.transaction.get_merkle`).
+    Merkle {
+        txid: Txid,
         related_hash: sha256::Hash,
     },
+    /// A transaction requested on its own, via `request_transaction` or
+    /// (`blocking`) `get_transaction`.
+    RawTransaction {
+        txid: Txid,
+        blocking: bool,
+    },
+    /// A balance looked up via `get_balances`.
+    Balance(sha256::Hash),
+    /// A transaction submitted via `broadcast`, kept so a lost connection
+    /// can resend it.
+    Broadcast {
+        request: u64,
+        tx: Transaction,
+    },
+    /// A `blockchain.scripthash.subscribe` call. An error response means the
+    /// server cannot stream updates for us at all.
+    Subscribe(sha256::Hash),
+    /// A `blockchain.scripthash.unsubscribe` call.
+    Unsubscribe(sha256::Hash),
+    /// A `server.ping` health check.
+    Ping,
+    /// The handshake's `server.version`.
+    Version,
+    /// The handshake's `blockchain.headers.subscribe`, answered with the tip.
+    HeadersSubscribe,
+    Banner,
+    DonationAddress,
 }
 
 // =====================================================================
@@ -102,15 +500,48 @@ pub enum RequestType {
 struct SharedState {
     // --- Output (Network -> Driver) ---
     /// Queue of script hashes that have received updates or finished syncing.
-    /// The driver polls this via `poll_scripthash_changed`.
+    /// The driver polls this via `poll_scripthash_changed`. Filled through
+    /// `mark_ready`, which keeps it bounded.
     ready: VecDeque<sha256::Hash>,
 
+    /// How often each hash is in `ready`.
+    ready_counts: HashMap<sha256::Hash, u8>,
+
+    /// Non-empty history lists (scripthash, tx count) not yet polled by the driver.
+    activity: VecDeque<(sha256::Hash, usize)>,
+
     /// Temporary storage for downloaded transaction histories.
     history_cache: HashMap<sha256::Hash, Vec<HistoryTx>>,   // CHANGED: was Vec<Transaction>
 
     /// Cache of block headers by height (used by orchestrator for anchors).
     block_header_cache: HashMap<u32, block::Header>,        // NEW
 
+    /// Scripthashes whose latest history had a tx confirmed at each height,
+    /// to re-sync when a reorg replaces that block.
+    confirmed_heights: HashMap<u32, HashSet<sha256::Hash>>,
+
+    /// The latest tip the server announced (kept across reconnects).
+    chain_tip: Option<(u32, block::Header)>,
+
+    /// Answers to `request_transaction` (`None`: the server doesn't have it), until taken.
+    fetched_txs: HashMap<Txid, Option<Transaction>>,
+
+    /// Recently downloaded transactions, so repeat lookups don't hit the server.
+    tx_cache: TxCache,
+
+    /// Answers to `get_transaction` lookups (`Err`: the server's error), until taken.
+    tx_lookups: HashMap<Txid, Result<Transaction, String>>,
+
+    /// Answers to `get_block_header` lookups (`Err`: the server's error), until taken.
+    header_lookups: HashMap<u32, Result<block::Header, String>>,
+
+    /// Outcomes of `broadcast` calls by the caller's request id (`Ok`: the
+    /// txid the server reported, `Err`: its rejection), until taken.
+    broadcast_results: HashMap<u64, Result<Txid, String>>,
+
+    /// Answers to `get_balances` lookups (`Err`: the server's error), until taken.
+    balance_lookups: HashMap<sha256::Hash, Result<ScriptBalance, String>>,
+
     // --- Input (Driver -> Network) ---
     /// Queue of commands waiting to be sent to the Electrum server.
     command_queue: VecDeque<InternalCommand>,
@@ -123,28 +554,669 @@ struct SharedState {
     /// Key: ScriptHash, Value: Count of txs still pending.
     remaining_txs: HashMap<sha256::Hash, usize>,
 
+    /// History members that failed to fetch or decode, per scripthash,
+    /// until taken (see `ElectrumApi::take_errors`).
+    fetch_errors: HashMap<sha256::Hash, Vec<FetchError>>,
+
+    /// Histories that lost a member to a fetch error, until `fetch_history_txs`
+    /// takes them.
+    incomplete_histories: HashSet<sha256::Hash>,
+
+    /// Which of the histories `fetch_history_txs` returned were incomplete,
+    /// until asked (see `ElectrumApi::history_incomplete`).
+    incomplete_taken: HashSet<sha256::Hash>,
+
     /// Counter for block headers remaining to be downloaded for a specific history request.
     /// Key: ScriptHash, Value: Count of unique heights still pending.
     remaining_headers: HashMap<sha256::Hash, usize>,        // NEW
 
-    /// Tracks which block heights have already been requested (to avoid duplicates).
-    headers_in_flight: HashSet<u32>,                        // NEW
+    /// Counter for merkle proofs of confirmed txs remaining for a specific
+    /// history request.
+    remaining_proofs: HashMap<sha256::Hash, usize>,
+
+    /// Merkle roots the proofs of a history's confirmed txs commit to, until
+    /// the history completes and they are checked against the headers. A tx
+    /// without an entry had no usable proof.
+    proven_roots: HashMap<(sha256::Hash, Txid), TxMerkleNode>,
+
+    /// Block each confirmed tx was last proven in. A refetch only asks for the
+    /// proofs of txs that are new or now anchored in a different block.
+    proven_blocks: HashMap<Txid, BlockHash>,
+
+    /// Block heights already requested (to avoid duplicates), mapped to every
+    /// scripthash waiting on that header. A scripthash whose history references
+    /// a height requested by *another* scripthash must still wait for it.
+    headers_in_flight: HashMap<u32, HashSet<sha256::Hash>>,
     
     /// Flag indicating if the TLS connection handshake is complete.
     connected: bool,
+
+    /// Bumped for every new connection. A reader task from an older session
+    /// drops whatever it still receives instead of applying it.
+    session: u64,
+
+    /// Set once the client has given up (connect failed, socket lost and not
+    /// re-established, or the server rejected subscriptions). The driver
+    /// stops when it sees this.
+    terminal_error: Option<String>,
+
+    // --- Health ---
+    /// How often to ping the server. A ping left unanswered for a full
+    /// interval marks the connection dead.
+    ping_interval: Duration,
+
+    /// When the last ping was queued.
+    last_ping_at: Instant,
+
+    /// When the currently unanswered ping was queued, if any.
+    ping_sent_at: Option<Instant>,
+
+    /// How long any other request may go unanswered before it is retried
+    /// (see `expire_requests`).
+    request_timeout: Duration,
+
+    health: ConnectionHealth,
+
+    /// What the server told us about itself (kept across reconnects).
+    server_info: ServerInfo,
+
+    /// A server agreeing to an older protocol than this fails the connect.
+    min_protocol_version: String,
+
+    /// Notified when this session's `server.version` is answered; `connect`
+    /// waits for it.
+    version_answered: Arc<tokio::sync::Notify>,
+
+    /// Why this session's `server.version` got no usable protocol, if it
+    /// didn't (see `check_protocol_version`).
+    version_refused: Option<String>,
+
+    /// Largest incoming frame (including its newline) the reader accepts
+    /// before tearing the connection down.
+    max_frame_bytes: usize,
+
+    /// Requests (pings aside) sent and not yet answered at any one time; the
+    /// rest wait in `command_queue`.
+    max_inflight: usize,
+
+    /// History fetches wait in `command_queue` while this many histories are
+    /// being fetched or wait for the driver (see `pending_histories`).
+    max_pending_histories: usize,
+
+    // --- Latency ---
+    /// When each in-flight request was written to the socket (by request id).
+    request_sent_at: HashMap<u64, Instant>,
+
+    /// When the pending history fetch for a scripthash was first sent.
+    history_started_at: HashMap<sha256::Hash, Instant>,
+
+    latency: LatencyRecorder,
+
+    // --- Idle disconnect ---
+    /// Every registered script, re-subscribed when the connection is reopened.
+    subscriptions: HashMap<sha256::Hash, ScriptBuf>,
+
+    /// The last status the server reported per scripthash. A different status
+    /// on re-subscribe means the history changed while we were disconnected.
+    statuses: HashMap<sha256::Hash, Option<String>>,
+
+    // --- History cache ---
+    /// Where `cached_histories` is saved (see `ElectrumAdapter::with_cache_path`).
+    history_cache_path: Option<PathBuf>,
+
+    /// Resolved histories, each with the status it was fetched under.
+    cached_histories: HashMap<sha256::Hash, CachedHistory>,
+
+    /// The status matching each pending history, as the server listed it.
+    fetched_status: HashMap<sha256::Hash, Option<String>>,
+
+    /// `cached_histories` changed since it was last saved.
+    history_cache_dirty: bool,
+
+    history_cache_saved_at: Instant,
+
+    /// Close the connection after this long caught up with no activity.
+    idle_disconnect_after: Option<Duration>,
+
+    /// While idle, reconnect this often to check for changes.
+    idle_recheck_every: Option<Duration>,
+
+    /// Last request sent or non-ping message received.
+    last_activity: Instant,
+
+    /// Wakes an idle connection when a request is queued or on shutdown.
+    wake: Arc<tokio::sync::Notify>,
+
+    /// Disconnected on purpose; reopened on the next request.
+    idle: bool,
+
+    /// Set by `ElectrumAdapter::shutdown`: the background task closes the
+    /// connection and its runtime exits.
+    shutdown: bool,
+
+    // --- Reconnect ---
+    /// Why the current connection was lost, set by the reader for the write
+    /// loop to act on.
+    dropped: Option<String>,
+
+    reconnect: ReconnectPolicy,
 }
 
 impl SharedState {
-    /// Checks if all data (txs + headers) is ready for a given scripthash.
-    /// If so, signals the driver via the `ready` queue.
+    fn new() -> Self {
+        Self {
+            ready: VecDeque::new(),
+            ready_counts: HashMap::new(),
+            activity: VecDeque::new(),
+            history_cache: HashMap::new(),
+            block_header_cache: HashMap::new(),
+            confirmed_heights: HashMap::new(),
+            chain_tip: None,
+            fetched_txs: HashMap::new(),
+            tx_cache: TxCache::new(MAX_CACHED_TXS),
+            tx_lookups: HashMap::new(),
+            header_lookups: HashMap::new(),
+            broadcast_results: HashMap::new(),
+            balance_lookups: HashMap::new(),
+            command_queue: VecDeque::new(),
+            inflight_requests: HashMap::new(),
+            remaining_txs: HashMap::new(),
+            fetch_errors: HashMap::new(),
+            incomplete_histories: HashSet::new(),
+            incomplete_taken: HashSet::new(),
+            remaining_headers: HashMap::new(),
+            remaining_proofs: HashMap::new(),
+            proven_roots: HashMap::new(),
+            proven_blocks: HashMap::new(),
+            headers_in_flight: HashMap::new(),
+            connected: false,
+            session: 0,
+            terminal_error: None,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            last_ping_at: Instant::now(),
+            ping_sent_at: None,
+            health: ConnectionHealth::default(),
+            server_info: ServerInfo::default(),
+            min_protocol_version: DEFAULT_MIN_PROTOCOL_VERSION.to_string(),
+            version_answered: Arc::new(tokio::sync::Notify::new()),
+            version_refused: None,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            max_pending_histories: DEFAULT_MAX_PENDING_HISTORIES,
+            request_sent_at: HashMap::new(),
+            history_started_at: HashMap::new(),
+            latency: LatencyRecorder::new(),
+            subscriptions: HashMap::new(),
+            statuses: HashMap::new(),
+            history_cache_path: None,
+            cached_histories: HashMap::new(),
+            fetched_status: HashMap::new(),
+            history_cache_dirty: false,
+            history_cache_saved_at: Instant::now(),
+            idle_disconnect_after: None,
+            idle_recheck_every: None,
+            last_activity: Instant::now(),
+            wake: Arc::new(tokio::sync::Notify::new()),
+            idle: false,
+            shutdown: false,
+            dropped: None,
+            reconnect: ReconnectPolicy::default(),
+        }
+    }
+
+    /// Queues a ping when one is due.
+    ///
+    /// Returns `Err` if the previous ping went unanswered for a whole interval,
+    /// which means the connection is dead even though the socket looks open;
+    /// the session is then dropped and reconnected like a closed socket.
+    fn ping_if_due(&mut self, now: Instant) -> Result<()> {
+        if let Some(sent_at) = self.ping_sent_at {
+            if now.duration_since(sent_at) >= self.ping_interval {
+                anyhow::bail!("server did not answer ping within {:?}", self.ping_interval);
+            }
+            return Ok(());
+        }
+
+        if now.duration_since(self.last_ping_at) >= self.ping_interval {
+            self.last_ping_at = now;
+            self.ping_sent_at = Some(now);
+            self.health.pings_sent += 1;
+            self.command_queue.push_back(InternalCommand::Ping);
+        }
+        Ok(())
+    }
+
+    /// Gives up on requests the server has left unanswered for
+    /// `request_timeout` and sends them again, so nobody waits forever on a
+    /// lost answer.
+    ///
+    /// Lookups and subscriptions are resent as they were. A history whose
+    /// list, tx or proof request expired is fetched again from scratch; a
+    /// header request is resent, since other histories may wait on it too.
+    /// Pings have their own deadline (`ping_if_due`); the handshake is only
+    /// repeated by a reconnect.
+    fn expire_requests(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .request_sent_at
+            .iter()
+            .filter(|(_, sent)| now.duration_since(**sent) >= self.request_timeout)
+            .map(|(id, _)| *id)
+            .filter(|id| !matches!(self.inflight_requests.get(id), Some(RequestType::Ping)))
+            .collect();
+        let mut histories: BTreeSet<sha256::Hash> = BTreeSet::new();
+        for id in expired {
+            self.request_sent_at.remove(&id);
+            let Some(request) = self.inflight_requests.remove(&id) else {
+                continue;
+            };
+            log::warn!(
+                "[ADAPTER] request {} unanswered after {:?}, retrying: {:?}",
+                id,
+                self.request_timeout,
+                request
+            );
+            self.health.requests_timed_out += 1;
+            let command = match request {
+                RequestType::History(hash)
+                | RequestType::Transaction { related_hash: hash, .. }
+                | RequestType::Merkle { related_hash: hash, .. } => {
+                    histories.insert(hash);
+                    continue;
+                }
+                RequestType::BlockHeader { height, related_hash } => {
+                    InternalCommand::FetchBlockHeader { height, related_hash }
+                }
+                RequestType::RawTransaction { txid, blocking } => {
+                    InternalCommand::FetchRawTransaction { txid, blocking }
+                }
+                RequestType::Balance(hash) => InternalCommand::GetBalance { hash },
+                RequestType::Broadcast { request, tx } => InternalCommand::Broadcast { request, tx },
+                RequestType::Subscribe(hash) => match self.subscriptions.get(&hash) {
+                    Some(script) => InternalCommand::Subscribe { hash, script: script.clone() },
+                    None => continue,
+                },
+                RequestType::Unsubscribe(hash) => InternalCommand::Unsubscribe { hash },
+                RequestType::Banner | RequestType::DonationAddress => {
+                    if self.command_queue.iter().any(|c| matches!(c, InternalCommand::FetchServerInfo)) {
+                        continue;
+                    }
+                    InternalCommand::FetchServerInfo
+                }
+                RequestType::Ping | RequestType::Version | RequestType::HeadersSubscribe => continue,
+            };
+            self.command_queue.push_back(command);
+        }
+        for hash in histories {
+            self.refetch_history(hash);
+        }
+    }
+
+    /// Abandons the history fetch in progress for `hash` and starts over.
+    /// Answers still due for the old fetch are dropped when they arrive, so
+    /// they can't count toward the new one.
+    fn refetch_history(&mut self, hash: sha256::Hash) {
+        self.inflight_requests.retain(|_, request| {
+            !matches!(
+                request,
+                RequestType::History(h)
+                | RequestType::Transaction { related_hash: h, .. }
+                | RequestType::Merkle { related_hash: h, .. } if *h == hash
+            )
+        });
+        self.command_queue.retain(|command| {
+            !matches!(
+                command,
+                InternalCommand::FetchTransaction { related_hash, .. }
+                | InternalCommand::FetchMerkle { related_hash, .. } if *related_hash == hash
+            )
+        });
+        for waiters in self.headers_in_flight.values_mut() {
+            waiters.remove(&hash);
+        }
+        self.proven_roots.retain(|(h, _), _| *h != hash);
+        self.history_cache.remove(&hash);
+        self.remaining_txs.remove(&hash);
+        self.remaining_headers.remove(&hash);
+        self.remaining_proofs.remove(&hash);
+        self.command_queue.push_back(InternalCommand::FetchHistory { hash });
+    }
+
+    /// Counts and logs an incoming message that could not be processed.
+    fn record_message_error(&mut self, e: &anyhow::Error) {
+        log::error!("[ADAPTER] process_message error: {:?}", e);
+        self.health.message_errors += 1;
+        self.health.last_error = Some(format!("{:#}", e));
+    }
+
+    /// Wakes the driver for `hash`. A hash is queued at most twice: entries
+    /// only say "look again", and a second one already covers every event
+    /// that arrives before the first is taken, however many there are (a
+    /// server flooding notifications, say).
+    fn mark_ready(&mut self, hash: sha256::Hash) {
+        let count = self.ready_counts.entry(hash).or_default();
+        if *count < 2 {
+            *count += 1;
+            self.ready.push_back(hash);
+        }
+    }
+
+    /// Histories requested, downloading or downloaded, and not yet taken.
+    fn pending_histories(&self) -> usize {
+        let requested = self.inflight_requests.values().filter(|r| matches!(r, RequestType::History(_))).count();
+        let downloading = self.remaining_txs.keys().filter(|h| !self.history_cache.contains_key(*h)).count();
+        requested + downloading + self.history_cache.len()
+    }
+
+    /// Pops the next hash queued by `mark_ready`, letting it be queued again.
+    fn take_ready(&mut self) -> Option<sha256::Hash> {
+        let hash = self.ready.pop_front()?;
+        if let Some(count) = self.ready_counts.get_mut(&hash) {
+            *count -= 1;
+            if *count == 0 {
+                self.ready_counts.remove(&hash);
+            }
+        }
+        Some(hash)
+    }
+
+    /// Refuses a server whose agreed protocol is older than the minimum, or
+    /// that agreed to none (it refused the handshake's `server.version`). An
+    /// unparseable version is let through.
+    fn check_protocol_version(&self) -> Result<()> {
+        if let Some(reason) = &self.version_refused {
+            anyhow::bail!("server.version refused: {}", reason);
+        }
+        let Some(protocol) = &self.server_info.protocol else {
+            return Ok(());
+        };
+        match (parse_version(protocol), parse_version(&self.min_protocol_version)) {
+            (Some(agreed), Some(min)) if agreed < min => anyhow::bail!(
+                "server speaks protocol {}, older than the required {}",
+                protocol,
+                self.min_protocol_version
+            ),
+            (None, _) => log::warn!("[ADAPTER] unparseable protocol version {:?}", protocol),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Records an unrecoverable failure. Only the first reason is kept.
+    fn fail(&mut self, reason: String) {
+        if self.terminal_error.is_none() {
+            log::error!("[ADAPTER] terminal failure: {}", reason);
+            self.terminal_error = Some(reason);
+        }
+    }
+
+    /// Starts a new connection session: requests sent on earlier connections
+    /// will never be answered here, so forget them.
+    fn begin_session(&mut self) -> u64 {
+        self.session += 1;
+        self.connected = false;
+        self.version_answered = Arc::new(tokio::sync::Notify::new());
+        self.version_refused = None;
+        self.idle = false;
+        self.dropped = None;
+        self.inflight_requests.clear();
+        self.request_sent_at.clear();
+        let now = Instant::now();
+        self.ping_sent_at = None;
+        self.last_ping_at = now;
+        self.last_activity = now;
+        self.session
+    }
+
+    /// Whether the connection has been caught up and quiet for long enough
+    /// to be closed (see `ElectrumAdapter::with_idle_disconnect`).
+    fn idle_due(&self, now: Instant) -> bool {
+        let Some(after) = self.idle_disconnect_after else {
+            return false;
+        };
+        self.command_queue.is_empty()
+            && self.inflight_requests.values().all(|r| matches!(r, RequestType::Ping))
+            && self.remaining_txs.is_empty()
+            && self.headers_in_flight.is_empty()
+            && self.ready.is_empty()
+            && now.duration_since(self.last_activity) >= after
+    }
+
+    /// Ends the current session without failing: the reader of the closed
+    /// connection sees a stale session and exits quietly.
+    fn go_idle(&mut self) {
+        self.begin_session();
+        self.idle = true;
+    }
+
+    /// Queues a request from the driver, waking an idle connection.
+    fn enqueue(&mut self, command: InternalCommand) {
+        self.command_queue.push_back(command);
+        self.wake.notify_one();
+    }
+
+    /// Stops the background task once it next looks.
+    fn request_shutdown(&mut self) {
+        self.shutdown = true;
+        self.wake.notify_one();
+    }
+
+    /// Whether an idle connection should be reopened: a request is waiting,
+    /// or it's time for a periodic check.
+    fn wake_due(&self, idle_since: Instant, now: Instant) -> bool {
+        self.shutdown
+            || !self.command_queue.is_empty()
+            || self.idle_recheck_every.is_some_and(|every| now.duration_since(idle_since) >= every)
+    }
+
+    /// Caches `header` at `height`.
+    ///
+    /// A different header already cached there means the chain reorganised:
+    /// every cached header from that height up belongs to the old branch and
+    /// is dropped, and each scripthash with a tx confirmed in that range goes
+    /// back on `ready` so the driver re-syncs it against the new blocks.
+    fn cache_header(&mut self, height: u32, header: block::Header) {
+        let replaced = self.block_header_cache.get(&height).filter(|old| **old != header).copied();
+        if let Some(old) = replaced {
+            log::warn!(
+                "[ADAPTER] reorg at height {}: {} replaced by {}",
+                height,
+                old.block_hash(),
+                header.block_hash()
+            );
+            self.block_header_cache.retain(|h, _| *h < height);
+            // The re-synced histories record their heights again.
+            let affected: BTreeSet<sha256::Hash> = self
+                .confirmed_heights
+                .extract_if(|h, _| *h >= height)
+                .flat_map(|(_, hashes)| hashes)
+                .collect();
+            for hash in affected {
+                if !self.ready.contains(&hash) {
+                    self.mark_ready(hash);
+                }
+            }
+        }
+        self.block_header_cache.insert(height, header);
+    }
+
+    /// Takes the answers to a `request_balances` once every hash has one, in
+    /// the order of `hashes`; an error if the server refused any.
+    fn take_balances(&mut self, hashes: &[sha256::Hash]) -> Option<Result<Vec<ScriptBalance>>> {
+        if !hashes.iter().all(|hash| self.balance_lookups.contains_key(hash)) {
+            return None;
+        }
+        let answers: HashMap<sha256::Hash, Result<ScriptBalance, String>> =
+            hashes.iter().filter_map(|hash| Some((*hash, self.balance_lookups.remove(hash)?))).collect();
+        let balances = hashes
+            .iter()
+            .map(|hash| answers[hash].clone().map_err(|e| anyhow::anyhow!("server refused balance of {}: {}", hash, e)))
+            .collect();
+        Some(balances)
+    }
+
+    /// Forgets the heights `hash`'s previous history had txs confirmed at,
+    /// before its new history records its own.
+    fn forget_confirmed_heights(&mut self, hash: sha256::Hash) {
+        self.confirmed_heights.retain(|_, hashes| {
+            hashes.remove(&hash);
+            !hashes.is_empty()
+        });
+    }
+
+    /// Sets `verified` on each confirmed tx of `hash`'s downloaded history:
+    /// whether its proof's merkle root matches the header cached at its height.
+    fn verify_history(&mut self, hash: sha256::Hash) {
+        let Some(txs) = self.history_cache.get_mut(&hash) else {
+            return;
+        };
+        for htx in txs.iter_mut().filter(|htx| htx.height > 0) {
+            let txid = htx.tx.compute_txid();
+            let root = self.proven_roots.remove(&(hash, txid));
+            let header = self.block_header_cache.get(&(htx.height as u32));
+            // Without a fresh proof, a tx already proven in this very block stays verified.
+            let proven_in = match (root, header) {
+                (Some(root), Some(header)) if root == header.merkle_root => Some(header.block_hash()),
+                (None, Some(header)) => self.proven_blocks.get(&txid).copied().filter(|b| *b == header.block_hash()),
+                _ => None,
+            };
+            htx.verified = proven_in.is_some();
+            if let Some(block) = proven_in {
+                self.proven_blocks.insert(txid, block);
+            } else {
+                self.proven_blocks.remove(&txid);
+                log::warn!(
+                    "[ADAPTER] tx {} not proven in block {}; treating it as unconfirmed",
+                    txid,
+                    htx.height
+                );
+            }
+        }
+    }
+
+    /// Records a `{"height", "hex"}` tip from `blockchain.headers.subscribe`
+    /// (its answer or a notification), caching the header too.
+    fn record_tip(&mut self, tip: &Value) -> Result<()> {
+        let height = tip["height"]
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("tip without height"))? as u32;
+        let hex_str = tip["hex"].as_str().ok_or_else(|| anyhow::anyhow!("tip without header"))?;
+        let header = block::Header::consensus_decode(&mut &hex::decode(hex_str)?[..])?;
+        log::debug!("[ADAPTER] chain tip {} at height {}", header.block_hash(), height);
+        self.cache_header(height, header);
+        self.chain_tip = Some((height, header));
+        Ok(())
+    }
+
+    /// Marks the connection as lost; the write loop reconnects.
+    fn connection_lost(&mut self, reason: String) {
+        log::warn!("[ADAPTER] connection lost: {}", reason);
+        self.connected = false;
+        self.dropped.get_or_insert(reason);
+    }
+
+    /// Re-queues the work a lost connection left unanswered.
+    ///
+    /// Histories still downloading are fetched again from scratch (their
+    /// queued tx, header and proof requests are dropped, so counts don't mix), and
+    /// lookups someone is blocked on are resent. Call with the old
+    /// connection gone, before the next session clears `inflight_requests`.
+    fn requeue_lost_requests(&mut self) {
+        let mut histories: BTreeSet<sha256::Hash> = BTreeSet::new();
+        histories.extend(
+            self.remaining_txs.keys().chain(self.remaining_headers.keys()).chain(self.remaining_proofs.keys()),
+        );
+        for request in std::mem::take(&mut self.inflight_requests).into_values() {
+            let command = match request {
+                RequestType::History(hash)
+                | RequestType::Transaction { related_hash: hash, .. }
+                | RequestType::BlockHeader { related_hash: Some(hash), .. }
+                | RequestType::Merkle { related_hash: hash, .. } => {
+                    histories.insert(hash);
+                    continue;
+                }
+                RequestType::BlockHeader { height, related_hash: None } => {
+                    InternalCommand::FetchBlockHeader { height, related_hash: None }
+                }
+                RequestType::RawTransaction { txid, blocking } => {
+                    InternalCommand::FetchRawTransaction { txid, blocking }
+                }
+                RequestType::Balance(hash) => InternalCommand::GetBalance { hash },
+                // Resending is harmless: a server that already has the tx
+                // either accepts it again or says so.
+                RequestType::Broadcast { request, tx } => InternalCommand::Broadcast { request, tx },
+                RequestType::Banner | RequestType::DonationAddress => InternalCommand::FetchServerInfo,
+                // Re-subscribing and the new handshake cover these.
+                RequestType::Subscribe(_) | RequestType::Unsubscribe(_) | RequestType::Ping | RequestType::Version
+                | RequestType::HeadersSubscribe => {
+                    continue;
+                }
+            };
+            if matches!(command, InternalCommand::FetchServerInfo)
+                && self.command_queue.iter().any(|c| matches!(c, InternalCommand::FetchServerInfo))
+            {
+                continue;
+            }
+            self.command_queue.push_back(command);
+        }
+        self.command_queue.retain(|command| match command {
+            InternalCommand::FetchTransaction { related_hash, .. }
+            | InternalCommand::FetchBlockHeader { related_hash: Some(related_hash), .. }
+            | InternalCommand::FetchMerkle { related_hash, .. } => !histories.contains(related_hash),
+            _ => true,
+        });
+        self.headers_in_flight.clear();
+        self.proven_roots.retain(|(hash, _), _| !histories.contains(hash));
+        for hash in histories {
+            log::debug!("[ADAPTER] refetching history of {} after reconnect", hash);
+            self.history_cache.remove(&hash);
+            self.remaining_txs.remove(&hash);
+            self.remaining_headers.remove(&hash);
+            self.remaining_proofs.remove(&hash);
+            self.command_queue.push_back(InternalCommand::FetchHistory { hash });
+        }
+    }
+
+    /// Queues a subscribe for every registered script, ahead of other requests.
+    /// Subscribes still queued from before are dropped, as they're covered.
+    fn resubscribe_all(&mut self) {
+        log::info!("[ADAPTER] re-subscribing {} scripts", self.subscriptions.len());
+        self.command_queue.retain(|c| !matches!(c, InternalCommand::Subscribe { .. }));
+        for (hash, script) in &self.subscriptions {
+            self.command_queue.push_front(InternalCommand::Subscribe { hash: *hash, script: script.clone() });
+        }
+    }
+
+    /// Records a history member that couldn't be used. The history is then
+    /// delivered without it, flagged as incomplete, and not cached: its
+    /// status covers the whole of it.
+    fn record_fetch_error(&mut self, error: FetchError) {
+        let hash = match &error {
+            FetchError::Transaction { hash, .. } | FetchError::Header { hash, .. } => *hash,
+        };
+        log::warn!("[ADAPTER] {:?}", error);
+        self.fetched_status.remove(&hash);
+        self.incomplete_histories.insert(hash);
+        self.fetch_errors.entry(hash).or_default().push(error);
+    }
+
+    /// Checks if all data (txs + headers + proofs) is ready for a given scripthash.
+    /// If so, verifies its confirmed txs and signals the driver via the `ready` queue.
     fn check_history_complete(&mut self, hash: sha256::Hash) {
         let txs_done = self.remaining_txs.get(&hash).copied().unwrap_or(0) == 0;
         let hdrs_done = self.remaining_headers.get(&hash).copied().unwrap_or(0) == 0;
+        let proofs_done = self.remaining_proofs.get(&hash).copied().unwrap_or(0) == 0;
 
-        if txs_done && hdrs_done {
+        if txs_done && hdrs_done && proofs_done {
             self.remaining_txs.remove(&hash);
             self.remaining_headers.remove(&hash);
-            self.ready.push_back(hash);
+            self.remaining_proofs.remove(&hash);
+            self.verify_history(hash);
+            self.cache_history(hash);
+            self.mark_ready(hash);
+            if let Some(started) = self.history_started_at.remove(&hash) {
+                self.latency.record_scripthash_sync(started.elapsed());
+            }
             log::info!(
                 "[ADAPTER] history complete for {} ({} txs)",
                 hash,
@@ -152,6 +1224,44 @@ impl SharedState {
             );
         }
     }
+
+    /// Keeps a just-completed history for the next start, under the status
+    /// its own entries hash to.
+    fn cache_history(&mut self, hash: sha256::Hash) {
+        let Some(status) = self.fetched_status.remove(&hash) else {
+            return;
+        };
+        if let Some(txs) = self.history_cache.get(&hash) {
+            self.cached_histories.insert(hash, CachedHistory { status, txs: txs.clone() });
+            self.history_cache_dirty = true;
+        }
+    }
+
+    /// Drops the cached history of `hash` if it was fetched under a status
+    /// other than `status`. Returns whether it did.
+    fn forget_stale_history(&mut self, hash: sha256::Hash, status: &Option<String>) -> bool {
+        if self.cached_histories.get(&hash).is_none_or(|cached| cached.status == *status) {
+            return false;
+        }
+        log::debug!("[ADAPTER] cached history of {} is stale", hash);
+        self.cached_histories.remove(&hash);
+        self.history_cache_dirty = true;
+        true
+    }
+
+    /// Copies the history cache out for writing if it changed and the last
+    /// write is at least `HISTORY_CACHE_SAVE_INTERVAL` old (or `force`).
+    fn history_cache_to_save(&mut self, force: bool) -> Option<(PathBuf, HashMap<sha256::Hash, CachedHistory>)> {
+        let path = self.history_cache_path.clone()?;
+        if !self.history_cache_dirty
+            || (!force && self.history_cache_saved_at.elapsed() < HISTORY_CACHE_SAVE_INTERVAL)
+        {
+            return None;
+        }
+        self.history_cache_dirty = false;
+        self.history_cache_saved_at = Instant::now();
+        Some((path, self.cached_histories.clone()))
+    }
 }
 
 // =====================================================================
@@ -164,53 +1274,342 @@ impl SharedState {
 /// asynchronously in a background thread.
 pub struct ElectrumAdapter {
     state: Arc<Mutex<SharedState>>,
+    /// Signalled by the background task whenever `state` changes in a way
+    /// callers wait for (connected, failed, a frame processed).
+    cv: Arc<std::sync::Condvar>,
+    /// The thread running the background Tokio runtime, until `shutdown` joins it.
+    background: Option<std::thread::JoinHandle<()>>,
 }
 
 impl ElectrumAdapter {
     /// Connects to the specified Electrum server (ssl/tcp).
     ///
     /// This function blocks the current thread until the background connection
-    /// is fully established and the SSL handshake is complete.
-    pub fn new(server: String) -> Self {
-        let state = Arc::new(Mutex::new(SharedState {
-            ready: VecDeque::new(),
-            history_cache: HashMap::new(),
-            block_header_cache: HashMap::new(),     // NEW
-            command_queue: VecDeque::new(),
-            inflight_requests: HashMap::new(),
-            remaining_txs: HashMap::new(),
-            remaining_headers: HashMap::new(),      // NEW
-            headers_in_flight: HashSet::new(),      // NEW
-            connected: false,
-        }));
+    /// is fully established and the SSL handshake is complete. A malformed
+    /// address or a failed first connection attempt is returned as an error.
+    pub fn new(server: String) -> Result<Self> {
+        Self::with_connector(tls_connector(server))
+    }
+
+    /// Like `new`, with non-default socket options.
+    pub fn with_connect_options(server: String, options: ConnectOptions) -> Result<Self> {
+        let min_protocol_version = options.min_protocol_version.clone();
+        Self::with_connector_and_min_protocol(tls_connector_with_options(server, options), &min_protocol_version)
+    }
+
+    /// Like `new`, but through the SOCKS5 proxy at `proxy` (e.g. Tor).
+    /// A `tcp://` server is then spoken to in plaintext, as `.onion` servers
+    /// usually are; `ssl://` still gets TLS on top of the proxied stream.
+    pub fn with_proxy(server: String, proxy: SocketAddr) -> Result<Self> {
+        let options = ConnectOptions { proxy: Some(proxy), ..ConnectOptions::default() };
+        Self::with_connect_options(server, options)
+    }
+
+    /// Like `new`, with relaxed or pinned certificate validation, for servers
+    /// with self-signed certificates (common on personal setups).
+    ///
+    /// Strict validation is what keeps a network attacker from impersonating
+    /// the server, which would let it hide or invent transactions. Pinning the
+    /// server's certificate keeps that protection; the `danger_*` flags give it
+    /// up and should only be used on a network you trust.
+    pub fn with_tls_config(server: String, tls: TlsConfig) -> Result<Self> {
+        Self::with_connect_options(server, ConnectOptions { tls, ..ConnectOptions::default() })
+    }
+
+    /// Like `new`, but opens the byte stream through a custom `Connector`.
+    ///
+    /// Tests use this to run the full adapter against an in-memory duplex stream.
+    pub fn with_connector(connector: Connector) -> Result<Self> {
+        Self::with_connector_and_min_protocol(connector, DEFAULT_MIN_PROTOCOL_VERSION)
+    }
+
+    /// Like `with_connector`, requiring at least `min_protocol_version` (see
+    /// `ConnectOptions::min_protocol_version`).
+    pub fn with_connector_and_min_protocol(connector: Connector, min_protocol_version: &str) -> Result<Self> {
+        let rt = tokio::runtime::Runtime::new()?;
+        let mut state = SharedState::new();
+        state.min_protocol_version = min_protocol_version.to_string();
+        let state = Arc::new(Mutex::new(state));
 
         let bg_state = state.clone();
         let cv = Arc::new(std::sync::Condvar::new());
         let bg_cv = cv.clone();
 
         // Spawn the background Tokio runtime and task
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
+        let background = std::thread::spawn(move || {
             rt.block_on(async move {
+                // Only the first connection fails outright; later ones go through `reconnect`.
                 let mut task =
-                    AsyncElectrumTask::connect(server, bg_state, bg_cv)
-                        .await
-                        .expect("connect electrum");
-
-                task.run_forever().await.expect("electrum loop");
+                    match AsyncElectrumTask::connect(connector.clone(), bg_state.clone(), bg_cv.clone()).await {
+                        Ok(task) => task,
+                        Err(e) => {
+                            bg_state.lock().unwrap().fail(format!("connect failed: {:#}", e));
+                            bg_cv.notify_all();
+                            return;
+                        }
+                    };
+                let mut backoff = Backoff::default();
+                let mut connected_at = Instant::now();
+
+                loop {
+                    let (reason, policy) = match task.run_forever().await {
+                        Ok(SessionEnd::Idle) => {
+                            drop(task);
+                            backoff.reset();
+                            wait_for_wake(&bg_state).await;
+                            let s = bg_state.lock().unwrap();
+                            if s.shutdown {
+                                drop(s);
+                                bg_cv.notify_all();
+                                return;
+                            }
+                            // Reopening isn't recovering from a drop: even a
+                            // policy that never reconnects gets one attempt.
+                            let policy = ReconnectPolicy { attempts: s.reconnect.attempts.max(1), ..s.reconnect };
+                            ("idle reopen".to_string(), policy)
+                        }
+                        Ok(SessionEnd::Shutdown) => {
+                            drop(task);
+                            bg_cv.notify_all();
+                            return;
+                        }
+                        Ok(SessionEnd::Dropped(reason)) => {
+                            drop(task);
+                            let policy = {
+                                let mut s = bg_state.lock().unwrap();
+                                s.requeue_lost_requests();
+                                s.reconnect
+                            };
+                            if connected_at.elapsed() >= policy.stable_after {
+                                backoff.reset();
+                            }
+                            (reason, policy)
+                        }
+                        Err(e) => {
+                            bg_state.lock().unwrap().fail(format!("write loop failed: {:#}", e));
+                            bg_cv.notify_all();
+                            return;
+                        }
+                    };
+                    task = match reconnect(&connector, &bg_state, &bg_cv, &reason, policy, &mut backoff).await {
+                        Ok(task) => task,
+                        Err(e) => {
+                            let mut s = bg_state.lock().unwrap();
+                            // Giving up on purpose isn't a failure.
+                            if !s.shutdown {
+                                s.fail(format!("{:#}", e));
+                            }
+                            drop(s);
+                            bg_cv.notify_all();
+                            return;
+                        }
+                    };
+                    bg_state.lock().unwrap().resubscribe_all();
+                    connected_at = Instant::now();
+                }
             });
         });
 
-        // Block until the background task signals connection success
+        // Block until the background task signals connection success (or failure)
         let mut guard = state.lock().unwrap();
-        while !guard.connected {
-            guard = cv.wait(guard).unwrap();
+        while !guard.connected && guard.terminal_error.is_none() && !background.is_finished() {
+            guard = cv.wait_timeout(guard, Duration::from_millis(100)).unwrap().0;
         }
 
+        if !guard.connected || guard.terminal_error.is_some() {
+            let reason = guard.terminal_error.clone();
+            guard.shutdown = true;
+            drop(guard);
+            // The background thread has given up (or died); reap it.
+            let _ = background.join();
+            let reason = reason.unwrap_or_else(|| "background thread exited before connecting".to_string());
+            return Err(anyhow::anyhow!("electrum connect failed: {}", reason));
+        }
         log::info!("[ADAPTER] client fully connected");
         drop(guard);
 
-        Self { state }
+        Ok(Self { state, cv, background: Some(background) })
+    }
+
+    /// Sets how often the server is pinged. A ping left unanswered for a whole
+    /// interval drops the connection, which is then reconnected with backoff
+    /// (see `with_reconnect_policy`).
+    pub fn with_ping_interval(self, interval: Duration) -> Self {
+        self.state.lock().unwrap().ping_interval = interval;
+        self
+    }
+
+    /// Sets how long a request may go unanswered before it is sent again
+    /// (a history is fetched again from scratch).
+    pub fn with_request_timeout(self, timeout: Duration) -> Self {
+        self.state.lock().unwrap().request_timeout = timeout;
+        self
+    }
+
+    /// Sets how a connection lost mid-session is re-established
+    /// (`ReconnectPolicy::NONE` makes it a terminal failure).
+    pub fn with_reconnect_policy(self, policy: ReconnectPolicy) -> Self {
+        self.state.lock().unwrap().reconnect = policy;
+        self
+    }
+
+    /// Sets the largest single frame accepted from the server. A bigger frame
+    /// tears the connection down instead of being buffered; it is then
+    /// reconnected with backoff (see `with_reconnect_policy`).
+    pub fn with_max_frame_bytes(self, max: usize) -> Self {
+        self.state.lock().unwrap().max_frame_bytes = max;
+        self
+    }
+
+    /// Sets how many downloaded histories may wait for the driver before
+    /// further history fetches are held back (at least one).
+    pub fn with_max_pending_histories(self, max: usize) -> Self {
+        self.state.lock().unwrap().max_pending_histories = max.max(1);
+        self
+    }
+
+    /// Sets how many requests may await an answer at once (at least one).
+    /// Queued requests go out as answers come in.
+    pub fn with_max_inflight(self, max: usize) -> Self {
+        self.state.lock().unwrap().max_inflight = max.max(1);
+        self
+    }
+
+    /// Closes the connection after `after` caught up with no activity, and
+    /// reopens it (re-subscribing every script) on the next request.
+    ///
+    /// While disconnected the server can't notify us, so with `recheck_every`
+    /// set the adapter also reconnects that often; any script whose status
+    /// changed meanwhile is reported as changed. Trades reconnect latency for
+    /// not holding a socket and subscriptions open.
+    pub fn with_idle_disconnect(self, after: Duration, recheck_every: Option<Duration>) -> Self {
+        {
+            let mut s = self.state.lock().unwrap();
+            s.idle_disconnect_after = Some(after);
+            s.idle_recheck_every = recheck_every;
+        }
+        self
+    }
+
+    /// Keeps resolved histories in `path` across runs, loading what an earlier
+    /// run saved there. A cached history is handed to the driver on
+    /// `request_history` without asking the server; once the subscribe ack
+    /// (or a notification) reports a different status for the script, the
+    /// entry is dropped and the history fetched again.
+    ///
+    /// The file is rewritten at most every 10 seconds while histories come
+    /// in, and on `shutdown`.
+    pub fn with_cache_path(self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        {
+            let mut s = self.state.lock().unwrap();
+            match persistence::load_history_cache(&path) {
+                Ok(histories) => {
+                    log::info!("[ADAPTER] loaded {} cached histories from {}", histories.len(), path.display());
+                    for history in histories.values() {
+                        for h in &history.txs {
+                            s.tx_cache.insert(h.tx.compute_txid(), h.tx.clone());
+                        }
+                    }
+                    s.cached_histories = histories;
+                }
+                Err(e) => log::warn!("[ADAPTER] Ignoring unreadable history cache {}: {}", path.display(), e),
+            }
+            s.history_cache_path = Some(path);
+        }
+        self
+    }
+
+    /// Whether a connection is currently open (false while idle-disconnected).
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    /// Returns a snapshot of the connection health counters.
+    pub fn health(&self) -> ConnectionHealth {
+        self.state.lock().unwrap().health.clone()
+    }
+
+    /// What the server has reported about itself so far. The software and
+    /// protocol version come with every handshake; the banner and donation
+    /// address only after `fetch_server_info`.
+    pub fn server_info(&self) -> ServerInfo {
+        self.state.lock().unwrap().server_info.clone()
+    }
+
+    /// Submits `tx` and blocks until the server accepts it (returning the
+    /// txid it reports) or rejects it (an error with the server's message).
+    pub fn broadcast_blocking(&self, tx: &Transaction) -> Result<Txid> {
+        let txid = tx.compute_txid();
+        let request = next_id();
+        self.state.lock().unwrap().enqueue(InternalCommand::Broadcast { request, tx: tx.clone() });
+
+        let answer = self.wait_for_answer(format_args!("broadcasting transaction {}", txid), |s| {
+            s.broadcast_results.remove(&request)
+        })?;
+        answer.map_err(|e| anyhow::anyhow!("server rejected transaction {}: {}", txid, e))
+    }
+
+    /// Sleeps on the condvar until `take` finds the answer to a blocking
+    /// request, failing if the connection fails or `BLOCKING_REQUEST_TIMEOUT`
+    /// passes first. `what` describes the request in those errors.
+    fn wait_for_answer<T>(
+        &self,
+        what: std::fmt::Arguments<'_>,
+        mut take: impl FnMut(&mut SharedState) -> Option<T>,
+    ) -> Result<T> {
+        let deadline = Instant::now() + BLOCKING_REQUEST_TIMEOUT;
+        let mut s = self.state.lock().unwrap();
+        loop {
+            if let Some(answer) = take(&mut s) {
+                return Ok(answer);
+            }
+            if let Some(reason) = &s.terminal_error {
+                anyhow::bail!("connection failed while {}: {}", what, reason);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                anyhow::bail!("timed out {}", what);
+            }
+            s = self.cv.wait_timeout(s, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Closes the connection and stops the background task, waiting for its
+    /// thread (and Tokio runtime) to exit. Nothing is sent or received
+    /// afterwards; requests still queued are dropped.
+    pub fn shutdown(&mut self) {
+        self.state.lock().unwrap().request_shutdown();
+        if let Some(background) = self.background.take() {
+            let _ = background.join();
+            log::info!("[ADAPTER] shut down");
+        }
+        self.save_history_cache(true);
+    }
+
+    /// Writes the history cache when due (see `history_cache_to_save`),
+    /// outside the state lock so the background task isn't held up.
+    fn save_history_cache(&self, force: bool) {
+        let Some((path, histories)) = self.state.lock().unwrap().history_cache_to_save(force) else {
+            return;
+        };
+        match persistence::save_history_cache(&path, &histories) {
+            Ok(()) => log::debug!("[ADAPTER] saved {} cached histories", histories.len()),
+            Err(e) => log::warn!("[ADAPTER] Failed to save history cache: {}", e),
+        }
+    }
+
+    /// Asks the server for its banner and donation address. The answers show
+    /// up in `server_info` once they arrive.
+    pub fn fetch_server_info(&self) {
+        self.state.lock().unwrap().enqueue(InternalCommand::FetchServerInfo);
+    }
+}
+
+/// Stops the background task without waiting for it (see `shutdown`).
+impl Drop for ElectrumAdapter {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().request_shutdown();
     }
 }
 
@@ -223,7 +1622,11 @@ impl ElectrumApi for ElectrumAdapter {
     fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash) {
         log::trace!("[ADAPTER] register_script({})", hash);
         let mut s = self.state.lock().unwrap();
-        s.command_queue.push_back(InternalCommand::Subscribe { hash, script });
+        // Already subscribed (or re-subscribed by `resubscribe_all`).
+        if s.subscriptions.insert(hash, script.clone()).is_some() {
+            return;
+        }
+        s.enqueue(InternalCommand::Subscribe { hash, script });
         log::trace!(
             "[ADAPTER] queued subscribe for {} (queue len={})",
             hash,
@@ -231,47 +1634,285 @@ impl ElectrumApi for ElectrumAdapter {
         );
     }
 
-    /// Queues a request to fetch transaction history for a script hash.
-    fn request_history(&mut self, hash: sha256::Hash) {
-        log::trace!("[ADAPTER] request_history({})", hash);
-        let mut s = self.state.lock().unwrap();
-        s.command_queue.push_back(InternalCommand::FetchHistory { hash });
+    /// Queues an unsubscribe request for a script hash.
+    fn unregister_script(&mut self, hash: sha256::Hash) {
+        log::trace!("[ADAPTER] unregister_script({})", hash);
+        let mut s = self.state.lock().unwrap();
+        s.subscriptions.remove(&hash);
+        s.statuses.remove(&hash);
+        if s.cached_histories.remove(&hash).is_some() {
+            s.history_cache_dirty = true;
+        }
+        s.enqueue(InternalCommand::Unsubscribe { hash });
+    }
+
+    /// Queues a request to fetch transaction history for a script hash, or
+    /// readies the cached one (see `with_cache_path`).
+    fn request_history(&mut self, hash: sha256::Hash) {
+        log::trace!("[ADAPTER] request_history({})", hash);
+        let mut s = self.state.lock().unwrap();
+        if let Some(cached) = s.cached_histories.get(&hash) {
+            log::debug!("[ADAPTER] history of {} served from cache ({} txs)", hash, cached.txs.len());
+            let txs = cached.txs.clone();
+            s.history_cache.insert(hash, txs);
+            s.mark_ready(hash);
+            return;
+        }
+        s.enqueue(InternalCommand::FetchHistory { hash });
+    }
+
+    /// Retrieves the downloaded transaction history for a hash.
+    ///
+    /// # Return Behavior (Crucial for Option B Logic)
+    /// * Returns `Some(Vec<HistoryTx>)` if the history AND its block headers are **ready**.
+    /// * Returns `None` if still **pending**.
+    ///
+    /// **Note:** This operation is destructive (it removes the item from the cache).
+    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
+        let mut s = self.state.lock().unwrap();
+        let txs = s.history_cache.remove(&hash);
+        if txs.is_some() {
+            if s.incomplete_histories.remove(&hash) {
+                s.incomplete_taken.insert(hash);
+            } else {
+                s.incomplete_taken.remove(&hash);
+            }
+        }
+        drop(s);
+        self.save_history_cache(false);
+        
+        if let Some(ref t) = txs {
+            log::trace!("[ADAPTER] fetch_history_txs({}) -> found {} txs", hash, t.len());
+        } else {
+            log::trace!("[ADAPTER] fetch_history_txs({}) -> cache miss", hash);
+        }
+        
+        txs
+    }
+
+    fn history_incomplete(&mut self, hash: sha256::Hash) -> bool {
+        self.state.lock().unwrap().incomplete_taken.remove(&hash)
+    }
+
+    /// Checks if any script hash has new activity or completed syncing.
+    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
+        let mut s = self.state.lock().unwrap();
+        let item = s.take_ready();
+        if let Some(h) = item {
+             log::trace!("[ENGINE] poll_scripthash_changed -> {:?}", h);
+        }
+        item
+    }
+
+    /// Sleeps on the adapter's condvar until the reader has handled a frame.
+    fn wait_for_change(&mut self, timeout: Duration) -> Option<sha256::Hash> {
+        let s = self.state.lock().unwrap();
+        if s.ready.is_empty() && s.terminal_error.is_none() {
+            let (mut s, _) = self.cv.wait_timeout(s, timeout).unwrap();
+            return s.take_ready();
+        }
+        drop(s);
+        self.poll_scripthash_changed()
+    }
+
+    /// Queues a `blockchain.transaction.get` for a standalone transaction.
+    /// A cached transaction is answered right away.
+    fn request_transaction(&mut self, txid: Txid) -> bool {
+        let mut s = self.state.lock().unwrap();
+        match s.tx_cache.get(&txid).cloned() {
+            Some(tx) => {
+                s.fetched_txs.insert(txid, Some(tx));
+            }
+            None => s.enqueue(InternalCommand::FetchRawTransaction { txid, blocking: false }),
+        }
+        true
+    }
+
+    fn take_transaction(&mut self, txid: &Txid) -> Option<Option<Transaction>> {
+        self.state.lock().unwrap().fetched_txs.remove(txid)
+    }
+
+    /// Returns the cached copy if any, otherwise blocks on a `blockchain.transaction.get`.
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        {
+            let mut s = self.state.lock().unwrap();
+            if let Some(tx) = s.tx_cache.get(&txid) {
+                return Ok(tx.clone());
+            }
+            s.enqueue(InternalCommand::FetchRawTransaction { txid, blocking: true });
+        }
+
+        let answer =
+            self.wait_for_answer(format_args!("fetching transaction {}", txid), |s| s.tx_lookups.remove(&txid))?;
+        answer.map_err(|e| anyhow::anyhow!("server has no transaction {}: {}", txid, e))
+    }
+
+    /// Sends one `blockchain.scripthash.get_balance` per hash, then blocks until
+    /// all are answered.
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> Result<Vec<ScriptBalance>> {
+        self.request_balances(hashes);
+        self.wait_for_answer(format_args!("fetching balances of {} scripthashes", hashes.len()), |s| {
+            s.take_balances(hashes)
+        })?
+    }
+
+    /// Queues one `blockchain.scripthash.get_balance` per hash.
+    fn request_balances(&mut self, hashes: &[sha256::Hash]) -> bool {
+        let wanted: HashSet<sha256::Hash> = hashes.iter().copied().collect();
+        let mut s = self.state.lock().unwrap();
+        for hash in wanted {
+            s.enqueue(InternalCommand::GetBalance { hash });
+        }
+        true
+    }
+
+    fn take_balances(&mut self, hashes: &[sha256::Hash]) -> Option<Result<Vec<ScriptBalance>>> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(reason) = &s.terminal_error {
+            return Some(Err(anyhow::anyhow!("connection failed while fetching balances: {}", reason)));
+        }
+        s.take_balances(hashes)
+    }
+
+    fn cached_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.state.lock().unwrap().tx_cache.get(txid).cloned()
+    }
+
+    /// Pops the next history list seen, queued as soon as `get_history`
+    /// answers and before its txs are downloaded.
+    fn poll_history_activity(&mut self) -> Option<(sha256::Hash, usize)> {
+        self.state.lock().unwrap().activity.pop_front()
+    }
+
+    /// NEW: Retrieves a cached block header by height.
+    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
+        let s = self.state.lock().unwrap();
+        s.block_header_cache.get(&height).copied()
+    }
+
+    fn get_block_header(&mut self, height: u32) -> Result<block::Header> {
+        {
+            let mut s = self.state.lock().unwrap();
+            if let Some(header) = s.block_header_cache.get(&height) {
+                return Ok(*header);
+            }
+            s.enqueue(InternalCommand::FetchBlockHeader { height, related_hash: None });
+        }
+
+        let answer = self.wait_for_answer(format_args!("fetching header {}", height), |s| {
+            s.header_lookups.remove(&height)
+        })?;
+        answer.map_err(|e| anyhow::anyhow!("server has no header at height {}: {}", height, e))
+    }
+
+    /// Queues a `blockchain.block.header`; a cached header is answered right away.
+    fn request_block_header(&mut self, height: u32) -> bool {
+        let mut s = self.state.lock().unwrap();
+        match s.block_header_cache.get(&height).copied() {
+            Some(header) => {
+                s.header_lookups.insert(height, Ok(header));
+            }
+            None => s.enqueue(InternalCommand::FetchBlockHeader { height, related_hash: None }),
+        }
+        true
+    }
+
+    fn take_block_header(&mut self, height: u32) -> Option<Result<block::Header>> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(answer) = s.header_lookups.remove(&height) {
+            return Some(answer.map_err(|e| anyhow::anyhow!("server has no header at height {}: {}", height, e)));
+        }
+        let reason = s.terminal_error.as_ref()?;
+        Some(Err(anyhow::anyhow!("connection failed while fetching header {}: {}", height, reason)))
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid> {
+        self.broadcast_blocking(tx)
+    }
+
+    fn request_broadcast(&mut self, tx: &Transaction) -> Option<u64> {
+        let request = next_id();
+        self.state.lock().unwrap().enqueue(InternalCommand::Broadcast { request, tx: tx.clone() });
+        Some(request)
+    }
+
+    fn take_broadcast(&mut self, request: u64) -> Option<Result<Txid>> {
+        let mut s = self.state.lock().unwrap();
+        if let Some(answer) = s.broadcast_results.remove(&request) {
+            return Some(answer.map_err(|e| anyhow::anyhow!("server rejected the transaction: {}", e)));
+        }
+        let reason = s.terminal_error.as_ref()?;
+        Some(Err(anyhow::anyhow!("connection failed while broadcasting: {}", reason)))
+    }
+
+    fn shutdown(&mut self) {
+        ElectrumAdapter::shutdown(self)
+    }
+
+    fn cached_header_heights(&self) -> Vec<u32> {
+        let s = self.state.lock().unwrap();
+        let mut heights: Vec<u32> = s.block_header_cache.keys().copied().collect();
+        heights.sort_unstable();
+        heights
+    }
+
+    fn terminal_error(&self) -> Option<String> {
+        self.state.lock().unwrap().terminal_error.clone()
+    }
+
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        self.state.lock().unwrap().fetch_errors.drain().flat_map(|(_, errors)| errors).collect()
+    }
+
+    fn latency_recorder(&self) -> Option<LatencyRecorder> {
+        Some(self.state.lock().unwrap().latency.clone())
+    }
+
+    fn pending_work(&self) -> PendingWork {
+        let s = self.state.lock().unwrap();
+        let mut work = PendingWork {
+            histories: 0,
+            // Every history tx not downloaded yet, queued or in flight.
+            txs: s.remaining_txs.values().sum(),
+            headers: s.headers_in_flight.len(),
+        };
+        for cmd in &s.command_queue {
+            match cmd {
+                InternalCommand::FetchHistory { .. } => work.histories += 1,
+                InternalCommand::FetchRawTransaction { .. } => work.txs += 1,
+                _ => {}
+            }
+        }
+        for req in s.inflight_requests.values() {
+            match req {
+                RequestType::History(_) => work.histories += 1,
+                RequestType::RawTransaction { .. } => work.txs += 1,
+                _ => {}
+            }
+        }
+        work
+    }
+
+    fn is_connecting(&self) -> bool {
+        let s = self.state.lock().unwrap();
+        !s.connected && !s.idle && s.terminal_error.is_none()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
     }
 
-    /// Retrieves the downloaded transaction history for a hash.
-    ///
-    /// # Return Behavior (Crucial for Option B Logic)
-    /// * Returns `Some(Vec<HistoryTx>)` if the history AND its block headers are **ready**.
-    /// * Returns `None` if still **pending**.
-    ///
-    /// **Note:** This operation is destructive (it removes the item from the cache).
-    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
-        let mut s = self.state.lock().unwrap();
-        let txs = s.history_cache.remove(&hash);
-        
-        if let Some(ref t) = txs {
-            log::trace!("[ADAPTER] fetch_history_txs({}) -> found {} txs", hash, t.len());
-        } else {
-            log::trace!("[ADAPTER] fetch_history_txs({}) -> cache miss", hash);
-        }
-        
-        txs
+    /// The number of sessions begun, including ones reopened after going idle.
+    fn connection_epoch(&self) -> u64 {
+        self.state.lock().unwrap().session
     }
 
-    /// Checks if any script hash has new activity or completed syncing.
-    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
-        let mut s = self.state.lock().unwrap();
-        let item = s.ready.pop_front();
-        if let Some(h) = item {
-             log::trace!("[ENGINE] poll_scripthash_changed -> {:?}", h);
-        }
-        item
+    fn server_version(&self) -> Option<String> {
+        self.state.lock().unwrap().server_info.protocol.clone()
     }
 
-    /// NEW: Retrieves a cached block header by height.
-    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
-        let s = self.state.lock().unwrap();
-        s.block_header_cache.get(&height).copied()
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        self.state.lock().unwrap().chain_tip
     }
 }
 
@@ -279,58 +1920,132 @@ impl ElectrumApi for ElectrumAdapter {
 // Async Task
 // =====================================================================
 
+/// The production `Connector`: TCP + TLS to `server` (`ssl://host:port`),
+/// with the default `ConnectOptions`.
+pub fn tls_connector(server: String) -> Connector {
+    tls_connector_with_options(server, ConnectOptions::default())
+}
+
+/// Like `tls_connector`, applying `options` to every socket it opens. With a
+/// proxy set, a `tcp://` server is reached without TLS.
+pub fn tls_connector_with_options(server: String, options: ConnectOptions) -> Connector {
+    Arc::new(move || {
+        let server = server.clone();
+        let options = options.clone();
+        Box::pin(async move {
+            let (host, port) = parse_server(&server)?;
+            log::debug!("[ADAPTER] Connecting to {}:{} ...", host, port);
+
+            let addr: SocketAddr = match options.proxy {
+                Some(proxy) => proxy,
+                None => (host.as_str(), port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("no address resolved"))?,
+            };
+
+            let std_tcp = std::net::TcpStream::connect(addr)?;
+            options.apply(&std_tcp)?;
+            std_tcp.set_nonblocking(true)?;
+
+            let mut tcp = TcpStream::from_std(std_tcp)?;
+            if options.proxy.is_some() {
+                socks::connect(&mut tcp, &host, port).await?;
+                if server.trim().starts_with("tcp://") {
+                    log::info!("[ADAPTER] Connected via proxy (plaintext)");
+                    return Ok(Box::new(tcp) as Box<dyn Transport>);
+                }
+            }
+
+            let connector = TlsConnector::from(options.tls.connector()?);
+            let tls = connector.connect(&host, tcp).await?;
+            options.tls.check_pin(tls.get_ref().peer_certificate()?)?;
+
+            log::info!("[ADAPTER] TLS connected");
+            Ok(Box::new(tls) as Box<dyn Transport>)
+        })
+    })
+}
+
 struct AsyncElectrumTask {
-    writer: WriteHalf<TlsStream<TcpStream>>,
+    writer: WriteHalf<Box<dyn Transport>>,
     state: Arc<Mutex<SharedState>>,
     cv: Arc<std::sync::Condvar>,
+    /// The reader task for this connection; aborted when the task is dropped.
+    reader: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for AsyncElectrumTask {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
 }
 
 impl AsyncElectrumTask {
-    /// Establishes the TCP/TLS connection and performs the version handshake.
+    /// Opens the transport and performs the version handshake.
     pub async fn connect(
-        server: String,
+        connector: Connector,
         state: Arc<Mutex<SharedState>>,
         cv: Arc<std::sync::Condvar>,
     ) -> Result<Self> {
-        let (host, port) = parse_server(&server)?;
-        log::debug!("[ADAPTER] Connecting to {}:{} ...", host, port);      
-        
-        let addr: SocketAddr = (host.as_str(), port)
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("no address resolved"))?;
-        
-        let std_tcp = std::net::TcpStream::connect(addr)?;
-        std_tcp.set_nonblocking(true)?;
-        
-        let tcp = TcpStream::from_std(std_tcp)?;
-        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
-        let tls = connector.connect(&host, tcp).await?;
-
-        log::info!("[ADAPTER] TLS connected");
+        let stream = connector().await?;
+        let session = state.lock().unwrap().begin_session();
 
-        let (r, w) = tokio::io::split(tls);
+        let (r, w) = tokio::io::split(stream);
         let reader_state = state.clone();
+        let reader_cv = cv.clone();
 
         // Dedicated reader task
-        tokio::spawn(async move {
+        let reader = tokio::spawn(async move {
             let mut reader = BufReader::new(r);
             loop {
-                let mut line = String::new();
-                match reader.read_line(&mut line).await {
+                // Read at most one byte past the limit, so an oversized frame is
+                // detected without ever buffering all of it. The limit is re-read
+                // afterwards in case it was lowered while we were waiting.
+                let limit = reader_state.lock().unwrap().max_frame_bytes;
+                let mut frame = Vec::new();
+                let read = (&mut reader).take(limit as u64 + 1).read_until(b'\n', &mut frame).await;
+                let max = limit.min(reader_state.lock().unwrap().max_frame_bytes);
+
+                // A newer connection has taken over: nothing read here (or the
+                // socket closing) concerns it.
+                if reader_state.lock().unwrap().session != session {
+                    log::debug!("[ADAPTER] reader of stale session {} exiting", session);
+                    break;
+                }
+
+                // Wake anyone blocked in `wait_for_change` (or a lookup)
+                // once this frame has been handled.
+                let done = match read {
                     Ok(0) => {
-                        log::error!("[ADAPTER] socket closed");
-                        break;
+                        reader_state.lock().unwrap().connection_lost("socket closed by server".to_string());
+                        true
+                    }
+                    // The rest of the frame can't be skipped safely, so the
+                    // connection goes; a reconnect starts from a clean stream.
+                    Ok(n) if n > max => {
+                        let method = sniff_method(&frame).unwrap_or_else(|| "unknown".to_string());
+                        let reason = format!("server frame exceeds {} bytes (method: {})", max, method);
+                        let mut s = reader_state.lock().unwrap();
+                        s.record_message_error(&anyhow::anyhow!(reason.clone()));
+                        s.connection_lost(reason);
+                        true
                     }
                     Ok(_) => {
+                        let line = String::from_utf8_lossy(&frame);
                         if let Err(e) = process_message(&line, &reader_state).await {
-                            log::error!("[ADAPTER] process_message error: {:?}", e);
+                            reader_state.lock().unwrap().record_message_error(&e);
                         }
+                        false
                     }
                     Err(e) => {
-                        log::error!("[ADAPTER] read error: {:?}", e);
-                        break;
+                        reader_state.lock().unwrap().connection_lost(format!("read error: {}", e));
+                        true
                     }
+                };
+                reader_cv.notify_all();
+                if done {
+                    break;
                 }
             }
         });
@@ -339,11 +2054,14 @@ impl AsyncElectrumTask {
             writer: w,
             state: state.clone(),
             cv,
+            reader,
         };
 
         this.handshake().await?;
+        this.wait_for_version().await?;
         {
             let mut s = this.state.lock().unwrap();
+            s.check_protocol_version()?;
             s.connected = true;
         }
 
@@ -353,77 +2071,230 @@ impl AsyncElectrumTask {
         Ok(this)
     }
 
+    /// Negotiates the protocol version and subscribes to new chain tips.
     async fn handshake(&mut self) -> Result<()> {
-        self.send(&json!({
+        let mut batch = Vec::new();
+        let version_id = next_id();
+        let headers_id = next_id();
+        let min_protocol_version = {
+            let mut s = self.state.lock().unwrap();
+            s.inflight_requests.insert(version_id, RequestType::Version);
+            s.inflight_requests.insert(headers_id, RequestType::HeadersSubscribe);
+            s.min_protocol_version.clone()
+        };
+        self.queue(&mut batch, &json!({
             "jsonrpc": "2.0",
-            "id": next_id(),
+            "id": version_id,
             "method": "server.version",
-            "params": ["bdk-streaming-poc", "1.4"]
-        }))
-        .await?;
-        Ok(())
+            "params": ["bdk-streaming-poc", min_protocol_version]
+        }));
+        self.queue(&mut batch, &json!({
+            "jsonrpc": "2.0",
+            "id": headers_id,
+            "method": "blockchain.headers.subscribe",
+            "params": []
+        }));
+        self.write_batch(&batch).await
+    }
+
+    /// Waits (at most the request timeout) for the server to answer the
+    /// handshake's `server.version`.
+    async fn wait_for_version(&self) -> Result<()> {
+        let (answered, timeout) = {
+            let s = self.state.lock().unwrap();
+            (s.version_answered.clone(), s.request_timeout)
+        };
+        tokio::time::timeout(timeout, answered.notified())
+            .await
+            .map_err(|_| anyhow::anyhow!("server.version unanswered after {:?}", timeout))
     }
 
-    /// The main write loop.
-    pub async fn run_forever(&mut self) -> Result<()> {
+    /// The main write loop. Returns `Ok` once the connection has been closed
+    /// for being idle or was lost.
+    async fn run_forever(&mut self) -> Result<SessionEnd> {
         log::info!("[ADAPTER] Running forever...");
-        loop {
-            self.flush_outgoing().await?;
+        let end = loop {
+            {
+                let mut s = self.state.lock().unwrap();
+                if let Some(reason) = s.dropped.take() {
+                    return Ok(SessionEnd::Dropped(reason));
+                }
+                if s.shutdown {
+                    s.begin_session();
+                    break SessionEnd::Shutdown;
+                }
+                let now = Instant::now();
+                if let Err(e) = s.ping_if_due(now) {
+                    s.connected = false;
+                    return Ok(SessionEnd::Dropped(format!("{:#}", e)));
+                }
+                s.expire_requests(now);
+                if s.idle_due(now) {
+                    s.go_idle();
+                    break SessionEnd::Idle;
+                }
+            }
+            if let Err(e) = self.flush_outgoing().await {
+                self.state.lock().unwrap().connected = false;
+                return Ok(SessionEnd::Dropped(format!("write failed: {:#}", e)));
+            }
             tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        match end {
+            SessionEnd::Idle => log::info!("[ADAPTER] idle; closing connection until the next request"),
+            _ => log::info!("[ADAPTER] shutting down; closing connection"),
         }
+        let _ = self.writer.shutdown().await;
+        Ok(end)
     }
 
     async fn flush_outgoing(&mut self) -> Result<()> {
         let commands: Vec<InternalCommand> = {
             let mut s = self.state.lock().unwrap();
-            s.command_queue.drain(..).collect()
+            if s.command_queue.iter().any(|cmd| !matches!(cmd, InternalCommand::Ping)) {
+                s.last_activity = Instant::now();
+            }
+            // Pings always go out; everything else waits for room under the
+            // cap, and history fetches also for the driver to catch up.
+            let in_flight = s.inflight_requests.values().filter(|r| !matches!(r, RequestType::Ping)).count();
+            let mut room = s.max_inflight.saturating_sub(in_flight);
+            let mut history_room = s.max_pending_histories.saturating_sub(s.pending_histories());
+            let mut commands = Vec::new();
+            let mut held = VecDeque::new();
+            for cmd in std::mem::take(&mut s.command_queue) {
+                if matches!(cmd, InternalCommand::Ping) {
+                    commands.push(cmd);
+                } else if matches!(cmd, InternalCommand::FetchHistory { .. }) && history_room == 0 {
+                    held.push_back(cmd);
+                } else if room > 0 {
+                    if matches!(cmd, InternalCommand::FetchHistory { .. }) {
+                        history_room -= 1;
+                    }
+                    room -= 1;
+                    commands.push(cmd);
+                } else {
+                    held.push_back(cmd);
+                }
+            }
+            s.command_queue = held;
+            commands
         };
 
+        // Everything drained goes out in one write: Electrum servers handle
+        // pipelined requests, and a cold start queues hundreds of subscribes.
+        let mut batch = Vec::new();
+
         for cmd in commands {
             match cmd {
-                InternalCommand::Subscribe { hash: _, script } => {
+                InternalCommand::Subscribe { hash, script } => {
                     let sh = electrum_scripthash(script.as_bytes());
-                    self.send(&json!({
+                    let id = next_id();
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Subscribe(hash));
+                    }
+
+                    self.queue(&mut batch, &json!({
                         "jsonrpc": "2.0",
-                        "id": next_id(),
+                        "id": id,
                         "method": "blockchain.scripthash.subscribe",
                         "params": [sh]
-                    })).await?;
+                    }));
+                }
+                InternalCommand::Unsubscribe { hash } => {
+                    let sh = scripthash_to_wire(&hash);
+                    let id = next_id();
+
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Unsubscribe(hash));
+                    }
+
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "blockchain.scripthash.unsubscribe",
+                        "params": [sh]
+                    }));
                 }
                 InternalCommand::FetchHistory { hash } => {
-                    let mut bytes = hash.to_byte_array();
-                    bytes.reverse();
-                    let sh = hex::encode(bytes);
+                    let sh = scripthash_to_wire(&hash);
                     let id = next_id();
 
                     {
                         let mut s = self.state.lock().unwrap();
                         s.inflight_requests.insert(id, RequestType::History(hash));
+                        s.history_started_at.entry(hash).or_insert_with(Instant::now);
                     }
 
-                    self.send(&json!({
+                    self.queue(&mut batch, &json!({
                         "jsonrpc": "2.0",
                         "id": id,
                         "method": "blockchain.scripthash.get_history",
                         "params": [sh]
-                    })).await?;
+                    }));
                 }
                 InternalCommand::FetchTransaction { txid, related_hash, height } => {
                     let id = next_id();
                     {
                         let mut s = self.state.lock().unwrap();
                         s.inflight_requests.insert(id, RequestType::Transaction {
+                            txid,
                             related_hash,
                             height,             // CHANGED: carry height
                         });
                     }
 
-                    self.send(&json!({
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "blockchain.transaction.get",
+                        "params": [txid.to_string(), false]
+                    }));
+                }
+                InternalCommand::FetchRawTransaction { txid, blocking } => {
+                    let id = next_id();
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::RawTransaction { txid, blocking });
+                    }
+
+                    self.queue(&mut batch, &json!({
                         "jsonrpc": "2.0",
                         "id": id,
                         "method": "blockchain.transaction.get",
                         "params": [txid.to_string(), false]
-                    })).await?;
+                    }));
+                }
+                InternalCommand::GetBalance { hash } => {
+                    let sh = scripthash_to_wire(&hash);
+                    let id = next_id();
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Balance(hash));
+                    }
+
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "blockchain.scripthash.get_balance",
+                        "params": [sh]
+                    }));
+                }
+                // Merkle proof that a confirmed transaction is in the block at its height
+                InternalCommand::FetchMerkle { txid, height, related_hash } => {
+                    let id = next_id();
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Merkle { txid, related_hash });
+                    }
+
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "blockchain.transaction.get_merkle",
+                        "params": [txid.to_string(), height]
+                    }));
                 }
                 // NEW: Fetch block header for a confirmed transaction's height
                 InternalCommand::FetchBlockHeader { height, related_hash } => {
@@ -436,24 +2307,79 @@ impl AsyncElectrumTask {
                         });
                     }
 
-                    self.send(&json!({
+                    self.queue(&mut batch, &json!({
                         "jsonrpc": "2.0",
                         "id": id,
                         "method": "blockchain.block.header",
                         "params": [height]
-                    })).await?;
+                    }));
+                }
+                InternalCommand::Broadcast { request, tx } => {
+                    let id = next_id();
+                    let raw = serialize_hex(&tx);
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Broadcast { request, tx });
+                    }
+
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "blockchain.transaction.broadcast",
+                        "params": [raw]
+                    }));
+                }
+                InternalCommand::Ping => {
+                    let id = next_id();
+                    {
+                        let mut s = self.state.lock().unwrap();
+                        s.inflight_requests.insert(id, RequestType::Ping);
+                    }
+
+                    self.queue(&mut batch, &json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "method": "server.ping",
+                        "params": []
+                    }));
+                }
+                InternalCommand::FetchServerInfo => {
+                    for (method, request) in [
+                        ("server.banner", RequestType::Banner),
+                        ("server.donation_address", RequestType::DonationAddress),
+                    ] {
+                        let id = next_id();
+                        self.state.lock().unwrap().inflight_requests.insert(id, request);
+                        self.queue(&mut batch, &json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "method": method,
+                            "params": []
+                        }));
+                    }
                 }
             }
         }
-        
-        Ok(())
+
+        self.write_batch(&batch).await
     }
 
-    async fn send(&mut self, v: &Value) -> Result<()> {
+    /// Appends `v` to `batch` as one newline-terminated request.
+    fn queue(&self, batch: &mut Vec<u8>, v: &Value) {
+        if let Some(id) = v["id"].as_u64() {
+            self.state.lock().unwrap().request_sent_at.insert(id, Instant::now());
+        }
         let s = v.to_string();
         log::trace!("[ADAPTER] Send payload:{}", s);
-        self.writer.write_all(s.as_bytes()).await?;
-        self.writer.write_all(b"\n").await?;
+        batch.extend_from_slice(s.as_bytes());
+        batch.push(b'\n');
+    }
+
+    async fn write_batch(&mut self, batch: &[u8]) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(batch).await?;
         self.writer.flush().await?;
         Ok(())
     }
@@ -463,6 +2389,26 @@ impl AsyncElectrumTask {
 // Message Processing
 // =====================================================================
 
+/// Decodes the hex `result` of a response with `decode`; a server error or
+/// a result that doesn't decode is an `Err`.
+fn decode_result<T>(msg: &Value, decode: impl FnOnce(Vec<u8>) -> Result<T>) -> Result<T> {
+    match msg.get("result").filter(|r| !r.is_null()) {
+        Some(result) => {
+            let hex_str = result.as_str().ok_or_else(|| anyhow::anyhow!("result is not a string"))?;
+            decode(hex::decode(hex_str)?)
+        }
+        None => Err(anyhow::anyhow!("server error: {}", msg["error"])),
+    }
+}
+
+/// Decodes a `blockchain.transaction.get` answer, rejecting a tx that isn't
+/// the one asked for.
+fn decode_tx(msg: &Value, txid: Txid) -> Result<Transaction> {
+    let tx = decode_result(msg, |bytes| Ok(Transaction::consensus_decode(&mut &bytes[..])?))?;
+    anyhow::ensure!(tx.compute_txid() == txid, "server sent {} for {}", tx.compute_txid(), txid);
+    Ok(tx)
+}
+
 async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<()> {
     let msg: Value = serde_json::from_str(line)?;
     log::trace!("[ADAPTER] process_message line:{}", line.trim());
@@ -481,14 +2427,25 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
                     anyhow::anyhow!("invalid scripthash in notification")
                 })?;
 
-                let mut bytes = hex::decode(sh_hex)?;
-                bytes.reverse();
-                let hash = sha256::Hash::from_slice(&bytes)?;
+                let hash = scripthash_from_wire(sh_hex)?;
 
                 log::debug!("[ADAPTER] scripthash notification for {}", hash);
 
+                let status = params.get(1).and_then(|s| s.as_str()).map(String::from);
                 let mut s = state.lock().unwrap();
-                s.ready.push_back(hash);
+                s.last_activity = Instant::now();
+                s.forget_stale_history(hash, &status);
+                s.statuses.insert(hash, status);
+                s.mark_ready(hash);
+            } else if method == "blockchain.headers.subscribe" {
+                let tip = msg["params"].get(0).ok_or_else(|| anyhow::anyhow!("invalid headers notification params"))?;
+                state.lock().unwrap().record_tip(tip)?;
+            } else if method == "server.banner" {
+                // Some servers push their banner unasked.
+                let banner = msg["params"].get(0).and_then(|b| b.as_str()).map(String::from);
+                state.lock().unwrap().server_info.banner = banner;
+            } else {
+                log::debug!("[ADAPTER] ignoring notification {}", method);
             }
         }
         return Ok(());
@@ -503,7 +2460,14 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
 
     let request_type = {
         let mut s = state.lock().unwrap();
-        s.inflight_requests.remove(&id)
+        if let Some(sent) = s.request_sent_at.remove(&id) {
+            s.latency.record_request_rtt(sent.elapsed());
+        }
+        let req = s.inflight_requests.remove(&id);
+        if !matches!(req, Some(RequestType::Ping)) {
+            s.last_activity = Instant::now();
+        }
+        req
     };
 
     if let Some(req) = request_type {
@@ -513,7 +2477,12 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
                     let arr = result.as_array().ok_or_else(|| anyhow::anyhow!("history not array"))?;
                     
                     let mut s = state.lock().unwrap();
+                    s.forget_confirmed_heights(hash);
                     s.remaining_txs.insert(hash, arr.len());
+                    if s.history_cache_path.is_some() {
+                        let status = history_status(arr)?;
+                        s.fetched_status.insert(hash, status);
+                    }
 
                     if arr.is_empty() {
                         // Empty history, ready immediately
@@ -521,8 +2490,11 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
                         s.remaining_headers.insert(hash, 0);
                         s.check_history_complete(hash);
                     } else {
+                        s.activity.push_back((hash, arr.len()));
+
                         // CHANGED: Collect unique confirmed heights that need headers
                         let mut needed_heights: HashSet<u32> = HashSet::new();
+                        let mut proofs = 0;
 
                         for item in arr {
                             let txid_str = item["tx_hash"]
@@ -542,48 +2514,73 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
                                 height,             // NEW: carry height
                             });
 
-                            // Track unique confirmed heights that need headers
                             if height > 0 {
-                                let h = height as u32;
-                                if !s.block_header_cache.contains_key(&h)
-                                    && !s.headers_in_flight.contains(&h)
-                                {
-                                    needed_heights.insert(h);
+                                s.confirmed_heights.entry(height as u32).or_default().insert(hash);
+                                let anchor = s.block_header_cache.get(&(height as u32)).map(|h| h.block_hash());
+                                if anchor.is_none() || s.proven_blocks.get(&txid) != anchor.as_ref() {
+                                    s.command_queue.push_back(InternalCommand::FetchMerkle {
+                                        txid,
+                                        height: height as u32,
+                                        related_hash: hash,
+                                    });
+                                    proofs += 1;
                                 }
                             }
+
+                            // Track unique confirmed heights whose header isn't cached yet
+                            if height > 0 && !s.block_header_cache.contains_key(&(height as u32)) {
+                                needed_heights.insert(height as u32);
+                            }
                         }
 
-                        // Queue header fetches for unique new heights
+                        // Wait for every missing header, but only request heights nobody
+                        // has requested yet. All of this happens under the single lock
+                        // hold, so a header can't land between the cache check and here.
+                        s.remaining_proofs.insert(hash, proofs);
                         s.remaining_headers.insert(hash, needed_heights.len());
                         for h in needed_heights {
-                            s.headers_in_flight.insert(h);
-                            s.command_queue.push_back(InternalCommand::FetchBlockHeader {
-                                height: h,
-                                related_hash: hash,
-                            });
+                            let waiters = s.headers_in_flight.entry(h).or_default();
+                            let first_request = waiters.is_empty();
+                            waiters.insert(hash);
+                            if first_request {
+                                s.command_queue.push_back(InternalCommand::FetchBlockHeader {
+                                    height: h,
+                                    related_hash: Some(hash),
+                                });
+                            }
                         }
                     }
                 }
             }
 
             // CHANGED: Now carries height alongside the transaction
-            RequestType::Transaction { related_hash, height } => {
-                if let Some(result) = msg.get("result") {
-                    let hex_str = result.as_str().ok_or_else(|| anyhow::anyhow!("tx result is not a string"))?;
-                    let tx_bytes = hex::decode(hex_str)?;
-                    let tx = Transaction::consensus_decode(&mut &tx_bytes[..])?;
+            RequestType::Transaction { txid, related_hash, height } => {
+                // An error or undecodable answer must still count the tx as
+                // done, or the history would never complete.
+                let tx = decode_tx(&msg, txid);
 
-                    let mut s = state.lock().unwrap();
-                    
-                    // Store as HistoryTx with the height from the original get_history
-                    s.history_cache.entry(related_hash).or_default().push(HistoryTx {
-                        tx,
-                        height,
-                    });
-                    
-                    let rem = s.remaining_txs.get_mut(&related_hash).unwrap();
-                    *rem -= 1;
+                let mut s = state.lock().unwrap();
+                match tx {
+                    Ok(tx) => {
+                        s.tx_cache.insert(txid, tx.clone());
+
+                        // Store as HistoryTx with the height from the original get_history
+                        // Confirmed txs are verified once their proof and header are in.
+                        s.history_cache.entry(related_hash).or_default().push(HistoryTx {
+                            tx,
+                            height,
+                            verified: height <= 0,
+                        });
+                    }
+                    Err(e) => s.record_fetch_error(FetchError::Transaction {
+                        hash: related_hash,
+                        txid,
+                        error: format!("{:#}", e),
+                    }),
+                }
 
+                if let Some(rem) = s.remaining_txs.get_mut(&related_hash) {
+                    *rem = rem.saturating_sub(1);
                     // Check if BOTH txs and headers are done
                     if *rem == 0 {
                         s.check_history_complete(related_hash);
@@ -593,50 +2590,410 @@ async fn process_message(line: &str, state: &Arc<Mutex<SharedState>>) -> Result<
 
             // NEW: Block header response
             RequestType::BlockHeader { height, related_hash } => {
-                if let Some(result) = msg.get("result") {
-                    let hex_str = result.as_str().ok_or_else(|| anyhow::anyhow!("header result is not a string"))?;
-                    let header_bytes = hex::decode(hex_str)?;
-                    let header = block::Header::consensus_decode(&mut &header_bytes[..])?;
+                let header = decode_result(&msg, |bytes| Ok(block::Header::consensus_decode(&mut &bytes[..])?));
 
-                    log::debug!(
-                        "[ADAPTER] block header for height {} -> hash={}",
-                        height,
-                        header.block_hash()
-                    );
-
-                    let mut s = state.lock().unwrap();
-                    s.block_header_cache.insert(height, header);
-                    s.headers_in_flight.remove(&height);
+                let mut s = state.lock().unwrap();
+                if related_hash.is_none() {
+                    s.header_lookups.insert(height, header.as_ref().copied().map_err(|e| format!("{:#}", e)));
+                }
+                let waiters = s.headers_in_flight.remove(&height).unwrap_or_default();
+                match header {
+                    Ok(header) => {
+                        log::debug!(
+                            "[ADAPTER] block header for height {} -> hash={}",
+                            height,
+                            header.block_hash()
+                        );
+                        s.cache_header(height, header);
+                    }
+                    Err(e) => {
+                        for waiter in &waiters {
+                            s.record_fetch_error(FetchError::Header { hash: *waiter, height, error: format!("{:#}", e) });
+                        }
+                    }
+                }
 
-                    // Decrement pending header count for this scripthash
-                    if let Some(rem) = s.remaining_headers.get_mut(&related_hash) {
+                // Release every scripthash waiting on this height, not just the requester
+                for waiter in waiters {
+                    if let Some(rem) = s.remaining_headers.get_mut(&waiter) {
                         *rem = rem.saturating_sub(1);
+                        // Check if BOTH txs and headers are done
+                        s.check_history_complete(waiter);
                     }
+                }
+            }
 
-                    // Check if BOTH txs and headers are done
-                    let txs_done = s.remaining_txs.get(&related_hash).copied().unwrap_or(0) == 0;
-                    let hdrs_done = s.remaining_headers.get(&related_hash).copied().unwrap_or(0) == 0;
-                    if txs_done && hdrs_done {
-                        s.check_history_complete(related_hash);
+            RequestType::Merkle { txid, related_hash } => {
+                let proof = msg.get("result").filter(|r| !r.is_null()).map(|result| {
+                    let branch = result["merkle"]
+                        .as_array()
+                        .ok_or_else(|| anyhow::anyhow!("merkle proof without branch"))?
+                        .iter()
+                        .map(|node| Ok(node.as_str().unwrap_or_default().parse::<TxMerkleNode>()?))
+                        .collect::<Result<Vec<_>>>()?;
+                    let pos = result["pos"].as_u64().ok_or_else(|| anyhow::anyhow!("merkle proof without pos"))?;
+                    Ok::<_, anyhow::Error>(merkle_root_from_branch(txid, &branch, pos as usize))
+                });
+
+                let mut s = state.lock().unwrap();
+                match proof {
+                    Some(Ok(root)) => {
+                        s.proven_roots.insert((related_hash, txid), root);
+                    }
+                    // Left unproven: `verify_history` marks the tx unverified.
+                    Some(Err(e)) => log::warn!("[ADAPTER] bad merkle proof for {}: {}", txid, e),
+                    None => log::warn!("[ADAPTER] no merkle proof for {}: {}", txid, msg["error"]),
+                }
+                if let Some(rem) = s.remaining_proofs.get_mut(&related_hash) {
+                    *rem = rem.saturating_sub(1);
+                    s.check_history_complete(related_hash);
+                }
+            }
+
+            RequestType::Subscribe(hash) => {
+                match msg.get("error").filter(|e| !e.is_null()) {
+                    Some(err) => {
+                        let mut s = state.lock().unwrap();
+                        s.fail(format!("server rejected subscribe for {}: {}", hash, err));
+                    }
+                    None => {
+                        log::trace!("[ADAPTER] subscribe ack for {}", hash);
+                        let status = msg["result"].as_str().map(String::from);
+                        let mut s = state.lock().unwrap();
+                        // Only a re-subscribe (or a cached history) has a
+                        // previous status to differ from.
+                        let stale = s.forget_stale_history(hash, &status);
+                        if s.statuses.insert(hash, status.clone()).is_some_and(|old| old != status) || stale {
+                            log::debug!("[ADAPTER] {} changed while disconnected", hash);
+                            s.mark_ready(hash);
+                        }
+                    }
+                }
+            }
+
+            RequestType::RawTransaction { txid, blocking } => {
+                let answer = decode_tx(&msg, txid);
+                let mut s = state.lock().unwrap();
+                if let Ok(tx) = &answer {
+                    s.tx_cache.insert(txid, tx.clone());
+                }
+                if blocking {
+                    s.tx_lookups.insert(txid, answer.map_err(|e| format!("{:#}", e)));
+                } else {
+                    if let Err(e) = &answer {
+                        log::warn!("[ADAPTER] transaction {} not available: {:#}", txid, e);
                     }
+                    s.fetched_txs.insert(txid, answer.ok());
                 }
             }
+
+            RequestType::Broadcast { request, .. } => {
+                let answer = match msg.get("result").and_then(|r| r.as_str()) {
+                    Some(txid) => txid.parse::<Txid>().map_err(|e| format!("bad txid {:?}: {}", txid, e)),
+                    None => Err(msg["error"].to_string()),
+                };
+                let mut s = state.lock().unwrap();
+                s.broadcast_results.insert(request, answer);
+            }
+
+            RequestType::Balance(hash) => {
+                let answer = match msg.get("result") {
+                    Some(result) => Ok(ScriptBalance {
+                        confirmed: result["confirmed"].as_u64().unwrap_or(0),
+                        unconfirmed: result["unconfirmed"].as_i64().unwrap_or(0),
+                    }),
+                    None => Err(msg["error"].to_string()),
+                };
+                state.lock().unwrap().balance_lookups.insert(hash, answer);
+            }
+
+            RequestType::Unsubscribe(hash) => {
+                log::trace!("[ADAPTER] unsubscribe ack for {}: {}", hash, msg["result"]);
+            }
+
+            RequestType::Ping => {
+                let mut s = state.lock().unwrap();
+                s.ping_sent_at = None;
+                s.health.pongs_received += 1;
+            }
+
+            RequestType::Version => {
+                // `[software, protocol]`. A server that can't speak the
+                // version asked for answers with an error (very old ones
+                // with just the software string), and agrees to nothing.
+                let result = &msg["result"];
+                let (software, protocol) = match result.as_array() {
+                    Some(parts) => (parts.first(), parts.get(1)),
+                    None => (Some(result), None),
+                };
+                let text = |v: Option<&Value>| v.and_then(|v| v.as_str()).map(String::from);
+                let refused = match msg.get("error") {
+                    Some(error) if !error.is_null() => Some(error.to_string()),
+                    _ if text(protocol).is_none() => Some(format!("no protocol agreed in {}", result)),
+                    _ => None,
+                };
+                let mut s = state.lock().unwrap();
+                s.server_info.software = text(software);
+                s.server_info.protocol = text(protocol);
+                s.version_refused = refused;
+                s.version_answered.notify_one();
+            }
+
+            RequestType::HeadersSubscribe => match msg.get("result") {
+                Some(tip) => state.lock().unwrap().record_tip(tip)?,
+                None => log::warn!("[ADAPTER] blockchain.headers.subscribe refused: {}", msg["error"]),
+            },
+
+            RequestType::Banner => {
+                let banner = msg["result"].as_str().map(String::from);
+                state.lock().unwrap().server_info.banner = banner;
+            }
+
+            RequestType::DonationAddress => {
+                let address = msg["result"].as_str().filter(|a| !a.is_empty()).map(String::from);
+                state.lock().unwrap().server_info.donation_address = address;
+            }
         }
     } else {
-        log::debug!("[ADAPTER] response with unknown id {} (might be a subscribe response)", id);
+        log::debug!("[ADAPTER] response with unknown id {}", id);
     }
 
     Ok(())
 }
 
+/// Opens a new connection after the last one was lost (or closed) for
+/// `reason`, retrying with backoff as `policy` allows.
+async fn reconnect(
+    connector: &Connector,
+    state: &Arc<Mutex<SharedState>>,
+    cv: &Arc<std::sync::Condvar>,
+    reason: &str,
+    policy: ReconnectPolicy,
+    backoff: &mut Backoff,
+) -> Result<AsyncElectrumTask> {
+    if policy.attempts == 0 {
+        anyhow::bail!("{}", reason);
+    }
+    for attempt in 1..=policy.attempts {
+        let delay = backoff.next_delay(&policy);
+        log::info!(
+            "[ADAPTER] reconnecting after \"{}\" in {:?} (attempt {}/{})",
+            reason,
+            delay,
+            attempt,
+            policy.attempts
+        );
+        if !sleep_unless_shutdown(state, delay).await {
+            anyhow::bail!("{}; shut down while reconnecting", reason);
+        }
+        match AsyncElectrumTask::connect(connector.clone(), state.clone(), cv.clone()).await {
+            Ok(task) => return Ok(task),
+            Err(e) if attempt == policy.attempts => {
+                anyhow::bail!("{}; reconnect failed after {} attempts: {:#}", reason, attempt, e);
+            }
+            Err(e) => log::warn!("[ADAPTER] reconnect attempt {} failed: {:#}", attempt, e),
+        }
+    }
+    unreachable!("the last attempt returns")
+}
+
+/// Sleeps for `delay`, cut short by a shutdown. Returns `false` if it was.
+async fn sleep_unless_shutdown(state: &Arc<Mutex<SharedState>>, delay: Duration) -> bool {
+    let until = Instant::now() + delay;
+    while Instant::now() < until {
+        if state.lock().unwrap().shutdown {
+            return false;
+        }
+        tokio::time::sleep((until - Instant::now()).min(Duration::from_millis(10))).await;
+    }
+    !state.lock().unwrap().shutdown
+}
+
+/// Waits, without polling, until an idle connection should be reopened
+/// (see `SharedState::wake_due`).
+async fn wait_for_wake(state: &Arc<Mutex<SharedState>>) {
+    let since = Instant::now();
+    loop {
+        let (wake, recheck_every) = {
+            let s = state.lock().unwrap();
+            if s.wake_due(since, Instant::now()) {
+                return;
+            }
+            (s.wake.clone(), s.idle_recheck_every)
+        };
+        // A request queued since the check above left a permit behind, so
+        // `notified` returns right away instead of missing it.
+        match recheck_every {
+            Some(every) => {
+                let until = (since + every).saturating_duration_since(Instant::now());
+                let _ = tokio::time::timeout(until, wake.notified()).await;
+            }
+            None => wake.notified().await,
+        }
+    }
+}
+
+/// `"1.4.2"` -> `[1, 4, 2]`, compared component-wise.
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version.trim().split('.').map(|part| part.parse().ok()).collect()
+}
+
 fn parse_server(s: &str) -> Result<(String, u16)> {
     let s = s.trim();
     let s = s.strip_prefix("ssl://")
         .or_else(|| s.strip_prefix("tcp://"))
         .unwrap_or(s);
 
-    let mut parts = s.split(':');
-    let host = parts.next().unwrap().to_string();
-    let port = parts.next().unwrap().parse::<u16>()?;
-    Ok((host, port))
-}
\ No newline at end of file
+    let (host, port) = s
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("server address {:?} has no port", s))?;
+    if host.is_empty() {
+        anyhow::bail!("server address {:?} has no host", s);
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| anyhow::anyhow!("server address {:?} has a bad port: {}", s, e))?;
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::electrum::tests::fake_server::{duplex_connector, reply, status_notification, wire_hash};
+    use bitcoin::hashes::Hash;
+    use tokio::io::{DuplexStream, Lines, ReadHalf};
+
+    /// Takes the server end of the next connection and answers its
+    /// `server.version`, so `AsyncElectrumTask::connect` can complete.
+    async fn accept_handshake(
+        servers: &std::sync::mpsc::Receiver<DuplexStream>,
+    ) -> (Lines<BufReader<ReadHalf<DuplexStream>>>, WriteHalf<DuplexStream>) {
+        let server = loop {
+            match servers.try_recv() {
+                Ok(server) => break server,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        let (r, mut w) = tokio::io::split(server);
+        let mut lines = BufReader::new(r).lines();
+        let version: Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(version["method"], "server.version");
+        let answer = reply(&version, json!(["fake-electrum", DEFAULT_MIN_PROTOCOL_VERSION]));
+        w.write_all(format!("{}\n", answer).as_bytes()).await.unwrap();
+        (lines, w)
+    }
+
+    #[test]
+    fn late_messages_from_previous_connection_are_discarded() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let (connector, servers) = duplex_connector();
+            let state = Arc::new(Mutex::new(SharedState::new()));
+            let cv = Arc::new(std::sync::Condvar::new());
+            let hash = sha256::Hash::hash(b"script");
+
+            // First connection: a history request goes out and stays unanswered.
+            let (first, (mut old_lines, mut old_w)) = tokio::join!(
+                AsyncElectrumTask::connect(connector.clone(), state.clone(), cv.clone()),
+                accept_handshake(&servers)
+            );
+            let mut first = first.unwrap();
+            state.lock().unwrap().command_queue.push_back(InternalCommand::FetchHistory { hash });
+            first.flush_outgoing().await.unwrap();
+            old_lines.next_line().await.unwrap(); // blockchain.headers.subscribe
+            let request: Value = serde_json::from_str(&old_lines.next_line().await.unwrap().unwrap()).unwrap();
+            assert_eq!(request["params"][0], wire_hash(&hash));
+
+            // Reconnect while the old reader is still alive.
+            let (second, _new_server) =
+                tokio::join!(AsyncElectrumTask::connect(connector, state.clone(), cv), accept_handshake(&servers));
+            let _second = second.unwrap();
+            assert!(state
+                .lock()
+                .unwrap()
+                .inflight_requests
+                .values()
+                .all(|r| matches!(r, RequestType::Version | RequestType::HeadersSubscribe)));
+
+            // The old server finally answers, and pushes a notification.
+            let late_reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": []});
+            for msg in [late_reply, status_notification(&hash)] {
+                old_w.write_all(format!("{}\n", msg).as_bytes()).await.unwrap();
+            }
+            drop(old_w);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let s = state.lock().unwrap();
+            assert!(s.ready.is_empty(), "stale messages must not reach the driver");
+            assert!(s.history_cache.is_empty());
+            assert!(s.terminal_error.is_none(), "the old socket closing must not fail the new session");
+            drop(first);
+        });
+    }
+
+    #[test]
+    fn server_metadata_is_captured_not_treated_as_unknown() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = Arc::new(Mutex::new(SharedState::new()));
+            {
+                let mut s = state.lock().unwrap();
+                s.inflight_requests.insert(1, RequestType::Version);
+                s.inflight_requests.insert(2, RequestType::Banner);
+                s.inflight_requests.insert(3, RequestType::DonationAddress);
+            }
+            let replies = [
+                json!({"jsonrpc": "2.0", "id": 1, "result": ["ElectrumX 1.16.0", "1.4"]}),
+                json!({"jsonrpc": "2.0", "id": 2, "result": "Welcome to ElectrumX"}),
+                json!({"jsonrpc": "2.0", "id": 3, "result": "bc1qdonate"}),
+            ];
+            for reply in replies {
+                process_message(&reply.to_string(), &state).await.unwrap();
+            }
+
+            let info = state.lock().unwrap().server_info.clone();
+            assert_eq!(info.software.as_deref(), Some("ElectrumX 1.16.0"));
+            assert_eq!(info.protocol.as_deref(), Some("1.4"));
+            assert_eq!(info.banner.as_deref(), Some("Welcome to ElectrumX"));
+            assert_eq!(info.donation_address.as_deref(), Some("bc1qdonate"));
+            assert!(state.lock().unwrap().inflight_requests.is_empty());
+
+            // A banner pushed unasked replaces the old one.
+            let push = json!({"jsonrpc": "2.0", "method": "server.banner", "params": ["maintenance at noon"]});
+            process_message(&push.to_string(), &state).await.unwrap();
+            let s = state.lock().unwrap();
+            assert_eq!(s.server_info.banner.as_deref(), Some("maintenance at noon"));
+            assert!(s.ready.is_empty());
+            assert_eq!(s.health.message_errors, 0);
+        });
+    }
+
+    #[test]
+    fn connect_options_are_set_on_the_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let socket = socket2::SockRef::from(&tcp);
+
+        let options = ConnectOptions {
+            nodelay: true,
+            keepalive: Some(KeepaliveOptions {
+                idle: Duration::from_secs(45),
+                interval: Duration::from_secs(7),
+                retries: 3,
+            }),
+            proxy: None,
+            tls: TlsConfig::default(),
+            min_protocol_version: DEFAULT_MIN_PROTOCOL_VERSION.to_string(),
+        };
+        options.apply(&tcp).unwrap();
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(45));
+        assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(7));
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+
+        ConnectOptions { nodelay: false, keepalive: None, ..ConnectOptions::default() }.apply(&tcp).unwrap();
+        assert!(!socket.tcp_nodelay().unwrap());
+        assert!(!socket.keepalive().unwrap());
+    }
+}
@@ -0,0 +1,89 @@
+//! Minimal SOCKS5 client (RFC 1928): no authentication, `CONNECT` only.
+//!
+//! Enough to reach Electrum servers through Tor. Host names are handed to the
+//! proxy unresolved, which `.onion` addresses require and which keeps lookups
+//! of clearnet servers off the local resolver.
+
+use anyhow::{anyhow, bail, Result};
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Asks the proxy at the other end of `stream` to connect to `host:port`.
+/// On success the stream carries the connection to the target.
+pub async fn connect<S>(stream: &mut S, host: &str, port: u16) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&[VERSION, 1, NO_AUTH]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [VERSION, NO_AUTH] {
+        bail!("SOCKS5 proxy refused unauthenticated access (reply {:?})", choice);
+    }
+
+    let mut request = vec![VERSION, CMD_CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| anyhow!("host name too long for SOCKS5: {}", host))?;
+            request.push(ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        bail!("not a SOCKS5 reply (version {})", reply[0]);
+    }
+    if reply[1] != 0 {
+        bail!("SOCKS5 proxy could not connect to {}:{}: {}", host, port, reply_error(reply[1]));
+    }
+
+    // Skip the bound address and port the proxy reports.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        other => bail!("SOCKS5 reply with unknown address type {}", other),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
@@ -1,38 +1,1144 @@
-#[cfg(test)]
-mod tests {
-    // FIX 1: Import the functions from the 'client' module
-    // Adjust the path 'super::client' if your file structure is different.
-    // If 'client.rs' is inside 'async_client' folder, this is likely correct:
-    use crate::streaming::electrum::asynchronous::adapter::{electrum_scripthash, next_id};
+#![cfg(test)]
+// FIX 1: Import the functions from the 'client' module
+// Adjust the path 'super::client' if your file structure is different.
+// If 'client.rs' is inside 'async_client' folder, this is likely correct:
+use crate::streaming::electrum::asynchronous::adapter::{electrum_scripthash, next_id};
+use crate::streaming::electrum::asynchronous::adapter::{
+    Backoff, Connector, ElectrumAdapter, ReconnectPolicy, TlsConfig, Transport,
+};
+use crate::streaming::electrum::api::ElectrumApi;
+use crate::streaming::electrum::tests::fake_server::{
+    connect_served, duplex_connector, dummy_tx, reply, serve, serve_chain, serve_chain_on, status_notification, tls_server,
+    try_connect_served, wait_until, wire_hash, FakeChain, SELF_SIGNED_CERT,
+};
+
+// FIX 2: Correctly import Bitcoin hash types
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::Network;
+use hex::FromHex;
+use serde_json::{json, Value};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// =========================================================================
+// Tests
+// =========================================================================
+
+#[test]
+fn test_electrum_scripthash_conversion() {
+    // Test Vector:
+    // P2WPKH script for a known address or arbitrary bytes.
+    // Let's use arbitrary bytes to verify the algo: Sha256 -> Reverse -> Hex
+    
+    let script_hex = "001479b7e77b4e941e12760630737402660126581831";
+    let script_bytes = Vec::from_hex(script_hex).unwrap();
+    
+    let result = electrum_scripthash(&script_bytes);
     
-    // FIX 2: Correctly import Bitcoin hash types
-    use bitcoin::hashes::{sha256, Hash};
-    use hex::FromHex;
-
-    #[test]
-    fn test_electrum_scripthash_conversion() {
-        // Test Vector:
-        // P2WPKH script for a known address or arbitrary bytes.
-        // Let's use arbitrary bytes to verify the algo: Sha256 -> Reverse -> Hex
-        
-        let script_hex = "001479b7e77b4e941e12760630737402660126581831";
-        let script_bytes = Vec::from_hex(script_hex).unwrap();
-        
-        let result = electrum_scripthash(&script_bytes);
-        
-        // Manual verification
-        let hash = sha256::Hash::hash(&script_bytes);
-        let mut bytes = hash.to_byte_array();
-        bytes.reverse();
-        let expected = hex::encode(bytes);
-
-        assert_eq!(result, expected);
-    }
-
-    #[test]
-    fn test_next_id_increments() {
-        let id1 = next_id();
-        let id2 = next_id();
-        assert_eq!(id2, id1 + 1);
-    }
-}
\ No newline at end of file
+    // Manual verification
+    let hash = sha256::Hash::hash(&script_bytes);
+    let mut bytes = hash.to_byte_array();
+    bytes.reverse();
+    let expected = hex::encode(bytes);
+
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_next_id_increments() {
+    let id1 = next_id();
+    let id2 = next_id();
+    assert_eq!(id2, id1 + 1);
+}
+
+#[test]
+fn unanswered_ping_reconnects() {
+    let (connector, servers) = duplex_connector();
+
+    // A server that keeps the socket open but answers nothing past the handshake.
+    let (adapter, _dead) =
+        connect_served(connector, &servers, |server| serve(server, vec!["not json".to_string()], |_| vec![]));
+    let adapter = adapter.with_ping_interval(Duration::from_millis(50));
+
+    // A dead server must not look like 'no changes forever': a new
+    // connection is opened instead.
+    let second = servers.recv_timeout(Duration::from_secs(2)).expect("no reconnect after an unanswered ping");
+    let health = adapter.health();
+    assert!(health.pings_sent >= 1);
+    assert_eq!(health.pongs_received, 0);
+    assert_eq!(health.message_errors, 1, "the garbage line is counted, not just swallowed");
+    assert!(health.last_error.is_some());
+
+    let _second = serve_chain(second, Arc::new(Mutex::new(FakeChain::default())));
+    assert!(wait_until(Duration::from_secs(2), || adapter.is_connected()));
+    assert!(adapter.terminal_error().is_none());
+}
+
+#[test]
+fn answered_pings_keep_connection_healthy() {
+    let (connector, servers) = duplex_connector();
+
+    let (adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], |req| {
+        (req["method"] == "server.ping")
+            .then(|| json!({"jsonrpc": "2.0", "id": req["id"], "result": null}))
+            .into_iter()
+            .collect()
+    }));
+    let adapter = adapter.with_ping_interval(Duration::from_millis(20));
+
+    assert!(wait_until(Duration::from_secs(2), || adapter.health().pongs_received >= 3));
+    assert!(adapter.terminal_error().is_none());
+}
+
+#[test]
+fn shared_block_header_is_awaited_by_every_scripthash() {
+    let (connector, servers) = duplex_connector();
+
+    let hash_a = sha256::Hash::hash(b"script a");
+    let hash_b = sha256::Hash::hash(b"script b");
+    let release = sha256::Hash::hash(b"release header");
+    let tx_a = dummy_tx(1);
+    let tx_b = dummy_tx(2);
+    let header = genesis_block(Network::Bitcoin).header;
+
+    // Both histories confirm at height 100. The server holds the header
+    // response until `release` is queried, so B's tx lands first.
+    let held_header = Arc::new(Mutex::new(None::<Value>));
+    let txs = [tx_a.clone(), tx_b.clone()];
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        let param = req["params"][0].clone();
+        match req["method"].as_str().unwrap() {
+            "blockchain.scripthash.get_history" if param == wire_hash(&release) => {
+                let mut out = vec![reply(req, json!([]))];
+                out.extend(held_header.lock().unwrap().take());
+                out
+            }
+            "blockchain.scripthash.get_history" => {
+                let tx = if param == wire_hash(&hash_a) { &txs[0] } else { &txs[1] };
+                vec![reply(req, json!([{"tx_hash": tx.compute_txid().to_string(), "height": 100}]))]
+            }
+            "blockchain.transaction.get" => {
+                let tx = txs.iter().find(|t| param == t.compute_txid().to_string()).unwrap();
+                vec![reply(req, json!(serialize_hex(tx)))]
+            }
+            "blockchain.transaction.get_merkle" => {
+                vec![reply(req, json!({"block_height": 100, "merkle": [], "pos": 0}))]
+            }
+            "blockchain.block.header" => {
+                *held_header.lock().unwrap() = Some(reply(req, json!(serialize_hex(&header))));
+                vec![]
+            }
+            _ => vec![],
+        }
+    }));
+
+    adapter.request_history(hash_a);
+    adapter.request_history(hash_b);
+
+    // Both tx responses are in, but the shared header isn't: nobody is ready.
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(adapter.poll_scripthash_changed(), None, "B must wait for the header A requested");
+    assert!(adapter.get_cached_header(100).is_none());
+
+    adapter.request_history(release);
+
+    let mut ready = Vec::new();
+    assert!(wait_until(Duration::from_secs(2), || {
+        ready.extend(adapter.poll_scripthash_changed());
+        ready.len() == 3
+    }));
+    assert!(ready.contains(&hash_a) && ready.contains(&hash_b));
+    assert_eq!(adapter.get_cached_header(100), Some(header));
+    assert_eq!(adapter.fetch_history_txs(hash_b).unwrap()[0].tx, tx_b);
+}
+
+#[test]
+fn oversized_frame_tears_connection_down_and_reconnects() {
+    let (connector, servers) = duplex_connector();
+
+    // Answer the first ping with a 4 KB notification.
+    let (adapter, _first) = connect_served(connector, &servers, |server| serve(server, vec![], |req| {
+        if req["method"] != "server.ping" {
+            return vec![];
+        }
+        vec![json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.scripthash.subscribe",
+            "params": ["a".repeat(4096), null]
+        })]
+    }));
+    let adapter = adapter.with_max_frame_bytes(1024)
+        .with_ping_interval(Duration::from_millis(20));
+
+    let second = servers.recv_timeout(Duration::from_secs(2)).expect("no reconnect after an oversized frame");
+    let reason = adapter.health().last_error.unwrap();
+    assert!(reason.contains("exceeds 1024 bytes"), "{}", reason);
+    assert!(reason.contains("blockchain.scripthash.subscribe"), "{}", reason);
+
+    let _second = serve_chain(second, Arc::new(Mutex::new(FakeChain::default())));
+    assert!(wait_until(Duration::from_secs(2), || adapter.is_connected()));
+    assert!(adapter.terminal_error().is_none());
+}
+
+#[test]
+fn get_transaction_decodes_and_caches_known_tx() {
+    let (connector, servers) = duplex_connector();
+
+    let tx = dummy_tx(7);
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(tx.clone(), 0);
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+    // A second lookup is served from the cache.
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+    assert_eq!(chain.lock().unwrap().count("blockchain.transaction.get"), 1);
+
+    let err = adapter.get_transaction(dummy_tx(8).compute_txid()).unwrap_err().to_string();
+    assert!(err.contains("No such mempool or blockchain transaction"), "{}", err);
+}
+
+#[test]
+fn requested_tx_comes_from_the_cache_and_a_wrong_tx_is_rejected() {
+    let (connector, servers) = duplex_connector();
+
+    let (tx, asked, sent) = (dummy_tx(7), dummy_tx(8).compute_txid(), dummy_tx(9));
+    let sent_txid = sent.compute_txid();
+    let chain = Mutex::new(FakeChain::default());
+    chain.lock().unwrap().add_tx(tx.clone(), 0);
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = requests.clone();
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        if req["method"] != "blockchain.transaction.get" {
+            return chain.lock().unwrap().handle(req);
+        }
+        counted.fetch_add(1, AtomicOrdering::SeqCst);
+        if req["params"][0] == asked.to_string() {
+            return vec![reply(req, json!(serialize_hex(&sent)))];
+        }
+        chain.lock().unwrap().handle(req)
+    }));
+
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+    assert!(adapter.request_transaction(tx.compute_txid()));
+    assert_eq!(adapter.take_transaction(&tx.compute_txid()), Some(Some(tx)));
+    assert_eq!(requests.load(AtomicOrdering::SeqCst), 1);
+
+    // A server answering with some other tx gets neither caller a wrong tx.
+    let err = adapter.get_transaction(asked).unwrap_err().to_string();
+    assert!(err.contains(&sent_txid.to_string()), "{}", err);
+    assert!(adapter.request_transaction(asked));
+    assert!(wait_until(Duration::from_secs(2), || adapter.take_transaction(&asked) == Some(None)));
+    assert!(adapter.cached_transaction(&sent_txid).is_none());
+}
+
+#[test]
+fn block_header_lookup_is_cached_and_a_malformed_reply_fails_fast() {
+    let (connector, servers) = duplex_connector();
+    let chain = Mutex::new(FakeChain::default());
+    let header = genesis_block(Network::Testnet).header;
+    chain.lock().unwrap().add_header(100, header);
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        if req["method"] == "blockchain.block.header" && req["params"][0] == 101 {
+            return vec![reply(req, json!("00"))];
+        }
+        chain.lock().unwrap().handle(req)
+    }));
+
+    assert_eq!(adapter.get_block_header(100).unwrap(), header);
+    assert_eq!(adapter.get_cached_header(100), Some(header));
+
+    let started = std::time::Instant::now();
+    assert!(adapter.get_block_header(101).is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(adapter.get_cached_header(101), None);
+}
+
+#[test]
+fn broadcast_returns_the_txid_or_the_servers_rejection() {
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    let tx = dummy_tx(7);
+    assert_eq!(adapter.broadcast(&tx).unwrap(), tx.compute_txid());
+    assert_eq!(chain.lock().unwrap().count("blockchain.transaction.broadcast"), 1);
+
+    // Spends the same outpoint to a different output.
+    let mut conflict = dummy_tx(7);
+    conflict.output[0].value = bitcoin::Amount::from_sat(900);
+    let err = adapter.broadcast(&conflict).unwrap_err().to_string();
+    assert!(err.contains(&conflict.compute_txid().to_string()), "{}", err);
+    assert!(err.contains("txn-mempool-conflict"), "{}", err);
+
+    // The rejection doesn't affect the connection.
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+}
+
+#[test]
+fn header_and_broadcast_answers_can_be_collected_later() {
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let header = genesis_block(Network::Testnet).header;
+    chain.lock().unwrap().add_header(100, header);
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    assert!(adapter.request_block_header(100));
+    let mut answer = None;
+    assert!(wait_until(Duration::from_secs(2), || {
+        answer = adapter.take_block_header(100);
+        answer.is_some()
+    }));
+    assert_eq!(answer.unwrap().unwrap(), header);
+
+    let tx = dummy_tx(7);
+    let request = adapter.request_broadcast(&tx).unwrap();
+    let mut answer = None;
+    assert!(wait_until(Duration::from_secs(2), || {
+        answer = adapter.take_broadcast(request);
+        answer.is_some()
+    }));
+    assert_eq!(answer.unwrap().unwrap(), tx.compute_txid());
+}
+
+#[test]
+fn concurrent_broadcasts_of_one_tx_each_get_an_answer() {
+    let (connector, servers) = duplex_connector();
+    let chain = Mutex::new(FakeChain::default());
+    // Holds the first submission back so both answers arrive together.
+    let held = Mutex::new(None::<Value>);
+    let (adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        if req["method"] != "blockchain.transaction.broadcast" {
+            return chain.lock().unwrap().handle(req);
+        }
+        let mut answers = chain.lock().unwrap().handle(req);
+        let mut held = held.lock().unwrap();
+        match held.take() {
+            Some(first) => {
+                answers.insert(0, first);
+                answers
+            }
+            None => {
+                *held = answers.pop();
+                vec![]
+            }
+        }
+    }));
+
+    let tx = dummy_tx(7);
+    let (a, b) = std::thread::scope(|scope| {
+        let a = scope.spawn(|| adapter.broadcast_blocking(&tx));
+        let b = scope.spawn(|| adapter.broadcast_blocking(&tx));
+        (a.join().unwrap(), b.join().unwrap())
+    });
+    assert_eq!(a.unwrap(), tx.compute_txid());
+    assert_eq!(b.unwrap(), tx.compute_txid());
+}
+
+/// A one-connection SOCKS5 proxy that serves `chain` to whoever connects
+/// through it, reporting the requested `(host, port)`.
+fn socks5_proxy(chain: Arc<Mutex<FakeChain>>) -> (std::net::SocketAddr, std::sync::mpsc::Receiver<(String, u16)>) {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut tcp, _) = listener.accept().unwrap();
+        let mut greeting = [0u8; 3];
+        tcp.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        tcp.write_all(&[5, 0]).unwrap();
+
+        let mut request = [0u8; 5];
+        tcp.read_exact(&mut request).unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 3], "expected CONNECT by domain name");
+        let mut host = vec![0u8; request[4] as usize];
+        tcp.read_exact(&mut host).unwrap();
+        let mut port = [0u8; 2];
+        tcp.read_exact(&mut port).unwrap();
+        tx.send((String::from_utf8(host).unwrap(), u16::from_be_bytes(port))).unwrap();
+        tcp.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            tcp.set_nonblocking(true).unwrap();
+            serve_chain_on(tokio::net::TcpStream::from_std(tcp).unwrap(), chain).await;
+        });
+    });
+    (addr, rx)
+}
+
+#[test]
+fn onion_server_is_reached_through_socks5_proxy() {
+    let tx = dummy_tx(7);
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(tx.clone(), 0);
+    let (proxy, requested) = socks5_proxy(chain);
+
+    let onion = "electrumx2qdhv3nfuyfokvw5yq5ozq2esbqpzxx6cfjbgbhlbnnycyd.onion";
+    let mut adapter = ElectrumAdapter::with_proxy(format!("tcp://{}:50001", onion), proxy).unwrap();
+
+    // The name goes to the proxy unresolved, and the session runs in plaintext over it.
+    assert_eq!(requested.recv_timeout(Duration::from_secs(5)).unwrap(), (onion.to_string(), 50001));
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+}
+
+#[test]
+fn unreachable_or_malformed_server_is_an_error_not_a_hang() {
+    let closed_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    for server in [format!("ssl://127.0.0.1:{}", closed_port), "ssl://127.0.0.1".to_string()] {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(ElectrumAdapter::new(server).err().map(|e| e.to_string())));
+        let err = rx.recv_timeout(Duration::from_secs(5)).expect("new() did not return");
+        assert!(err.is_some());
+    }
+}
+
+#[test]
+fn cached_histories_skip_the_download_until_the_status_changes() {
+    let path = std::env::temp_dir().join(format!("bdk_test_histories_{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let script = bitcoin::ScriptBuf::new_op_return([21; 4]);
+    let hash = sha256::Hash::hash(script.as_bytes());
+    let paid = |tag| bitcoin::Transaction {
+        output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(1_000), script_pubkey: script.clone() }],
+        ..dummy_tx(tag)
+    };
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(paid(1), 0);
+    let start = || {
+        let (connector, servers) = duplex_connector();
+        let (adapter, server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+        (adapter.with_cache_path(&path), server)
+    };
+
+    // Cold start: downloaded, then saved on shutdown.
+    let (mut adapter, _server) = start();
+    adapter.request_history(hash);
+    adapter.register_script(script.clone(), hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    assert_eq!(adapter.fetch_history_txs(hash).unwrap().len(), 1);
+    adapter.shutdown();
+
+    // Warm start: the history is ready without asking, and the matching
+    // status leaves it alone.
+    chain.lock().unwrap().requests.clear();
+    let (mut adapter, _server) = start();
+    adapter.request_history(hash);
+    assert_eq!(adapter.poll_scripthash_changed(), Some(hash));
+    assert_eq!(adapter.fetch_history_txs(hash).unwrap()[0].tx, paid(1));
+    adapter.register_script(script.clone(), hash);
+    assert!(!wait_until(Duration::from_millis(200), || adapter.poll_scripthash_changed().is_some()));
+    adapter.shutdown();
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.subscribe"), 1);
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.get_history"), 0);
+
+    // Paid while we were away: the new status drops the cached history and
+    // flags the script, so it is downloaded again.
+    chain.lock().unwrap().add_tx(paid(2), 0);
+    let (mut adapter, _server) = start();
+    adapter.register_script(script.clone(), hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    assert_eq!(adapter.fetch_history_txs(hash).unwrap().len(), 2);
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.get_history"), 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn server_below_the_minimum_protocol_version_is_refused() {
+    let (connector, servers) = duplex_connector();
+    let chain = Mutex::new(FakeChain::default());
+    let (adapter, _server) = try_connect_served(|| ElectrumAdapter::with_connector(connector), &servers, |server| {
+        serve(server, vec![], move |req| {
+            if req["method"] == "server.version" {
+                return vec![reply(req, json!(["old-electrum", "1.2"]))];
+            }
+            chain.lock().unwrap().handle(req)
+        })
+    });
+    let err = adapter.err().expect("connecting to a 1.2 server fails").to_string();
+    assert!(err.contains("1.2") && err.contains("1.4"), "{}", err);
+
+    // A higher minimum is asked for in the handshake and refuses a 1.4 server.
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (adapter, _server) = try_connect_served(
+        || ElectrumAdapter::with_connector_and_min_protocol(connector, "1.4.1"),
+        &servers,
+        |server| serve_chain(server, chain.clone()),
+    );
+    let err = adapter.err().expect("connecting to a 1.4 server fails").to_string();
+    assert!(err.contains("protocol 1.4,") && err.contains("1.4.1"), "{}", err);
+    let version = chain.lock().unwrap().requests.iter().find(|r| r["method"] == "server.version").cloned().unwrap();
+    assert_eq!(version["params"][1], "1.4.1");
+}
+
+#[test]
+fn server_refusing_the_handshake_version_is_refused() {
+    let (connector, servers) = duplex_connector();
+    let chain = Mutex::new(FakeChain::default());
+    let (adapter, _server) = try_connect_served(|| ElectrumAdapter::with_connector(connector), &servers, |server| {
+        serve(server, vec![], move |req| {
+            if req["method"] == "server.version" {
+                let error = json!({"code": 1, "message": "unsupported protocol version: 1.4"});
+                return vec![json!({"jsonrpc": "2.0", "id": req["id"], "error": error})];
+            }
+            chain.lock().unwrap().handle(req)
+        })
+    });
+    let err = adapter.err().expect("connecting to a server that refuses 1.4 fails").to_string();
+    assert!(err.contains("unsupported protocol version"), "{}", err);
+}
+
+#[test]
+fn self_signed_server_needs_a_pinned_cert_or_relaxed_validation() {
+    let tx = dummy_tx(7);
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(tx.clone(), 0);
+    let server = format!("ssl://localhost:{}", tls_server(chain).port());
+
+    // Strict validation (the default) rejects the self-signed certificate.
+    assert!(ElectrumAdapter::with_tls_config(server.clone(), TlsConfig::default()).is_err());
+
+    let cert = native_tls::Certificate::from_pem(SELF_SIGNED_CERT.as_bytes()).unwrap().to_der().unwrap();
+    let pinned = TlsConfig { pinned_cert: Some(cert.clone()), ..TlsConfig::default() };
+    let mut adapter = ElectrumAdapter::with_tls_config(server.clone(), pinned).unwrap();
+    assert_eq!(adapter.get_transaction(tx.compute_txid()).unwrap(), tx);
+
+    // Any other certificate is refused, even with validation otherwise off.
+    let mut other = cert;
+    *other.last_mut().unwrap() ^= 1;
+    let wrong_pin = TlsConfig {
+        danger_accept_invalid_certs: true,
+        danger_accept_invalid_hostnames: true,
+        pinned_cert: Some(other),
+    };
+    let err = ElectrumAdapter::with_tls_config(server, wrong_pin).err().unwrap().to_string();
+    assert!(err.contains("pinned"), "{}", err);
+}
+
+#[test]
+fn confirmed_txs_are_verified_against_the_block_merkle_root() {
+    let (connector, servers) = duplex_connector();
+
+    // Block 100 holds only `proven`, so its merkle root is that txid. The
+    // header at 101 commits to nothing the server claims is in it.
+    let (proven, unproven, pending) = (dummy_tx(1), dummy_tx(2), dummy_tx(3));
+    let genesis = genesis_block(Network::Testnet).header;
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    {
+        let mut c = chain.lock().unwrap();
+        c.add_tx(proven.clone(), 100);
+        c.add_tx(unproven.clone(), 101);
+        c.add_tx(pending.clone(), 0);
+        c.add_header(100, bitcoin::block::Header {
+            merkle_root: bitcoin::TxMerkleNode::from_raw_hash(proven.compute_txid().to_raw_hash()),
+            ..genesis
+        });
+        c.add_header(101, bitcoin::block::Header { nonce: 101, ..genesis });
+    }
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    // Every `dummy_tx` pays the empty script.
+    let hash = sha256::Hash::hash(&[]);
+    adapter.request_history(hash);
+    let mut history = None;
+    assert!(wait_until(Duration::from_secs(2), || {
+        history = adapter.fetch_history_txs(hash);
+        history.is_some()
+    }));
+    let verified = |tx: &bitcoin::Transaction| {
+        history.as_ref().unwrap().iter().find(|h| h.tx == *tx).unwrap().verified
+    };
+    assert!(verified(&proven));
+    assert!(!verified(&unproven));
+    assert!(verified(&pending), "unconfirmed txs need no proof");
+    assert_eq!(chain.lock().unwrap().count("blockchain.transaction.get_merkle"), 2);
+}
+
+#[test]
+fn refetch_only_asks_for_proofs_it_does_not_already_hold() {
+    let (connector, servers) = duplex_connector();
+    let (proven, unproven) = (dummy_tx(1), dummy_tx(2));
+    let genesis = genesis_block(Network::Testnet).header;
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    {
+        let mut c = chain.lock().unwrap();
+        c.add_tx(proven.clone(), 100);
+        c.add_tx(unproven.clone(), 101);
+        c.add_header(100, bitcoin::block::Header {
+            merkle_root: bitcoin::TxMerkleNode::from_raw_hash(proven.compute_txid().to_raw_hash()),
+            ..genesis
+        });
+        c.add_header(101, bitcoin::block::Header { nonce: 101, ..genesis });
+    }
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    let hash = sha256::Hash::hash(&[]);
+    for fetch in 1..=2 {
+        adapter.request_history(hash);
+        let mut history = None;
+        assert!(wait_until(Duration::from_secs(2), || {
+            history = adapter.fetch_history_txs(hash);
+            history.is_some()
+        }));
+        let verified = |tx: &bitcoin::Transaction| {
+            history.as_ref().unwrap().iter().find(|h| h.tx == *tx).unwrap().verified
+        };
+        assert!(verified(&proven), "fetch {}", fetch);
+        assert!(!verified(&unproven), "fetch {}", fetch);
+    }
+    // The refetch only asks again for the proof that failed.
+    assert_eq!(chain.lock().unwrap().count("blockchain.transaction.get_merkle"), 3);
+}
+
+#[test]
+fn shutdown_closes_the_socket_and_stops_the_background_thread() {
+    let (connector, servers) = duplex_connector();
+
+    // The server end answers the handshake, then reads until the adapter hangs up.
+    let (closed_tx, closed_rx) = std::sync::mpsc::channel();
+    let (mut adapter, ()) = connect_served(connector, &servers, |server| {
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async move {
+                use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+                let (r, mut w) = tokio::io::split(server);
+                let mut lines = tokio::io::BufReader::new(r).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let req: Value = serde_json::from_str(&line).unwrap();
+                    if req["method"] == "server.version" {
+                        let answer = reply(&req, json!(["fake-electrum", "1.4"]));
+                        w.write_all(format!("{}\n", answer).as_bytes()).await.unwrap();
+                    }
+                }
+            });
+            closed_tx.send(()).unwrap();
+        });
+    });
+
+    adapter.shutdown();
+    assert!(closed_rx.recv_timeout(Duration::from_secs(2)).is_ok(), "connection closed");
+    assert!(!adapter.is_connected());
+    assert_eq!(adapter.terminal_error(), None, "shutting down is not a failure");
+    assert!(servers.try_recv().is_err(), "no reconnect");
+}
+
+#[test]
+fn unanswered_history_request_times_out_and_is_retried() {
+    let (connector, servers) = duplex_connector();
+
+    // The first get_history is swallowed; everything else is answered.
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(dummy_tx(1), 0);
+    let swallowed = Arc::new(AtomicUsize::new(0));
+    let (adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], {
+        let (chain, swallowed) = (chain.clone(), swallowed.clone());
+        move |req| {
+            if req["method"] == "blockchain.scripthash.get_history" && swallowed.fetch_add(1, AtomicOrdering::SeqCst) == 0 {
+                chain.lock().unwrap().requests.push(req.clone());
+                return vec![];
+            }
+            chain.lock().unwrap().handle(req)
+        }
+    }));
+    let mut adapter = adapter.with_request_timeout(Duration::from_millis(100));
+
+    // Every `dummy_tx` pays the empty script.
+    let hash = sha256::Hash::hash(&[]);
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    assert_eq!(adapter.fetch_history_txs(hash).unwrap().len(), 1);
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.get_history"), 2);
+    assert_eq!(adapter.health().requests_timed_out, 1);
+    assert_eq!(adapter.pending_work().histories, 0);
+}
+
+#[test]
+fn idle_connection_closes_and_reopens_on_next_request() {
+    let (connector, servers) = duplex_connector();
+
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+    let mut adapter = adapter.with_idle_disconnect(Duration::from_millis(100), None);
+
+    let script = bitcoin::ScriptBuf::new_op_return([1u8; 4]);
+    let hash = sha256::Hash::hash(script.as_bytes());
+    adapter.register_script(script.clone(), hash);
+
+    // Caught up and quiet: the connection is closed, without failing.
+    assert!(wait_until(Duration::from_secs(2), || !adapter.is_connected()));
+    assert!(adapter.terminal_error().is_none());
+    assert!(!adapter.is_connecting());
+
+    // The script is paid while we're away.
+    let payment = bitcoin::Transaction {
+        output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(1_000), script_pubkey: script }],
+        ..dummy_tx(3)
+    };
+    chain.lock().unwrap().add_tx(payment.clone(), 0);
+
+    // The next request reopens the connection and re-subscribes every
+    // script once, including the one whose registration woke it.
+    assert!(servers.try_recv().is_err(), "no reconnect before a request");
+    let other = bitcoin::ScriptBuf::new_op_return([2u8; 4]);
+    adapter.register_script(other.clone(), sha256::Hash::hash(other.as_bytes()));
+    serve_chain(servers.recv_timeout(Duration::from_secs(2)).unwrap(), chain.clone());
+    assert_eq!(adapter.get_transaction(payment.compute_txid()).unwrap(), payment);
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.subscribe"), 3);
+
+    // The status changed while disconnected, so the script is reported.
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+}
+
+#[test]
+fn dropped_connection_mid_sync_reconnects_and_resumes() {
+    let (connector, servers) = duplex_connector();
+
+    let script = bitcoin::ScriptBuf::new_op_return([2u8; 4]);
+    let hash = sha256::Hash::hash(script.as_bytes());
+    let payment = bitcoin::Transaction {
+        output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(5_000), script_pubkey: script.clone() }],
+        ..dummy_tx(4)
+    };
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(payment.clone(), 0);
+
+    // The first server answers everything but the history, then goes away.
+    let (mut adapter, first) = connect_served(connector, &servers, |server| serve(server, vec![], {
+        let chain = chain.clone();
+        move |req| {
+            let mut chain = chain.lock().unwrap();
+            match req["method"].as_str() {
+                Some("blockchain.scripthash.get_history") => {
+                    chain.requests.push(req.clone());
+                    vec![]
+                }
+                _ => chain.handle(req),
+            }
+        }
+    }));
+    adapter.register_script(script, hash);
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || {
+        chain.lock().unwrap().count("blockchain.scripthash.get_history") == 1
+    }));
+    first.close();
+
+    // The adapter reconnects on its own, re-subscribes, and asks again.
+    let _second = serve_chain(servers.recv_timeout(Duration::from_secs(2)).unwrap(), chain.clone());
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    let history = adapter.fetch_history_txs(hash).unwrap();
+    assert_eq!(history[0].tx, payment);
+    assert!(adapter.is_connected());
+    assert!(adapter.terminal_error().is_none());
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.subscribe"), 2);
+    assert_eq!(chain.lock().unwrap().count("server.version"), 2);
+}
+
+#[test]
+fn reconnect_restores_every_subscription_and_reports_missed_changes() {
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (mut adapter, first) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    let scripts: Vec<bitcoin::ScriptBuf> =
+        (0..3u8).map(|i| bitcoin::ScriptBuf::new_op_return([10 + i; 4])).collect();
+    let hashes: Vec<sha256::Hash> = scripts.iter().map(|s| sha256::Hash::hash(s.as_bytes())).collect();
+    for (script, hash) in scripts.iter().zip(&hashes) {
+        adapter.register_script(script.clone(), *hash);
+    }
+    assert!(wait_until(Duration::from_secs(2), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 3
+    }));
+
+    // Paid during the outage: no notification ever reaches us for it.
+    let missed = bitcoin::Transaction {
+        output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(2_000), script_pubkey: scripts[1].clone() }],
+        ..dummy_tx(5)
+    };
+    chain.lock().unwrap().add_tx(missed, 0);
+    chain.lock().unwrap().requests.clear();
+    first.close();
+
+    let _second = serve_chain(servers.recv_timeout(Duration::from_secs(2)).unwrap(), chain.clone());
+    assert!(wait_until(Duration::from_secs(2), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 3
+    }));
+    let mut resubscribed: Vec<String> = chain
+        .lock()
+        .unwrap()
+        .requests
+        .iter()
+        .filter(|r| r["method"] == "blockchain.scripthash.subscribe")
+        .map(|r| r["params"][0].as_str().unwrap().to_string())
+        .collect();
+    resubscribed.sort();
+    let mut expected: Vec<String> = hashes.iter().map(wire_hash).collect();
+    expected.sort();
+    assert_eq!(resubscribed, expected);
+
+    // Only the script whose status changed is reported, so its history is refetched.
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hashes[1])));
+    assert_eq!(adapter.poll_scripthash_changed(), None);
+}
+
+#[test]
+fn reconnect_backoff_doubles_with_jitter_up_to_the_cap_and_resets() {
+    let policy = ReconnectPolicy {
+        jitter: Duration::from_millis(100),
+        ..ReconnectPolicy::default()
+    };
+    let mut backoff = Backoff::default();
+    let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay(&policy)).collect();
+
+    // Immediate first attempt, then 1s, 2s, 4s, ... capped at 30s, each
+    // plus at most the jitter.
+    let expected = [0, 1, 2, 4, 8, 16, 30, 30].map(Duration::from_secs);
+    assert_eq!(delays[0], Duration::ZERO);
+    for (delay, base) in delays.iter().zip(expected).skip(1) {
+        assert!(*delay >= base && *delay <= base + policy.jitter, "{:?} not within jitter of {:?}", delay, base);
+    }
+
+    backoff.reset();
+    assert_eq!(backoff.next_delay(&policy), Duration::ZERO);
+    let after_reset = backoff.next_delay(&policy);
+    assert!(after_reset >= Duration::from_secs(1) && after_reset <= Duration::from_millis(1_100));
+}
+
+#[test]
+fn wait_for_change_wakes_on_a_notification() {
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (mut adapter, server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    let script = bitcoin::ScriptBuf::new_op_return([20u8; 4]);
+    let hash = sha256::Hash::hash(script.as_bytes());
+    adapter.register_script(script, hash);
+    assert!(wait_until(Duration::from_secs(2), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 1
+    }));
+    // Let the subscribe ack land first.
+    std::thread::sleep(Duration::from_millis(50));
+
+    // Nothing happening: the full timeout passes.
+    let started = std::time::Instant::now();
+    assert_eq!(adapter.wait_for_change(Duration::from_millis(100)), None);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // A notification ends the wait long before the timeout.
+    let pusher = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        server.push(json!({
+            "jsonrpc": "2.0",
+            "method": "blockchain.scripthash.subscribe",
+            "params": [wire_hash(&hash), "new status"]
+        }));
+        server
+    });
+    let started = std::time::Instant::now();
+    assert_eq!(adapter.wait_for_change(Duration::from_secs(5)), Some(hash));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let _server = pusher.join().unwrap();
+}
+
+#[test]
+fn undecodable_tx_is_reported_and_the_history_is_flagged_incomplete() {
+    use crate::streaming::electrum::api::FetchError;
+
+    let script = bitcoin::ScriptBuf::new_op_return([22; 4]);
+    let hash = sha256::Hash::hash(script.as_bytes());
+    let paid = |tag| bitcoin::Transaction {
+        output: vec![bitcoin::TxOut { value: bitcoin::Amount::from_sat(1_000), script_pubkey: script.clone() }],
+        ..dummy_tx(tag)
+    };
+    let (good, bad) = (paid(1), paid(2));
+    let bad_txid = bad.compute_txid();
+    let chain = Mutex::new(FakeChain::default());
+    chain.lock().unwrap().add_tx(good.clone(), 0);
+    chain.lock().unwrap().add_tx(bad, 0);
+
+    let (connector, servers) = duplex_connector();
+    let (mut adapter, _server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        if req["method"] == "blockchain.transaction.get" && req["params"][0] == bad_txid.to_string() {
+            return vec![reply(req, json!("not hex at all"))];
+        }
+        chain.lock().unwrap().handle(req)
+    }));
+
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    let txs = adapter.fetch_history_txs(hash).unwrap();
+    assert_eq!(txs.iter().map(|htx| &htx.tx).collect::<Vec<_>>(), vec![&good]);
+    assert!(adapter.history_incomplete(hash));
+    assert!(!adapter.history_incomplete(hash));
+
+    let errors = adapter.take_errors();
+    assert!(
+        matches!(&errors[..], [FetchError::Transaction { hash: h, txid, .. }] if *h == hash && *txid == bad_txid),
+        "{:?}",
+        errors
+    );
+    assert!(adapter.take_errors().is_empty());
+}
+
+#[test]
+fn chain_tip_follows_header_notifications() {
+    let (connector, servers) = duplex_connector();
+    let genesis = genesis_block(Network::Testnet).header;
+    let header_at = |height: u32| bitcoin::block::Header { nonce: height, ..genesis };
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_header(100, header_at(100));
+    let (adapter, server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    // Subscribed during the handshake.
+    assert!(wait_until(Duration::from_secs(2), || adapter.chain_tip().is_some()));
+    assert_eq!(adapter.chain_tip(), Some((100, header_at(100))));
+
+    server.push(json!({
+        "jsonrpc": "2.0",
+        "method": "blockchain.headers.subscribe",
+        "params": [{"height": 101, "hex": serialize_hex(&header_at(101))}]
+    }));
+    assert!(wait_until(Duration::from_secs(2), || {
+        adapter.chain_tip() == Some((101, header_at(101)))
+    }));
+    assert_eq!(adapter.get_cached_header(101), Some(header_at(101)));
+    assert_eq!(chain.lock().unwrap().count("blockchain.headers.subscribe"), 1);
+}
+
+#[test]
+fn replaced_header_sends_scripthashes_confirmed_there_back_to_sync() {
+    let (connector, servers) = duplex_connector();
+    let genesis = genesis_block(Network::Testnet).header;
+    let (old_block, new_block) = (
+        bitcoin::block::Header { nonce: 1, ..genesis },
+        bitcoin::block::Header { nonce: 2, ..genesis },
+    );
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    chain.lock().unwrap().add_tx(dummy_tx(1), 100);
+    chain.lock().unwrap().add_header(100, old_block);
+    let (mut adapter, server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+
+    let script = bitcoin::ScriptBuf::new();
+    let hash = sha256::Hash::hash(script.as_bytes());
+    adapter.register_script(script, hash);
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || adapter.fetch_history_txs(hash).is_some()));
+    while adapter.poll_scripthash_changed().is_some() {}
+    assert_eq!(adapter.get_cached_header(100), Some(old_block));
+
+    // The server switches to another block at the same height.
+    server.push(json!({
+        "jsonrpc": "2.0",
+        "method": "blockchain.headers.subscribe",
+        "params": [{"height": 100, "hex": serialize_hex(&new_block)}]
+    }));
+    assert!(wait_until(Duration::from_secs(2), || adapter.poll_scripthash_changed() == Some(hash)));
+    assert_eq!(adapter.get_cached_header(100), Some(new_block));
+
+    // The driver re-fetches the history, now anchored to the new block.
+    assert!(adapter.fetch_history_txs(hash).is_none());
+    adapter.request_history(hash);
+    assert!(wait_until(Duration::from_secs(2), || {
+        chain.lock().unwrap().count("blockchain.scripthash.get_history") == 2
+    }));
+}
+
+#[test]
+fn refetched_history_forgets_the_heights_it_left() {
+    let (connector, servers) = duplex_connector();
+    let genesis = genesis_block(Network::Testnet).header;
+    let tx = dummy_tx(1);
+    let txid = tx.compute_txid();
+    let chain = Mutex::new(FakeChain::default());
+    chain.lock().unwrap().add_tx(tx, 101);
+    chain.lock().unwrap().add_header(100, bitcoin::block::Header { nonce: 1, ..genesis });
+    chain.lock().unwrap().add_header(101, bitcoin::block::Header { nonce: 2, ..genesis });
+    // First mined at 101, then (after a reorg) at 100.
+    let histories = AtomicUsize::new(0);
+    let (mut adapter, server) = connect_served(connector, &servers, |server| serve(server, vec![], move |req| {
+        if req["method"] == "blockchain.scripthash.get_history" {
+            let height = if histories.fetch_add(1, AtomicOrdering::SeqCst) == 0 { 101 } else { 100 };
+            return vec![reply(req, json!([{"tx_hash": txid.to_string(), "height": height}]))];
+        }
+        chain.lock().unwrap().handle(req)
+    }));
+
+    let hash = sha256::Hash::hash(b"moved tx");
+    for _ in 0..2 {
+        adapter.request_history(hash);
+        assert!(wait_until(Duration::from_secs(2), || adapter.fetch_history_txs(hash).is_some()));
+        while adapter.poll_scripthash_changed().is_some() {}
+    }
+
+    // Replacing block 101 no longer concerns the history.
+    let replacement = bitcoin::block::Header { nonce: 3, ..genesis };
+    server.push(json!({
+        "jsonrpc": "2.0",
+        "method": "blockchain.headers.subscribe",
+        "params": [{"height": 101, "hex": serialize_hex(&replacement)}]
+    }));
+    assert!(wait_until(Duration::from_secs(2), || adapter.get_cached_header(101) == Some(replacement)));
+    assert_eq!(adapter.poll_scripthash_changed(), None);
+}
+
+/// Wraps a transport, counting how often the adapter flushes it.
+struct CountingFlushes {
+    inner: tokio::io::DuplexStream,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl tokio::io::AsyncRead for CountingFlushes {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for CountingFlushes {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.flushes.fetch_add(1, AtomicOrdering::Relaxed);
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[test]
+fn requests_beyond_the_inflight_cap_wait_for_answers() {
+    const CAP: usize = 5;
+    let (connector, servers) = duplex_connector();
+    // History requests are held until the test answers them.
+    let held = Arc::new(Mutex::new(Vec::<Value>::new()));
+    let chain = Mutex::new(FakeChain::default());
+    let (adapter, server) = connect_served(connector, &servers, |server| serve(server, vec![], {
+        let held = held.clone();
+        move |req| {
+            if req["method"] == "blockchain.scripthash.get_history" {
+                held.lock().unwrap().push(req.clone());
+                return vec![];
+            }
+            chain.lock().unwrap().handle(req)
+        }
+    }));
+    let mut adapter = adapter.with_max_inflight(CAP);
+
+    let hashes: Vec<sha256::Hash> = (0..20u8).map(|i| sha256::Hash::hash(&[i])).collect();
+    for hash in &hashes {
+        adapter.request_history(*hash);
+    }
+
+    let mut answered = 0;
+    let mut rounds = 0;
+    while answered < hashes.len() {
+        assert!(wait_until(Duration::from_secs(2), || held.lock().unwrap().len() > answered));
+        // Give anything over the cap the chance to show up.
+        std::thread::sleep(Duration::from_millis(50));
+        let sent = held.lock().unwrap().clone();
+        assert!(sent.len() - answered <= CAP, "{} requests unanswered", sent.len() - answered);
+        for req in &sent[answered..] {
+            server.push(reply(req, json!([])));
+        }
+        answered = sent.len();
+        rounds += 1;
+    }
+    assert!(rounds >= hashes.len() / CAP);
+    for hash in hashes {
+        assert!(wait_until(Duration::from_secs(2), || adapter.fetch_history_txs(hash).is_some()));
+    }
+}
+
+#[test]
+fn slow_driver_holds_back_history_fetches_and_collapses_notification_floods() {
+    const MAX_PENDING: usize = 10;
+    let (connector, servers) = duplex_connector();
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (adapter, server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+    let mut adapter = adapter.with_max_pending_histories(MAX_PENDING);
+    let get_histories = || chain.lock().unwrap().count("blockchain.scripthash.get_history");
+
+    let hashes: Vec<sha256::Hash> = (0..100u8).map(|i| sha256::Hash::hash(&[i])).collect();
+    for hash in &hashes {
+        adapter.request_history(*hash);
+    }
+    // Nobody takes the histories, so only the first few are fetched.
+    assert!(wait_until(Duration::from_secs(2), || get_histories() == MAX_PENDING));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(get_histories(), MAX_PENDING);
+
+    // However hard the server pushes, a script is queued for the driver at most twice.
+    for _ in 0..1000 {
+        server.push(status_notification(&hashes[0]));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+    let mut woken = Vec::new();
+    while let Some(hash) = adapter.poll_scripthash_changed() {
+        woken.push(hash);
+    }
+    assert!(woken.iter().filter(|h| **h == hashes[0]).count() <= 2);
+
+    // Taking the histories lets the rest through.
+    let mut taken = std::collections::HashSet::new();
+    assert!(wait_until(Duration::from_secs(5), || {
+        for hash in &hashes {
+            if adapter.fetch_history_txs(*hash).is_some() {
+                taken.insert(*hash);
+            }
+        }
+        taken.len() == hashes.len()
+    }));
+    assert_eq!(get_histories(), hashes.len());
+}
+
+#[test]
+fn queued_requests_go_out_in_one_write() {
+    let flushes = Arc::new(AtomicUsize::new(0));
+    let (tx, servers) = std::sync::mpsc::channel();
+    let connector: Connector = {
+        let flushes = flushes.clone();
+        let tx = Mutex::new(tx);
+        Arc::new(move || {
+            let (client, server) = tokio::io::duplex(1024 * 1024);
+            tx.lock().unwrap().send(server).unwrap();
+            let client = CountingFlushes { inner: client, flushes: flushes.clone() };
+            Box::pin(async move { Ok(Box::new(client) as Box<dyn Transport>) })
+        })
+    };
+    // Room for all of them under the in-flight cap.
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    let (adapter, _server) = connect_served(connector, &servers, |server| serve_chain(server, chain.clone()));
+    let mut adapter = adapter.with_max_inflight(200);
+    assert!(wait_until(Duration::from_secs(2), || adapter.is_connected()));
+    let after_handshake = flushes.load(AtomicOrdering::Relaxed);
+
+    // Queued while the write loop sleeps, so one flush picks up all of them.
+    for i in 0..200u32 {
+        let script = bitcoin::ScriptBuf::new_op_return(i.to_le_bytes());
+        let hash = sha256::Hash::hash(script.as_bytes());
+        adapter.register_script(script, hash);
+    }
+    assert!(wait_until(Duration::from_secs(5), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 200
+    }));
+    let writes = flushes.load(AtomicOrdering::Relaxed) - after_handshake;
+    assert!(writes <= 2, "200 subscribes took {} writes", writes);
+}
+
@@ -1,8 +1,10 @@
 use std::collections::{BTreeSet, HashMap, VecDeque};
 
 use bitcoin::hashes::sha256;
-use bitcoin::{block, ScriptBuf, Transaction};
+use anyhow::Result;
+use bitcoin::{block, ScriptBuf, Transaction, Txid};
 
+use crate::streaming::electrum::api::FetchError;
 use crate::streaming::electrum::ElectrumApi;
 use crate::streaming::engine::types::HistoryTx;
 
@@ -12,6 +14,23 @@ pub struct MockElectrumClient {
     pub scripts: HashMap<sha256::Hash, ScriptBuf>,
     pub histories: HashMap<sha256::Hash, Vec<Transaction>>,
     pub notifications: VecDeque<sha256::Hash>,
+    /// Transactions `get_transaction` can return.
+    pub transactions: HashMap<Txid, Transaction>,
+    /// Every `request_history` call, in order.
+    pub history_requests: Vec<sha256::Hash>,
+    /// Reported by `terminal_error`.
+    pub failure: Option<String>,
+    /// Reported by `is_connected`.
+    pub connected: bool,
+    /// Reported by `connection_epoch`; bump it to simulate a reconnect.
+    pub epoch: u64,
+    /// Handed out (and cleared) by `take_errors`.
+    pub errors: Vec<FetchError>,
+    /// Every `request_block_header` call, in order.
+    pub header_requests: Vec<u32>,
+    /// Headers `take_block_header` answers with; a request waits until its
+    /// height is here.
+    pub headers: HashMap<u32, block::Header>,
 }
 
 impl MockElectrumClient {
@@ -21,6 +40,14 @@ impl MockElectrumClient {
             scripts: HashMap::new(),
             histories: HashMap::new(),
             notifications: VecDeque::new(),
+            transactions: HashMap::new(),
+            history_requests: Vec::new(),
+            failure: None,
+            connected: true,
+            epoch: 0,
+            errors: Vec::new(),
+            header_requests: Vec::new(),
+            headers: HashMap::new(),
         }
     }
 
@@ -42,6 +69,12 @@ impl MockElectrumClient {
     }
 }
 
+impl Default for MockElectrumClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ElectrumApi for MockElectrumClient {
     fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash) {
         self.subscribed.insert(hash);
@@ -59,13 +92,14 @@ impl ElectrumApi for MockElectrumClient {
     fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
         self.histories.get(&hash).cloned().map(|txs| {
             txs.into_iter()
-                .map(|tx| HistoryTx { tx, height: 0 }) // Mock: treat all as unconfirmed
+                .map(|tx| HistoryTx { tx, height: 0, verified: true }) // Mock: treat all as unconfirmed
                 .collect()
         })
     }
 
     fn request_history(&mut self, hash: sha256::Hash) {
         println!("[MOCK] request_history called for {}", hash); // DEBUG LOG
+        self.history_requests.push(hash);
         // Simulate async completion
         self.notifications.push_back(hash);
     }
@@ -73,4 +107,36 @@ impl ElectrumApi for MockElectrumClient {
     fn get_cached_header(&self, _height: u32) -> Option<block::Header> {
         None // Mock doesn't need real block headers
     }
+
+    fn request_block_header(&mut self, height: u32) -> bool {
+        self.header_requests.push(height);
+        true
+    }
+
+    fn take_block_header(&mut self, height: u32) -> Option<Result<block::Header>> {
+        self.headers.get(&height).copied().map(Ok)
+    }
+
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        self.transactions
+            .get(&txid)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("mock has no transaction {}", txid))
+    }
+
+    fn terminal_error(&self) -> Option<String> {
+        self.failure.clone()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn connection_epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        std::mem::take(&mut self.errors)
+    }
 }
\ No newline at end of file
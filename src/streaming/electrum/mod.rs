@@ -1,9 +1,15 @@
 pub mod api;
 pub mod mock;
 pub mod asynchronous;
+pub mod pool;
+pub mod quorum;
+pub mod shared;
 
-pub use api::ElectrumApi;
+pub use api::{ElectrumApi, FetchError};
 pub use mock::client::MockElectrumClient;
+pub use pool::{ConnectionStats, PooledElectrumClient};
+pub use quorum::QuorumElectrumClient;
+pub use shared::{SharedElectrumClient, TenantClient};
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
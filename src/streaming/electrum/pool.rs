@@ -0,0 +1,492 @@
+//! Several connections to the same Electrum server(s), used as one client.
+//!
+//! `PooledElectrumClient` keeps every subscription on a single connection, so
+//! status notifications come from one consistent view of the server, and
+//! spreads history and transaction fetches across the pool: each request goes
+//! to the healthy connection with the least outstanding work (ties broken by
+//! fewer errors, then lower latency). A cold bootstrap of a large wallet then
+//! downloads over all connections at once.
+//!
+//! A connection that fails (reports a terminal error) stops receiving work;
+//! fetches still outstanding on it are re-sent elsewhere and counted as its
+//! errors. If it held the subscriptions, they move to the next healthy
+//! connection and every script is reported as changed, since notifications
+//! may have been missed in between.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bitcoin::hashes::sha256;
+use bitcoin::{block, ScriptBuf, Transaction, Txid};
+use serde::Serialize;
+
+use crate::streaming::electrum::api::{FetchError, PendingWork, ScriptBalance};
+use crate::streaming::electrum::ElectrumApi;
+use crate::streaming::engine::types::HistoryTx;
+use crate::streaming::metrics::LatencyRecorder;
+
+/// What the pool has observed of one connection (see `PooledElectrumClient::stats`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ConnectionStats {
+    /// History fetches routed here and not yet taken.
+    pub in_flight: usize,
+    /// Fetches (histories and standalone lookups) answered by this connection.
+    pub completed: u64,
+    /// Fetches lost to this connection failing, or lookups it answered with an error.
+    pub errors: u64,
+    /// Mean time from requesting a history to taking it, over `completed` histories.
+    pub mean_latency: Option<Duration>,
+    /// Whether this connection holds the pool's subscriptions.
+    pub subscriptions: bool,
+    /// `false` once the connection reported a terminal error.
+    pub healthy: bool,
+}
+
+impl ConnectionStats {
+    /// Share of this connection's fetches that failed, if it has had any.
+    pub fn error_rate(&self) -> Option<f64> {
+        let total = self.completed + self.errors;
+        (total > 0).then(|| self.errors as f64 / total as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    in_flight: usize,
+    completed: u64,
+    errors: u64,
+    /// Latency samples behind `mean_latency`: their sum and count.
+    latency_total: Duration,
+    latency_samples: u32,
+    failed: bool,
+}
+
+pub struct PooledElectrumClient<C> {
+    clients: Vec<C>,
+    connections: Vec<Connection>,
+    /// Index of the connection holding every subscription.
+    subscriber: usize,
+    /// Every registered script, to move subscriptions if `subscriber` fails.
+    scripts: BTreeMap<sha256::Hash, ScriptBuf>,
+    /// Histories requested and not yet taken: the connection fetching each, and when.
+    routed: HashMap<sha256::Hash, (usize, Instant)>,
+    /// Standalone transaction requests and the connection answering each.
+    routed_txs: HashMap<Txid, usize>,
+    /// The connection answering a `request_balances`, until taken.
+    routed_balances: Option<usize>,
+    /// Which of the histories taken were incomplete, until asked.
+    incomplete_taken: HashSet<sha256::Hash>,
+    ready: VecDeque<sha256::Hash>,
+}
+
+impl<C: ElectrumApi> PooledElectrumClient<C> {
+    /// Pools `clients`; the first one holds the subscriptions. There must be
+    /// at least one.
+    pub fn new(clients: Vec<C>) -> Result<Self> {
+        anyhow::ensure!(!clients.is_empty(), "a pool needs at least one connection");
+        let connections = clients.iter().map(|_| Connection::default()).collect();
+        Ok(Self {
+            clients,
+            connections,
+            subscriber: 0,
+            scripts: BTreeMap::new(),
+            routed: HashMap::new(),
+            routed_txs: HashMap::new(),
+            routed_balances: None,
+            incomplete_taken: HashSet::new(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    /// Per-connection load, latency and error counts, in pool order.
+    pub fn stats(&self) -> Vec<ConnectionStats> {
+        self.connections
+            .iter()
+            .enumerate()
+            .map(|(i, c)| ConnectionStats {
+                in_flight: c.in_flight,
+                completed: c.completed,
+                errors: c.errors,
+                mean_latency: (c.latency_samples > 0).then(|| c.latency_total / c.latency_samples),
+                subscriptions: i == self.subscriber,
+                healthy: !c.failed,
+            })
+            .collect()
+    }
+
+    /// Healthy connections, least loaded first.
+    fn by_load(&self) -> Vec<usize> {
+        let mut healthy: Vec<usize> = (0..self.clients.len())
+            .filter(|i| !self.connections[*i].failed && self.clients[*i].terminal_error().is_none())
+            .collect();
+        healthy.sort_by_key(|i| {
+            let c = &self.connections[*i];
+            // Our routed histories, plus the tx downloads the client still
+            // has queued for them (the bulk of a history's cost).
+            let load = c.in_flight + self.clients[*i].pending_work().txs;
+            let latency = (c.latency_samples > 0).then(|| c.latency_total / c.latency_samples);
+            (load, c.errors, latency)
+        });
+        healthy
+    }
+
+    /// Records whether the history just taken from `conn` was incomplete.
+    fn note_incomplete(&mut self, conn: usize, hash: sha256::Hash) {
+        if self.clients[conn].history_incomplete(hash) {
+            self.incomplete_taken.insert(hash);
+        } else {
+            self.incomplete_taken.remove(&hash);
+        }
+    }
+
+    /// The connection the next fetch should go to (the subscriber if all failed,
+    /// which `terminal_error` then reports).
+    fn least_loaded(&self) -> usize {
+        self.by_load().first().copied().unwrap_or(self.subscriber)
+    }
+
+    /// Notices newly failed connections and moves their work elsewhere.
+    fn check_health(&mut self) {
+        for i in 0..self.clients.len() {
+            if self.connections[i].failed {
+                continue;
+            }
+            let Some(reason) = self.clients[i].terminal_error() else {
+                continue;
+            };
+            log::warn!("[POOL] connection {} failed: {}", i, reason);
+            self.connections[i].failed = true;
+            if self.by_load().is_empty() {
+                return;
+            }
+
+            let lost: Vec<sha256::Hash> =
+                self.routed.iter().filter(|(_, (conn, _))| *conn == i).map(|(hash, _)| *hash).collect();
+            self.connections[i].errors += lost.len() as u64;
+            self.connections[i].in_flight = 0;
+            for hash in lost {
+                self.routed.remove(&hash);
+                self.request_history(hash);
+            }
+            let lost_txs: Vec<Txid> =
+                self.routed_txs.iter().filter(|(_, conn)| **conn == i).map(|(txid, _)| *txid).collect();
+            for txid in lost_txs {
+                self.connections[i].errors += 1;
+                self.routed_txs.remove(&txid);
+                self.request_transaction(txid);
+            }
+
+            if i == self.subscriber {
+                self.subscriber = self.least_loaded();
+                log::info!(
+                    "[POOL] moving {} subscriptions to connection {}",
+                    self.scripts.len(),
+                    self.subscriber
+                );
+                for (hash, script) in &self.scripts {
+                    self.clients[self.subscriber].register_script(script.clone(), *hash);
+                    if !self.ready.contains(hash) {
+                        self.ready.push_back(*hash);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves every hash the connections report into the pool's ready queue.
+    fn collect_ready(&mut self) {
+        self.check_health();
+        for client in &mut self.clients {
+            while let Some(hash) = client.poll_scripthash_changed() {
+                if !self.ready.contains(&hash) {
+                    self.ready.push_back(hash);
+                }
+            }
+        }
+    }
+}
+
+impl<C: ElectrumApi> ElectrumApi for PooledElectrumClient<C> {
+    fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash) {
+        self.scripts.insert(hash, script.clone());
+        self.clients[self.subscriber].register_script(script, hash);
+    }
+
+    fn unregister_script(&mut self, hash: sha256::Hash) {
+        self.scripts.remove(&hash);
+        self.clients[self.subscriber].unregister_script(hash);
+    }
+
+    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
+        self.collect_ready();
+        self.ready.pop_front()
+    }
+
+    /// Takes the history from the connection it was routed to, or from the
+    /// subscriber for a hash nobody requested.
+    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
+        let Some(&(conn, requested_at)) = self.routed.get(&hash) else {
+            let txs = self.clients[self.subscriber].fetch_history_txs(hash)?;
+            self.note_incomplete(self.subscriber, hash);
+            return Some(txs);
+        };
+        let txs = self.clients[conn].fetch_history_txs(hash)?;
+        self.note_incomplete(conn, hash);
+        self.routed.remove(&hash);
+        let c = &mut self.connections[conn];
+        c.in_flight = c.in_flight.saturating_sub(1);
+        c.completed += 1;
+        c.latency_total += requested_at.elapsed();
+        c.latency_samples += 1;
+        Some(txs)
+    }
+
+    fn history_incomplete(&mut self, hash: sha256::Hash) -> bool {
+        self.incomplete_taken.remove(&hash)
+    }
+
+    /// Routes to the least-loaded healthy connection; a hash already being
+    /// fetched stays on its connection.
+    fn request_history(&mut self, hash: sha256::Hash) {
+        let conn = match self.routed.get(&hash) {
+            Some((conn, _)) => *conn,
+            None => {
+                let conn = self.least_loaded();
+                self.routed.insert(hash, (conn, Instant::now()));
+                self.connections[conn].in_flight += 1;
+                conn
+            }
+        };
+        log::trace!("[POOL] history of {} -> connection {}", hash, conn);
+        self.clients[conn].request_history(hash);
+    }
+
+    fn poll_history_activity(&mut self) -> Option<(sha256::Hash, usize)> {
+        self.clients.iter_mut().find_map(|c| c.poll_history_activity())
+    }
+
+    /// Headers are cached by whichever connection fetched the history that needed them.
+    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
+        self.clients.iter().find_map(|c| c.get_cached_header(height))
+    }
+
+    fn cached_header_heights(&self) -> Vec<u32> {
+        let mut heights: Vec<u32> = self.clients.iter().flat_map(|c| c.cached_header_heights()).collect();
+        heights.sort_unstable();
+        heights.dedup();
+        heights
+    }
+
+    fn get_block_header(&mut self, height: u32) -> Result<block::Header> {
+        let mut last_err = None;
+        for conn in self.by_load() {
+            match self.clients[conn].get_block_header(height) {
+                Ok(header) => {
+                    self.connections[conn].completed += 1;
+                    return Ok(header);
+                }
+                Err(e) => {
+                    self.connections[conn].errors += 1;
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no healthy connections")))
+    }
+
+    fn request_transaction(&mut self, txid: Txid) -> bool {
+        let conn = self.least_loaded();
+        let requested = self.clients[conn].request_transaction(txid);
+        if requested {
+            self.routed_txs.insert(txid, conn);
+        }
+        requested
+    }
+
+    fn take_transaction(&mut self, txid: &Txid) -> Option<Option<Transaction>> {
+        let conn = *self.routed_txs.get(txid)?;
+        let answer = self.clients[conn].take_transaction(txid)?;
+        self.routed_txs.remove(txid);
+        self.connections[conn].completed += 1;
+        Some(answer)
+    }
+
+    fn cached_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.clients.iter().find_map(|c| c.cached_transaction(txid))
+    }
+
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        let mut last_err = None;
+        for conn in self.by_load() {
+            match self.clients[conn].get_transaction(txid) {
+                Ok(tx) => {
+                    self.connections[conn].completed += 1;
+                    return Ok(tx);
+                }
+                Err(e) => {
+                    self.connections[conn].errors += 1;
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no healthy connections")))
+    }
+
+    fn shutdown(&mut self) {
+        for client in &mut self.clients {
+            client.shutdown();
+        }
+    }
+
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid> {
+        let mut last_err = None;
+        for conn in self.by_load() {
+            match self.clients[conn].broadcast(tx) {
+                Ok(txid) => {
+                    self.connections[conn].completed += 1;
+                    return Ok(txid);
+                }
+                Err(e) => {
+                    self.connections[conn].errors += 1;
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no healthy connections")))
+    }
+
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> Result<Vec<ScriptBalance>> {
+        let mut last_err = None;
+        for conn in self.by_load() {
+            match self.clients[conn].get_balances(hashes) {
+                Ok(balances) => {
+                    self.connections[conn].completed += 1;
+                    return Ok(balances);
+                }
+                Err(e) => {
+                    self.connections[conn].errors += 1;
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no healthy connections")))
+    }
+
+    fn request_balances(&mut self, hashes: &[sha256::Hash]) -> bool {
+        let conn = self.least_loaded();
+        let requested = self.clients[conn].request_balances(hashes);
+        if requested {
+            self.routed_balances = Some(conn);
+        }
+        requested
+    }
+
+    fn take_balances(&mut self, hashes: &[sha256::Hash]) -> Option<Result<Vec<ScriptBalance>>> {
+        let conn = self.routed_balances?;
+        let answer = self.clients[conn].take_balances(hashes)?;
+        self.routed_balances = None;
+        match answer {
+            Ok(_) => self.connections[conn].completed += 1,
+            Err(_) => self.connections[conn].errors += 1,
+        }
+        Some(answer)
+    }
+
+    /// Fails only once every connection has.
+    fn terminal_error(&self) -> Option<String> {
+        let failed: Vec<String> = self.clients.iter().filter_map(|c| c.terminal_error()).collect();
+        (failed.len() == self.clients.len()).then(|| format!("every pooled connection failed: {}", failed.join("; ")))
+    }
+
+    fn pending_work(&self) -> PendingWork {
+        self.clients.iter().map(|c| c.pending_work()).fold(PendingWork::default(), |acc, w| PendingWork {
+            histories: acc.histories + w.histories,
+            txs: acc.txs + w.txs,
+            headers: acc.headers + w.headers,
+        })
+    }
+
+    fn is_connecting(&self) -> bool {
+        self.clients[self.subscriber].is_connecting()
+    }
+
+    /// The subscriptions' connection decides: without it no notifications arrive.
+    fn is_connected(&self) -> bool {
+        self.clients[self.subscriber].is_connected()
+    }
+
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        self.clients[self.subscriber].chain_tip()
+    }
+
+    /// Changes whenever any connection reconnects (the subscriber included).
+    fn connection_epoch(&self) -> u64 {
+        self.clients.iter().map(|c| c.connection_epoch()).sum()
+    }
+
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        self.clients.iter_mut().flat_map(|client| client.take_errors()).collect()
+    }
+
+    fn server_version(&self) -> Option<String> {
+        self.clients[self.subscriber].server_version()
+    }
+
+    fn latency_recorder(&self) -> Option<LatencyRecorder> {
+        self.clients[self.subscriber].latency_recorder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::electrum::tests::fake_server::dummy_tx as tx;
+    use crate::streaming::electrum::MockElectrumClient;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn fetches_spread_across_healthy_connections_and_avoid_a_failed_one() {
+        let hashes: Vec<sha256::Hash> = (0u8..6).map(|i| sha256::Hash::hash(&[i])).collect();
+        let mut connections: Vec<MockElectrumClient> = (0..3).map(|_| MockElectrumClient::new()).collect();
+        for conn in &mut connections {
+            for (i, hash) in hashes.iter().enumerate() {
+                conn.histories.insert(*hash, vec![tx(i as u8)]);
+            }
+        }
+        connections[1].failure = Some("connection reset".into());
+
+        let mut pool = PooledElectrumClient::new(connections).unwrap();
+        for hash in &hashes {
+            pool.register_script(ScriptBuf::new(), *hash);
+        }
+        for hash in &hashes {
+            pool.request_history(*hash);
+        }
+
+        let mut fetched = Vec::new();
+        while let Some(hash) = pool.poll_scripthash_changed() {
+            fetched.extend(pool.fetch_history_txs(hash));
+        }
+        assert_eq!(fetched.len(), 6);
+
+        // Subscriptions stay on one connection; fetches skip the failed one.
+        assert_eq!(pool.clients[0].subscribed_len(), 6);
+        assert_eq!(pool.clients[2].subscribed_len(), 0);
+        assert_eq!(pool.clients[0].history_requests.len(), 3);
+        assert!(pool.clients[1].history_requests.is_empty());
+        assert_eq!(pool.clients[2].history_requests.len(), 3);
+
+        let stats = pool.stats();
+        assert_eq!(stats.iter().map(|s| s.completed).collect::<Vec<_>>(), vec![3, 0, 3]);
+        assert!(stats[0].subscriptions && !stats[1].healthy);
+        assert!(stats.iter().all(|s| s.in_flight == 0));
+        assert!(pool.terminal_error().is_none());
+    }
+
+    #[test]
+    fn empty_pool_is_refused() {
+        let err = PooledElectrumClient::<MockElectrumClient>::new(vec![]).err().unwrap();
+        assert!(err.to_string().contains("at least one connection"), "{}", err);
+    }
+}
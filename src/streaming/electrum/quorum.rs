@@ -0,0 +1,439 @@
+//! Cross-validating client over several independent Electrum servers.
+//!
+//! `QuorumElectrumClient` fans every request out to all wrapped clients and
+//! hands a scripthash history to the driver once `quorum` of them gave the
+//! same answer, or every client still working has answered. A transaction is
+//! kept if at least `quorum` clients report it; everything else is logged and
+//! recorded as a `Disagreement`, so a single lying (or lagging) server can't
+//! inject or hide transactions on its own, and a dead one can't stall the rest.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use bitcoin::hashes::sha256;
+use anyhow::Result;
+use bitcoin::{block, ScriptBuf, Transaction, Txid};
+
+use crate::streaming::electrum::api::{FetchError, PendingWork, ScriptBalance};
+use crate::streaming::electrum::ElectrumApi;
+use crate::streaming::engine::types::HistoryTx;
+
+/// Transactions for one scripthash that the servers did not agree on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disagreement {
+    pub hash: sha256::Hash,
+    /// Txids reported by fewer than `quorum` servers (dropped), or reported
+    /// at different heights (kept at the most common height).
+    pub disputed: Vec<Txid>,
+}
+
+pub struct QuorumElectrumClient<C> {
+    clients: Vec<C>,
+    quorum: usize,
+    /// Per scripthash, each client's history once it has answered.
+    responses: HashMap<sha256::Hash, Vec<Option<Vec<HistoryTx>>>>,
+    /// Scripthashes with an incomplete answer in the current round; their
+    /// agreed history is flagged incomplete too.
+    incomplete: HashSet<sha256::Hash>,
+    /// Per scripthash released before every client answered, the clients
+    /// whose answer is still due; it belongs to the released round and is
+    /// dropped when it comes.
+    late: HashMap<sha256::Hash, HashSet<usize>>,
+    /// Agreed histories waiting for `fetch_history_txs`, and whether incomplete.
+    agreed: HashMap<sha256::Hash, (Vec<HistoryTx>, bool)>,
+    /// Which of the histories taken were incomplete, until asked.
+    incomplete_taken: HashSet<sha256::Hash>,
+    ready: VecDeque<sha256::Hash>,
+    disagreements: Vec<Disagreement>,
+}
+
+impl<C: ElectrumApi> QuorumElectrumClient<C> {
+    /// Wraps `clients`; a transaction needs `quorum` of them to be accepted.
+    /// `quorum` must be between 1 and the number of clients.
+    pub fn new(clients: Vec<C>, quorum: usize) -> Result<Self> {
+        anyhow::ensure!(!clients.is_empty(), "a quorum client needs at least one server");
+        anyhow::ensure!(
+            (1..=clients.len()).contains(&quorum),
+            "quorum must be between 1 and the number of clients ({}), got {}",
+            clients.len(),
+            quorum
+        );
+        Ok(Self {
+            clients,
+            quorum,
+            responses: HashMap::new(),
+            incomplete: HashSet::new(),
+            late: HashMap::new(),
+            agreed: HashMap::new(),
+            incomplete_taken: HashSet::new(),
+            ready: VecDeque::new(),
+            disagreements: Vec::new(),
+        })
+    }
+
+    /// Every disagreement seen so far, oldest first.
+    pub fn disagreements(&self) -> &[Disagreement] {
+        &self.disagreements
+    }
+
+    /// Collects histories from every client ("fetch-or-request", as the driver
+    /// does) and releases each scripthash that `is_decided`.
+    fn collect_responses(&mut self) {
+        let n = self.clients.len();
+        for (i, client) in self.clients.iter_mut().enumerate() {
+            let changed: Vec<sha256::Hash> = std::iter::from_fn(|| client.poll_scripthash_changed()).collect();
+            for hash in changed {
+                match client.fetch_history_txs(hash) {
+                    Some(txs) => {
+                        let incomplete = client.history_incomplete(hash);
+                        if self.late.get_mut(&hash).is_some_and(|late| late.remove(&i)) {
+                            log::debug!("[QUORUM] dropping a late answer for {} (already decided)", hash);
+                            continue;
+                        }
+                        if incomplete {
+                            self.incomplete.insert(hash);
+                        }
+                        self.responses.entry(hash).or_insert_with(|| vec![None; n])[i] = Some(txs);
+                    }
+                    None => client.request_history(hash),
+                }
+            }
+        }
+        self.late.retain(|_, late| !late.is_empty());
+
+        let failed: Vec<bool> = self.clients.iter().map(|c| c.terminal_error().is_some()).collect();
+        let decided: Vec<sha256::Hash> = self
+            .responses
+            .iter()
+            .filter(|(_, answers)| self.is_decided(answers, &failed))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in decided {
+            let outstanding: HashSet<usize> = self.responses[&hash]
+                .iter()
+                .enumerate()
+                .filter(|(i, answer)| answer.is_none() && !failed[*i])
+                .map(|(i, _)| i)
+                .collect();
+            if !outstanding.is_empty() {
+                self.late.insert(hash, outstanding);
+            }
+            let answers = self.responses.remove(&hash).unwrap().into_iter().flatten().collect();
+            let agreed = self.resolve(hash, answers);
+            let incomplete = self.incomplete.remove(&hash);
+            self.agreed.insert(hash, (agreed, incomplete));
+            self.ready.push_back(hash);
+        }
+    }
+
+    /// Whether a scripthash's answers can be released: `quorum` of them list
+    /// the same txs at the same heights, or no client still working owes one.
+    fn is_decided(&self, answers: &[Option<Vec<HistoryTx>>], failed: &[bool]) -> bool {
+        let mut matching: HashMap<Vec<(Txid, i32)>, usize> = HashMap::new();
+        for txs in answers.iter().flatten() {
+            let mut key: Vec<(Txid, i32)> = txs.iter().map(|htx| (htx.tx.compute_txid(), htx.height)).collect();
+            key.sort();
+            *matching.entry(key).or_default() += 1;
+        }
+        matching.values().any(|count| *count >= self.quorum)
+            || answers.iter().zip(failed).all(|(answer, failed)| answer.is_some() || *failed)
+    }
+
+    /// Keeps the transactions reported by at least `quorum` clients.
+    fn resolve(&mut self, hash: sha256::Hash, answers: Vec<Vec<HistoryTx>>) -> Vec<HistoryTx> {
+        // txid -> (tx, votes per reported height)
+        let mut votes: BTreeMap<Txid, (HistoryTx, BTreeMap<i32, usize>)> = BTreeMap::new();
+        for htx in answers.into_iter().flatten() {
+            let entry = votes
+                .entry(htx.tx.compute_txid())
+                .or_insert_with(|| (htx.clone(), BTreeMap::new()));
+            *entry.1.entry(htx.height).or_default() += 1;
+        }
+
+        let mut agreed = Vec::new();
+        let mut disputed = Vec::new();
+        for (txid, (mut htx, heights)) in votes {
+            let total: usize = heights.values().sum();
+            if total < self.quorum {
+                disputed.push(txid);
+                continue;
+            }
+            if heights.len() > 1 {
+                disputed.push(txid);
+            }
+            htx.height = heights.iter().max_by_key(|(_, count)| **count).map(|(h, _)| *h).unwrap();
+            agreed.push(htx);
+        }
+
+        if !disputed.is_empty() {
+            log::warn!(
+                "[QUORUM] servers disagree on {} txs for {}: {:?}",
+                disputed.len(),
+                hash,
+                disputed
+            );
+            self.disagreements.push(Disagreement { hash, disputed });
+        }
+        agreed
+    }
+}
+
+impl<C: ElectrumApi> ElectrumApi for QuorumElectrumClient<C> {
+    fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash) {
+        for client in &mut self.clients {
+            client.register_script(script.clone(), hash);
+        }
+    }
+
+    fn unregister_script(&mut self, hash: sha256::Hash) {
+        for client in &mut self.clients {
+            client.unregister_script(hash);
+        }
+    }
+
+    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
+        self.collect_responses();
+        self.ready.pop_front()
+    }
+
+    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
+        let (txs, incomplete) = self.agreed.remove(&hash)?;
+        if incomplete {
+            self.incomplete_taken.insert(hash);
+        } else {
+            self.incomplete_taken.remove(&hash);
+        }
+        Some(txs)
+    }
+
+    fn history_incomplete(&mut self, hash: sha256::Hash) -> bool {
+        self.incomplete_taken.remove(&hash)
+    }
+
+    fn request_history(&mut self, hash: sha256::Hash) {
+        for client in &mut self.clients {
+            client.request_history(hash);
+        }
+    }
+
+    /// Activity is only a hint (it widens the watched window, nothing is
+    /// applied from it), so any one server's report is passed on.
+    fn poll_history_activity(&mut self) -> Option<(sha256::Hash, usize)> {
+        self.clients.iter_mut().find_map(|c| c.poll_history_activity())
+    }
+
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        self.clients.iter_mut().flat_map(|client| client.take_errors()).collect()
+    }
+
+    /// Returns a header only if `quorum` clients have the same one cached.
+    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
+        let mut counts: HashMap<block::Header, usize> = HashMap::new();
+        for header in self.clients.iter().filter_map(|c| c.get_cached_header(height)) {
+            *counts.entry(header).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .find(|(_, count)| *count >= self.quorum)
+            .map(|(header, _)| header)
+    }
+
+    /// The highest tip at least `quorum` clients agree on.
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        let mut counts: HashMap<(u32, block::Header), usize> = HashMap::new();
+        for tip in self.clients.iter().filter_map(|c| c.chain_tip()) {
+            *counts.entry(tip).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .filter(|(_, count)| *count >= self.quorum)
+            .map(|(tip, _)| tip)
+            .max_by_key(|(height, _)| *height)
+    }
+
+    fn cached_header_heights(&self) -> Vec<u32> {
+        let heights: BTreeSet<u32> =
+            self.clients.iter().flat_map(|c| c.cached_header_heights()).collect();
+        heights.into_iter().filter(|h| self.get_cached_header(*h).is_some()).collect()
+    }
+
+    /// Nothing ties a height to its header, so at least `quorum` servers must agree.
+    fn get_block_header(&mut self, height: u32) -> Result<block::Header> {
+        let mut counts: HashMap<block::Header, usize> = HashMap::new();
+        for client in &mut self.clients {
+            match client.get_block_header(height) {
+                Ok(header) => *counts.entry(header).or_default() += 1,
+                Err(e) => log::warn!("[QUORUM] a server failed to report header {}: {}", height, e),
+            }
+        }
+        counts
+            .into_iter()
+            .find(|(_, count)| *count >= self.quorum)
+            .map(|(header, _)| header)
+            .ok_or_else(|| anyhow::anyhow!("fewer than {} servers agree on the header at height {}", self.quorum, height))
+    }
+
+    /// A transaction commits to its own txid, so any one server's copy will do.
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        let mut last_err = None;
+        for client in &mut self.clients {
+            match client.get_transaction(txid) {
+                Ok(tx) if tx.compute_txid() == txid => return Ok(tx),
+                Ok(_) => last_err = Some(anyhow::anyhow!("server returned a different tx for {}", txid)),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("no clients")))
+    }
+
+    /// Closes every server's connection.
+    fn shutdown(&mut self) {
+        for client in &mut self.clients {
+            client.shutdown();
+        }
+    }
+
+    /// Submitted to every server, so the tx propagates even if some of them
+    /// are dishonest; succeeds if any one accepts it.
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid> {
+        let mut accepted = None;
+        let mut last_err = None;
+        for client in &mut self.clients {
+            match client.broadcast(tx) {
+                Ok(txid) => accepted = Some(txid),
+                Err(e) => {
+                    log::warn!("[QUORUM] a server rejected {}: {}", tx.compute_txid(), e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        accepted.ok_or_else(|| last_err.unwrap_or_else(|| anyhow::anyhow!("no clients")))
+    }
+
+    /// Each scripthash's balance is the one at least `quorum` servers report.
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> Result<Vec<ScriptBalance>> {
+        let mut reports: Vec<Vec<ScriptBalance>> = Vec::new();
+        for client in &mut self.clients {
+            match client.get_balances(hashes) {
+                Ok(balances) => reports.push(balances),
+                Err(e) => log::warn!("[QUORUM] a server failed to report balances: {}", e),
+            }
+        }
+        hashes
+            .iter()
+            .enumerate()
+            .map(|(i, hash)| {
+                let mut votes: BTreeMap<(u64, i64), usize> = BTreeMap::new();
+                for report in &reports {
+                    *votes.entry((report[i].confirmed, report[i].unconfirmed)).or_default() += 1;
+                }
+                votes
+                    .into_iter()
+                    .find(|(_, count)| *count >= self.quorum)
+                    .map(|((confirmed, unconfirmed), _)| ScriptBalance { confirmed, unconfirmed })
+                    .ok_or_else(|| anyhow::anyhow!("fewer than {} servers agree on the balance of {}", self.quorum, hash))
+            })
+            .collect()
+    }
+
+    /// A history is only ready once every client answered, so work adds up.
+    fn pending_work(&self) -> PendingWork {
+        self.clients.iter().map(|c| c.pending_work()).fold(PendingWork::default(), |acc, w| PendingWork {
+            histories: acc.histories + w.histories,
+            txs: acc.txs + w.txs,
+            headers: acc.headers + w.headers,
+        })
+    }
+
+    fn is_connecting(&self) -> bool {
+        self.clients.iter().any(|c| c.is_connecting())
+    }
+
+    /// Connected while at least `quorum` clients are.
+    fn is_connected(&self) -> bool {
+        self.clients.iter().filter(|c| c.is_connected()).count() >= self.quorum
+    }
+
+    /// Changes whenever any client reconnects.
+    fn connection_epoch(&self) -> u64 {
+        self.clients.iter().map(|c| c.connection_epoch()).sum()
+    }
+
+    /// Fails once fewer than `quorum` clients are still usable.
+    fn terminal_error(&self) -> Option<String> {
+        let failed: Vec<String> = self.clients.iter().filter_map(|c| c.terminal_error()).collect();
+        if self.clients.len() - failed.len() < self.quorum {
+            return Some(format!("quorum lost: {}", failed.join("; ")));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::electrum::tests::fake_server::dummy_tx as tx;
+    use crate::streaming::electrum::MockElectrumClient;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn extra_tx_from_one_server_is_flagged_and_dropped() {
+        let hash = sha256::Hash::hash(b"script");
+        let (honest, injected) = (tx(1), tx(2));
+
+        let mut a = MockElectrumClient::new();
+        a.histories.insert(hash, vec![honest.clone()]);
+        let mut b = MockElectrumClient::new();
+        b.histories.insert(hash, vec![honest.clone(), injected.clone()]);
+
+        let mut quorum = QuorumElectrumClient::new(vec![a, b], 2).unwrap();
+        quorum.register_script(ScriptBuf::new(), hash);
+        quorum.request_history(hash);
+
+        assert_eq!(quorum.poll_scripthash_changed(), Some(hash));
+        let agreed = quorum.fetch_history_txs(hash).unwrap();
+
+        assert_eq!(agreed.len(), 1);
+        assert_eq!(agreed[0].tx, honest);
+        assert_eq!(
+            quorum.disagreements(),
+            &[Disagreement { hash, disputed: vec![injected.compute_txid()] }]
+        );
+    }
+
+    #[test]
+    fn dead_server_does_not_hold_back_an_agreed_history() {
+        let hash = sha256::Hash::hash(b"script");
+        let payment = tx(1);
+
+        let mut a = MockElectrumClient::new();
+        a.histories.insert(hash, vec![payment.clone()]);
+        let mut b = MockElectrumClient::new();
+        b.histories.insert(hash, vec![payment.clone()]);
+        // Never answers, without having failed (yet).
+        let silent = MockElectrumClient::new();
+
+        let mut quorum = QuorumElectrumClient::new(vec![a, b, silent], 2).unwrap();
+        quorum.request_history(hash);
+        assert_eq!(quorum.poll_scripthash_changed(), Some(hash));
+        assert_eq!(quorum.fetch_history_txs(hash).unwrap()[0].tx, payment);
+
+        // Without a quorum of matching answers, waits for the others unless they failed.
+        let other = sha256::Hash::hash(b"other script");
+        quorum.clients[0].histories.insert(other, vec![tx(2)]);
+        quorum.clients[1].histories.insert(other, vec![tx(3)]);
+        quorum.request_history(other);
+        assert_eq!(quorum.poll_scripthash_changed(), None);
+        quorum.clients[2].failure = Some("connection refused".to_string());
+        assert_eq!(quorum.poll_scripthash_changed(), Some(other));
+        assert!(quorum.fetch_history_txs(other).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_quorum_is_an_error() {
+        let clients = || vec![MockElectrumClient::new(), MockElectrumClient::new()];
+        assert!(QuorumElectrumClient::new(clients(), 0).is_err());
+        assert!(QuorumElectrumClient::new(clients(), 3).is_err());
+        assert!(QuorumElectrumClient::<MockElectrumClient>::new(vec![], 1).is_err());
+        assert!(QuorumElectrumClient::new(clients(), 2).is_ok());
+    }
+}
@@ -0,0 +1,516 @@
+//! One Electrum connection shared by several wallets.
+//!
+//! `SharedElectrumClient` wraps a single client (typically one
+//! `ElectrumAdapter`, i.e. one TLS connection and one tokio runtime) and hands
+//! out a `TenantClient` per wallet. Each tenant is an `ElectrumApi` for its own
+//! `SyncOrchestrator`: the shared state records which tenants own each
+//! scripthash, and every ready scripthash the connection reports is routed to
+//! the ready queues of its owners only.
+//!
+//! A tenant's blocking lookups (headers, transactions, balances, broadcasts)
+//! only hold the shared state while sending the request and while checking
+//! for its answer, so one wallet waiting on the server doesn't stall the others.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bitcoin::hashes::sha256;
+use bitcoin::{block, ScriptBuf, Transaction, Txid};
+
+use crate::streaming::electrum::api::{FetchError, PendingWork, ScriptBalance};
+use crate::streaming::electrum::ElectrumApi;
+use crate::streaming::engine::types::HistoryTx;
+use crate::streaming::metrics::LatencyRecorder;
+
+/// Identifies one `TenantClient` of a `SharedElectrumClient`.
+pub type TenantId = usize;
+
+/// How long a tenant's blocking lookup waits for its answer.
+const BLOCKING_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a blocking lookup checks for its answer, with the shared state
+/// released in between.
+const ANSWER_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+struct Shared<C> {
+    client: C,
+    next_tenant: TenantId,
+    /// Tenants that registered each scripthash.
+    owners: HashMap<sha256::Hash, BTreeSet<TenantId>>,
+    /// Scripthashes ready for each tenant, in arrival order.
+    ready: HashMap<TenantId, VecDeque<sha256::Hash>>,
+    /// History activity for each tenant, in arrival order.
+    activity: HashMap<TenantId, VecDeque<(sha256::Hash, usize)>>,
+    /// Histories taken from the client, one copy per owner still to fetch it
+    /// (the client's `fetch_history_txs` is destructive), with whether the
+    /// client reported it incomplete.
+    histories: HashMap<(TenantId, sha256::Hash), (Vec<HistoryTx>, bool)>,
+    /// Which of the histories a tenant fetched were incomplete, until asked.
+    incomplete_taken: HashSet<(TenantId, sha256::Hash)>,
+    /// Fetch errors taken from the client, for each owner of the failed
+    /// history, until taken.
+    errors: HashMap<TenantId, Vec<FetchError>>,
+    /// Tenants waiting on each `request_transaction`.
+    tx_requesters: HashMap<Txid, BTreeSet<TenantId>>,
+    /// Answered transaction requests not yet taken by their tenant.
+    fetched_txs: HashMap<(TenantId, Txid), Option<Transaction>>,
+}
+
+impl<C: ElectrumApi> Shared<C> {
+    /// Moves everything the client reports as ready into the owners' queues.
+    fn route_ready(&mut self) {
+        while let Some((hash, tx_count)) = self.client.poll_history_activity() {
+            for tenant in self.owners.get(&hash).into_iter().flatten() {
+                self.activity.entry(*tenant).or_default().push_back((hash, tx_count));
+            }
+        }
+        while let Some(hash) = self.client.poll_scripthash_changed() {
+            let owners = self.owners.get(&hash).cloned().unwrap_or_default();
+            let history = self.client.fetch_history_txs(hash);
+            let incomplete = history.is_some() && self.client.history_incomplete(hash);
+            if owners.is_empty() {
+                log::debug!("[SHARED] dropping event for unowned scripthash {}", hash);
+                continue;
+            }
+            for tenant in owners {
+                if let Some(txs) = &history {
+                    self.histories.insert((tenant, hash), (txs.clone(), incomplete));
+                }
+                let queue = self.ready.entry(tenant).or_default();
+                if !queue.contains(&hash) {
+                    queue.push_back(hash);
+                }
+            }
+        }
+    }
+
+    /// Requests `txid` for `tenant` (see `ElectrumApi::request_transaction`).
+    fn request_transaction(&mut self, tenant: TenantId, txid: Txid) -> bool {
+        let requested = self.client.request_transaction(txid);
+        if requested {
+            self.tx_requesters.entry(txid).or_default().insert(tenant);
+        }
+        requested
+    }
+
+    /// Takes `tenant`'s answer for `txid`, keeping a copy for every other
+    /// tenant waiting on the same tx.
+    fn take_transaction(&mut self, tenant: TenantId, txid: &Txid) -> Option<Option<Transaction>> {
+        if let Some(answer) = self.fetched_txs.remove(&(tenant, *txid)) {
+            return Some(answer);
+        }
+        let answer = self.client.take_transaction(txid)?;
+        for other in self.tx_requesters.remove(txid).unwrap_or_default() {
+            if other != tenant {
+                self.fetched_txs.insert((other, *txid), answer.clone());
+            }
+        }
+        Some(answer)
+    }
+
+    /// Moves the client's fetch errors to the owners of the failed histories.
+    fn route_errors(&mut self) {
+        for error in self.client.take_errors() {
+            let hash = match &error {
+                FetchError::Transaction { hash, .. } | FetchError::Header { hash, .. } => *hash,
+            };
+            for tenant in self.owners.get(&hash).into_iter().flatten() {
+                self.errors.entry(*tenant).or_default().push(error.clone());
+            }
+        }
+    }
+}
+
+/// A single client multiplexed across wallets (see the module docs).
+pub struct SharedElectrumClient<C> {
+    shared: Arc<Mutex<Shared<C>>>,
+}
+
+impl<C: ElectrumApi> SharedElectrumClient<C> {
+    pub fn new(client: C) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                client,
+                next_tenant: 0,
+                owners: HashMap::new(),
+                ready: HashMap::new(),
+                activity: HashMap::new(),
+                histories: HashMap::new(),
+                incomplete_taken: HashSet::new(),
+                errors: HashMap::new(),
+                tx_requesters: HashMap::new(),
+                fetched_txs: HashMap::new(),
+            })),
+        }
+    }
+
+    /// A new client for one more wallet on this connection.
+    pub fn tenant(&self) -> TenantClient<C> {
+        let mut shared = self.shared.lock().unwrap();
+        let id = shared.next_tenant;
+        shared.next_tenant += 1;
+        shared.ready.insert(id, VecDeque::new());
+        TenantClient { id, shared: self.shared.clone() }
+    }
+}
+
+/// One wallet's view of a `SharedElectrumClient`.
+///
+/// Sees events only for the scripthashes it registered. A scripthash
+/// registered by several tenants is subscribed once and reported to all of
+/// them. Connection-wide state (pending work, terminal errors, latency) is
+/// the shared connection's.
+pub struct TenantClient<C> {
+    id: TenantId,
+    shared: Arc<Mutex<Shared<C>>>,
+}
+
+impl<C> TenantClient<C> {
+    pub fn id(&self) -> TenantId {
+        self.id
+    }
+}
+
+impl<C: ElectrumApi> TenantClient<C> {
+    /// Checks with `take` until it finds the answer to a request already
+    /// sent, sleeping without the shared state held in between. Fails if the
+    /// connection fails or `BLOCKING_WAIT` passes first; `what` describes the
+    /// request in those errors.
+    fn wait_for_answer<T>(
+        &self,
+        what: std::fmt::Arguments<'_>,
+        mut take: impl FnMut(&mut Shared<C>) -> Option<T>,
+    ) -> Result<T> {
+        let deadline = Instant::now() + BLOCKING_WAIT;
+        loop {
+            {
+                let mut shared = self.shared.lock().unwrap();
+                if let Some(answer) = take(&mut shared) {
+                    return Ok(answer);
+                }
+                if let Some(reason) = shared.client.terminal_error() {
+                    anyhow::bail!("connection failed while {}: {}", what, reason);
+                }
+            }
+            if Instant::now() >= deadline {
+                anyhow::bail!("timed out {}", what);
+            }
+            std::thread::sleep(ANSWER_POLL_INTERVAL);
+        }
+    }
+}
+
+impl<C: ElectrumApi> ElectrumApi for TenantClient<C> {
+    fn register_script(&mut self, script: ScriptBuf, hash: sha256::Hash) {
+        let mut shared = self.shared.lock().unwrap();
+        let owners = shared.owners.entry(hash).or_default();
+        let first = owners.is_empty();
+        owners.insert(self.id);
+        if first {
+            shared.client.register_script(script, hash);
+        }
+    }
+
+    fn unregister_script(&mut self, hash: sha256::Hash) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.histories.remove(&(self.id, hash));
+        if let Some(queue) = shared.ready.get_mut(&self.id) {
+            queue.retain(|h| *h != hash);
+        }
+        if let Some(queue) = shared.activity.get_mut(&self.id) {
+            queue.retain(|(h, _)| *h != hash);
+        }
+        let Some(owners) = shared.owners.get_mut(&hash) else {
+            return;
+        };
+        owners.remove(&self.id);
+        if owners.is_empty() {
+            shared.owners.remove(&hash);
+            shared.client.unregister_script(hash);
+        }
+    }
+
+    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.route_ready();
+        shared.ready.get_mut(&self.id)?.pop_front()
+    }
+
+    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.route_ready();
+        let (txs, incomplete) = shared.histories.remove(&(self.id, hash))?;
+        if incomplete {
+            shared.incomplete_taken.insert((self.id, hash));
+        } else {
+            shared.incomplete_taken.remove(&(self.id, hash));
+        }
+        Some(txs)
+    }
+
+    fn history_incomplete(&mut self, hash: sha256::Hash) -> bool {
+        self.shared.lock().unwrap().incomplete_taken.remove(&(self.id, hash))
+    }
+
+    fn poll_history_activity(&mut self) -> Option<(sha256::Hash, usize)> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.route_ready();
+        shared.activity.get_mut(&self.id)?.pop_front()
+    }
+
+    fn request_history(&mut self, hash: sha256::Hash) {
+        self.shared.lock().unwrap().client.request_history(hash);
+    }
+
+    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
+        self.shared.lock().unwrap().client.get_cached_header(height)
+    }
+
+    fn cached_header_heights(&self) -> Vec<u32> {
+        self.shared.lock().unwrap().client.cached_header_heights()
+    }
+
+    /// Waits without holding up other tenants if the client can fetch
+    /// headers without blocking; otherwise blocks them for the lookup.
+    fn get_block_header(&mut self, height: u32) -> Result<block::Header> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(header) = shared.client.get_cached_header(height) {
+                return Ok(header);
+            }
+            if !shared.client.request_block_header(height) {
+                return shared.client.get_block_header(height);
+            }
+        }
+        self.wait_for_answer(format_args!("fetching header {}", height), |shared| {
+            shared.client.take_block_header(height)
+        })?
+    }
+
+    fn request_block_header(&mut self, height: u32) -> bool {
+        self.shared.lock().unwrap().client.request_block_header(height)
+    }
+
+    fn take_block_header(&mut self, height: u32) -> Option<Result<block::Header>> {
+        self.shared.lock().unwrap().client.take_block_header(height)
+    }
+
+    fn request_transaction(&mut self, txid: Txid) -> bool {
+        self.shared.lock().unwrap().request_transaction(self.id, txid)
+    }
+
+    fn take_transaction(&mut self, txid: &Txid) -> Option<Option<Transaction>> {
+        self.shared.lock().unwrap().take_transaction(self.id, txid)
+    }
+
+    fn cached_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.shared.lock().unwrap().client.cached_transaction(txid)
+    }
+
+    /// Like `get_block_header`: other tenants are only held up for clients
+    /// that can't fetch transactions without blocking.
+    fn get_transaction(&mut self, txid: Txid) -> Result<Transaction> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(tx) = shared.client.cached_transaction(&txid) {
+                return Ok(tx);
+            }
+            if !shared.request_transaction(self.id, txid) {
+                return shared.client.get_transaction(txid);
+            }
+        }
+        let id = self.id;
+        let answer = self.wait_for_answer(format_args!("fetching transaction {}", txid), |shared| {
+            shared.take_transaction(id, &txid)
+        })?;
+        answer.ok_or_else(|| anyhow::anyhow!("server has no transaction {}", txid))
+    }
+
+    /// Like `get_block_header`: other tenants are only held up for clients
+    /// that can't query balances without blocking.
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> Result<Vec<ScriptBalance>> {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            if !shared.client.request_balances(hashes) {
+                return shared.client.get_balances(hashes);
+            }
+        }
+        self.wait_for_answer(format_args!("fetching balances of {} scripthashes", hashes.len()), |shared| {
+            shared.client.take_balances(hashes)
+        })?
+    }
+
+    fn request_balances(&mut self, hashes: &[sha256::Hash]) -> bool {
+        self.shared.lock().unwrap().client.request_balances(hashes)
+    }
+
+    fn take_balances(&mut self, hashes: &[sha256::Hash]) -> Option<Result<Vec<ScriptBalance>>> {
+        self.shared.lock().unwrap().client.take_balances(hashes)
+    }
+
+    /// Like `get_block_header`: other tenants are only held up for clients
+    /// that can't broadcast without blocking.
+    fn broadcast(&mut self, tx: &Transaction) -> Result<Txid> {
+        let txid = tx.compute_txid();
+        let request = {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.client.request_broadcast(tx) {
+                Some(request) => request,
+                None => return shared.client.broadcast(tx),
+            }
+        };
+        self.wait_for_answer(format_args!("broadcasting transaction {}", txid), |shared| {
+            shared.client.take_broadcast(request)
+        })?
+        .map_err(|e| anyhow::anyhow!("transaction {}: {:#}", txid, e))
+    }
+
+    fn request_broadcast(&mut self, tx: &Transaction) -> Option<u64> {
+        self.shared.lock().unwrap().client.request_broadcast(tx)
+    }
+
+    fn take_broadcast(&mut self, request: u64) -> Option<Result<Txid>> {
+        self.shared.lock().unwrap().client.take_broadcast(request)
+    }
+
+    fn terminal_error(&self) -> Option<String> {
+        self.shared.lock().unwrap().client.terminal_error()
+    }
+
+    fn pending_work(&self) -> PendingWork {
+        self.shared.lock().unwrap().client.pending_work()
+    }
+
+    fn is_connecting(&self) -> bool {
+        self.shared.lock().unwrap().client.is_connecting()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.shared.lock().unwrap().client.is_connected()
+    }
+
+    fn connection_epoch(&self) -> u64 {
+        self.shared.lock().unwrap().client.connection_epoch()
+    }
+
+    /// Takes the errors of this tenant's histories only; the rest stay for
+    /// their owners.
+    fn take_errors(&mut self) -> Vec<FetchError> {
+        let mut shared = self.shared.lock().unwrap();
+        shared.route_errors();
+        shared.errors.remove(&self.id).unwrap_or_default()
+    }
+
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        self.shared.lock().unwrap().client.chain_tip()
+    }
+
+    fn server_version(&self) -> Option<String> {
+        self.shared.lock().unwrap().client.server_version()
+    }
+
+    fn latency_recorder(&self) -> Option<LatencyRecorder> {
+        self.shared.lock().unwrap().client.latency_recorder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streaming::electrum::tests::fake_server::dummy_tx as tx;
+    use crate::streaming::electrum::MockElectrumClient;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn each_tenant_sees_only_its_own_scripthashes() {
+        let (a_hash, b_hash, both_hash) = (
+            sha256::Hash::hash(b"wallet a"),
+            sha256::Hash::hash(b"wallet b"),
+            sha256::Hash::hash(b"shared script"),
+        );
+        let shared = SharedElectrumClient::new(MockElectrumClient::new());
+        let mut a = shared.tenant();
+        let mut b = shared.tenant();
+        a.register_script(ScriptBuf::new(), a_hash);
+        a.register_script(ScriptBuf::new(), both_hash);
+        b.register_script(ScriptBuf::new(), b_hash);
+        b.register_script(ScriptBuf::new(), both_hash);
+        assert_eq!(shared.shared.lock().unwrap().client.subscribed_len(), 3);
+
+        {
+            let mut s = shared.shared.lock().unwrap();
+            s.client.push_history(a_hash, vec![tx(1)]);
+            s.client.push_history(b_hash, vec![tx(2)]);
+            s.client.push_history(both_hash, vec![tx(3)]);
+            // Nobody registered this one.
+            s.client.push_history(sha256::Hash::hash(b"stranger"), vec![tx(4)]);
+        }
+
+        // B polls first, which routes A's events too.
+        assert_eq!(b.poll_scripthash_changed(), Some(b_hash));
+        assert_eq!(b.poll_scripthash_changed(), Some(both_hash));
+        assert_eq!(b.poll_scripthash_changed(), None);
+        assert_eq!(b.fetch_history_txs(b_hash).unwrap()[0].tx, tx(2));
+        assert!(b.fetch_history_txs(a_hash).is_none());
+
+        assert_eq!(a.poll_scripthash_changed(), Some(a_hash));
+        assert_eq!(a.poll_scripthash_changed(), Some(both_hash));
+        assert_eq!(a.poll_scripthash_changed(), None);
+        assert_eq!(a.fetch_history_txs(a_hash).unwrap()[0].tx, tx(1));
+
+        // A history both own reaches both, though the client hands it out once.
+        assert_eq!(a.fetch_history_txs(both_hash).unwrap()[0].tx, tx(3));
+        assert_eq!(b.fetch_history_txs(both_hash).unwrap()[0].tx, tx(3));
+    }
+
+    #[test]
+    fn fetch_errors_go_to_the_owners_of_the_failed_history() {
+        let (a_hash, b_hash, both_hash) = (
+            sha256::Hash::hash(b"wallet a"),
+            sha256::Hash::hash(b"wallet b"),
+            sha256::Hash::hash(b"shared script"),
+        );
+        let shared = SharedElectrumClient::new(MockElectrumClient::new());
+        let mut a = shared.tenant();
+        let mut b = shared.tenant();
+        a.register_script(ScriptBuf::new(), a_hash);
+        a.register_script(ScriptBuf::new(), both_hash);
+        b.register_script(ScriptBuf::new(), b_hash);
+        b.register_script(ScriptBuf::new(), both_hash);
+
+        let failed = |hash| FetchError::Header { hash, height: 7, error: "bad header".to_string() };
+        shared.shared.lock().unwrap().client.errors = vec![failed(a_hash), failed(b_hash), failed(both_hash)];
+
+        // A takes first, but B's errors stay for B.
+        assert_eq!(a.take_errors(), vec![failed(a_hash), failed(both_hash)]);
+        assert!(a.take_errors().is_empty());
+        assert_eq!(b.take_errors(), vec![failed(b_hash), failed(both_hash)]);
+    }
+
+    #[test]
+    fn a_slow_lookup_does_not_hold_up_other_tenants() {
+        let b_hash = sha256::Hash::hash(b"wallet b");
+        let shared = SharedElectrumClient::new(MockElectrumClient::new());
+        let mut a = shared.tenant();
+        let mut b = shared.tenant();
+        b.register_script(ScriptBuf::new(), b_hash);
+
+        // A waits on a header the server hasn't sent yet.
+        let lookup = std::thread::spawn(move || a.get_block_header(7));
+        while shared.shared.lock().unwrap().client.header_requests.is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Meanwhile B's events still get through.
+        shared.shared.lock().unwrap().client.push_history(b_hash, vec![tx(1)]);
+        assert_eq!(b.wait_for_change(Duration::from_secs(1)), Some(b_hash));
+        assert_eq!(b.fetch_history_txs(b_hash).unwrap()[0].tx, tx(1));
+        assert!(!lookup.is_finished());
+
+        let header = bitcoin::constants::genesis_block(bitcoin::Network::Regtest).header;
+        shared.shared.lock().unwrap().client.headers.insert(7, header);
+        assert_eq!(lookup.join().unwrap().unwrap(), header);
+    }
+}
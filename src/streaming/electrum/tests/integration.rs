@@ -1,17 +1,28 @@
 #![cfg(test)]
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::env;
+use std::time::Duration;
 
 use bdk_wallet::miniscript::{Descriptor, DescriptorPublicKey};
 use bdk_wallet::{PersistedWallet, ChangeSet, Wallet};
 use bdk_wallet::bitcoin::Network;
 use bdk_wallet::file_store::Store;
+use bdk_wallet::chain::ChainPosition;
+use bdk_wallet::KeychainKind;
+use bitcoin::constants::genesis_block;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{block, Amount, ScriptBuf, Transaction, TxMerkleNode, TxOut};
 
 use crate::streaming::engine::{SyncEngine, EngineEvent};
 use crate::streaming::runtime::SyncOrchestrator;
 use crate::streaming::electrum::mock::client::MockElectrumClient;
 use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+use crate::streaming::electrum::asynchronous::adapter::{Connector, ElectrumAdapter};
+use crate::streaming::electrum::tests::fake_server::{
+    duplex_connector, dummy_tx, serve_chain, status_notification, wait_until, FakeChain,
+};
 
 type TestWallet = PersistedWallet<Store<ChangeSet>>;
 
@@ -103,4 +114,133 @@ fn end_to_end_derives_and_subscribes_more() {
         "engine should subscribe to the new descriptor scripts (prev: {}, now: {})",
         initial_subs, after_subs
     );
-}
\ No newline at end of file
+}
+// =========================================================================
+// Full adapter session over the in-memory transport
+// =========================================================================
+
+/// The header of a block at `height` holding only `tx`, whose merkle root
+/// is then its txid.
+fn header_at(height: u32, tx: &Transaction) -> block::Header {
+    let mut header = genesis_block(Network::Testnet).header;
+    header.time = 1_700_000_000 + height;
+    header.nonce = height;
+    header.merkle_root = TxMerkleNode::from_raw_hash(tx.compute_txid().to_raw_hash());
+    header
+}
+
+fn payment(tag: u8, to: ScriptBuf, sats: u64) -> Transaction {
+    let mut tx = dummy_tx(tag);
+    tx.output = vec![TxOut { value: Amount::from_sat(sats), script_pubkey: to }];
+    tx
+}
+
+fn session_engine() -> SyncEngine<KeychainKind> {
+    let mut tracker = DerivedSpkTracker::new(2);
+    tracker.insert_descriptor(KeychainKind::External, test_descriptor(), 0);
+    tracker.insert_descriptor(KeychainKind::Internal, test_change_descriptor(), 0);
+    SyncEngine::new(tracker)
+}
+
+/// Spawns a driver for a fresh adapter; returns once its initial sync is done.
+fn start_session(
+    connector: Connector,
+    wallet: Arc<Mutex<TestWallet>>,
+) -> std::thread::JoinHandle<anyhow::Result<()>> {
+    let adapter = ElectrumAdapter::with_connector(connector).unwrap();
+    let synced = Arc::new(AtomicBool::new(false));
+    let driver = SyncOrchestrator::new(session_engine(), adapter, wallet).with_initial_sync_notifier({
+        let synced = synced.clone();
+        move || synced.store(true, Ordering::SeqCst)
+    });
+    let handle = std::thread::spawn(move || driver.run_forever());
+    assert!(wait_until(Duration::from_secs(5), || synced.load(Ordering::SeqCst)), "initial sync");
+    handle
+}
+
+fn assert_anchored(wallet: &TestWallet, tx: &Transaction, height: u32) {
+    let wtx = wallet.get_tx(tx.compute_txid()).expect("tx in wallet");
+    match wtx.chain_position {
+        ChainPosition::Confirmed { anchor, .. } => {
+            assert_eq!(anchor.block_id.height, height);
+            assert_eq!(anchor.block_id.hash, header_at(height, tx).block_hash());
+            assert_eq!(anchor.confirmation_time, header_at(height, tx).time as u64);
+        }
+        other => panic!("expected tx anchored at {}, got {:?}", height, other),
+    }
+}
+
+#[test]
+fn full_session_over_duplex_transport() {
+    let wallet = dummy_wallet();
+    let (receive0, receive2, change0) = {
+        let w = wallet.lock().unwrap();
+        (
+            w.peek_address(KeychainKind::External, 0).script_pubkey(),
+            w.peek_address(KeychainKind::External, 2).script_pubkey(),
+            w.peek_address(KeychainKind::Internal, 0).script_pubkey(),
+        )
+    };
+
+    // Confirmed payment at 100 plus an unconfirmed one.
+    let confirmed = payment(1, receive0, 100_000);
+    let pending = payment(2, change0, 50_000);
+    let later = payment(3, receive2.clone(), 20_000);
+    let chain = Arc::new(Mutex::new(FakeChain::default()));
+    {
+        let mut c = chain.lock().unwrap();
+        c.add_tx(confirmed.clone(), 100);
+        c.add_tx(pending.clone(), 0);
+        c.add_header(100, header_at(100, &confirmed));
+        c.add_header(101, header_at(101, &later));
+    }
+
+    let (connector, servers) = duplex_connector();
+
+    // --- Session 1: handshake, subscribe, bootstrap histories ---
+    let first = std::thread::spawn({
+        let wallet = wallet.clone();
+        move || start_session(connector, wallet)
+    });
+    let server = serve_chain(servers.recv().unwrap(), chain.clone());
+    let driver = first.join().unwrap();
+
+    {
+        let w = wallet.lock().unwrap();
+        assert_eq!(w.balance().confirmed.to_sat(), 100_000);
+        assert_eq!(w.balance().total().to_sat(), 150_000);
+        assert_anchored(&w, &confirmed, 100);
+    }
+    // 3 external + 3 internal scripts, plus the windows extended past the used ones.
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.subscribe"), 8);
+
+    // --- Status notification: a new confirmed payment to external/2 ---
+    chain.lock().unwrap().add_tx(later.clone(), 101);
+    server.push(status_notification(&sha256::Hash::hash(receive2.as_bytes())));
+
+    assert!(wait_until(Duration::from_secs(5), || {
+        wallet.lock().unwrap().balance().total().to_sat() == 170_000
+    }));
+    assert_anchored(&wallet.lock().unwrap(), &later, 101);
+    // Using external/2 extends the external window to 5.
+    assert!(wait_until(Duration::from_secs(5), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 10
+    }));
+
+    // --- Connection lost: the adapter reconnects and re-subscribes everything ---
+    chain.lock().unwrap().requests.clear();
+    server.close();
+    let _server = serve_chain(servers.recv_timeout(Duration::from_secs(5)).unwrap(), chain.clone());
+    assert!(wait_until(Duration::from_secs(5), || {
+        chain.lock().unwrap().count("blockchain.scripthash.subscribe") == 10
+    }));
+    assert!(!driver.is_finished(), "the driver keeps running across the reconnect");
+
+    let w = wallet.lock().unwrap();
+    assert_eq!(w.balance().confirmed.to_sat(), 120_000);
+    assert_eq!(w.balance().total().to_sat(), 170_000);
+    assert_eq!(w.transactions().count(), 3, "re-sync must not duplicate txs");
+    assert_anchored(&w, &confirmed, 100);
+    assert_anchored(&w, &later, 101);
+    assert_eq!(chain.lock().unwrap().count("blockchain.scripthash.subscribe"), 10);
+}
@@ -1 +1,2 @@
-pub mod integration;
\ No newline at end of file
+pub mod fake_server;
+pub mod integration;
@@ -1,8 +1,10 @@
 use bitcoin::hashes::sha256;
+use std::collections::{hash_map, HashMap, HashSet};
 use std::time::Instant;
-use bitcoin::Txid;
+use bitcoin::{OutPoint, Txid, ScriptBuf};
 use crate::streaming::engine::state::EngineState;
-use crate::streaming::engine::types::{EngineCommand, HistoryTx};
+use crate::streaming::engine::types::{EngineCommand, HistoryTx, TxRelevance};
+use crate::streaming::util::script_hash;
 
 pub fn on_connected<K: Ord + Clone>(state: &mut EngineState<K>) -> Vec<EngineCommand> {
     log::info!("[ENGINE] on_connected: enumerating scripts");
@@ -15,32 +17,191 @@ pub fn on_connected<K: Ord + Clone>(state: &mut EngineState<K>) -> Vec<EngineCom
 
     for (hash, script) in state.spk_tracker.all_spks() {
         log::trace!("[ENGINE] discovered script {}", hash);
-        if let Some((kc, idx)) = state.spk_tracker.index_of_spk_hash(hash) {
-            state.spk_index_by_hash.insert(*hash, (kc, idx));
+        let owners = state.spk_tracker.index_of_spk_hash(hash);
+        if !owners.is_empty() {
+            state.spk_index_by_hash.insert(*hash, owners);
             state.script_by_hash.insert(*hash, script.clone());
         }
 
         if state.subscribed.insert(*hash) {
-            // 1) WARM BOOTSTRAP: fetch full history first
-            cmds.push(EngineCommand::FetchHistory(*hash));
+            state.buried.remove(hash);
+            // 1) WARM BOOTSTRAP: fetch full history first (unless lazy)
+            if !is_lazy(state, hash) {
+                cmds.push(EngineCommand::FetchHistory(*hash));
+            }
 
             // 2) Then subscribe for future updates
             cmds.push(EngineCommand::Subscribe(*hash));
         }
     }
 
+    let early = std::mem::take(&mut state.early_histories);
+    if !early.is_empty() {
+        log::debug!("[ENGINE] replaying {} histories received before connect", early.len());
+    }
+    for (hash, txs, incomplete) in early {
+        cmds.extend(on_scripthash_history(state, hash, txs, incomplete));
+    }
+
     cmds
 }
 
+pub fn on_reconnected<K>(state: &EngineState<K>) -> Vec<EngineCommand> {
+    log::info!("[ENGINE] on_reconnected: re-subscribing {} scripts", state.subscribed.len());
+    state.subscribed.iter().map(|hash| EngineCommand::Subscribe(*hash)).collect()
+}
+
 pub fn on_scripthash_changed<K>(_: &mut EngineState<K>, hash: sha256::Hash) -> Vec<EngineCommand> {
     vec![EngineCommand::FetchHistory(hash)]
 }
 
+/// History metadata ahead of the txs: marks the script used (extending the
+/// gap) right away when activity discovery is on.
+pub fn on_scripthash_activity<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    hash: sha256::Hash,
+    tx_count: usize,
+) -> Vec<EngineCommand> {
+    if !state.activity_discovery || !state.connected || tx_count == 0 {
+        return vec![];
+    }
+    let Some(owners) = state.spk_index_by_hash.get(&hash).cloned() else {
+        return vec![];
+    };
+    let known_used = state.histories.get(&hash).is_some_and(|h| !h.is_empty());
+    if known_used || !state.active.insert(hash) {
+        return vec![];
+    }
+    log::debug!("[ENGINE] activity on {} ({} txs pending download)", hash, tx_count);
+
+    let mut cmds = Vec::new();
+    for (keychain, index) in owners {
+        let newly = state.spk_tracker.mark_used_and_derive_new(&keychain, index);
+        watch_new_spks(state, newly, &mut cmds);
+    }
+    cmds
+}
+
+pub fn on_address_revealed<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    script: ScriptBuf,
+    index: u32,
+) -> Vec<EngineCommand> {
+    let Some(keychain) = state.spk_tracker.keychain_of_script_at(&script, index) else {
+        log::warn!("[ENGINE] revealed address at index {} matches no tracked keychain", index);
+        return vec![];
+    };
+
+    let newly = state.spk_tracker.mark_revealed_and_derive_new(&keychain, index);
+    let mut cmds = Vec::new();
+    watch_new_spks(state, newly, &mut cmds);
+    cmds
+}
+
+pub fn on_lookahead_reduced<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    lookahead: u32,
+) -> Vec<EngineCommand> {
+    let removed = state.spk_tracker.shrink_lookahead(lookahead);
+    log::info!("[ENGINE] lookahead reduced to {}: dropping {} scripts", lookahead, removed.len());
+    forget_scripts(state, removed)
+}
+
+pub fn on_keychain_removed<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    keychain: &K,
+) -> Vec<EngineCommand> {
+    let removed = state.spk_tracker.remove_keychain(keychain);
+    log::info!("[ENGINE] keychain removed: dropping {} scripts", removed.len());
+    forget_scripts(state, removed)
+}
+
+/// Drops everything known about scripts the tracker no longer tracks, and
+/// unsubscribes them. A later `Subscribe` for the same hash starts afresh.
+fn forget_scripts<K: Ord + Clone>(state: &mut EngineState<K>, removed: Vec<sha256::Hash>) -> Vec<EngineCommand> {
+    let mut cmds = Vec::new();
+    for hash in removed {
+        state.spk_index_by_hash.remove(&hash);
+        state.script_by_hash.remove(&hash);
+        state.histories.remove(&hash);
+        state.active.remove(&hash);
+        state.spent_at.remove(&hash);
+        state.buried.remove(&hash);
+        if state.subscribed.remove(&hash) {
+            cmds.push(EngineCommand::Unsubscribe(hash));
+        }
+    }
+    cmds
+}
+
+/// Unsubscribes the fully spent scripts whose latest tx is now buried deep
+/// enough. They keep their history, so their index stays used.
+pub fn on_tip_changed<K>(state: &mut EngineState<K>, height: u32) -> Vec<EngineCommand> {
+    let Some(depth) = state.unsubscribe_buried else {
+        return vec![];
+    };
+    let buried: Vec<sha256::Hash> = state
+        .spent_at
+        .iter()
+        .filter(|(_, &at)| height.saturating_sub(at) + 1 >= depth)
+        .map(|(hash, _)| *hash)
+        .collect();
+
+    let mut cmds = Vec::new();
+    for hash in buried {
+        state.spent_at.remove(&hash);
+        if state.subscribed.remove(&hash) {
+            log::debug!("[ENGINE] {} fully spent and {} deep; unsubscribing", hash, depth);
+            state.buried.insert(hash);
+            cmds.push(EngineCommand::Unsubscribe(hash));
+        }
+    }
+    cmds
+}
+
+/// The height of the latest tx in `txs` if every output they pay to `script`
+/// is spent by another of them, all confirmed (and verified).
+fn fully_spent_height(script: &ScriptBuf, txs: &[HistoryTx]) -> Option<u32> {
+    if txs.is_empty() || txs.iter().any(|htx| htx.height <= 0 || !htx.verified) {
+        return None;
+    }
+    let spent: HashSet<OutPoint> = txs
+        .iter()
+        .flat_map(|htx| htx.tx.input.iter().map(|txin| txin.previous_output))
+        .collect();
+    let all_spent = txs.iter().all(|htx| {
+        let txid = htx.tx.compute_txid();
+        htx.tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, out)| out.script_pubkey == *script)
+            .all(|(vout, _)| spent.contains(&OutPoint::new(txid, vout as u32)))
+    });
+    all_spent.then(|| txs.iter().map(|htx| htx.height as u32).max().unwrap_or(0))
+}
+
+/// Handles `hash`'s new history. An `incomplete` one lacks members the
+/// client failed to fetch, so nothing it doesn't list counts as dropped and
+/// the previous history's txids are kept alongside its own.
 pub fn on_scripthash_history<K: Ord + Clone>(
     state: &mut EngineState<K>,
     hash: sha256::Hash,
     txs: Vec<HistoryTx>,                  // CHANGED: was Vec<Transaction>
+    incomplete: bool,
 ) -> Vec<EngineCommand> {
+    // The script maps are only populated by `on_connected`; hold on to
+    // anything that arrives earlier (e.g. a notification queued during setup).
+    if !state.connected {
+        log::debug!("[ENGINE] history for {} before connect; buffering", hash);
+        state.early_histories.push((hash, txs, incomplete));
+        return vec![];
+    }
+    let Some(script) = state.script_by_hash.get(&hash).cloned() else {
+        log::warn!("[ENGINE] history for untracked scripthash {}; ignoring", hash);
+        return vec![];
+    };
+
     // BENCHMARK HOOK — FIRST REAL DATA
     if state.first_history_seen_at.is_none() && !txs.is_empty() {
         state.first_history_seen_at = Some(Instant::now());
@@ -52,10 +213,23 @@ pub fn on_scripthash_history<K: Ord + Clone>(
 
     let mut cmds = Vec::new();
 
-    let prev = state.histories.get(&hash);
-    let was_empty = prev.map(|v| v.is_empty()).unwrap_or(true);
+    let prev = state.histories.get(&hash).cloned().unwrap_or_default();
+    let was_empty = prev.is_empty();
     let is_empty = txs.is_empty();
 
+    // CHANGED: Extract txids from HistoryTx for the histories map
+    let mut txids: Vec<Txid> = txs.iter().map(|ht| ht.tx.compute_txid()).collect();
+    let dropped: Vec<Txid> = if incomplete {
+        log::warn!("[ENGINE] history of {} is incomplete; keeping the txs it lacks", hash);
+        let kept: Vec<Txid> = prev.iter().filter(|txid| !txids.contains(txid)).copied().collect();
+        txids.extend(kept);
+        vec![]
+    } else {
+        prev.into_iter().filter(|txid| !txids.contains(txid)).collect()
+    };
+
+    classify_relevance(state, hash, &txs, &dropped);
+
     let now = Instant::now();
     // First history response with any content
     if state.first_history_seen_at.is_none() && !txs.is_empty() {
@@ -74,36 +248,341 @@ pub fn on_scripthash_history<K: Ord + Clone>(
         );
     }    
 
-    // CHANGED: Extract txids from HistoryTx for the histories map
-    let txids: Vec<Txid> = txs.iter().map(|ht| ht.tx.compute_txid()).collect();
+    track_unconfirmed(state, &txs);
+    let replaced = detect_replacements(state, &txs);
+
+    if !was_empty && is_empty && !incomplete {
+        // The index stays used: a script once paid is never handed out again.
+        log::info!("[ENGINE] history of {} emptied; dropping {} txs", hash, dropped.len());
+    }
+    record_dropped_replacements(state, &dropped, &txs);
+    prune_replacements(state, &dropped, &txs);
     state.histories.insert(hash, txids.clone());
+    state.active.remove(&hash);
+    if state.unsubscribe_buried.is_some() {
+        match fully_spent_height(&script, &txs) {
+            _ if incomplete => {}
+            Some(height) if state.subscribed.contains(&hash) => {
+                state.spent_at.insert(hash, height);
+            }
+            _ => {
+                state.spent_at.remove(&hash);
+            }
+        }
+        resubscribe_buried(state, hash, &txs, &mut cmds);
+    }
 
     if was_empty && !is_empty {
-        if let Some((keychain, index)) = state.spk_index_by_hash.get(&hash).cloned() {
+        for (keychain, index) in state.spk_index_by_hash.get(&hash).cloned().unwrap_or_default() {
             let newly = state
                 .spk_tracker
                 .mark_used_and_derive_new(&keychain, index);
+            watch_new_spks(state, newly, &mut cmds);
+        }
+    }
 
-            for (new_hash, new_script) in newly {
-                state.spk_index_by_hash.insert(new_hash, (keychain.clone(), index + 1));
-                state.script_by_hash.insert(new_hash, new_script.clone());
-
-                if state.subscribed.insert(new_hash) {
-                    // 1) Warm fetch for newly derived script
-                    cmds.push(EngineCommand::FetchHistory(new_hash));
+    extend_eager_keychains(state, hash, &txs, &mut cmds);
 
-                    // 2) Then subscribe for future updates
-                    cmds.push(EngineCommand::Subscribe(new_hash));
+    // A tx touching several of our scripts is in each of their histories;
+    // only the first script's carries it, unless its confirmation changed.
+    // That script's own refetches still do (e.g. after a reorg replaced its
+    // block at the same height). RBF losers are always passed on, so their
+    // replacement is seen after them, and dropped txs are applied afresh
+    // should they come back.
+    for txid in &dropped {
+        state.applied.remove(txid);
+    }
+    let txs: Vec<HistoryTx> = txs
+        .into_iter()
+        .filter(|htx| {
+            let txid = htx.tx.compute_txid();
+            let status = (htx.height, htx.verified);
+            match state.applied.get(&txid) {
+                Some(&(by, applied)) if by != hash && applied == status && !replaced.contains(&txid) => false,
+                _ => {
+                    state.applied.insert(txid, (hash, status));
+                    true
                 }
             }
-        }
-    }
+        })
+        .collect();
 
-    let script = state.script_by_hash.get(&hash).cloned().unwrap();
+    let txs = if state.order_unconfirmed_chains { order_parent_first(txs) } else { txs };
     cmds.push(EngineCommand::ApplyTransactions {
         script,
         txs,                              // CHANGED: now Vec<HistoryTx>
+        replaced,
+        dropped,
     });
 
     cmds
-}
\ No newline at end of file
+}
+
+/// Classifies the txs of `hash`'s new history (see `TxRelevance`), merging
+/// with what other histories listing them showed. A tx in the history that
+/// has no output to `hash` must spend from it. The `gone` txs, which the
+/// previous history listed and this one doesn't, are forgotten along with
+/// their outputs.
+fn classify_relevance<K>(state: &mut EngineState<K>, hash: sha256::Hash, txs: &[HistoryTx], gone: &[Txid]) {
+    let txids: Vec<Txid> = txs.iter().map(|htx| htx.tx.compute_txid()).collect();
+    for txid in gone {
+        if let Some(relevance) = state.relevance.remove(txid) {
+            state.relevance_counts.remove(relevance);
+        }
+    }
+    if !gone.is_empty() {
+        state.owned_outputs.retain(|outpoint| !gone.contains(&outpoint.txid));
+    }
+
+    for (htx, txid) in txs.iter().zip(txids) {
+        let mut relevance = TxRelevance::default();
+        let mut pays_hash = false;
+        for (vout, out) in htx.tx.output.iter().enumerate() {
+            let out_hash = script_hash(&out.script_pubkey);
+            // A data output is no payment, even to a tracked script.
+            if out.script_pubkey.is_op_return() {
+                relevance.data_outputs += 1;
+                pays_hash |= out_hash == hash;
+                continue;
+            }
+            if state.script_by_hash.contains_key(&out_hash) {
+                relevance.receives = true;
+                pays_hash |= out_hash == hash;
+                state.owned_outputs.insert(OutPoint::new(txid, vout as u32));
+            }
+        }
+        relevance.spends = !pays_hash
+            || htx.tx.input.iter().any(|txin| state.owned_outputs.contains(&txin.previous_output));
+
+        let previous = state.relevance.get(&txid).copied();
+        let merged = state.relevance_counts.update(previous, relevance);
+        state.relevance.insert(txid, merged);
+    }
+}
+
+/// Reorders `txs` so every unconfirmed tx comes after the unconfirmed txs
+/// of the list it spends, e.g. a chain of self-spends of unconfirmed change.
+/// Otherwise the server's order is kept.
+fn order_parent_first(txs: Vec<HistoryTx>) -> Vec<HistoryTx> {
+    let unconfirmed: HashMap<Txid, usize> = txs
+        .iter()
+        .enumerate()
+        .filter(|(_, htx)| htx.height <= 0)
+        .map(|(i, htx)| (htx.tx.compute_txid(), i))
+        .collect();
+    if unconfirmed.len() < 2 {
+        return txs;
+    }
+
+    fn emit(i: usize, slots: &mut [Option<HistoryTx>], unconfirmed: &HashMap<Txid, usize>, out: &mut Vec<HistoryTx>) {
+        let Some(htx) = slots[i].take() else {
+            return;
+        };
+        if htx.height <= 0 {
+            for txin in &htx.tx.input {
+                if let Some(parent) = unconfirmed.get(&txin.previous_output.txid) {
+                    emit(*parent, slots, unconfirmed, out);
+                }
+            }
+        }
+        out.push(htx);
+    }
+
+    let mut slots: Vec<Option<HistoryTx>> = txs.into_iter().map(Some).collect();
+    let mut ordered = Vec::with_capacity(slots.len());
+    for i in 0..slots.len() {
+        emit(i, &mut slots, &unconfirmed, &mut ordered);
+    }
+    ordered
+}
+
+/// Records the unconfirmed txs of `txs` by what they spend and when they
+/// first showed up (in list order, for txs new to this history), forgetting
+/// the ones now confirmed.
+fn track_unconfirmed<K>(state: &mut EngineState<K>, txs: &[HistoryTx]) {
+    for htx in txs {
+        let txid = htx.tx.compute_txid();
+        if htx.height <= 0 {
+            let inputs = htx.tx.input.iter().map(|txin| txin.previous_output).collect();
+            state.unconfirmed_inputs.insert(txid, inputs);
+            if let hash_map::Entry::Vacant(entry) = state.first_seen.entry(txid) {
+                entry.insert(state.next_seen);
+                state.next_seen += 1;
+            }
+        } else {
+            state.unconfirmed_inputs.remove(&txid);
+            state.first_seen.remove(&txid);
+        }
+    }
+}
+
+/// Marks each dropped tx replaced by the unconfirmed tx of `txs` now
+/// spending one of its inputs, if any (an RBF whose original the server no
+/// longer lists).
+fn record_dropped_replacements<K>(state: &mut EngineState<K>, dropped: &[Txid], txs: &[HistoryTx]) {
+    for txid in dropped {
+        state.first_seen.remove(txid);
+        let Some(inputs) = state.unconfirmed_inputs.remove(txid) else {
+            continue;
+        };
+        let replacement = txs
+            .iter()
+            .filter(|htx| htx.height <= 0)
+            .find(|htx| htx.tx.input.iter().any(|txin| inputs.contains(&txin.previous_output)));
+        if let Some(htx) = replacement {
+            let winner = htx.tx.compute_txid();
+            if state.replacements.insert(*txid, winner) != Some(winner) {
+                log::info!("[ENGINE] tx {} replaced by {}", txid, winner);
+            }
+        }
+    }
+}
+
+/// Finds unconfirmed txs in `txs` spending the same outpoint and records the
+/// one seen last as having replaced the others, which is also the one the
+/// wallet ends up preferring (see `ApplyTransactions::replaced`). Returns
+/// every member of `txs` known to be replaced, including by a decision made
+/// for an earlier history. Confirmed txs are left to the wallet, which
+/// always prefers them.
+fn detect_replacements<K>(state: &mut EngineState<K>, txs: &[HistoryTx]) -> Vec<Txid> {
+    let mut spenders: HashMap<OutPoint, Vec<Txid>> = HashMap::new();
+    for htx in txs.iter().filter(|htx| htx.height <= 0) {
+        for txin in &htx.tx.input {
+            spenders.entry(txin.previous_output).or_default().push(htx.tx.compute_txid());
+        }
+    }
+
+    for group in spenders.into_values().filter(|g| g.len() > 1) {
+        let winner = *group
+            .iter()
+            .max_by_key(|txid| (state.first_seen.get(*txid).copied(), **txid))
+            .expect("conflict group is non-empty");
+        for txid in group {
+            if txid != winner && state.replacements.insert(txid, winner) != Some(winner) {
+                log::info!("[ENGINE] tx {} replaced by {}", txid, winner);
+            }
+        }
+    }
+
+    txs.iter()
+        .map(|htx| htx.tx.compute_txid())
+        .filter(|txid| state.replacements.contains_key(txid))
+        .collect()
+}
+
+/// Forgets the replacements settled by `hash`'s new history: either side
+/// confirmed, or the replacement was dropped (and not replaced in turn).
+fn prune_replacements<K>(state: &mut EngineState<K>, dropped: &[Txid], txs: &[HistoryTx]) {
+    let settled: HashSet<Txid> = txs
+        .iter()
+        .filter(|htx| htx.height > 0)
+        .map(|htx| htx.tx.compute_txid())
+        .chain(dropped.iter().copied().filter(|txid| !state.replacements.contains_key(txid)))
+        .collect();
+    if settled.is_empty() {
+        return;
+    }
+    state.replacements.retain(|replaced, by| {
+        let keep = !settled.contains(by) && !settled.contains(replaced);
+        if !keep {
+            log::debug!("[ENGINE] replacement of {} by {} settled", replaced, by);
+        }
+        keep
+    });
+}
+
+/// For keychains with an eager `GapPolicy`: any tx in `hash`'s history paying
+/// one of their scripts (e.g. the change of a self-send) marks that index used
+/// right away, and the script is refetched if its own history lacks the tx.
+fn extend_eager_keychains<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    hash: sha256::Hash,
+    txs: &[HistoryTx],
+    cmds: &mut Vec<EngineCommand>,
+) {
+    for htx in txs {
+        let txid = htx.tx.compute_txid();
+        for out in &htx.tx.output {
+            let out_hash = script_hash(&out.script_pubkey);
+            if out_hash == hash {
+                continue;
+            }
+            let eager: Vec<(K, u32)> = state
+                .spk_tracker
+                .index_of_spk_hash(&out_hash)
+                .into_iter()
+                .filter(|(keychain, _)| state.spk_tracker.is_eager(keychain))
+                .collect();
+            if eager.is_empty() {
+                continue;
+            }
+            for (keychain, index) in &eager {
+                let newly = state.spk_tracker.mark_used_and_derive_new(keychain, *index);
+                watch_new_spks(state, newly, cmds);
+            }
+
+            let known = state.histories.get(&out_hash).is_some_and(|h| h.contains(&txid));
+            if !known && !cmds.iter().any(|c| matches!(c, EngineCommand::FetchHistory(h) if *h == out_hash)) {
+                log::debug!("[ENGINE] eager refetch of {} for tx {}", out_hash, txid);
+                cmds.push(EngineCommand::FetchHistory(out_hash));
+            }
+        }
+    }
+}
+
+/// Subscribes again (and refetches) any buried script that a tx in `hash`'s
+/// history pays: the server no longer tells us about it.
+fn resubscribe_buried<K>(
+    state: &mut EngineState<K>,
+    hash: sha256::Hash,
+    txs: &[HistoryTx],
+    cmds: &mut Vec<EngineCommand>,
+) {
+    if state.buried.is_empty() {
+        return;
+    }
+    for htx in txs {
+        for out in &htx.tx.output {
+            let out_hash = script_hash(&out.script_pubkey);
+            if out_hash == hash || !state.buried.remove(&out_hash) {
+                continue;
+            }
+            log::debug!("[ENGINE] activity on buried {}; subscribing again", out_hash);
+            state.subscribed.insert(out_hash);
+            cmds.push(EngineCommand::FetchHistory(out_hash));
+            cmds.push(EngineCommand::Subscribe(out_hash));
+        }
+    }
+}
+
+/// Whether every keychain deriving `hash` is lazy, i.e. nobody wants its
+/// history before the server reports a change.
+fn is_lazy<K: Ord + Clone>(state: &EngineState<K>, hash: &sha256::Hash) -> bool {
+    let owners = state.spk_tracker.index_of_spk_hash(hash);
+    !owners.is_empty() && owners.iter().all(|(keychain, _)| state.spk_tracker.is_lazy(keychain))
+}
+
+/// Records newly derived scripts and emits the fetch + subscribe pair for each.
+fn watch_new_spks<K: Ord + Clone>(
+    state: &mut EngineState<K>,
+    newly: Vec<(sha256::Hash, ScriptBuf)>,
+    cmds: &mut Vec<EngineCommand>,
+) {
+    for (new_hash, new_script) in newly {
+        let owners = state.spk_tracker.index_of_spk_hash(&new_hash);
+        if !owners.is_empty() {
+            state.spk_index_by_hash.insert(new_hash, owners);
+        }
+        state.script_by_hash.insert(new_hash, new_script);
+
+        if state.subscribed.insert(new_hash) {
+            // 1) Warm fetch for newly derived script (unless lazy)
+            if !is_lazy(state, &new_hash) {
+                cmds.push(EngineCommand::FetchHistory(new_hash));
+            }
+
+            // 2) Then subscribe for future updates
+            cmds.push(EngineCommand::Subscribe(new_hash));
+        }
+    }
+}
@@ -18,12 +18,12 @@ pub mod types;
 mod tests;
 
 // Re-export core types for easy access
-pub use crate::streaming::engine::types::{EngineEvent, EngineCommand};
+pub use crate::streaming::engine::types::{EngineEvent, EngineCommand, KeychainUsage, TxRelevance, TxRelevanceCounts};
 
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::time::Instant;
 
-use bitcoin::{ScriptBuf};
+use bitcoin::{ScriptBuf, Txid};
 use bitcoin::hashes::sha256;
 
 use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
@@ -55,13 +55,55 @@ impl<K: Ord + Clone> SyncEngine<K> {
                 spk_tracker,
                 spk_index_by_hash: HashMap::new(),
                 script_by_hash: HashMap::new(),
+                relevance: HashMap::new(),
+                relevance_counts: TxRelevanceCounts::default(),
+                owned_outputs: HashSet::new(),
                 subscribed: BTreeSet::new(),
                 histories: HashMap::new(),
+                activity_discovery: false,
+                active: BTreeSet::new(),
+                order_unconfirmed_chains: true,
+                replacements: BTreeMap::new(),
+                applied: HashMap::new(),
+                unconfirmed_inputs: HashMap::new(),
+                first_seen: HashMap::new(),
+                next_seen: 0,
+                unsubscribe_buried: None,
+                spent_at: HashMap::new(),
+                buried: BTreeSet::new(),
                 connected: false,
+                early_histories: Vec::new(),
             },
         }
     }
 
+    /// Treat a script as used as soon as `get_history` lists any tx for it
+    /// (`EngineEvent::ScriptHashActivity`), instead of once its txs are
+    /// downloaded. Gap extension then runs ahead of the tx downloads, so
+    /// wallets with long histories discover their used range sooner.
+    pub fn with_activity_discovery(mut self, enabled: bool) -> Self {
+        self.state.activity_discovery = enabled;
+        self
+    }
+
+    /// Whether `ApplyTransactions` lists unconfirmed txs parent-first
+    /// (default), so the driver can mark a chain of them as seen in dependency
+    /// order. When disabled, txs are passed on in the server's order.
+    pub fn with_unconfirmed_chain_ordering(mut self, enabled: bool) -> Self {
+        self.state.order_unconfirmed_chains = enabled;
+        self
+    }
+
+    /// Unsubscribe scripts whose outputs are all spent once their latest tx
+    /// has `depth` confirmations (counted on `EngineEvent::TipChanged`), to
+    /// stay under the server's subscription limit. Such a script stays known
+    /// (its index remains used) and is subscribed again as soon as a tx in
+    /// another script's history pays it.
+    pub fn with_unsubscribe_buried(mut self, depth: u32) -> Self {
+        self.state.unsubscribe_buried = Some(depth.max(1));
+        self
+    }
+
     /// The main event handler.
     ///
     /// Consumes an event and returns a list of commands that the driver must execute.
@@ -71,15 +113,40 @@ impl<K: Ord + Clone> SyncEngine<K> {
             EngineEvent::Connected => {
                 on_connected(&mut self.state)
             },
+            EngineEvent::Reconnected => {
+                logic::on_reconnected(&self.state)
+            },
             EngineEvent::ScriptHashChanged(hash) => {
                 logic::on_scripthash_changed(&mut self.state, hash)
             },
             EngineEvent::ScriptHashHistory { hash, txs } => {
-                logic::on_scripthash_history(&mut self.state, hash, txs)
+                logic::on_scripthash_history(&mut self.state, hash, txs, false)
+            },
+            EngineEvent::IncompleteHistory { hash, txs } => {
+                logic::on_scripthash_history(&mut self.state, hash, txs, true)
+            },
+            EngineEvent::ScriptHashActivity { hash, tx_count } => {
+                logic::on_scripthash_activity(&mut self.state, hash, tx_count)
+            },
+            EngineEvent::AddressRevealed { script, index } => {
+                logic::on_address_revealed(&mut self.state, script, index)
+            },
+            EngineEvent::LookaheadReduced(lookahead) => {
+                logic::on_lookahead_reduced(&mut self.state, lookahead)
+            },
+            EngineEvent::TipChanged(height) => {
+                logic::on_tip_changed(&mut self.state, height)
             },
         }
     }
 
+    /// Stops tracking `keychain` (see `DerivedSpkTracker::remove_keychain`).
+    ///
+    /// Returns an `Unsubscribe` for every script that was subscribed.
+    pub fn remove_keychain(&mut self, keychain: &K) -> Vec<EngineCommand> {
+        logic::on_keychain_removed(&mut self.state, keychain)
+    }
+
     /// Resolves a ScriptHash back to its original ScriptBuf.
     ///
     /// Useful for the driver to reconstruct full objects when only a hash is available.
@@ -87,6 +154,81 @@ impl<K: Ord + Clone> SyncEngine<K> {
         self.state.script_by_hash.get(hash).cloned()
     }
 
+    /// How `txid` relates to our scripts, if it is in any of our histories.
+    pub fn relevance(&self, txid: &Txid) -> Option<TxRelevance> {
+        self.state.relevance.get(txid).copied()
+    }
+
+    /// Txs in our histories per relevance class (see `TxRelevance`).
+    pub fn relevance_counts(&self) -> TxRelevanceCounts {
+        self.state.relevance_counts
+    }
+
+    /// Reports, per keychain, which indices have received funds and how far ahead
+    /// we are watching. A small `gap` means the lookahead may be too tight.
+    ///
+    /// A `BTreeMap` rather than a `HashMap`: keychains only need to be `Ord`
+    /// here, and reports list them in a stable order.
+    pub fn keychain_usage(&self) -> BTreeMap<K, KeychainUsage> {
+        let tracker = &self.state.spk_tracker;
+
+        let mut used: BTreeMap<K, Vec<u32>> = BTreeMap::new();
+        let used_hashes = self
+            .state
+            .histories
+            .iter()
+            .filter(|(_, txids)| !txids.is_empty())
+            .map(|(hash, _)| hash)
+            .chain(&self.state.active);
+        for hash in used_hashes {
+            for (keychain, index) in tracker.index_of_spk_hash(hash) {
+                used.entry(keychain).or_default().push(index);
+            }
+        }
+
+        tracker
+            .keychains()
+            .filter_map(|keychain| {
+                let watched_max = tracker.max_derived_index(keychain)?;
+                let mut used_indices = used.remove(keychain).unwrap_or_default();
+                used_indices.sort_unstable();
+                let gap = match used_indices.last() {
+                    Some(highest) => watched_max - highest,
+                    None => watched_max + 1,
+                };
+                Some((keychain.clone(), KeychainUsage { used_indices, watched_max, gap }))
+            })
+            .collect()
+    }
+
+    /// Whether any tracked script has history (or reported activity).
+    ///
+    /// `false` after a complete sync usually means a wrong descriptor
+    /// (fingerprint, derivation path or xpub) rather than an empty wallet.
+    pub fn any_activity(&self) -> bool {
+        !self.state.active.is_empty() || self.state.histories.values().any(|txids| !txids.is_empty())
+    }
+
+    /// The SPK tracker the engine derives scripts with.
+    pub fn tracker(&self) -> &DerivedSpkTracker<K> {
+        &self.state.spk_tracker
+    }
+
+    /// Scripthashes the engine has issued `Subscribe` for.
+    pub fn subscribed(&self) -> &BTreeSet<sha256::Hash> {
+        &self.state.subscribed
+    }
+
+    /// Txids of the last history received per scripthash.
+    pub fn histories(&self) -> &HashMap<sha256::Hash, Vec<Txid>> {
+        &self.state.histories
+    }
+
+    /// Every RBF replacement seen so far: replaced txid -> the txid that won.
+    pub fn replacements(&self) -> &BTreeMap<Txid, Txid> {
+        &self.state.replacements
+    }
+
     /// Accessor for the internal SPK tracker (Test only).
     ///
     /// Allows tests to inspect or mutate derivation state directly.
@@ -1,9 +1,10 @@
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeSet, HashMap, HashSet, BTreeMap};
 use std::time::Instant;
-use bitcoin::{Txid, ScriptBuf};
+use bitcoin::{OutPoint, Txid, ScriptBuf};
 use bitcoin::hashes::sha256;
 
 use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+use crate::streaming::engine::types::{HistoryTx, TxRelevance, TxRelevanceCounts};
 
 #[derive(Debug)]
 pub struct EngineState<K> {
@@ -13,13 +14,57 @@ pub struct EngineState<K> {
 
     pub spk_tracker: DerivedSpkTracker<K>,
 
-    /// scripthash -> (keychain, index)
-    pub spk_index_by_hash: HashMap<sha256::Hash, (K, u32)>,
+    /// scripthash -> every (keychain, index) deriving it
+    pub spk_index_by_hash: HashMap<sha256::Hash, Vec<(K, u32)>>,
 
     /// scripthash -> Script
     pub script_by_hash: HashMap<sha256::Hash, ScriptBuf>,
 
+    /// How each tx in our histories relates to our scripts, and the tallies.
+    pub relevance: HashMap<Txid, TxRelevance>,
+    pub relevance_counts: TxRelevanceCounts,
+    /// Outputs paying our scripts, to recognise txs spending them.
+    pub owned_outputs: HashSet<OutPoint>,
+
     pub subscribed: BTreeSet<sha256::Hash>,
     pub histories: HashMap<sha256::Hash, Vec<Txid>>,
+
+    /// Extend the gap on history metadata, before the txs arrive.
+    pub activity_discovery: bool,
+    /// Scripts with reported activity whose full history hasn't arrived yet.
+    pub active: BTreeSet<sha256::Hash>,
+
+    /// List unconfirmed parents before their children in `ApplyTransactions`.
+    pub order_unconfirmed_chains: bool,
+
+    /// Unconfirmed txs that lost an input conflict -> the tx that replaced
+    /// them, until either confirms or the replacement is dropped.
+    pub replacements: BTreeMap<Txid, Txid>,
+
+    /// Txs already sent out in an `ApplyTransactions` -> the script whose
+    /// history carried them and the (height, verified) they were sent with.
+    pub applied: HashMap<Txid, (sha256::Hash, (i32, bool))>,
+
+    /// Unconfirmed txs seen in a history -> the outpoints they spend, to tell
+    /// one replaced (RBF) out of a history from one merely dropped.
+    pub unconfirmed_inputs: HashMap<Txid, Vec<OutPoint>>,
+    /// Unconfirmed txs seen in a history -> when they first showed up (a
+    /// sequence number), so of two conflicting txs the later one wins.
+    pub first_seen: HashMap<Txid, u64>,
+    /// The next `first_seen` sequence number.
+    pub next_seen: u64,
+
+    /// Unsubscribe scripts once fully spent this many blocks deep
+    /// (`None`: keep every script subscribed).
+    pub unsubscribe_buried: Option<u32>,
+    /// Fully spent scripts with only confirmed txs -> the height of the
+    /// latest one; unsubscribed once it is `unsubscribe_buried` deep.
+    pub spent_at: HashMap<sha256::Hash, u32>,
+    /// Scripts unsubscribed as buried; subscribed again on new activity.
+    pub buried: BTreeSet<sha256::Hash>,
     pub connected: bool,
+
+    /// Histories that arrived before `Connected`, replayed once it has
+    /// populated the script maps.
+    pub early_histories: Vec<(sha256::Hash, Vec<HistoryTx>, bool)>,
 }
\ No newline at end of file
@@ -1,12 +1,13 @@
 #![cfg(test)]
 use crate::streaming::engine::{SyncEngine, EngineEvent, EngineCommand};
-use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+use crate::streaming::engine::types::HistoryTx;
+use crate::streaming::domain::spk_tracker::{DerivedSpkTracker, GapPolicy};
 use bdk_wallet::miniscript::Descriptor;
 use std::str::FromStr;
 use bitcoin::{Transaction, TxIn, TxOut, ScriptBuf, Amount, Txid};
 use bitcoin::transaction::Version;
 use bitcoin::absolute::LockTime;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{sha256, Hash};
 
 // =========================================================================
 // Helpers
@@ -17,8 +18,6 @@ fn fake_descriptor(idx: u32) -> Descriptor<bdk_wallet::miniscript::DescriptorPub
     Descriptor::from_str(&s).unwrap()
 }
 
-// Suppress warning if not used in the modified test flow
-#[allow(dead_code)]
 fn fake_tx() -> Transaction {
     Transaction {
         version: Version(2),
@@ -39,6 +38,14 @@ fn fake_tx() -> Transaction {
     }
 }
 
+fn spk_hash_at(desc_idx: u32, index: u32) -> sha256::Hash {
+    let spk = fake_descriptor(desc_idx)
+        .at_derivation_index(index)
+        .unwrap()
+        .script_pubkey();
+    sha256::Hash::hash(spk.as_bytes())
+}
+
 fn setup_engine(lookahead: u32, _start_index: u32) -> SyncEngine<String> {
     let mut tracker = DerivedSpkTracker::new(lookahead);
     tracker.insert_descriptor("external".to_string(), fake_descriptor(0), 0);
@@ -74,6 +81,47 @@ fn connected_subscribes_all_spks() {
     assert_eq!(sub_count, 6, "Should generate exactly 6 subscriptions");
 }
 
+#[test]
+fn payment_with_a_data_output_is_a_receive_with_data_noted() {
+    let mut engine = setup_engine(2, 0);
+    engine.handle_event(EngineEvent::Connected);
+    let hash = spk_hash_at(0, 0);
+    let script = fake_descriptor(0).at_derivation_index(0).unwrap().script_pubkey();
+
+    let mut payment = fake_tx();
+    payment.output = vec![
+        TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::new_op_return([0xab; 8]) },
+        TxOut { value: Amount::from_sat(10_000), script_pubkey: script.clone() },
+    ];
+    let mut spend = fake_tx();
+    spend.input[0].previous_output = bitcoin::OutPoint::new(payment.compute_txid(), 1);
+    let txids = (payment.compute_txid(), spend.compute_txid());
+    let txs = [payment, spend].into_iter().map(|tx| HistoryTx { tx, height: 0, verified: true }).collect();
+    engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs });
+
+    let received = engine.relevance(&txids.0).unwrap();
+    assert!(received.receives && !received.spends && !received.is_data_only());
+    assert_eq!(received.data_outputs, 1);
+    let spent = engine.relevance(&txids.1).unwrap();
+    assert!(spent.spends && !spent.receives);
+    let counts = engine.relevance_counts();
+    assert_eq!((counts.receives, counts.spends, counts.with_data, counts.data_only), (1, 1, 1, 0));
+
+    // Gone from the history, they no longer count.
+    engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs: vec![] });
+    assert_eq!(engine.relevance_counts(), Default::default());
+
+    // Nor do their outputs: paying us from the dropped payment's output is
+    // no spend from us any more.
+    let mut self_pay = fake_tx();
+    self_pay.input[0].previous_output = bitcoin::OutPoint::new(txids.0, 1);
+    self_pay.output = vec![TxOut { value: Amount::from_sat(9_000), script_pubkey: script }];
+    let self_pay_txid = self_pay.compute_txid();
+    let txs = vec![HistoryTx { tx: self_pay, height: 0, verified: true }];
+    engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs });
+    assert!(!engine.relevance(&self_pay_txid).unwrap().spends);
+}
+
 #[test]
 fn history_transition_derives_more() {
     let mut engine = setup_engine(2, 0);
@@ -105,4 +153,337 @@ fn history_transition_derives_more() {
         .count();
 
     assert!(new_subs > 0, "Should derive and subscribe to new scripts after tracker update");
-}
\ No newline at end of file
+}
+
+#[test]
+fn keychain_usage_reports_used_indices_and_gap() {
+    let mut tracker = DerivedSpkTracker::new(20);
+    tracker.insert_descriptor("external".to_string(), fake_descriptor(0), 0);
+    let mut engine = SyncEngine::new(tracker);
+    engine.handle_event(EngineEvent::Connected);
+
+    for index in [0, 5] {
+        engine.handle_event(EngineEvent::ScriptHashHistory {
+            hash: spk_hash_at(0, index),
+            txs: vec![HistoryTx { tx: fake_tx(), height: 0, verified: true }],
+        });
+    }
+
+    let usage = engine.keychain_usage();
+    let external = &usage["external"];
+
+    assert_eq!(external.used_indices, vec![0, 5]);
+    // Using index 5 extends the window to [6 ..= 6 + 20].
+    assert_eq!(external.watched_max, 26);
+    assert_eq!(external.gap, 21);
+}
+
+#[test]
+fn shrinking_lookahead_unsubscribes_unused_tail() {
+    let mut engine = setup_engine(50, 0);
+    engine.handle_event(EngineEvent::Connected);
+
+    let cmds = engine.handle_event(EngineEvent::LookaheadReduced(10));
+
+    let mut unsubscribed: Vec<sha256::Hash> = cmds
+        .iter()
+        .filter_map(|c| match c {
+            EngineCommand::Unsubscribe(h) => Some(*h),
+            _ => None,
+        })
+        .collect();
+    let mut expected: Vec<sha256::Hash> = (11..=50)
+        .flat_map(|i| [spk_hash_at(0, i), spk_hash_at(1, i)])
+        .collect();
+    unsubscribed.sort();
+    expected.sort();
+    assert_eq!(unsubscribed, expected);
+
+    assert!(engine.script_for_hash(&spk_hash_at(0, 10)).is_some());
+    assert!(engine.script_for_hash(&spk_hash_at(0, 11)).is_none());
+}
+
+#[test]
+fn removed_keychain_is_unsubscribed_and_forgotten() {
+    let mut engine = setup_engine(2, 0);
+    engine.handle_event(EngineEvent::Connected);
+    let removed: Vec<sha256::Hash> = (0..=2).map(|i| spk_hash_at(1, i)).collect();
+
+    let cmds = engine.remove_keychain(&"internal".to_string());
+
+    let mut unsubscribed: Vec<sha256::Hash> = cmds
+        .iter()
+        .filter_map(|c| match c {
+            EngineCommand::Unsubscribe(h) => Some(*h),
+            _ => None,
+        })
+        .collect();
+    unsubscribed.sort();
+    let mut expected = removed.clone();
+    expected.sort();
+    assert_eq!(unsubscribed, expected);
+    for hash in &removed {
+        assert!(engine.tracker().index_of_spk_hash(hash).is_empty());
+        assert!(engine.script_for_hash(hash).is_none());
+        assert!(!engine.subscribed().contains(hash));
+    }
+    assert!(engine.script_for_hash(&spk_hash_at(0, 0)).is_some());
+
+    // Inserting the keychain again watches its scripts anew.
+    let added = engine.tracker_mut().insert_descriptor("internal".to_string(), fake_descriptor(1), 0);
+    assert_eq!(added.len(), 3);
+}
+
+#[test]
+fn self_send_change_extends_eager_change_keychain() {
+    let mut tracker = DerivedSpkTracker::new(2)
+        .with_gap_policy("internal".to_string(), GapPolicy { lookahead: 3, eager: true });
+    tracker.insert_descriptor("external".to_string(), fake_descriptor(0), 0);
+    tracker.insert_descriptor("internal".to_string(), fake_descriptor(1), 0);
+    let mut engine = SyncEngine::new(tracker);
+    engine.handle_event(EngineEvent::Connected);
+
+    // A self-send to external/0 with change at internal/3, seen via the
+    // external script before the change script's own notification.
+    let mut self_send = fake_tx();
+    self_send.output.push(TxOut {
+        value: Amount::from_sat(500),
+        script_pubkey: fake_descriptor(1).at_derivation_index(3).unwrap().script_pubkey(),
+    });
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: spk_hash_at(0, 0),
+        txs: vec![HistoryTx { tx: self_send, height: 0, verified: true }],
+    });
+
+    let fetched: Vec<sha256::Hash> = cmds
+        .iter()
+        .filter_map(|c| match c {
+            EngineCommand::FetchHistory(h) => Some(*h),
+            _ => None,
+        })
+        .collect();
+    // Change window extended past 3 by the change lookahead (4..=4 + 3), and
+    // the change script itself refetched.
+    for index in 3..=7 {
+        assert!(fetched.contains(&spk_hash_at(1, index)), "internal/{} not fetched", index);
+    }
+    assert_eq!(engine.tracker_mut().max_derived_index(&"internal".to_string()), Some(7));
+    // The external keychain keeps the standard gap: 0 used -> watch 1..=1 + 2.
+    assert_eq!(engine.tracker_mut().max_derived_index(&"external".to_string()), Some(3));
+}
+
+#[test]
+fn history_before_connected_is_replayed_on_connect() {
+    let mut engine = setup_engine(2, 0);
+
+    // A notification queued during setup lands before `Connected`.
+    let early = engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: spk_hash_at(0, 0),
+        txs: vec![HistoryTx { tx: fake_tx(), height: 0, verified: true }],
+    });
+    assert!(early.is_empty());
+
+    let cmds = engine.handle_event(EngineEvent::Connected);
+    let expected_script = fake_descriptor(0).at_derivation_index(0).unwrap().script_pubkey();
+    assert!(cmds.iter().any(|c| matches!(
+        c,
+        EngineCommand::ApplyTransactions { script, txs, .. } if *script == expected_script && txs.len() == 1
+    )));
+    // The replayed history marks external/0 used: 0 used -> watch 1..=1 + 2.
+    assert!(cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(h) if *h == spk_hash_at(0, 3))));
+    assert_eq!(engine.keychain_usage()["external"].used_indices, vec![0]);
+
+    // Once connected, a hash the tracker never derived is ignored.
+    let unknown = engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: sha256::Hash::hash(b"not a wallet script"),
+        txs: vec![HistoryTx { tx: fake_tx(), height: 0, verified: true }],
+    });
+    assert!(unknown.is_empty());
+}
+
+#[test]
+fn activity_metadata_extends_gap_before_txs_arrive() {
+    let mut engine = setup_engine(2, 0).with_activity_discovery(true);
+    engine.handle_event(EngineEvent::Connected);
+
+    // get_history listed txs for external/2, none of which is downloaded yet.
+    let cmds = engine.handle_event(EngineEvent::ScriptHashActivity { hash: spk_hash_at(0, 2), tx_count: 3 });
+    // 2 used -> watch 3..=3 + 2.
+    for index in 3..=5 {
+        let hash = spk_hash_at(0, index);
+        assert!(cmds.iter().any(|c| matches!(c, EngineCommand::FetchHistory(h) if *h == hash)));
+        assert!(cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(h) if *h == hash)));
+    }
+    assert!(!cmds.iter().any(|c| matches!(c, EngineCommand::ApplyTransactions { .. })));
+    assert_eq!(engine.keychain_usage()["external"].used_indices, vec![2]);
+
+    // Repeats (e.g. from a second server) change nothing; the full history
+    // later only applies.
+    assert!(engine.handle_event(EngineEvent::ScriptHashActivity { hash: spk_hash_at(0, 2), tx_count: 3 }).is_empty());
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: spk_hash_at(0, 2),
+        txs: vec![HistoryTx { tx: fake_tx(), height: 0, verified: true }],
+    });
+    assert!(!cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(_))));
+
+    // Without the mode, metadata alone derives nothing.
+    let mut plain = setup_engine(2, 0);
+    plain.handle_event(EngineEvent::Connected);
+    assert!(plain.handle_event(EngineEvent::ScriptHashActivity { hash: spk_hash_at(0, 2), tx_count: 3 }).is_empty());
+}
+
+#[test]
+fn all_empty_sync_reports_no_activity() {
+    let mut engine = setup_engine(2, 0);
+    engine.handle_event(EngineEvent::Connected);
+    for index in 0..=2 {
+        for desc in 0..=1 {
+            engine.handle_event(EngineEvent::ScriptHashHistory { hash: spk_hash_at(desc, index), txs: vec![] });
+        }
+    }
+    assert!(!engine.any_activity());
+
+    engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: spk_hash_at(1, 2),
+        txs: vec![HistoryTx { tx: fake_tx(), height: 0, verified: true }],
+    });
+    assert!(engine.any_activity());
+}
+
+#[test]
+fn lazy_keychain_is_subscribed_but_not_bootstrapped() {
+    let mut tracker = DerivedSpkTracker::new(2);
+    tracker.insert_descriptor("primary".to_string(), fake_descriptor(0), 0);
+    tracker.insert_lazy_descriptor("secondary".to_string(), fake_descriptor(1), 0);
+    let mut engine = SyncEngine::new(tracker);
+
+    let cmds = engine.handle_event(EngineEvent::Connected);
+    let fetched: Vec<sha256::Hash> = cmds
+        .iter()
+        .filter_map(|c| match c {
+            EngineCommand::FetchHistory(h) => Some(*h),
+            _ => None,
+        })
+        .collect();
+    let subscribed = cmds.iter().filter(|c| matches!(c, EngineCommand::Subscribe(_))).count();
+
+    assert_eq!(fetched, (0..=2).map(|i| spk_hash_at(0, i)).collect::<Vec<_>>());
+    assert_eq!(subscribed, 6);
+
+    // A change notification still fetches a lazy script.
+    let cmds = engine.handle_event(EngineEvent::ScriptHashChanged(spk_hash_at(1, 1)));
+    assert!(matches!(cmds.as_slice(), [EngineCommand::FetchHistory(h)] if *h == spk_hash_at(1, 1)));
+}
+
+#[test]
+fn emptied_history_reports_its_txs_as_dropped() {
+    let mut engine = setup_engine(2, 0);
+    engine.handle_event(EngineEvent::Connected);
+    let hash = spk_hash_at(0, 0);
+    let mut other = fake_tx();
+    other.output[0].value = Amount::from_sat(2000);
+    let txids = vec![fake_tx().compute_txid(), other.compute_txid()];
+
+    let applied = |cmds: &[EngineCommand]| -> Vec<(usize, Vec<Txid>)> {
+        cmds.iter()
+            .filter_map(|c| match c {
+                EngineCommand::ApplyTransactions { txs, dropped, .. } => Some((txs.len(), dropped.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs: vec![] });
+    assert_eq!(applied(&cmds), vec![(0, vec![])]);
+
+    let txs = [fake_tx(), other]
+        .into_iter()
+        .map(|tx| HistoryTx { tx, height: 0, verified: true })
+        .collect();
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs });
+    assert_eq!(applied(&cmds), vec![(2, vec![])]);
+    // 0 used -> watch 1..=1 + 2.
+    assert!(cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(h) if *h == spk_hash_at(0, 3))));
+
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs: vec![] });
+    assert_eq!(applied(&cmds), vec![(0, txids)]);
+    // Nothing is unsubscribed; the scripts derived past the used index stay watched.
+    assert!(!cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(_) | EngineCommand::Unsubscribe(_))));
+    assert_eq!(engine.tracker_mut().max_derived_index(&"external".to_string()), Some(3));
+}
+
+#[test]
+fn tx_shared_by_two_scripts_is_applied_once_until_it_confirms() {
+    let mut engine = setup_engine(2, 0);
+    engine.handle_event(EngineEvent::Connected);
+    let (receive, change) = (spk_hash_at(0, 0), spk_hash_at(1, 0));
+    let history = |height| vec![HistoryTx { tx: fake_tx(), height, verified: true }];
+    let applied = |cmds: &[EngineCommand]| -> Vec<usize> {
+        cmds.iter()
+            .filter_map(|c| match c {
+                EngineCommand::ApplyTransactions { txs, .. } => Some(txs.len()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash: receive, txs: history(0) });
+    assert_eq!(applied(&cmds), vec![1]);
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash: change, txs: history(0) });
+    assert_eq!(applied(&cmds), vec![0]);
+    // Both scripts still count as used.
+    assert_eq!(engine.keychain_usage()["internal"].used_indices, vec![0]);
+
+    // Confirmation is news for the first history listing it, not the second.
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash: change, txs: history(100) });
+    assert_eq!(applied(&cmds), vec![1]);
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory { hash: receive, txs: history(100) });
+    assert_eq!(applied(&cmds), vec![0]);
+}
+
+#[test]
+fn fully_spent_script_is_unsubscribed_once_buried_and_resubscribed_on_activity() {
+    let mut engine = setup_engine(2, 0).with_unsubscribe_buried(6);
+    engine.handle_event(EngineEvent::Connected);
+    let hash = spk_hash_at(0, 0);
+    let script = fake_descriptor(0).at_derivation_index(0).unwrap().script_pubkey();
+
+    let mut funding = fake_tx();
+    funding.output[0].script_pubkey = script.clone();
+    let mut spend = fake_tx();
+    spend.input[0].previous_output = bitcoin::OutPoint::new(funding.compute_txid(), 0);
+    let txs = vec![
+        HistoryTx { tx: funding, height: 100, verified: true },
+        HistoryTx { tx: spend, height: 105, verified: true },
+    ];
+    engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs });
+
+    let unsubscribed = |cmds: &[EngineCommand]| -> Vec<sha256::Hash> {
+        cmds.iter()
+            .filter_map(|c| match c {
+                EngineCommand::Unsubscribe(h) => Some(*h),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // The spend has 5 confirmations at 109, 6 at 110.
+    assert!(unsubscribed(&engine.handle_event(EngineEvent::TipChanged(109))).is_empty());
+    assert_eq!(unsubscribed(&engine.handle_event(EngineEvent::TipChanged(110))), vec![hash]);
+    assert!(!engine.subscribed().contains(&hash));
+    assert!(unsubscribed(&engine.handle_event(EngineEvent::TipChanged(111))).is_empty());
+    // Still used: nothing past the lookahead beyond it is dropped.
+    assert_eq!(engine.keychain_usage()["external"].used_indices, vec![0]);
+
+    // A tx in another history pays the buried script again.
+    let mut reuse = fake_tx();
+    reuse.output[0].value = Amount::from_sat(5000);
+    reuse.output.push(TxOut { value: Amount::from_sat(1000), script_pubkey: script });
+    let cmds = engine.handle_event(EngineEvent::ScriptHashHistory {
+        hash: spk_hash_at(1, 0),
+        txs: vec![HistoryTx { tx: reuse, height: 0, verified: true }],
+    });
+    assert!(cmds.iter().any(|c| matches!(c, EngineCommand::Subscribe(h) if *h == hash)));
+    assert!(cmds.iter().any(|c| matches!(c, EngineCommand::FetchHistory(h) if *h == hash)));
+    assert!(engine.subscribed().contains(&hash));
+}
@@ -1,5 +1,6 @@
 use bitcoin::hashes::sha256;
-use bitcoin::{Transaction, ScriptBuf};
+use bitcoin::{Transaction, ScriptBuf, Txid};
+use serde::Serialize;
 
 /// A transaction paired with its confirmation height from Electrum's `get_history`.
 ///
@@ -11,24 +12,148 @@ use bitcoin::{Transaction, ScriptBuf};
 pub struct HistoryTx {
     pub tx: Transaction,
     pub height: i32,
+    /// `false` if a confirmed tx's merkle proof didn't match the header at
+    /// `height` (or the server had none); the driver then treats it as
+    /// unconfirmed. Always `true` for unconfirmed txs, and for clients that
+    /// don't check proofs.
+    pub verified: bool,
+}
+
+/// Derivation usage of one keychain: which indices received funds versus
+/// how far ahead we are watching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeychainUsage {
+    /// Indices whose script has a non-empty history, ascending.
+    pub used_indices: Vec<u32>,
+    /// Highest derived (subscribed) index.
+    pub watched_max: u32,
+    /// Number of watched indices above the highest used one
+    /// (all of them if nothing is used yet).
+    pub gap: u32,
 }
 
 #[derive(Debug, Clone)]
 pub enum EngineEvent {
     Connected,
+    /// The client opened a new connection; every subscribed script has to be
+    /// subscribed on it again. Histories aren't refetched: the client reports
+    /// scripts that changed meanwhile as usual.
+    Reconnected,
     ScriptHashChanged(sha256::Hash),
     ScriptHashHistory {
         hash: sha256::Hash,
         txs: Vec<HistoryTx>,             // CHANGED: was Vec<Transaction>
     },
+    /// Like `ScriptHashHistory`, for a history lacking members the client
+    /// failed to fetch (see `ElectrumApi::history_incomplete`): what it lists
+    /// is applied, but nothing it doesn't list counts as dropped.
+    IncompleteHistory {
+        hash: sha256::Hash,
+        txs: Vec<HistoryTx>,
+    },
+    /// `get_history` listed `tx_count` txs for `hash`; the txs themselves are
+    /// still downloading (see `SyncEngine::with_activity_discovery`).
+    ScriptHashActivity {
+        hash: sha256::Hash,
+        tx_count: usize,
+    },
+    /// The wallet handed out the address for `script` at derivation `index`;
+    /// watch it (and a full lookahead beyond it) right away.
+    AddressRevealed {
+        script: ScriptBuf,
+        index: u32,
+    },
+    /// The lookahead was reduced at runtime; stop watching the unused tail.
+    LookaheadReduced(u32),
+    /// The chain tip moved to this height; fully spent scripts buried deep
+    /// enough are unsubscribed (see `SyncEngine::with_unsubscribe_buried`).
+    TipChanged(u32),
 }
 
 #[derive(Debug, Clone)]
 pub enum EngineCommand {
     Subscribe(sha256::Hash),
+    /// Stop receiving notifications for a script that is no longer tracked.
+    Unsubscribe(sha256::Hash),
     FetchHistory(sha256::Hash),
     ApplyTransactions {
         script: ScriptBuf,
         txs: Vec<HistoryTx>,             // CHANGED: was Vec<Transaction>
+        /// Members of `txs` replaced (RBF) by a conflicting tx; they must not
+        /// look fresher to the wallet than their replacement.
+        replaced: Vec<Txid>,
+        /// Txs the previous history of `script` listed and this one doesn't
+        /// (dropped from the mempool, or replaced); the wallet must stop
+        /// counting them.
+        dropped: Vec<Txid>,
     },
-}
\ No newline at end of file
+}
+/// How a tx in our histories relates to the tracked scripts (see
+/// `SyncEngine::relevance`). Diagnostics only: every tx is applied the same
+/// way regardless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TxRelevance {
+    /// Pays one of our scripts.
+    pub receives: bool,
+    /// Spends an output of one of our scripts.
+    pub spends: bool,
+    /// `OP_RETURN` (data) outputs it carries.
+    pub data_outputs: usize,
+}
+
+impl TxRelevance {
+    /// Neither pays nor spends from us, yet lists one of our scripts in its
+    /// history, e.g. a tracked script only appearing as data.
+    pub fn is_data_only(&self) -> bool {
+        !self.receives && !self.spends && self.data_outputs > 0
+    }
+
+    fn merge(self, other: TxRelevance) -> TxRelevance {
+        TxRelevance {
+            receives: self.receives || other.receives,
+            spends: self.spends || other.spends,
+            data_outputs: self.data_outputs.max(other.data_outputs),
+        }
+    }
+}
+
+/// Txs in our histories per `TxRelevance` class; a tx can count in several.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct TxRelevanceCounts {
+    pub receives: u64,
+    pub spends: u64,
+    /// Txs with at least one data output.
+    pub with_data: u64,
+    pub data_only: u64,
+}
+
+impl TxRelevanceCounts {
+    fn tally(&mut self, relevance: TxRelevance, add: bool) {
+        let classes = [
+            (&mut self.receives, relevance.receives),
+            (&mut self.spends, relevance.spends),
+            (&mut self.with_data, relevance.data_outputs > 0),
+            (&mut self.data_only, relevance.is_data_only()),
+        ];
+        for (count, applies) in classes {
+            if applies {
+                *count = if add { *count + 1 } else { count.saturating_sub(1) };
+            }
+        }
+    }
+
+    /// Records a tx's (re)classification, replacing what `previous` counted.
+    pub(crate) fn update(&mut self, previous: Option<TxRelevance>, relevance: TxRelevance) -> TxRelevance {
+        let merged = previous.map_or(relevance, |p| p.merge(relevance));
+        if let Some(previous) = previous {
+            self.tally(previous, false);
+        }
+        self.tally(merged, true);
+        merged
+    }
+
+    /// Forgets a tx that left our histories.
+    pub(crate) fn remove(&mut self, relevance: TxRelevance) {
+        self.tally(relevance, false);
+    }
+}
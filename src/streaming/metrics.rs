@@ -0,0 +1,162 @@
+//! Session-long latency recording.
+//!
+//! The adapter feeds two distributions into a shared `LatencyRecorder`:
+//! * **scripthash sync**: from the `get_history` request until the history
+//!   (txs and headers) is ready for the driver;
+//! * **request RTT**: from writing a JSON-RPC request until its response arrives.
+//!
+//! `LatencyRecorder::report` turns them into p50/p90/p99 summaries, which is a
+//! fairer streaming-vs-polling comparison than a single wall-clock number.
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Every recorded sample, in microseconds.
+///
+/// Sessions see at most a few thousand requests, so keeping exact samples is
+/// cheaper than it sounds and makes the percentiles exact too.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    samples_us: Vec<u64>,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        self.samples_us.push(latency.as_micros() as u64);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples_us.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples_us.is_empty()
+    }
+
+    /// Summarizes the distribution using nearest-rank percentiles.
+    pub fn percentiles(&self) -> Percentiles {
+        let mut sorted = self.samples_us.clone();
+        sorted.sort_unstable();
+
+        let at = |p: f64| -> Option<Duration> {
+            if sorted.is_empty() {
+                return None;
+            }
+            let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+            Some(Duration::from_micros(sorted[rank.clamp(1, sorted.len()) - 1]))
+        };
+
+        Percentiles {
+            count: sorted.len(),
+            p50: at(50.0),
+            p90: at(90.0),
+            p99: at(99.0),
+            max: sorted.last().map(|us| Duration::from_micros(*us)),
+        }
+    }
+}
+
+/// Percentile summary of one latency distribution. All `None` when empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50: Option<Duration>,
+    pub p90: Option<Duration>,
+    pub p99: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |d: Option<Duration>| d.map(|d| format!("{:?}", d)).unwrap_or_else(|| "-".into());
+        write!(
+            f,
+            "n={} p50={} p90={} p99={} max={}",
+            self.count,
+            show(self.p50),
+            show(self.p90),
+            show(self.p99),
+            show(self.max)
+        )
+    }
+}
+
+/// Snapshot of the session's latency distributions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyReport {
+    pub scripthash_sync: Percentiles,
+    pub request_rtt: Percentiles,
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Scripthash sync:  {}", self.scripthash_sync)?;
+        write!(f, "Request RTT:      {}", self.request_rtt)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histograms {
+    scripthash_sync: LatencyHistogram,
+    request_rtt: LatencyHistogram,
+}
+
+/// Cloneable, thread-safe recorder shared by the adapter (which feeds it) and
+/// the `DriverHandle` (which reports it).
+#[derive(Debug, Clone, Default)]
+pub struct LatencyRecorder {
+    inner: Arc<Mutex<Histograms>>,
+}
+
+impl LatencyRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_scripthash_sync(&self, latency: Duration) {
+        self.inner.lock().unwrap().scripthash_sync.record(latency);
+    }
+
+    pub fn record_request_rtt(&self, latency: Duration) {
+        self.inner.lock().unwrap().request_rtt.record(latency);
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        let h = self.inner.lock().unwrap();
+        LatencyReport {
+            scripthash_sync: h.scripthash_sync.percentiles(),
+            request_rtt: h.request_rtt.percentiles(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_of_known_latencies() {
+        let recorder = LatencyRecorder::new();
+        // Insert 1..=100 ms out of order.
+        for ms in (1..=100).rev() {
+            recorder.record_request_rtt(Duration::from_millis(ms));
+        }
+        recorder.record_scripthash_sync(Duration::from_millis(7));
+
+        let report = recorder.report();
+
+        assert_eq!(report.request_rtt.count, 100);
+        assert_eq!(report.request_rtt.p50, Some(Duration::from_millis(50)));
+        assert_eq!(report.request_rtt.p90, Some(Duration::from_millis(90)));
+        assert_eq!(report.request_rtt.p99, Some(Duration::from_millis(99)));
+        assert_eq!(report.request_rtt.max, Some(Duration::from_millis(100)));
+
+        // A single sample is every percentile.
+        assert_eq!(report.scripthash_sync.p50, Some(Duration::from_millis(7)));
+        assert_eq!(report.scripthash_sync.p99, Some(Duration::from_millis(7)));
+
+        assert_eq!(LatencyHistogram::default().percentiles(), Percentiles::default());
+    }
+}
@@ -1,8 +1,6 @@
-pub mod client;
 pub mod engine;
 pub mod domain;
 pub mod runtime;
 pub mod electrum;
-
-//#[cfg(test)]
-//pub mod tests;
\ No newline at end of file
+pub mod metrics;
+pub mod util;
@@ -0,0 +1,65 @@
+use crate::streaming::electrum::api::PendingWork;
+use crate::streaming::metrics::LatencyReport;
+use crate::streaming::runtime::SyncStatus;
+
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Snapshot of the driver, engine and client state for bug reports (see
+/// `DriverHandle::dump_state`). Scripthashes are in Electrum's wire encoding.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateDump {
+    /// Every derived script the engine watches.
+    pub scripts: Vec<ScriptDump>,
+    /// Scripthashes the engine has asked the client to subscribe.
+    pub subscribed: Vec<String>,
+    /// Txids of each scripthash's last history, in server order.
+    pub histories: BTreeMap<String, Vec<String>>,
+    pub pending: PendingDump,
+    /// Heights of the block headers the client has cached.
+    pub cached_headers: Vec<u32>,
+    pub tip: Option<TipDump>,
+    /// The wallet's own chain tip, which anchors are connected to.
+    pub wallet_tip_height: u32,
+    pub balance_sat: u64,
+    pub status: SyncStatus,
+    /// Unconfirmed txids replaced via RBF -> the txid that replaced them.
+    pub replacements: BTreeMap<String, String>,
+    pub latency: LatencyReport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScriptDump {
+    /// The keychain's `Debug` rendering.
+    pub keychain: String,
+    pub index: u32,
+    pub address: String,
+    pub scripthash: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingDump {
+    /// Requests the client is still waiting on.
+    pub client: PendingWork,
+    /// Bootstrap histories not yet applied.
+    pub initial_syncs: Vec<String>,
+    /// Parent txs parked updates are waiting for.
+    pub parked_parents: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TipDump {
+    pub height: u32,
+    pub hash: String,
+}
+
+impl StateDump {
+    /// Writes the dump to `path` as pretty-printed JSON.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use crate::persistence::{persist_with_retry, StoreRetry};
+use crate::streaming::domain::tip::ChainTip;
+use crate::streaming::engine::EngineEvent;
+use crate::streaming::metrics::{LatencyRecorder, LatencyReport};
+use crate::streaming::runtime::dump::StateDump;
+use crate::streaming::runtime::orchestrator::{DumpRequests, Inbox, StreamingWallet};
+use crate::streaming::runtime::{StreamingStats, SyncStatus};
+
+use anyhow::Result;
+use bdk_wallet::file_store::Store;
+use bdk_wallet::{ChangeSet, KeychainKind};
+use bdk_wallet::chain::ChainPosition;
+use bitcoin::constants::COINBASE_MATURITY;
+use bitcoin::{Address, Amount, Txid};
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+/// How long `DriverHandle::dump_state` waits for the driver loop.
+const DUMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A cloneable handle to a running `SyncOrchestrator`.
+///
+/// `run_forever` consumes the orchestrator, so take a handle first (via
+/// `SyncOrchestrator::handle`) to query it from other threads.
+#[derive(Clone)]
+pub struct DriverHandle {
+    pub(crate) wallet: Arc<Mutex<StreamingWallet>>,
+    pub(crate) store: Option<Arc<Mutex<Store<ChangeSet>>>>,
+    pub(crate) store_retry: StoreRetry,
+    pub(crate) inbox: Inbox,
+    pub(crate) latency: LatencyRecorder,
+    pub(crate) tip: Arc<Mutex<Option<ChainTip>>>,
+    pub(crate) status: Arc<Mutex<SyncStatus>>,
+    pub(crate) replacements: Arc<Mutex<BTreeMap<Txid, Txid>>>,
+    pub(crate) stats: Arc<Mutex<StreamingStats>>,
+    pub(crate) dump_requests: DumpRequests,
+}
+
+impl DriverHandle {
+    /// Reveals the next unused address of `keychain` and starts watching it.
+    ///
+    /// The reveal is persisted (if the driver has a store) and the driver is told
+    /// to subscribe the new script before the wallet lock is released, so no
+    /// other caller can observe the address while it is still unwatched. The
+    /// driver fetches its history before subscribing, so a payment that lands
+    /// in between is still picked up.
+    pub fn reveal_next_address(&self, keychain: KeychainKind) -> Result<Address> {
+        let mut wallet = self.wallet.lock().unwrap();
+        let info = wallet.reveal_next_address(keychain);
+        if let Some(store) = &self.store {
+            persist_with_retry(&mut wallet, &mut store.lock().unwrap(), &self.store_retry)?;
+        }
+        self.inbox.lock().unwrap().push_back(EngineEvent::AddressRevealed {
+            script: info.address.script_pubkey(),
+            index: info.index,
+        });
+        Ok(info.address)
+    }
+
+    /// Reveals `keychain` up to `to_index` and starts watching through it.
+    ///
+    /// The wallet is the source of truth for how far each keychain is revealed:
+    /// the reveal is persisted first, and only then is the driver told to
+    /// advance its tracker and subscribe the new scripts. The tracker is
+    /// derived from the wallet on load, or restored and then caught up to it
+    /// (see `persistence::tracker_for_wallet` and `restore_tracker`), so a
+    /// crash in between leaves the engine behind the wallet, never ahead of
+    /// it, and the restart catches up.
+    ///
+    /// Returns the keychain's revealed index afterwards; an index at or below
+    /// it reveals nothing but still brings the driver up to it.
+    pub fn advance_derivation(&self, keychain: KeychainKind, to_index: u32) -> Result<u32> {
+        let mut wallet = self.wallet.lock().unwrap();
+        let _ = wallet.reveal_addresses_to(keychain, to_index);
+        if let Some(store) = &self.store {
+            persist_with_retry(&mut wallet, &mut store.lock().unwrap(), &self.store_retry)?;
+        }
+        let index = wallet.derivation_index(keychain).unwrap_or(to_index);
+        self.inbox.lock().unwrap().push_back(EngineEvent::AddressRevealed {
+            script: wallet.peek_address(keychain, index).script_pubkey(),
+            index,
+        });
+        Ok(index)
+    }
+
+    /// The highest revealed index of `keychain`, if any address was revealed.
+    pub fn derivation_index(&self, keychain: KeychainKind) -> Option<u32> {
+        self.wallet.lock().unwrap().derivation_index(keychain)
+    }
+
+    /// Percentiles of per-scripthash sync latency and per-request round-trip
+    /// time recorded so far. Empty if the client doesn't measure latency.
+    pub fn latency_report(&self) -> LatencyReport {
+        self.latency.report()
+    }
+
+    /// Unconfirmed txs replaced via RBF, mapped to the tx that won the
+    /// conflict (and that the wallet's balance reflects). A conflict is
+    /// forgotten once either side confirms or the winner is dropped.
+    pub fn replacements(&self) -> BTreeMap<Txid, Txid> {
+        self.replacements.lock().unwrap().clone()
+    }
+
+    /// A snapshot of the driver's full state for bug reports (see `StateDump`).
+    ///
+    /// Taken by the driver loop between events, so it is consistent; fails if
+    /// the driver doesn't get to it within `DUMP_TIMEOUT` (e.g. it has stopped).
+    pub fn dump_state(&self) -> Result<StateDump> {
+        let (tx, rx) = mpsc::channel();
+        self.dump_requests.lock().unwrap().push(tx);
+        rx.recv_timeout(DUMP_TIMEOUT)
+            .map_err(|_| anyhow::anyhow!("driver did not answer the state dump within {:?}", DUMP_TIMEOUT))
+    }
+
+    /// The best header seen so far, or loaded from the persisted tip.
+    pub fn tip(&self) -> Option<ChainTip> {
+        *self.tip.lock().unwrap()
+    }
+
+    /// Confirmations of a wallet tx against `tip()`: 0 while unconfirmed,
+    /// `None` if the tx is unknown or no tip is available yet.
+    pub fn confirmations(&self, txid: Txid) -> Option<u32> {
+        let tip = self.tip()?;
+        let wallet = self.wallet.lock().unwrap();
+        match wallet.get_tx(txid)?.chain_position {
+            ChainPosition::Confirmed { anchor, .. } => Some(tip.confirmations(anchor.block_id.height)),
+            ChainPosition::Unconfirmed { .. } => Some(0),
+        }
+    }
+
+    /// Sum of the wallet's UTXOs with at least `min_conf` confirmations.
+    ///
+    /// Confirmations are counted against `tip()`, or the wallet's own chain
+    /// tip before one is known. `min_conf == 0` includes unconfirmed outputs.
+    /// Coinbase outputs are never counted before they mature.
+    pub fn spendable_balance(&self, min_conf: u32) -> Amount {
+        let wallet = self.wallet.lock().unwrap();
+        let tip_height = self
+            .tip()
+            .map(|t| t.height)
+            .unwrap_or_else(|| wallet.latest_checkpoint().height());
+        wallet
+            .list_unspent()
+            .filter(|utxo| {
+                let confs = match utxo.chain_position {
+                    ChainPosition::Confirmed { anchor, .. } => {
+                        (tip_height + 1).saturating_sub(anchor.block_id.height)
+                    }
+                    ChainPosition::Unconfirmed { .. } => 0,
+                };
+                let is_coinbase = wallet
+                    .get_tx(utxo.outpoint.txid)
+                    .is_some_and(|tx| tx.tx_node.tx.is_coinbase());
+                confs >= min_conf && (!is_coinbase || confs >= COINBASE_MATURITY)
+            })
+            .map(|utxo| utxo.txout.value)
+            .sum()
+    }
+
+    /// Why the driver is or isn't caught up, as of its last loop iteration.
+    pub fn sync_status(&self) -> SyncStatus {
+        *self.status.lock().unwrap()
+    }
+
+    pub fn is_caught_up(&self) -> bool {
+        self.sync_status().is_caught_up()
+    }
+
+    /// Subscriptions, histories and applied txs counted so far.
+    pub fn stats(&self) -> StreamingStats {
+        *self.stats.lock().unwrap()
+    }
+}
@@ -1,6 +1,18 @@
+mod dump;
+mod handle;
+mod notify;
 mod orchestrator;
+mod sink;
+mod status;
+mod verify;
 
 #[cfg(test)]
-mod tests;
+pub(crate) mod tests;
 
-pub use orchestrator::SyncOrchestrator;
\ No newline at end of file
+pub use dump::{PendingDump, ScriptDump, StateDump, TipDump};
+pub use handle::DriverHandle;
+pub use notify::{LoggingNotifier, PaymentAlertPolicy, PaymentNotifier};
+pub use orchestrator::{BalanceNotifyMode, SyncOrchestrator};
+pub use sink::{TxGraphSink, UpdateSink};
+pub use status::{StreamingStats, SyncStatus};
+pub use verify::{BalanceCheck, ScriptMismatch};
\ No newline at end of file
@@ -0,0 +1,35 @@
+use bitcoin::{Amount, SignedAmount, Transaction};
+
+/// Receives alerts for incoming payments, e.g. to show a desktop
+/// notification or call a webhook. Registered with
+/// `SyncOrchestrator::with_payment_notifier`.
+pub trait PaymentNotifier: Send {
+    /// `amount` is the tx's net effect on the wallet (always positive here);
+    /// `confs` is 0 while the tx is unconfirmed.
+    fn on_payment(&self, tx: &Transaction, amount: SignedAmount, confs: u32);
+}
+
+/// Logs each payment at info level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingNotifier;
+
+impl PaymentNotifier for LoggingNotifier {
+    fn on_payment(&self, tx: &Transaction, amount: SignedAmount, confs: u32) {
+        log::info!("[PAYMENT] Received {} in {} ({} confirmations)", amount, tx.compute_txid(), confs);
+    }
+}
+
+/// Which payments reach the notifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentAlertPolicy {
+    /// Payments with a smaller net amount are ignored.
+    pub min_amount: Amount,
+    /// Fire as soon as a payment is seen in the mempool, rather than once it confirms.
+    pub notify_unconfirmed: bool,
+}
+
+impl Default for PaymentAlertPolicy {
+    fn default() -> Self {
+        Self { min_amount: Amount::ZERO, notify_unconfirmed: true }
+    }
+}
@@ -1,15 +1,88 @@
 use crate::streaming::engine::SyncEngine;
-use crate::streaming::engine::types::{EngineCommand, EngineEvent};
-use crate::streaming::electrum::api::ElectrumApi;
+use crate::streaming::engine::types::{EngineCommand, EngineEvent, HistoryTx};
+use crate::streaming::electrum::api::{ElectrumApi, ScriptBalance};
+use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+use crate::streaming::domain::tip::ChainTip;
+use crate::streaming::metrics::LatencyRecorder;
+use crate::persistence::{self, StoreRetry};
+use crate::streaming::runtime::dump::{PendingDump, ScriptDump, StateDump, TipDump};
+use crate::streaming::runtime::sink::UpdateSink;
+use crate::streaming::runtime::verify::BalanceCheck;
+use crate::streaming::runtime::{DriverHandle, PaymentAlertPolicy, PaymentNotifier, StreamingStats, SyncStatus};
+use crate::streaming::util::{script_hash, scripthash_to_wire};
 
-use bdk_wallet::{PersistedWallet, ChangeSet};
+use anyhow::{anyhow, Context, Result};
+use bdk_wallet::{Balance, PersistedWallet, ChangeSet};
 use bdk_wallet::file_store::Store;
 use bitcoin::hashes::sha256;
-use std::sync::{Arc, Mutex};
+use bdk_wallet::chain::ChainPosition;
+use bitcoin::{block, Amount, OutPoint, SignedAmount, Transaction, Txid};
+use std::path::{Path, PathBuf};
+use std::fmt::Debug;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Instant, Duration};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
-type StreamingWallet = PersistedWallet<Store<ChangeSet>>;
+pub(crate) type StreamingWallet = PersistedWallet<Store<ChangeSet>>;
+
+/// Callback for txs the wallet rejects (see `with_apply_error_notifier`).
+type ApplyErrorCallback = Box<dyn Fn(Txid, &str) + Send>;
+type BalanceCheckCallback = Box<dyn FnOnce(Result<BalanceCheck>) + Send>;
+type ActivityCallback = Box<dyn Fn(sha256::Hash, usize) + Send>;
+type ProgressCallback = Box<dyn Fn(usize, usize) + Send>;
+type TrackerSaver<K> = Box<dyn Fn(&DerivedSpkTracker<K>) + Send>;
+
+/// Events queued for the driver from other threads (see `DriverHandle`).
+pub(crate) type Inbox = Arc<Mutex<VecDeque<EngineEvent>>>;
+
+/// Pending `DriverHandle::dump_state` calls, answered from the driver loop.
+pub(crate) type DumpRequests = Arc<Mutex<Vec<mpsc::Sender<StateDump>>>>;
+
+/// How long an update waits for the missing parents of its mempool txs before
+/// being applied without them.
+const PARENT_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest the idle loop waits on the client before checking its own inputs
+/// (handle requests, parked updates) again.
+const IDLE_WAIT: Duration = Duration::from_millis(50);
+
+/// How long the balance verification waits for the server's answers before
+/// reporting an error.
+const BALANCE_CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A balance verification waiting on the server's answers, finished from
+/// the driver loop (see `with_balance_verification`).
+struct PendingBalanceCheck {
+    hashes: Vec<sha256::Hash>,
+    requested_at: Instant,
+    callback: BalanceCheckCallback,
+}
+
+/// A wallet update held back until the unknown parents of its mempool txs,
+/// and the headers to anchor its confirmed txs, arrive.
+struct ParkedUpdate {
+    update: bdk_wallet::Update,
+    waiting_for: HashSet<Txid>,
+    /// Heights whose header was requested, and the txs to anchor there.
+    headers: HashMap<u32, Vec<Txid>>,
+    parked_at: Instant,
+}
+
+/// Controls when balance observers are notified after transactions are applied.
+///
+/// Histories arrive one scripthash at a time, so a funding tx and the tx that
+/// spends it may be applied in separate steps. Observers notified after every
+/// step can see a transient balance that never existed on-chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalanceNotifyMode {
+    /// Notify after every `ApplyTransactions` command.
+    #[default]
+    PerApply,
+    /// Notify once the client has no more ready histories, i.e. after the
+    /// whole batch of pending updates has been applied.
+    AfterBatch,
+}
 
 /// **SyncOrchestrator**
 ///
@@ -20,62 +93,663 @@ type StreamingWallet = PersistedWallet<Store<ChangeSet>>;
 /// 3. **Execute Side Effects** (Commands) emitted by the Engine, such as updating the wallet database.
 ///
 /// It runs in the main application thread and blocks when waiting for events.
-pub struct SyncOrchestrator<K, C> {
+/// Updates go to an `UpdateSink`, by default the wallet.
+pub struct SyncOrchestrator<K, C, S = StreamingWallet> {
     /// The functional core that makes decisions.
     engine: SyncEngine<K>,
     
     /// The adapter for the Electrum protocol.
     client: C,
 
-    /// Thread-safe reference to the BDK wallet (shared with the UI/App).
-    wallet: Arc<Mutex<StreamingWallet>>,
+    /// Thread-safe reference to the sink updates are applied to, usually the
+    /// BDK wallet (shared with the UI/App).
+    sink: Arc<Mutex<S>>,
+
+    /// The wallet's file store, if the caller wants driver-side writes persisted.
+    store: Option<Arc<Mutex<Store<ChangeSet>>>>,
+
+    /// Persist every applied update before observers hear of it (see `with_write_ahead`).
+    write_ahead: bool,
+
+    /// How persisting to `store` retries transient I/O errors.
+    store_retry: StoreRetry,
+
+    /// Events pushed by `DriverHandle`s, drained on every loop iteration.
+    inbox: Inbox,
 
     /// Optional callback fired after the initial bootstrap (first scan) is complete.
     /// Useful for UI loading screens.
     on_initial_sync: Option<Box<dyn FnOnce() + Send>>,
 
+    /// Told about scripts with history as soon as the client lists it,
+    /// before the txs are downloaded.
+    on_activity: Option<ActivityCallback>,
+
+    /// Given (done, total) bootstrap histories as they complete (see `with_progress_notifier`).
+    on_progress: Option<ProgressCallback>,
+    /// Bootstrap histories completed so far, and scripts bootstrapped in total.
+    progress: (usize, usize),
+
+    /// Given the server-vs-wallet balance comparison once the initial sync is done.
+    on_balance_check: Option<BalanceCheckCallback>,
+    /// The balance verification in flight; the initial sync completes with it.
+    balance_check: Option<PendingBalanceCheck>,
+
+    /// Warn after the initial sync if no tracked script has any history.
+    expect_funds: bool,
+
+    /// How long a change notification waits for more of the same scripthash
+    /// before its history is fetched (see `with_notification_debounce`).
+    debounce: Option<Duration>,
+
+    /// Scripthashes with a history fetch held back by `debounce`, and when it is due.
+    debounced: HashMap<sha256::Hash, Instant>,
+
     /// Tracks which script hashes are currently syncing during the bootstrap phase.
     pending_initial_syncs: HashSet<sha256::Hash>,
 
+    /// Optional callback fired with the wallet balance after transactions are applied.
+    on_balance_change: Option<Box<dyn Fn(Balance) + Send>>,
+
+    /// When `on_balance_change` fires (see `BalanceNotifyMode`).
+    balance_notify_mode: BalanceNotifyMode,
+
+    /// Set when an apply happened that observers have not been told about yet.
+    balance_dirty: bool,
+
+    /// Applied txs whose write to the store failed. Their payments and the
+    /// balance are announced after the next successful persist.
+    unannounced: Vec<Txid>,
+
+    /// Persists left to fail on purpose (see `fail_next_persists`).
+    #[cfg(test)]
+    failing_persists: usize,
+
+    /// The balance observers last saw (initially the wallet's at startup);
+    /// an apply that leaves it unchanged isn't reported.
+    last_balance: Option<Balance>,
+
+    /// Bootstrap updates accumulated for a single apply (see `with_bulk_initial_apply`).
+    bulk_update: Option<bdk_wallet::Update>,
+
+    /// Updates waiting for parent transactions (see `ParkedUpdate`).
+    parked_updates: Vec<ParkedUpdate>,
+
+    /// Latency samples fed by the client (empty if it doesn't measure them).
+    latency: LatencyRecorder,
+
+    /// Best header seen so far (shared with `DriverHandle`s).
+    tip: Arc<Mutex<Option<ChainTip>>>,
+
+    /// Where `tip` is persisted, if anywhere (see `with_persisted_tip`).
+    tip_path: Option<PathBuf>,
+
+    /// Saves the engine's tracker after it derives new scripts (see
+    /// `with_persisted_tracker`).
+    tracker_saver: Option<TrackerSaver<K>>,
+
+    /// Last computed `SyncStatus` (shared with `DriverHandle`s).
+    status: Arc<Mutex<SyncStatus>>,
+
+    /// The engine's RBF decisions, replaced -> replacement (shared with `DriverHandle`s).
+    replacements: Arc<Mutex<BTreeMap<Txid, Txid>>>,
+
+    /// Counters of what the sync has done (shared with `DriverHandle`s).
+    stats: Arc<Mutex<StreamingStats>>,
+
+    dump_requests: DumpRequests,
+
+    /// Alerted once per incoming payment that passes `payment_policy`.
+    payment_notifier: Option<Box<dyn PaymentNotifier>>,
+
+    payment_policy: PaymentAlertPolicy,
+
+    /// Txs already passed to (or deliberately withheld from) the notifier.
+    payments_seen: HashSet<Txid>,
+
+    /// Told about txs the wallet refused even when applied on their own.
+    on_apply_error: Option<ApplyErrorCallback>,
+
+    /// Where scripthashes are recorded as their bootstrap history is applied.
+    bootstrap_progress_path: Option<PathBuf>,
+
+    /// Scripthashes an interrupted earlier bootstrap already applied; their
+    /// bootstrap fetch is skipped and the engine is fed the wallet's txs.
+    resumed: HashSet<sha256::Hash>,
+
+    /// Txs applied from a backup (see `with_seed_transactions`) and the height
+    /// the backup listed, until the server reports them.
+    seeded: HashMap<Txid, i32>,
+
+    /// The client's `connection_epoch` when last checked (`None`: not yet).
+    connection_epoch: Option<u64>,
+
     /// Start time for logging relative timestamps.
     t0: Instant,
 }
 
-impl<K, C> SyncOrchestrator<K, C>
+impl<K, C, S> SyncOrchestrator<K, C, S>
 where
-    K: Ord + Clone,
+    K: Ord + Clone + Debug,
     C: ElectrumApi,
+    S: UpdateSink,
 {
     pub fn new(
         engine: SyncEngine<K>,
         client: C,
-        wallet: Arc<Mutex<StreamingWallet>>,
+        sink: Arc<Mutex<S>>,
     ) -> Self {
+        let latency = client.latency_recorder().unwrap_or_default();
+        let last_balance = sink.lock().unwrap().wallet().map(|wallet| wallet.balance());
         Self {
             engine,
             client,
-            wallet,
+            sink,
+            store: None,
+            write_ahead: true,
+            store_retry: StoreRetry::default(),
+            inbox: Inbox::default(),
             on_initial_sync: None,
+            on_balance_check: None,
+            balance_check: None,
+            on_activity: None,
+            on_progress: None,
+            progress: (0, 0),
+            expect_funds: false,
+            debounce: None,
+            debounced: HashMap::new(),
             pending_initial_syncs: HashSet::new(),
+            on_balance_change: None,
+            balance_notify_mode: BalanceNotifyMode::default(),
+            balance_dirty: false,
+            unannounced: Vec::new(),
+            #[cfg(test)]
+            failing_persists: 0,
+            last_balance,
+            bulk_update: None,
+            parked_updates: Vec::new(),
+            latency,
+            tip: Arc::default(),
+            tip_path: None,
+            tracker_saver: None,
+            status: Arc::default(),
+            replacements: Arc::default(),
+            stats: Arc::default(),
+            dump_requests: Arc::default(),
+            payment_notifier: None,
+            payment_policy: PaymentAlertPolicy::default(),
+            payments_seen: HashSet::new(),
+            on_apply_error: None,
+            bootstrap_progress_path: None,
+            resumed: HashSet::new(),
+            seeded: HashMap::new(),
+            connection_epoch: None,
             t0: Instant::now(),
         }
     }
 
+    /// Persist wallet changes made through the driver (e.g. revealed addresses) to `store`.
+    pub fn with_store(mut self, store: Store<ChangeSet>) -> Self {
+        self.store = Some(Arc::new(Mutex::new(store)));
+        self
+    }
+
     /// Register a callback to be called once the engine has subscribed to all initial scripts.
     pub fn with_initial_sync_notifier<F: FnOnce() + Send + 'static>(mut self, f: F) -> Self {
         self.on_initial_sync = Some(Box::new(f));
         self
     }
 
+    /// Register a callback for the first sign of activity on a script: its
+    /// scripthash and the number of txs `get_history` listed, before any of
+    /// them is downloaded. Pair with `SyncEngine::with_activity_discovery` to
+    /// also extend the gap at that point.
+    pub fn with_activity_notifier<F: Fn(sha256::Hash, usize) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_activity = Some(Box::new(f));
+        self
+    }
+
+    /// Register a callback for bootstrap progress, e.g. for a progress bar:
+    /// `f(done, total)` fires each time an initial history is applied, and a
+    /// last time with `(total, total)` right before the initial sync notifier.
+    /// `total` starts at the scripts subscribed on connect and grows as gap
+    /// extension derives more of them during the bootstrap.
+    pub fn with_progress_notifier<F: Fn(usize, usize) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Cross-check the wallet against the server once the initial sync is done.
+    ///
+    /// Queries `blockchain.scripthash.get_balance` for every subscribed script
+    /// and compares it with the wallet's unspent outputs on that script; `f`
+    /// gets the comparison (or the error if the client couldn't answer)
+    /// before the initial sync notifier fires. The driver keeps processing
+    /// updates while the answers come in, unless the client can only query
+    /// balances blocking. Txs arriving between the last history and the
+    /// balance query show up as transient mismatches.
+    pub fn with_balance_verification<F: FnOnce(Result<BalanceCheck>) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_balance_check = Some(Box::new(f));
+        self
+    }
+
+    /// Register a callback invoked with the wallet balance whenever applied
+    /// transactions change it.
+    pub fn with_balance_change_notifier<F: Fn(Balance) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_balance_change = Some(Box::new(f));
+        self
+    }
+
+    /// Choose whether balance observers are notified per apply or once per batch.
+    pub fn with_balance_notify_mode(mut self, mode: BalanceNotifyMode) -> Self {
+        self.balance_notify_mode = mode;
+        self
+    }
+
+    /// Alert `notifier` about incoming payments that pass `policy`.
+    ///
+    /// Each tx is reported once. Payments already in the wallet's history when
+    /// they are applied during the initial scan are not reported.
+    pub fn with_payment_notifier<N: PaymentNotifier + 'static>(
+        mut self,
+        notifier: N,
+        policy: PaymentAlertPolicy,
+    ) -> Self {
+        self.payment_notifier = Some(Box::new(notifier));
+        self.payment_policy = policy;
+        self
+    }
+
+    /// Register a callback for txs the wallet rejects (with the reason).
+    ///
+    /// A rejected batch is retried one tx at a time, so only the txs that
+    /// can't be applied on their own are reported.
+    pub fn with_apply_error_notifier<F: Fn(Txid, &str) + Send + 'static>(mut self, f: F) -> Self {
+        self.on_apply_error = Some(Box::new(f));
+        self
+    }
+
+    /// Whether each applied update is written to the store before balance and
+    /// payment observers are told about it (on by default; needs `with_store`).
+    ///
+    /// Otherwise applied txs only reach the store with the next reveal or
+    /// bootstrap checkpoint, and a crash in between loses them.
+    pub fn with_write_ahead(mut self, enabled: bool) -> Self {
+        self.write_ahead = enabled;
+        self
+    }
+
+    /// How persisting the wallet retries transient I/O errors (e.g. a full
+    /// disk); fatal store errors are reported straight away.
+    pub fn with_store_retry(mut self, retry: StoreRetry) -> Self {
+        self.store_retry = retry;
+        self
+    }
+
+    /// The wallet is known to hold funds: if the initial sync finds no history
+    /// on any tracked script, warn that the descriptor is probably wrong.
+    pub fn with_expect_funds(mut self, enabled: bool) -> Self {
+        self.expect_funds = enabled;
+        self
+    }
+
+    /// Coalesce change notifications for the same scripthash arriving within
+    /// `window` into a single history fetch, sent when the window closes.
+    ///
+    /// An address in active use can be notified once per new mempool tx, and
+    /// each notification would otherwise refetch its whole history.
+    pub fn with_notification_debounce(mut self, window: Duration) -> Self {
+        self.debounce = Some(window);
+        self
+    }
+
+    /// Apply the whole initial scan as one wallet update instead of one per scripthash.
+    ///
+    /// Cheaper to index and free of intermediate balances, at the cost of no
+    /// partial balance while the scan runs. Later events are applied incrementally.
+    pub fn with_bulk_initial_apply(mut self, enabled: bool) -> Self {
+        self.bulk_update = enabled.then(bdk_wallet::Update::default);
+        self
+    }
+
+    /// Load the last-seen chain tip from `path` and keep it there as it advances.
+    ///
+    /// A warm start then has a tip for confirmation counts right away; it is
+    /// re-validated (and replaced on a mismatch) by the first header the server
+    /// reports at or above it.
+    pub fn with_persisted_tip(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match persistence::load_tip(&path) {
+            Ok(tip) => {
+                if let Some(tip) = &tip {
+                    log::info!("[RUNTIME] Loaded persisted tip at height {}", tip.height);
+                }
+                *self.tip.lock().unwrap() = tip;
+            }
+            Err(e) => log::warn!("[RUNTIME] Ignoring unreadable tip file {}: {}", path.display(), e),
+        }
+        self.tip_path = Some(path);
+        self
+    }
+
+    /// Save the engine's script tracker to `path` whenever it derives new
+    /// scripts, for `persistence::restore_tracker` to load on the next start.
+    pub fn with_persisted_tracker(mut self, path: impl Into<PathBuf>) -> Self
+    where
+        K: serde::Serialize,
+    {
+        let path = path.into();
+        self.tracker_saver = Some(Box::new(move |tracker| {
+            if let Err(e) = persistence::save_tracker(&path, tracker) {
+                log::warn!("[RUNTIME] Failed to persist script tracker: {}", e);
+            }
+        }));
+        self
+    }
+
+    /// Record bootstrap progress in `path` so an interrupted cold scan resumes
+    /// where it stopped instead of fetching every history again.
+    ///
+    /// Each scripthash is recorded once its history has been applied and the
+    /// wallet persisted, which requires `with_store`. The file is removed when
+    /// the bootstrap finishes. Scripts recorded by the interrupted run are
+    /// still subscribed, and the engine learns their txs from the wallet, so
+    /// it extends the gap as usual.
+    pub fn with_bootstrap_progress(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        match persistence::load_bootstrap_progress(&path) {
+            Ok(done) => {
+                if !done.is_empty() {
+                    log::info!("[RUNTIME] Resuming bootstrap: {} scripts already synced", done.len());
+                }
+                self.resumed = done;
+            }
+            Err(e) => log::warn!("[RUNTIME] Ignoring unreadable bootstrap progress {}: {}", path.display(), e),
+        }
+        self.bootstrap_progress_path = Some(path);
+        self
+    }
+
+    /// Applies transactions exported from another wallet (the format of
+    /// `persistence::load_seed_transactions`) right away, so the balance is
+    /// known before the server has answered anything.
+    ///
+    /// Without their block hashes they can't be anchored, so they're applied
+    /// as unconfirmed; a confirmed one gains its confirmation when its
+    /// script's history streams in. An unconfirmed one the server reports
+    /// again isn't re-applied, and seeded txs never trigger payment alerts.
+    ///
+    /// With `with_store` set beforehand, the seeded txs are persisted like
+    /// any other apply. An unreadable seed file, a rejected apply or a failed
+    /// write is an error.
+    pub fn with_seed_transactions(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let txs = persistence::load_seed_transactions(path)
+            .with_context(|| format!("unreadable seed file {}", path.display()))?;
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        let mut update = bdk_wallet::Update::default();
+        for htx in txs {
+            let txid = htx.tx.compute_txid();
+            self.seeded.insert(txid, htx.height);
+            self.payments_seen.insert(txid);
+            update.tx_update.seen_ats.insert((txid, now));
+            update.tx_update.txs.push(Arc::new(htx.tx));
+        }
+        let count = update.tx_update.txs.len();
+        self.sink
+            .lock()
+            .unwrap()
+            .apply(update)
+            .with_context(|| format!("seed txs from {} rejected", path.display()))?;
+        if !self.persist_applied() {
+            anyhow::bail!("failed to persist seed txs from {}", path.display());
+        }
+        log::info!("[RUNTIME] Seeded {} txs from {}", count, path.display());
+        Ok(self)
+    }
+
+    /// Why the driver is or isn't caught up, from the client's pending requests
+    /// and the updates still waiting for parent transactions.
+    pub fn sync_status(&self) -> SyncStatus {
+        let parked_parents = self.parked_updates.iter().map(|p| p.waiting_for.len()).sum();
+        SyncStatus::compute(
+            self.client.terminal_error().is_some(),
+            self.client.is_connecting(),
+            self.client.pending_work(),
+            parked_parents,
+        )
+    }
+
+    /// Publishes the current `sync_status` to handles.
+    fn refresh_status(&mut self) {
+        let status = self.sync_status();
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// Records a header reported by the server, advancing (and persisting) the tip.
+    ///
+    /// A different header at the persisted tip's height means the chain
+    /// reorganised while we were offline, so the stored tip is replaced.
+    pub(crate) fn observe_tip(&mut self, height: u32, header: block::Header) {
+        let mut tip = self.tip.lock().unwrap();
+        match *tip {
+            Some(current) if height < current.height => return,
+            Some(current) if height == current.height => {
+                if current.header == header {
+                    return;
+                }
+                log::warn!(
+                    "[RUNTIME] Persisted tip {} at height {} replaced by {}",
+                    current.header.block_hash(),
+                    height,
+                    header.block_hash()
+                );
+            }
+            _ => {}
+        }
+        let new_tip = ChainTip { height, header };
+        *tip = Some(new_tip);
+        if let Some(path) = &self.tip_path {
+            if let Err(e) = persistence::save_tip(path, &new_tip) {
+                log::warn!("[RUNTIME] Failed to persist tip: {}", e);
+            }
+        }
+    }
+
+    /// Feeds every event queued by handles into the engine.
+    fn drain_inbox(&mut self) {
+        loop {
+            let Some(event) = self.inbox.lock().unwrap().pop_front() else {
+                break;
+            };
+            self.process_engine(event);
+        }
+
+        let requests = std::mem::take(&mut *self.dump_requests.lock().unwrap());
+        if !requests.is_empty() {
+            let dump = self.state_dump();
+            for reply in requests {
+                let _ = reply.send(dump.clone());
+            }
+        }
+    }
+
+    /// Snapshot of everything needed to diagnose a sync from the outside.
+    pub fn state_dump(&self) -> StateDump {
+        let wire = |hash: &sha256::Hash| scripthash_to_wire(hash);
+        let (network, wallet_tip_height, balance_sat) = {
+            let sink = self.sink.lock().unwrap();
+            let balance_sat = sink.wallet().map_or(0, |wallet| wallet.balance().total().to_sat());
+            (sink.network(), sink.latest_checkpoint().height(), balance_sat)
+        };
+
+        let mut initial_syncs: Vec<String> = self.pending_initial_syncs.iter().map(wire).collect();
+        initial_syncs.sort();
+        let mut parked_parents: Vec<String> = self
+            .parked_updates
+            .iter()
+            .flat_map(|p| p.waiting_for.iter().map(|txid| txid.to_string()))
+            .collect();
+        parked_parents.sort();
+
+        StateDump {
+            scripts: self
+                .engine
+                .tracker()
+                .audit(network)
+                .into_iter()
+                .map(|(keychain, index, address, hash)| ScriptDump {
+                    keychain: format!("{:?}", keychain),
+                    index,
+                    address: address.to_string(),
+                    scripthash: wire(&hash),
+                })
+                .collect(),
+            subscribed: self.engine.subscribed().iter().map(wire).collect(),
+            histories: self
+                .engine
+                .histories()
+                .iter()
+                .map(|(hash, txids)| (wire(hash), txids.iter().map(|t| t.to_string()).collect()))
+                .collect(),
+            pending: PendingDump { client: self.client.pending_work(), initial_syncs, parked_parents },
+            cached_headers: self.client.cached_header_heights(),
+            tip: self.tip.lock().unwrap().map(|tip| TipDump {
+                height: tip.height,
+                hash: tip.header.block_hash().to_string(),
+            }),
+            wallet_tip_height,
+            balance_sat,
+            status: self.sync_status(),
+            replacements: self
+                .engine
+                .replacements()
+                .iter()
+                .map(|(replaced, by)| (replaced.to_string(), by.to_string()))
+                .collect(),
+            latency: self.latency.report(),
+        }
+    }
+
+    /// Fires the balance callback if an apply happened since the last notification.
+    fn notify_balance_change(&mut self) {
+        if !self.balance_dirty {
+            return;
+        }
+        self.balance_dirty = false;
+
+        if let Some(cb) = &self.on_balance_change {
+            let Some(balance) = self.sink.lock().unwrap().wallet().map(|wallet| wallet.balance()) else {
+                return;
+            };
+            if self.last_balance.replace(balance.clone()) == Some(balance.clone()) {
+                return;
+            }
+            self.debug(&format!("[RUNTIME] Balance notification: {}", balance.total()));
+            cb(balance);
+        }
+    }
+
     /// Checks if the initial sync is pending and if all items are done.
     fn check_initial_sync_complete(&mut self) {
-        // If we have a callback registered AND the pending set is empty...
-        if self.on_initial_sync.is_some() && self.pending_initial_syncs.is_empty() {
-            self.info("[SYNC] initial engine bootstrap finished (all responses received)");
-            if let Some(cb) = self.on_initial_sync.take() {
-                cb();
+        // If we are tracking bootstrap AND the pending set is empty...
+        if !self.bootstrapping() || !self.pending_initial_syncs.is_empty() || self.balance_check.is_some() {
+            return;
+        }
+        // A bulk apply (or balance check) also waits for updates still fetching their parents.
+        if (self.bulk_update.is_some() || self.on_balance_check.is_some()) && !self.parked_updates.is_empty() {
+            return;
+        }
+        self.info("[SYNC] initial engine bootstrap finished (all responses received)");
+        if let Some(path) = self.bootstrap_progress_path.take() {
+            if let Err(e) = persistence::clear_bootstrap_progress(&path) {
+                log::warn!("[RUNTIME] Failed to clear bootstrap progress: {}", e);
+            }
+        }
+        if let Some(update) = self.bulk_update.take() {
+            self.info(&format!(
+                "[SYNC] Applying {} bootstrap txs in a single update",
+                update.tx_update.txs.len()
+            ));
+            self.apply_wallet_update(update);
+        }
+        if self.expect_funds && !self.engine.any_activity() {
+            log::warn!(
+                "[SYNC] !!! None of the {} tracked scripts has any history. Funds were expected: \
+                 check the descriptor's fingerprint, derivation path and xpub !!!",
+                self.engine.subscribed().len()
+            );
+        }
+        if let Some(callback) = self.on_balance_check.take() {
+            let hashes: Vec<sha256::Hash> = self.engine.subscribed().iter().copied().collect();
+            if self.client.request_balances(&hashes) {
+                // Finished by `poll_balance_check`.
+                self.balance_check = Some(PendingBalanceCheck { hashes, requested_at: Instant::now(), callback });
+                return;
+            }
+            let server = self.client.get_balances(&hashes);
+            self.report_balance_check(callback, &hashes, server);
+        }
+        self.finish_initial_sync();
+    }
+
+    /// Finishes the balance verification once the server has answered (or
+    /// failed to in time), then the initial sync it held back.
+    fn poll_balance_check(&mut self) {
+        let Some(pending) = &self.balance_check else {
+            return;
+        };
+        let server = match self.client.take_balances(&pending.hashes) {
+            Some(server) => server,
+            None if pending.requested_at.elapsed() >= BALANCE_CHECK_TIMEOUT => {
+                Err(anyhow!("timed out fetching balances of {} scripthashes", pending.hashes.len()))
             }
+            None => return,
+        };
+        let pending = self.balance_check.take().expect("checked above");
+        self.report_balance_check(pending.callback, &pending.hashes, server);
+        self.finish_initial_sync();
+    }
+
+    /// Compares the server's balances of `hashes` with the wallet and hands
+    /// the result to `callback`.
+    fn report_balance_check(
+        &mut self,
+        callback: BalanceCheckCallback,
+        hashes: &[sha256::Hash],
+        server: Result<Vec<ScriptBalance>>,
+    ) {
+        let check = server.and_then(|server| self.verify_balance(hashes, server));
+        match &check {
+            Ok(check) if check.is_consistent() => self.info(&format!("[VERIFY] balance {}", check)),
+            Ok(check) => log::warn!("[VERIFY] balance mismatch: {}", check),
+            Err(e) => log::warn!("[VERIFY] could not verify balance: {}", e),
         }
+        callback(check);
+    }
+
+    /// Fires the bootstrap's completion callbacks.
+    fn finish_initial_sync(&mut self) {
+        if let Some(cb) = self.on_progress.take() {
+            let (_, total) = self.progress;
+            cb(total, total);
+        }
+        if let Some(cb) = self.on_initial_sync.take() {
+            cb();
+        }
+    }
+
+    /// Compares the server's balance of each of `hashes` with the wallet's
+    /// unspent outputs (see `with_balance_verification`).
+    fn verify_balance(&self, hashes: &[sha256::Hash], server: Vec<ScriptBalance>) -> Result<BalanceCheck> {
+        let sink = self.sink.lock().unwrap();
+        let wallet = sink.wallet().ok_or_else(|| anyhow!("no wallet to verify the balance of"))?;
+        let mut by_script: HashMap<sha256::Hash, Amount> = HashMap::new();
+        for utxo in wallet.list_unspent() {
+            *by_script.entry(script_hash(&utxo.txout.script_pubkey)).or_insert(Amount::ZERO) += utxo.txout.value;
+        }
+        let server: Vec<_> = hashes.iter().copied().zip(server).collect();
+        Ok(BalanceCheck::compare(&server, &by_script, wallet.balance().total()))
     }
 
     fn t(&self) -> u128 {
@@ -88,10 +762,23 @@ where
     /// 1. Bootstraps the engine (Connected event).
     /// 2. Enters a loop polling the client for changes.
     /// 3. Handles the **"Fetch-or-Request"** logic to prevent zero-balance bugs.
-    pub fn run_forever(mut self) -> ! {
+    ///
+    /// It only returns (with `Err`) when the client reports a terminal failure,
+    /// so the caller can fall back to another sync strategy.
+    pub fn run_forever(self) -> Result<()> {
+        self.run(Arc::new(AtomicBool::new(false)))
+    }
+
+    /// Like `run_forever`, but also returns (with `Ok`) once `shutdown` is
+    /// set, after shutting the client down.
+    ///
+    /// The flag is checked once per loop iteration, so the driver stops
+    /// within `IDLE_WAIT` when idle.
+    pub fn run(mut self, shutdown: Arc<AtomicBool>) -> Result<()> {
         self.info("[DRIVER] Starting...");
 
         // 1. Bootstrap: Tell the engine we are connected so it generates initial subscriptions.
+        self.connection_epoch = Some(self.client.connection_epoch());
         self.process_engine(EngineEvent::Connected);
         // Safety check: If wallet is empty (0 addresses), fire immediately.
         self.check_initial_sync_complete();
@@ -104,73 +791,299 @@ where
 
         // 3. Event Loop
         loop {
-            // POLL CLIENT for notification (status changed) or download completion.
-            if let Some(hash) = self.client.poll_scripthash_changed() {
-                self.debug(&format!("[LOOP] Event: ScriptHashChanged({})", hash));
-                
-                // === OPTION B FIX (The "Fetch-or-Request" Pattern) ===
-                // Problem: A "changed" notification arrives before we have the transaction history.
-                // If we tell the Engine now, it sees 0 txs and sets balance to 0.
-                //
-                // Solution: Check if the client actually HAS the data.
-                match self.client.fetch_history_txs(hash) {
-                    Some(txs) => {
-                        // CASE A: Cache Hit (Data Ready)
-                        self.info(&format!("[LOOP] FetchHistory: Cache Hit for {}, processing {} txs", hash, txs.len()));
-                        
-                        // 1. Update Wallet
-                        self.process_engine(EngineEvent::ScriptHashHistory { hash, txs });
-                        
-                        // 2. Mark this hash as synced
-                        self.pending_initial_syncs.remove(&hash);
-                        
-                        // LOG PROGRESS
-                        if self.on_initial_sync.is_some() {
-                            let remaining = self.pending_initial_syncs.len();
-                            self.info(&format!("[LOOP] FetchHistory: Initial sync progress > {} pending", remaining));
-                        }
+            if shutdown.load(Ordering::SeqCst) {
+                self.info("[DRIVER] Shutting down");
+                self.client.shutdown();
+                return Ok(());
+            }
 
-                        // 3. Check if we are done with the initial load
-                        self.check_initial_sync_complete();
-                    }
-                    None => {
-                        // CASE B: Cache Miss. We got a notification, but data is missing.
-                        // This happens when we get the first "status changed" message.
-                        // ACTION: Do NOT wake the engine. Explicitly request history from network.
-                        // Result: When history arrives later, `poll_scripthash_changed` fires again, 
-                        // and we will hit CASE A.
-                        if self.pending_initial_syncs.contains(&hash) {
-                            self.trace(&format!("[LOOP] NoHistory: Ignoring cache miss for {} (already pending)", hash));
-                        } else {
-                            // Only request if it's a TRULY new event (post-bootstrap)
-                            self.trace(&format!("[LOOP] NoHistory: Cache miss for {}, requesting history", hash));
-                            self.client.request_history(hash);
-                        }
-                    }
+            // Stop if the client has given up; nothing more will ever arrive.
+            self.refresh_status();
+            if let Some(reason) = self.client.terminal_error() {
+                self.info(&format!("[DRIVER] Client failed terminally: {}", reason));
+                anyhow::bail!("streaming client failed: {}", reason);
+            }
+
+            if !self.poll_once() {
+                // Idle: the current batch is fully applied.
+                self.notify_balance_change();
+
+                // Sleep until the client has news, but wake for handle
+                // requests and closing debounce windows too.
+                let wait = self
+                    .debounced
+                    .values()
+                    .min()
+                    .map_or(IDLE_WAIT, |at| at.saturating_duration_since(Instant::now()).min(IDLE_WAIT));
+                if let Some(hash) = self.client.wait_for_change(wait) {
+                    self.on_scripthash_changed(hash);
                 }
-            } else {
-                // Avoid busy-waiting.
-                // TODO: In a production app, use a CondVar or Channel to sleep until notified.
-                std::thread::sleep(Duration::from_millis(5));
             }
         }
     }
 
+    /// One pass of the event loop, shared by `run_forever` and `run_until_idle`:
+    /// feeds queued inputs to the engine, then polls the client for one
+    /// notification (status changed) or download completion.
+    ///
+    /// Returns `false` if the client had nothing ready.
+    fn poll_once(&mut self) -> bool {
+        self.check_connection_epoch();
+        self.check_chain_tip();
+        self.drain_inbox();
+        self.retry_parked_updates();
+        self.poll_balance_check();
+        self.drain_history_activity();
+        self.drain_fetch_errors();
+        self.flush_debounced();
+
+        match self.client.poll_scripthash_changed() {
+            Some(hash) => {
+                self.on_scripthash_changed(hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles a change notification (or download completion) for `hash`.
+    pub(crate) fn on_scripthash_changed(&mut self, hash: sha256::Hash) {
+        self.debug(&format!("[LOOP] Event: ScriptHashChanged({})", hash));
+
+        // === OPTION B FIX (The "Fetch-or-Request" Pattern) ===
+        // Problem: A "changed" notification arrives before we have the transaction history.
+        // If we tell the Engine now, it sees 0 txs and sets balance to 0.
+        //
+        // Solution: Check if the client actually HAS the data.
+        match self.client.fetch_history_txs(hash) {
+            Some(txs) => {
+                // CASE A: Cache Hit (Data Ready)
+                self.info(&format!("[LOOP] FetchHistory: Cache Hit for {}, processing {} txs", hash, txs.len()));
+
+                if self.client.history_incomplete(hash) {
+                    self.handle_incomplete_history(hash, txs);
+                } else {
+                    self.handle_history(hash, txs);
+                }
+            }
+            None => {
+                // CASE B: Cache Miss. We got a notification, but data is missing.
+                // This happens when we get the first "status changed" message.
+                // ACTION: Do NOT wake the engine. Explicitly request history from network.
+                // Result: When history arrives later, `poll_scripthash_changed` fires again, 
+                // and we will hit CASE A.
+                if self.pending_initial_syncs.contains(&hash) {
+                    self.trace(&format!("[LOOP] NoHistory: Ignoring cache miss for {} (already pending)", hash));
+                } else {
+                    // Only request if it's a TRULY new event (post-bootstrap)
+                    self.trace(&format!("[LOOP] NoHistory: Cache miss for {}, requesting history", hash));
+                    self.request_history_debounced(hash);
+                }
+            }
+        }
+    }
+
+    /// Re-bootstraps the engine's subscriptions once the client reports a
+    /// new connection.
+    fn check_connection_epoch(&mut self) {
+        let epoch = self.client.connection_epoch();
+        match self.connection_epoch.replace(epoch) {
+            Some(seen) if seen != epoch => {
+                self.info(&format!("[DRIVER] Client reconnected (epoch {}); re-subscribing", epoch));
+                self.process_engine(EngineEvent::Reconnected);
+            }
+            _ => {}
+        }
+    }
+
+    /// Follows the tip the client announces: a new one advances `tip` and is
+    /// connected to the sink's local chain, so confirmation counts move with
+    /// every block rather than only with the next applied tx.
+    fn check_chain_tip(&mut self) {
+        let Some((height, header)) = self.client.chain_tip() else {
+            return;
+        };
+        let before = *self.tip.lock().unwrap();
+        self.observe_tip(height, header);
+        if *self.tip.lock().unwrap() == before {
+            return;
+        }
+        self.debug(&format!("[DRIVER] New chain tip {} at height {}", header.block_hash(), height));
+        if height > self.sink.lock().unwrap().latest_checkpoint().height() {
+            let mut update = bdk_wallet::Update::default();
+            self.connect_tip(&mut update);
+            self.apply_wallet_update(update);
+        }
+        self.process_engine(EngineEvent::TipChanged(height));
+    }
+
+    /// Requests `hash`'s history, or holds the request back for the debounce
+    /// window so notifications arriving meanwhile share it.
+    fn request_history_debounced(&mut self, hash: sha256::Hash) {
+        let Some(window) = self.debounce else {
+            self.client.request_history(hash);
+            return;
+        };
+        self.debounced.entry(hash).or_insert_with(|| Instant::now() + window);
+    }
+
+    /// Sends the debounced history requests whose window has closed.
+    fn flush_debounced(&mut self) {
+        let now = Instant::now();
+        let due: Vec<sha256::Hash> =
+            self.debounced.iter().filter(|(_, at)| **at <= now).map(|(hash, _)| *hash).collect();
+        for hash in due {
+            self.debounced.remove(&hash);
+            self.trace(&format!("[LOOP] Debounce: requesting history for {}", hash));
+            self.client.request_history(hash);
+        }
+    }
+
+    /// Feeds history metadata the client reported ahead of the txs to the
+    /// engine (for early gap extension) and the activity observer.
+    fn drain_history_activity(&mut self) {
+        while let Some((hash, tx_count)) = self.client.poll_history_activity() {
+            self.debug(&format!("[LOOP] Activity: {} lists {} txs", hash, tx_count));
+            if let Some(cb) = &self.on_activity {
+                cb(hash, tx_count);
+            }
+            self.process_engine(EngineEvent::ScriptHashActivity { hash, tx_count });
+        }
+    }
+
+    /// Counts the history members the client failed to fetch, so its error
+    /// list doesn't grow for the driver's whole life.
+    fn drain_fetch_errors(&mut self) {
+        let errors = self.client.take_errors();
+        if errors.is_empty() {
+            return;
+        }
+        for error in &errors {
+            self.debug(&format!("[LOOP] Fetch error: {:?}", error));
+        }
+        self.stats.lock().unwrap().fetch_errors += errors.len() as u64;
+    }
+
+    /// Feeds a downloaded history into the engine and tracks bootstrap progress.
+    pub(crate) fn handle_history(&mut self, hash: sha256::Hash, txs: Vec<HistoryTx>) {
+        self.finish_history(hash, EngineEvent::ScriptHashHistory { hash, txs }, true);
+    }
+
+    /// Like `handle_history`, for a history the client flagged incomplete:
+    /// the wallet keeps the txs it lacks, and a resumed bootstrap fetches it
+    /// again.
+    pub(crate) fn handle_incomplete_history(&mut self, hash: sha256::Hash, txs: Vec<HistoryTx>) {
+        self.finish_history(hash, EngineEvent::IncompleteHistory { hash, txs }, false);
+    }
+
+    fn finish_history(&mut self, hash: sha256::Hash, event: EngineEvent, complete: bool) {
+        // 1. Update Wallet
+        self.process_engine(event);
+
+        // 2. Mark this hash as synced
+        if self.pending_initial_syncs.remove(&hash) {
+            if complete {
+                self.record_bootstrap_progress(hash);
+            }
+            self.progress.0 += 1;
+            // The last one is reported once the bootstrap is really done.
+            if let Some(cb) = self.on_progress.as_ref().filter(|_| !self.pending_initial_syncs.is_empty()) {
+                cb(self.progress.0, self.progress.1);
+            }
+        }
+
+        // LOG PROGRESS
+        if self.bootstrapping() {
+            let remaining = self.pending_initial_syncs.len();
+            self.info(&format!("[LOOP] FetchHistory: Initial sync progress > {} pending", remaining));
+        }
+
+        // 3. Check if we are done with the initial load
+        self.check_initial_sync_complete();
+    }
+
+    /// Persists the wallet and records `hash` as done for a resumed bootstrap.
+    ///
+    /// Skipped while any update is held back (bulk apply, missing parents):
+    /// the history isn't in the wallet yet, so a restart must fetch it again.
+    fn record_bootstrap_progress(&mut self, hash: sha256::Hash) {
+        let (Some(path), Some(store)) = (&self.bootstrap_progress_path, &self.store) else {
+            return;
+        };
+        if self.bulk_update.is_some() || !self.parked_updates.is_empty() {
+            return;
+        }
+        let mut sink = self.sink.lock().unwrap();
+        let Some(wallet) = sink.wallet_mut() else {
+            return;
+        };
+        let persisted = persistence::persist_with_retry(wallet, &mut store.lock().unwrap(), &self.store_retry);
+        let recorded = persisted.and_then(|_| {
+            persistence::record_bootstrap_progress(path, &hash)
+        });
+        if let Err(e) = recorded {
+            log::warn!("[RUNTIME] Failed to record bootstrap progress for {}: {}", hash, e);
+        }
+    }
+
+    /// Whether bootstrap progress is being tracked (someone is waiting for it).
+    fn bootstrapping(&self) -> bool {
+        self.on_initial_sync.is_some()
+            || self.on_progress.is_some()
+            || self.on_balance_check.is_some()
+            || self.balance_check.is_some()
+            || self.bulk_update.is_some()
+            || self.bootstrap_progress_path.is_some()
+    }
+
     /// Feeds an event into the Engine and executes all resulting commands.
     pub fn process_engine(&mut self, event: EngineEvent) {
         let mut queue = vec![event];
+        let mut derived = false;
 
         while let Some(ev) = queue.pop() {
             self.debug(&format!("[RUNTIME] EngineEvent: HandleEvent({:?})", ev));
+            if let EngineEvent::ScriptHashHistory { txs, .. } | EngineEvent::IncompleteHistory { txs, .. } = &ev {
+                let mut stats = self.stats.lock().unwrap();
+                stats.histories_processed += 1;
+                if !txs.is_empty() && stats.first_history_latency.is_none() {
+                    stats.first_history_latency = Some(self.t0.elapsed());
+                }
+            }
 
             // PURE LOGIC STEP: Engine decides what to do
             let cmds = self.engine.handle_event(ev);
+            derived |= cmds.iter().any(|cmd| matches!(cmd, EngineCommand::Subscribe(_)));
 
             // SIDE EFFECT STEP: Driver executes the commands
             for cmd in cmds {
                 self.execute_command(cmd, &mut queue);
             }
         }
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.scripts_subscribed = self.engine.subscribed().len();
+            stats.tx_relevance = self.engine.relevance_counts();
+            stats.replacements = self.engine.replacements().len();
+        }
+        *self.replacements.lock().unwrap() = self.engine.replacements().clone();
+        if let Some(save) = self.tracker_saver.as_ref().filter(|_| derived) {
+            save(self.engine.tracker());
+        }
+    }
+
+    /// Stops watching every script of `keychain` (e.g. a rotated-out
+    /// descriptor), unsubscribing them at the server.
+    pub fn remove_keychain(&mut self, keychain: &K) {
+        let mut queue = Vec::new();
+        for cmd in self.engine.remove_keychain(keychain) {
+            self.execute_command(cmd, &mut queue);
+        }
+        self.stats.lock().unwrap().scripts_subscribed = self.engine.subscribed().len();
+        if let Some(save) = &self.tracker_saver {
+            save(self.engine.tracker());
+        }
     }
 
     /// Executes a single command emitted by the engine.
@@ -189,22 +1102,42 @@ where
                 }
             }
 
+            EngineCommand::Unsubscribe(hash) => {
+                self.trace(&format!("[RUNTIME] EngineCommand cmd: Unsubscribe({})", hash));
+                if self.pending_initial_syncs.remove(&hash) {
+                    self.progress.1 -= 1;
+                }
+                self.client.unregister_script(hash);
+            }
+
             EngineCommand::FetchHistory(hash) => {
                 // Explicit request for history (used during bootstrap).
                 self.trace(&format!("[RUNTIME] EngineCommand: FetchHistory({})", hash));
-                // If we are in the bootstrap phase (callback exists),
+                // Already applied by an interrupted earlier bootstrap: the
+                // engine only needs the txs, which the wallet has.
+                if self.resumed.remove(&hash) {
+                    self.trace(&format!("[RUNTIME] FetchHistory: {} resumed, replaying from wallet", hash));
+                    let txs = self.wallet_history(&hash);
+                    for cmd in self.engine.handle_event(EngineEvent::ScriptHashHistory { hash, txs }) {
+                        if !matches!(cmd, EngineCommand::ApplyTransactions { .. }) {
+                            self.execute_command(cmd, _queue);
+                        }
+                    }
+                    return;
+                }
+                // If we are in the bootstrap phase (someone waits for it),
                 // track this hash as "pending download".
-                if self.on_initial_sync.is_some() {
-                    self.pending_initial_syncs.insert(hash);
+                if self.bootstrapping() && self.pending_initial_syncs.insert(hash) {
+                    self.progress.1 += 1;
                 }
                 
                 self.client.request_history(hash);
             }
 
-            EngineCommand::ApplyTransactions { script: _, txs } => {
+            EngineCommand::ApplyTransactions { script: _, txs, replaced, dropped } => {
                 self.trace(&format!("[RUNTIME] EngineCommand: ApplyTransactions({} txs)", txs.len()));
 
-                if txs.is_empty() {
+                if txs.is_empty() && dropped.is_empty() {
                     self.trace("[RUNTIME] EngineCommand: no txs to apply");
                     return;
                 }
@@ -217,84 +1150,574 @@ where
                     .unwrap()
                     .as_secs();
 
+                // Depth of each unconfirmed tx in a chain of this update's
+                // txs (listed parent-first by the engine), so children are
+                // seen after their parents and the chain stays canonical.
+                let mut chain_depth: HashMap<Txid, u64> = HashMap::new();
+
+                // RBF losers keep the last_seen they have (or now, if new),
+                // and whatever conflicts with them is seen later, so the
+                // wallet canonicalizes to the engine's pick.
+                let loser_seen: HashMap<Txid, u64> = {
+                    let sink = self.sink.lock().unwrap();
+                    replaced.iter().map(|txid| (*txid, sink.last_seen(*txid).unwrap_or(now))).collect()
+                };
+                // Headers not cached: requested for parking the update, or
+                // (for clients that can only block) looked up once each.
+                let mut awaited_headers: HashMap<u32, Vec<Txid>> = HashMap::new();
+                let mut looked_up: HashMap<u32, Option<block::Header>> = HashMap::new();
+
+                let mut losers_by_input: HashMap<OutPoint, u64> = HashMap::new();
+                for htx in txs.iter().filter(|htx| htx.height <= 0) {
+                    if let Some(&seen) = loser_seen.get(&htx.tx.compute_txid()) {
+                        for txin in &htx.tx.input {
+                            let at = losers_by_input.entry(txin.previous_output).or_default();
+                            *at = (*at).max(seen);
+                        }
+                    }
+                }
+
                 for htx in txs {
                     let txid = htx.tx.compute_txid();
 
-                    if htx.height > 0 {
+                    // Seeded and still unconfirmed: the wallet already has it as is.
+                    let seeded = self.seeded.remove(&txid);
+                    if htx.height <= 0 && seeded.is_some_and(|height| height <= 0) {
+                        self.trace(&format!("[RUNTIME] Wallet skip tx {} (seeded)", txid));
+                        continue;
+                    }
+
+                    if htx.height > 0 && !htx.verified {
+                        // The server's height wasn't backed by a merkle proof:
+                        // no anchor, so it counts like a mempool tx until a
+                        // later history proves it.
+                        self.trace(&format!(
+                            "[RUNTIME] Wallet apply tx {} (unverified at height {}, seen_at={})",
+                            txid, htx.height, now
+                        ));
+                        update.tx_update.seen_ats.insert((txid, now));
+                    } else if htx.height > 0 {
                         // CONFIRMED: Build a proper ConfirmationBlockTime anchor.
                         //
                         // The adapter pre-fetched the block header alongside the
                         // transaction history, so it should be in the cache.
+                        // Heights the history flow didn't cover (e.g. a wallet
+                        // whose txs confirmed long before its persisted tip)
+                        // are fetched, parking the update meanwhile.
                         let h = htx.height as u32;
                         if let Some(header) = self.client.get_cached_header(h) {
-                            let anchor = bdk_wallet::chain::ConfirmationBlockTime {
-                                block_id: bdk_wallet::chain::BlockId {
-                                    height: h,
-                                    hash: header.block_hash(),
-                                },
-                                confirmation_time: header.time as u64,
-                            };
-                            self.trace(&format!(
-                                "[RUNTIME] Wallet apply tx {} anchored at height {}",
-                                txid, h
-                            ));
-                            update.tx_update.anchors.insert((anchor, txid));
+                            self.anchor_tx(&mut update, txid, h, header);
+                        } else if let Some(txids) = awaited_headers.get_mut(&h) {
+                            txids.push(txid);
+                        } else if self.client.request_block_header(h) {
+                            self.debug(&format!("[RUNTIME] Fetching historical header at height {}", h));
+                            awaited_headers.insert(h, vec![txid]);
                         } else {
-                            // Fallback: header not cached yet (shouldn't happen
-                            // if adapter pipeline is correct). Use seen_at so the
-                            // tx is still counted.
-                            log::warn!(
-                                "[RUNTIME] Missing block header for height {}; \
-                                 falling back to seen_at for tx {}",
-                                h, txid
-                            );
-                            update.tx_update.seen_ats.insert((txid, now));
+                            let header = match looked_up.get(&h) {
+                                Some(header) => *header,
+                                None => {
+                                    let header = self.lookup_header(h);
+                                    looked_up.insert(h, header);
+                                    header
+                                }
+                            };
+                            match header {
+                                Some(header) => self.anchor_tx(&mut update, txid, h, header),
+                                None => unanchored(&mut update, txid, h, now),
+                            }
                         }
+                    } else if let Some(&seen_at) = loser_seen.get(&txid) {
+                        // RBF LOSER: not refreshed, however often a history
+                        // still lists it.
+                        self.trace(&format!("[RUNTIME] Wallet apply tx {} (replaced, seen_at={})", txid, seen_at));
+                        update.tx_update.seen_ats.insert((txid, seen_at));
                     } else {
                         // UNCONFIRMED (mempool): Use seen_at timestamp.
+                        let depth = htx
+                            .tx
+                            .input
+                            .iter()
+                            .filter_map(|txin| chain_depth.get(&txin.previous_output.txid))
+                            .max()
+                            .map_or(0, |parent| parent + 1);
+                        chain_depth.insert(txid, depth);
+                        // Seen after any conflicting tx the wallet already has
+                        // or this update replaces, even within the same
+                        // second, so the newer one wins.
+                        let conflict_seen = {
+                            let sink = self.sink.lock().unwrap();
+                            htx.tx
+                                .input
+                                .iter()
+                                .flat_map(|txin| sink.spenders(txin.previous_output))
+                                .filter(|spender| *spender != txid)
+                                .filter_map(|spender| sink.last_seen(spender))
+                                .chain(
+                                    htx.tx
+                                        .input
+                                        .iter()
+                                        .filter_map(|txin| losers_by_input.get(&txin.previous_output).copied()),
+                                )
+                                .max()
+                        };
+                        let seen_at = conflict_seen.map_or(now + depth, |seen| (now + depth).max(seen + 1));
                         self.trace(&format!(
                             "[RUNTIME] Wallet apply tx {} (unconfirmed, seen_at={})",
-                            txid, now
+                            txid, seen_at
                         ));
-                        update.tx_update.seen_ats.insert((txid, now));
+                        update.tx_update.seen_ats.insert((txid, seen_at));
                     }
 
                     update.tx_update.txs.push(Arc::new(htx.tx));
+                    self.stats.lock().unwrap().txs_applied += 1;
                 }
 
-                log::debug!(
-                    "[RUNTIME] EngineCommand: Wallet applying {} txs",
-                    update.tx_update.txs.len()
-                );
-                let mut w = self.wallet.lock().unwrap();
-                let r = w.apply_update(update);
-                log::debug!(
-                    "[RUNTIME] EngineCommand: Wallet apply_update result = {:?}",
-                    r
+                // DROPPED: evicted no earlier than last seen, which is what
+                // takes it (and the outputs it created) out of the canonical
+                // view. Anchored txs stay canonical regardless.
+                for &txid in &dropped {
+                    let last_seen = self.sink.lock().unwrap().last_seen(txid);
+                    let evicted_at = last_seen.map_or(now, |seen| seen.max(now));
+                    self.trace(&format!("[RUNTIME] Wallet evict tx {} (dropped from history)", txid));
+                    update.tx_update.evicted_ats.insert((txid, evicted_at));
+                }
+
+                // Spends applied before their parents still count as spends.
+                self.add_known_prevouts(&mut update);
+
+                // The wallet measures coinbase maturity against its own chain
+                // tip, which otherwise only reaches the highest anchor applied.
+                if update.tx_update.txs.iter().any(|tx| tx.is_coinbase()) {
+                    self.connect_tip(&mut update);
+                }
+
+                // A mempool tx spending outputs the wallet has never seen
+                // would be applied without its parents; fetch them first.
+                let waiting_for = self.request_missing_parents(&update);
+                if waiting_for.is_empty() && awaited_headers.is_empty() {
+                    self.apply_wallet_update(update);
+                } else {
+                    self.debug(&format!(
+                        "[RUNTIME] Parking update until {} parent txs and {} headers arrive",
+                        waiting_for.len(),
+                        awaited_headers.len()
+                    ));
+                    self.parked_updates.push(ParkedUpdate {
+                        update,
+                        waiting_for,
+                        headers: awaited_headers,
+                        parked_at: Instant::now(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// The wallet's txs paying to or spending from the script of `hash`, as
+    /// the server would list them.
+    fn wallet_history(&self, hash: &sha256::Hash) -> Vec<HistoryTx> {
+        let Some(script) = self.engine.script_for_hash(hash) else {
+            return Vec::new();
+        };
+        let sink = self.sink.lock().unwrap();
+        let Some(wallet) = sink.wallet() else {
+            return Vec::new();
+        };
+        let graph = wallet.tx_graph();
+        wallet
+            .transactions()
+            .filter(|wtx| {
+                wtx.tx_node.tx.output.iter().any(|out| out.script_pubkey == script)
+                    || wtx.tx_node.tx.input.iter().any(|input| {
+                        graph.get_txout(input.previous_output).is_some_and(|out| out.script_pubkey == script)
+                    })
+            })
+            .map(|wtx| HistoryTx {
+                tx: (*wtx.tx_node.tx).clone(),
+                height: match wtx.chain_position {
+                    bdk_wallet::chain::ChainPosition::Confirmed { anchor, .. } => anchor.block_id.height as i32,
+                    bdk_wallet::chain::ChainPosition::Unconfirmed { .. } => 0,
+                },
+                verified: true,
+            })
+            .collect()
+    }
+
+    /// Fetches the header at `height` with the client's blocking lookup, for
+    /// clients that can't request it without blocking.
+    fn lookup_header(&mut self, height: u32) -> Option<block::Header> {
+        self.debug(&format!("[RUNTIME] Fetching historical header at height {}", height));
+        match self.client.get_block_header(height) {
+            Ok(header) => Some(header),
+            Err(e) => {
+                log::warn!("[RUNTIME] Could not fetch header at height {}: {}", height, e);
+                None
+            }
+        }
+    }
+
+    /// Anchors `txid` in the block at `height` (whose header is `header`).
+    fn anchor_tx(&mut self, update: &mut bdk_wallet::Update, txid: Txid, height: u32, header: block::Header) {
+        self.observe_tip(height, header);
+        let anchor = bdk_wallet::chain::ConfirmationBlockTime {
+            block_id: bdk_wallet::chain::BlockId { height, hash: header.block_hash() },
+            confirmation_time: header.time as u64,
+        };
+        self.trace(&format!("[RUNTIME] Wallet apply tx {} anchored at height {}", txid, height));
+        update.tx_update.anchors.insert((anchor, txid));
+
+        // The anchor only confirms the tx if its block is in the wallet's
+        // local chain, so connect it there too.
+        let chain = match update.chain.take() {
+            Some(cp) => cp,
+            None => self.sink.lock().unwrap().latest_checkpoint(),
+        };
+        update.chain = Some(chain.insert(anchor.block_id));
+    }
+
+    /// Supplies `txouts` for inputs whose parent is neither in `update` nor
+    /// in the wallet but is in the client's cache, so the wallet can compute
+    /// the amount sent (and the fee) without the full parent.
+    fn add_known_prevouts(&self, update: &mut bdk_wallet::Update) {
+        let in_update: HashSet<Txid> =
+            update.tx_update.txs.iter().map(|tx| tx.compute_txid()).collect();
+        let sink = self.sink.lock().unwrap();
+        let mut parents: HashMap<Txid, Option<Transaction>> = HashMap::new();
+
+        for tx in &update.tx_update.txs {
+            for txin in &tx.input {
+                let prevout = txin.previous_output;
+                if in_update.contains(&prevout.txid) || sink.contains_tx(prevout.txid) {
+                    continue;
+                }
+                let parent = parents
+                    .entry(prevout.txid)
+                    .or_insert_with(|| self.client.cached_transaction(&prevout.txid));
+                if let Some(txout) = parent.as_ref().and_then(|p| p.output.get(prevout.vout as usize)) {
+                    update.tx_update.txouts.insert(prevout, txout.clone());
+                }
+            }
+        }
+    }
+
+    /// Adds the current tip to `update`'s chain so the wallet knows how deep
+    /// its confirmed txs are (needed to classify coinbase outputs as immature).
+    fn connect_tip(&self, update: &mut bdk_wallet::Update) {
+        let Some(tip) = *self.tip.lock().unwrap() else {
+            return;
+        };
+        let chain = match update.chain.take() {
+            Some(cp) => cp,
+            None => self.sink.lock().unwrap().latest_checkpoint(),
+        };
+        if tip.height <= chain.height() {
+            update.chain = Some(chain);
+            return;
+        }
+        let block_id = bdk_wallet::chain::BlockId { height: tip.height, hash: tip.header.block_hash() };
+        update.chain = Some(chain.insert(block_id));
+    }
+
+    /// Requests the parents of unconfirmed txs in `update` that neither the
+    /// wallet nor the update itself contains. Returns the ones the client will deliver.
+    fn request_missing_parents(&mut self, update: &bdk_wallet::Update) -> HashSet<Txid> {
+        let in_update: HashSet<Txid> =
+            update.tx_update.txs.iter().map(|tx| tx.compute_txid()).collect();
+        let unconfirmed: HashSet<Txid> =
+            update.tx_update.seen_ats.iter().map(|(txid, _)| *txid).collect();
+
+        let missing: HashSet<Txid> = {
+            let sink = self.sink.lock().unwrap();
+            update
+                .tx_update
+                .txs
+                .iter()
+                .filter(|tx| unconfirmed.contains(&tx.compute_txid()))
+                .flat_map(|tx| tx.input.iter().map(|txin| txin.previous_output.txid))
+                .filter(|parent| !in_update.contains(parent))
+                .filter(|parent| !sink.contains_tx(*parent))
+                .collect()
+        };
+
+        missing
+            .into_iter()
+            .filter(|parent| self.client.request_transaction(*parent))
+            .collect()
+    }
+
+    /// Applies parked updates whose parents have all arrived (or timed out).
+    fn retry_parked_updates(&mut self) {
+        let mut still_parked = Vec::new();
+        for mut parked in std::mem::take(&mut self.parked_updates) {
+            let answered: Vec<(Txid, Option<Transaction>)> = parked
+                .waiting_for
+                .iter()
+                .filter_map(|txid| self.client.take_transaction(txid).map(|tx| (*txid, tx)))
+                .collect();
+            for (txid, parent) in answered {
+                parked.waiting_for.remove(&txid);
+                match parent {
+                    Some(parent) => parked.update.tx_update.txs.push(Arc::new(parent)),
+                    None => log::warn!("[RUNTIME] Parent tx {} unavailable; applying without it", txid),
+                }
+            }
+            let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+            let heights: Vec<u32> = parked.headers.keys().copied().collect();
+            for height in heights {
+                let Some(answer) = self.client.take_block_header(height) else {
+                    continue;
+                };
+                let txids = parked.headers.remove(&height).unwrap_or_default();
+                match answer {
+                    Ok(header) => {
+                        for txid in txids {
+                            self.anchor_tx(&mut parked.update, txid, height, header);
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("[RUNTIME] Could not fetch header at height {}: {}", height, e);
+                        for txid in txids {
+                            unanchored(&mut parked.update, txid, height, now);
+                        }
+                    }
+                }
+            }
+
+            if parked.waiting_for.is_empty() && parked.headers.is_empty() {
+                self.apply_wallet_update(parked.update);
+            } else if parked.parked_at.elapsed() >= PARENT_FETCH_TIMEOUT {
+                log::warn!(
+                    "[RUNTIME] {} parent txs and {} headers never arrived; applying update without them",
+                    parked.waiting_for.len(),
+                    parked.headers.len()
                 );
+                for (height, txids) in std::mem::take(&mut parked.headers) {
+                    for txid in txids {
+                        unanchored(&mut parked.update, txid, height, now);
+                    }
+                }
+                self.apply_wallet_update(parked.update);
+            } else {
+                still_parked.push(parked);
+            }
+        }
+        self.parked_updates = still_parked;
+        self.check_initial_sync_complete();
+    }
+
+    pub(crate) fn apply_wallet_update(&mut self, update: bdk_wallet::Update) {
+        // Bulk bootstrap: hold everything back for one apply at the end.
+        if let Some(bulk) = &mut self.bulk_update {
+            bulk.tx_update.extend(update.tx_update);
+            if let Some(chain) = update.chain {
+                let mut merged = match bulk.chain.take() {
+                    Some(cp) => cp,
+                    None => self.sink.lock().unwrap().latest_checkpoint(),
+                };
+                for cp in chain.iter() {
+                    merged = merged.insert(cp.block_id());
+                }
+                bulk.chain = Some(merged);
+            }
+            return;
+        }
+
+        log::debug!(
+            "[RUNTIME] EngineCommand: Wallet applying {} txs",
+            update.tx_update.txs.len()
+        );
+        let txids: Vec<Txid> = update.tx_update.txs.iter().map(|tx| tx.compute_txid()).collect();
+        // Keep a copy to split up if the batch is rejected as a whole.
+        let fallback = (txids.len() > 1).then(|| update.clone());
+        let result = self.sink.lock().unwrap().apply(update);
+        let applied = match result {
+            Ok(()) => txids,
+            Err(e) => {
+                log::error!("[RUNTIME] Wallet rejected update: {}", e);
+                let applied = match fallback {
+                    Some(update) => self.apply_individually(update),
+                    None => {
+                        if let Some(txid) = txids.first() {
+                            self.report_apply_error(*txid, &e.to_string());
+                        }
+                        vec![]
+                    }
+                };
+                if applied.is_empty() {
+                    return;
+                }
+                applied
+            }
+        };
+
+        // Observers must never see state a crash could take back.
+        self.unannounced.extend(applied);
+        if !self.persist_applied() {
+            return;
+        }
+        let applied = std::mem::take(&mut self.unannounced);
+        self.notify_payments(&applied);
+
+        self.balance_dirty = true;
+        if self.balance_notify_mode == BalanceNotifyMode::PerApply {
+            self.notify_balance_change();
+        }
+    }
+
+    /// Writes the wallet's staged changes to the store (with write-ahead on).
+    /// Returns false if that failed, so the apply must not be announced yet.
+    fn persist_applied(&mut self) -> bool {
+        let Some(store) = self.store.as_ref().filter(|_| self.write_ahead) else {
+            return true;
+        };
+        #[cfg(test)]
+        if self.failing_persists > 0 {
+            self.failing_persists -= 1;
+            log::error!("[RUNTIME] Failed to persist applied update: injected failure");
+            return false;
+        }
+        let mut sink = self.sink.lock().unwrap();
+        let Some(wallet) = sink.wallet_mut() else {
+            return true;
+        };
+        match persistence::persist_with_retry(wallet, &mut store.lock().unwrap(), &self.store_retry) {
+            Ok(_) => true,
+            Err(e) => {
+                log::error!("[RUNTIME] Failed to persist applied update: {}", e);
+                false
             }
         }
     }
 
+    /// Applies each tx of a rejected batch on its own, so one bad tx doesn't
+    /// keep the rest out. Returns the txs that were applied.
+    ///
+    /// Each tx gets a chain built from the wallet's current tip plus its own
+    /// anchor blocks. An anchor contradicting a block the wallet already has
+    /// is reported rather than reorganising the wallet's chain.
+    fn apply_individually(&mut self, update: bdk_wallet::Update) -> Vec<Txid> {
+        let tx_update = update.tx_update;
+        let mut applied = Vec::new();
+        for tx in &tx_update.txs {
+            let txid = tx.compute_txid();
+            let mut single = bdk_wallet::Update::default();
+            single.tx_update.txs.push(tx.clone());
+            single.tx_update.anchors =
+                tx_update.anchors.iter().filter(|(_, t)| *t == txid).cloned().collect();
+            single.tx_update.seen_ats =
+                tx_update.seen_ats.iter().filter(|(t, _)| *t == txid).cloned().collect();
+            single.tx_update.evicted_ats =
+                tx_update.evicted_ats.iter().filter(|(t, _)| *t == txid).cloned().collect();
+            single.tx_update.txouts = tx
+                .input
+                .iter()
+                .filter_map(|txin| {
+                    let prevout = txin.previous_output;
+                    tx_update.txouts.get(&prevout).map(|txout| (prevout, txout.clone()))
+                })
+                .collect();
+
+            let result = {
+                let mut sink = self.sink.lock().unwrap();
+                let mut chain = sink.latest_checkpoint();
+                let mut conflict = None;
+                for (anchor, _) in &single.tx_update.anchors {
+                    let block = anchor.block_id;
+                    match chain.get(block.height) {
+                        Some(cp) if cp.hash() != block.hash => {
+                            conflict = Some(format!(
+                                "anchored to block {} at height {}, but the wallet's chain has {}",
+                                block.hash,
+                                block.height,
+                                cp.hash()
+                            ));
+                        }
+                        _ => chain = chain.insert(block),
+                    }
+                }
+                match conflict {
+                    Some(reason) => Err(reason),
+                    None => {
+                        single.chain = Some(chain);
+                        sink.apply(single).map_err(|e| e.to_string())
+                    }
+                }
+            };
+            match result {
+                Ok(()) => applied.push(txid),
+                Err(reason) => self.report_apply_error(txid, &reason),
+            }
+        }
+        log::warn!(
+            "[RUNTIME] Applied {} of {} txs individually after the batch was rejected",
+            applied.len(),
+            tx_update.txs.len()
+        );
+        applied
+    }
+
+    fn report_apply_error(&self, txid: Txid, reason: &str) {
+        log::error!("[RUNTIME] Wallet rejected tx {}: {}", txid, reason);
+        if let Some(cb) = &self.on_apply_error {
+            cb(txid, reason);
+        }
+    }
+
+    /// Passes the incoming payments among `txids` that meet the alert policy
+    /// to the payment notifier. Unconfirmed ones are retried when they confirm.
+    fn notify_payments(&mut self, txids: &[Txid]) {
+        let Some(notifier) = &self.payment_notifier else {
+            return;
+        };
+        let bootstrapping = self.bootstrapping();
+        let tip = *self.tip.lock().unwrap();
+        let sink = self.sink.lock().unwrap();
+        let Some(wallet) = sink.wallet() else {
+            return;
+        };
+        let tip_height = tip.map(|t| t.height).unwrap_or_else(|| wallet.latest_checkpoint().height());
+
+        for txid in txids {
+            if self.payments_seen.contains(txid) {
+                continue;
+            }
+            let Some(wtx) = wallet.get_tx(*txid) else {
+                continue;
+            };
+            let (sent, received) = wallet.sent_and_received(&wtx.tx_node.tx);
+            let amount = SignedAmount::from_sat(received.to_sat() as i64 - sent.to_sat() as i64);
+            let incoming = amount > SignedAmount::ZERO && amount.unsigned_abs() >= self.payment_policy.min_amount;
+            if bootstrapping || !incoming {
+                self.payments_seen.insert(*txid);
+                continue;
+            }
+            let confs = match wtx.chain_position {
+                ChainPosition::Confirmed { anchor, .. } => (tip_height + 1).saturating_sub(anchor.block_id.height),
+                ChainPosition::Unconfirmed { .. } => 0,
+            };
+            if confs == 0 && !self.payment_policy.notify_unconfirmed {
+                continue;
+            }
+            self.payments_seen.insert(*txid);
+            notifier.on_payment(&wtx.tx_node.tx, amount, confs);
+        }
+    }
+
     /// Run the event loop until no more events are pending from the client.
     /// STRICTLY FOR TESTING.
     #[cfg(test)]
     pub fn run_until_idle(&mut self) {
         let mut sanity = 0;
-        // Poll continuously until the client returns None
-        while let Some(hash) = self.client.poll_scripthash_changed() {
-            self.trace(&format!("test run_until_idle: ScriptHashChanged({})", hash));
-            // In tests, we might want to bypass the Option B check if we mock the client differently,
-            // but for integration tests, this should mirror run_forever logic.
-            // For simplicity here, we assume direct processing, but ideally, copy the logic below.
-            self.process_engine(EngineEvent::ScriptHashChanged(hash));
-            
+        // Same loop body as `run_forever`, until the client returns None.
+        while self.poll_once() {
             sanity += 1;
             if sanity > 100 {
                 log::warn!("[DRIVER] run_until_idle exceeded 100 iterations, breaking");
                 break;
             }
         }
+        self.notify_balance_change();
+        self.refresh_status();
     }
 
     #[cfg(test)]
@@ -314,9 +1737,37 @@ where
     }
 }
 
+impl<K, C> SyncOrchestrator<K, C> {
+    /// Returns a handle for querying the driver from other threads.
+    pub fn handle(&self) -> DriverHandle {
+        DriverHandle {
+            wallet: self.sink.clone(),
+            store: self.store.clone(),
+            store_retry: self.store_retry,
+            inbox: self.inbox.clone(),
+            latency: self.latency.clone(),
+            tip: self.tip.clone(),
+            status: self.status.clone(),
+            replacements: self.replacements.clone(),
+            stats: self.stats.clone(),
+            dump_requests: self.dump_requests.clone(),
+        }
+    }
+}
+
+/// Counts a tx confirmed at `height` by `seen_at` instead, when the header
+/// to anchor it couldn't be fetched.
+fn unanchored(update: &mut bdk_wallet::Update, txid: Txid, height: u32, now: u64) {
+    log::warn!(
+        "[RUNTIME] Missing block header for height {}; falling back to seen_at for tx {}",
+        height, txid
+    );
+    update.tx_update.seen_ats.insert((txid, now));
+}
+
 // Helper methods for testing interaction
 #[cfg(test)]
-impl<K, C> SyncOrchestrator<K, C> {
+impl<K, C, S> SyncOrchestrator<K, C, S> {
     pub fn client_ref(&self) -> &C {
         &self.client
     }
@@ -324,4 +1775,9 @@ impl<K, C> SyncOrchestrator<K, C> {
     pub fn client_mut(&mut self) -> &mut C {
         &mut self.client
     }
+
+    /// Makes the next `count` writes of applied updates to the store fail.
+    pub fn fail_next_persists(&mut self, count: usize) {
+        self.failing_persists = count;
+    }
 }
\ No newline at end of file
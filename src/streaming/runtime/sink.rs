@@ -0,0 +1,133 @@
+use crate::streaming::runtime::orchestrator::StreamingWallet;
+
+use anyhow::Result;
+use bdk_wallet::chain::local_chain::LocalChain;
+use bdk_wallet::chain::{CheckPoint, ConfirmationBlockTime, TxGraph};
+use bdk_wallet::Update;
+use bitcoin::{Network, OutPoint, Txid};
+
+/// Where the driver applies the updates it builds from streamed histories.
+///
+/// A `PersistedWallet` is the usual sink. Consumers working with `bdk_chain`
+/// directly can use `TxGraphSink` (or their own implementation) instead; the
+/// wallet-only features (store, balance and payment notifications, balance
+/// verification) then stay inactive.
+pub trait UpdateSink {
+    fn network(&self) -> Network;
+
+    /// Tip of the sink's local chain, which anchor blocks are connected to.
+    fn latest_checkpoint(&self) -> CheckPoint;
+
+    /// Whether the sink already holds the full transaction.
+    fn contains_tx(&self, txid: Txid) -> bool;
+
+    /// When the tx was last seen unconfirmed, if ever.
+    fn last_seen(&self, txid: Txid) -> Option<u64>;
+
+    /// Txs the sink holds that spend `outpoint`.
+    fn spenders(&self, outpoint: OutPoint) -> Vec<Txid>;
+
+    /// Applies one update; `Err` means it was rejected as a whole.
+    fn apply(&mut self, update: Update) -> Result<()>;
+
+    /// The wallet behind this sink, if it is one.
+    fn wallet(&self) -> Option<&StreamingWallet> {
+        None
+    }
+
+    fn wallet_mut(&mut self) -> Option<&mut StreamingWallet> {
+        None
+    }
+}
+
+impl UpdateSink for StreamingWallet {
+    fn network(&self) -> Network {
+        bdk_wallet::Wallet::network(self)
+    }
+
+    fn latest_checkpoint(&self) -> CheckPoint {
+        bdk_wallet::Wallet::latest_checkpoint(self)
+    }
+
+    fn contains_tx(&self, txid: Txid) -> bool {
+        self.tx_graph().get_tx(txid).is_some()
+    }
+
+    fn last_seen(&self, txid: Txid) -> Option<u64> {
+        self.tx_graph().get_tx_node(txid)?.last_seen
+    }
+
+    fn spenders(&self, outpoint: OutPoint) -> Vec<Txid> {
+        self.tx_graph().outspends(outpoint).iter().copied().collect()
+    }
+
+    fn apply(&mut self, update: Update) -> Result<()> {
+        Ok(self.apply_update(update)?)
+    }
+
+    fn wallet(&self) -> Option<&StreamingWallet> {
+        Some(self)
+    }
+
+    fn wallet_mut(&mut self) -> Option<&mut StreamingWallet> {
+        Some(self)
+    }
+}
+
+/// A bare `TxGraph` and the chain its anchors point into, for consumers
+/// that don't use `bdk_wallet::Wallet`.
+#[derive(Debug)]
+pub struct TxGraphSink {
+    network: Network,
+    graph: TxGraph<ConfirmationBlockTime>,
+    chain: LocalChain,
+}
+
+impl TxGraphSink {
+    /// An empty graph on `network`'s chain.
+    pub fn new(network: Network) -> Self {
+        let genesis = bitcoin::constants::genesis_block(network).block_hash();
+        let (chain, _) = LocalChain::from_genesis_hash(genesis);
+        Self { network, graph: TxGraph::default(), chain }
+    }
+
+    pub fn graph(&self) -> &TxGraph<ConfirmationBlockTime> {
+        &self.graph
+    }
+
+    pub fn chain(&self) -> &LocalChain {
+        &self.chain
+    }
+}
+
+impl UpdateSink for TxGraphSink {
+    fn network(&self) -> Network {
+        self.network
+    }
+
+    fn latest_checkpoint(&self) -> CheckPoint {
+        self.chain.tip()
+    }
+
+    fn contains_tx(&self, txid: Txid) -> bool {
+        self.graph.get_tx(txid).is_some()
+    }
+
+    fn last_seen(&self, txid: Txid) -> Option<u64> {
+        self.graph.get_tx_node(txid)?.last_seen
+    }
+
+    fn spenders(&self, outpoint: OutPoint) -> Vec<Txid> {
+        self.graph.outspends(outpoint).iter().copied().collect()
+    }
+
+    fn apply(&mut self, update: Update) -> Result<()> {
+        // Like the wallet: the chain first, so a disconnected update leaves
+        // the graph untouched.
+        if let Some(tip) = update.chain {
+            self.chain.apply_update(tip)?;
+        }
+        let _ = self.graph.apply_update(update.tx_update);
+        Ok(())
+    }
+}
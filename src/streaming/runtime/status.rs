@@ -0,0 +1,79 @@
+use crate::streaming::electrum::api::PendingWork;
+use crate::streaming::engine::TxRelevanceCounts;
+
+use serde::Serialize;
+use std::time::Duration;
+
+/// What the streaming sync has done so far, the counterpart of the polling
+/// path's rounds (see `DriverHandle::stats`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct StreamingStats {
+    /// Scripts currently subscribed at the server.
+    pub scripts_subscribed: usize,
+    /// Script histories fed to the engine, empty ones included.
+    pub histories_processed: u64,
+    /// Txs handed to the wallet (or sink).
+    pub txs_applied: u64,
+    /// From the driver's creation to the first history listing any tx.
+    pub first_history_latency: Option<Duration>,
+    /// Txs in our histories by how they relate to our scripts (receives,
+    /// spends, data outputs); see `SyncEngine::relevance`.
+    pub tx_relevance: TxRelevanceCounts,
+    /// Unconfirmed txs currently replaced via RBF (see
+    /// `DriverHandle::replacements` for which tx won each).
+    pub replacements: usize,
+    /// History members the client failed to fetch or decode (see
+    /// `FetchError`); their histories were applied as incomplete.
+    pub fetch_errors: u64,
+}
+
+/// Why the driver is (or isn't) caught up with the server, for status UIs.
+///
+/// When several reasons apply, the most fundamental one wins: connection
+/// problems first, then histories, then the txs and headers they pull in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum SyncStatus {
+    /// Nothing pending: the wallet reflects everything the server told us.
+    CaughtUp,
+    /// Scripthash histories requested but not yet answered.
+    FetchingHistories(usize),
+    /// Transactions (history members or missing parents) still downloading.
+    DownloadingTxs(usize),
+    /// Block headers needed for confirmation anchors still downloading.
+    FetchingHeaders(usize),
+    /// The client gave up; nothing more will arrive.
+    Disconnected,
+    /// A connection is being (re-)established.
+    #[default]
+    Reconnecting,
+}
+
+impl SyncStatus {
+    /// Picks the status for the given client state; `parked_parents` are
+    /// parent txs the driver is waiting on before applying an update.
+    pub(crate) fn compute(
+        disconnected: bool,
+        connecting: bool,
+        pending: PendingWork,
+        parked_parents: usize,
+    ) -> Self {
+        let txs = pending.txs + parked_parents;
+        if disconnected {
+            SyncStatus::Disconnected
+        } else if connecting {
+            SyncStatus::Reconnecting
+        } else if pending.histories > 0 {
+            SyncStatus::FetchingHistories(pending.histories)
+        } else if txs > 0 {
+            SyncStatus::DownloadingTxs(txs)
+        } else if pending.headers > 0 {
+            SyncStatus::FetchingHeaders(pending.headers)
+        } else {
+            SyncStatus::CaughtUp
+        }
+    }
+
+    pub fn is_caught_up(&self) -> bool {
+        *self == SyncStatus::CaughtUp
+    }
+}
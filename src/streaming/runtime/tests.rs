@@ -1,116 +1,1711 @@
-#[cfg(test)]
-mod tests {
-    use crate::streaming::engine::{SyncEngine, EngineEvent}; 
-    use crate::streaming::runtime::SyncOrchestrator;
-    use crate::streaming::electrum::api::ElectrumApi;
-    use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
-    use bdk_wallet::miniscript::Descriptor;
-    use bdk_wallet::{PersistedWallet, ChangeSet, Wallet};
-    use bdk_wallet::file_store::Store;
-    use bdk_wallet::bitcoin::Network;
-    use bitcoin::{ScriptBuf, Transaction};
-    use bitcoin::hashes::{sha256, Hash};
-    use std::sync::{Arc, Mutex};
-    use std::collections::VecDeque;
-    use std::str::FromStr;
-    use std::sync::atomic::{AtomicUsize, Ordering};
-
-    // --- Mocks ---
-
-    struct MockApi {
-        pub registered: Arc<Mutex<Vec<sha256::Hash>>>,
-        pub history_requests: Arc<Mutex<Vec<sha256::Hash>>>,
-        pub notifications: VecDeque<sha256::Hash>,
-    }
-
-    impl ElectrumApi for MockApi {
-        fn register_script(&mut self, _script: ScriptBuf, hash: sha256::Hash) {
-            self.registered.lock().unwrap().push(hash);
+#![cfg(test)]
+use crate::streaming::engine::{SyncEngine, EngineEvent}; 
+use crate::streaming::runtime::{
+    BalanceNotifyMode, PaymentAlertPolicy, PaymentNotifier, SyncOrchestrator, SyncStatus, TxGraphSink,
+};
+use crate::streaming::electrum::api::{ElectrumApi, PendingWork, ScriptBalance};
+use crate::streaming::domain::spk_tracker::DerivedSpkTracker;
+use crate::streaming::domain::tip::ChainTip;
+use crate::persistence::{load_bootstrap_progress, save_tip};
+use bdk_wallet::miniscript::Descriptor;
+use bdk_wallet::{PersistedWallet, ChangeSet, Wallet};
+use bdk_wallet::file_store::Store;
+use bdk_wallet::bitcoin::Network;
+use crate::streaming::engine::types::HistoryTx;
+use bitcoin::{block, Amount, OutPoint, ScriptBuf, SignedAmount, Transaction, TxIn, TxOut, Txid};
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::absolute::LockTime;
+use bitcoin::transaction::Version;
+use bdk_wallet::KeychainKind;
+use bitcoin::hashes::{sha256, Hash};
+use std::sync::{Arc, Mutex};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const EXTERNAL_DESC: &str = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)";
+const INTERNAL_DESC: &str = "wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)";
+
+// --- Mocks ---
+
+struct MockApi {
+    pub registered: Arc<Mutex<Vec<sha256::Hash>>>,
+    pub history_requests: Arc<Mutex<Vec<sha256::Hash>>>,
+    pub notifications: VecDeque<sha256::Hash>,
+    /// Transactions `request_transaction` can deliver.
+    pub available_txs: HashMap<Txid, Transaction>,
+    pub tx_requests: Arc<Mutex<Vec<Txid>>>,
+    /// Reported as-is by `pending_work`.
+    pub pending: PendingWork,
+    pub headers: HashMap<u32, block::Header>,
+    /// Served by `get_block_header` only (never cached), or by
+    /// `take_block_header` when `requestable_headers` is set.
+    pub archived_headers: HashMap<u32, block::Header>,
+    /// Makes `request_block_header` accept requests.
+    pub requestable_headers: bool,
+    /// Heights looked up, blocking or by request.
+    pub header_requests: Arc<Mutex<Vec<u32>>>,
+    /// Returned by `cached_transaction`.
+    pub cached_txs: HashMap<Txid, Transaction>,
+    /// Reported by `get_balances` and `take_balances` (zero for anything else).
+    pub balances: HashMap<sha256::Hash, ScriptBalance>,
+    /// Makes `take_balances` report the balances as not answered yet.
+    pub balances_held: bool,
+    /// Makes `fetch_history_txs` report every history as not downloaded yet.
+    pub history_misses: bool,
+    /// Reported by `connection_epoch`.
+    pub epoch: u64,
+    /// Reported by `chain_tip`.
+    pub tip: Option<(u32, block::Header)>,
+    /// Served by `fetch_history_txs` (empty for anything else).
+    pub histories: HashMap<sha256::Hash, Vec<HistoryTx>>,
+    /// Histories `history_incomplete` reports as lacking members.
+    pub incomplete: HashSet<sha256::Hash>,
+    /// Makes `terminal_error` fail once a poll found no notification left,
+    /// so `run_forever` returns.
+    pub stop_when_drained: bool,
+    drained: bool,
+    /// Set by `shutdown`.
+    pub shut_down: Arc<AtomicBool>,
+}
+
+impl ElectrumApi for MockApi {
+    fn register_script(&mut self, _script: ScriptBuf, hash: sha256::Hash) {
+        self.registered.lock().unwrap().push(hash);
+    }
+    fn request_history(&mut self, hash: sha256::Hash) {
+        self.history_requests.lock().unwrap().push(hash);
+    }
+    fn fetch_history_txs(&mut self, hash: sha256::Hash) -> Option<Vec<HistoryTx>> {
+        if self.history_misses {
+            return None;
         }
-        fn request_history(&mut self, hash: sha256::Hash) {
-            self.history_requests.lock().unwrap().push(hash);
+        Some(self.histories.get(&hash).cloned().unwrap_or_default())
+    }
+    fn history_incomplete(&mut self, hash: sha256::Hash) -> bool {
+        self.incomplete.contains(&hash)
+    }
+    fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
+        let hash = self.notifications.pop_front();
+        self.drained |= hash.is_none();
+        hash
+    }
+    fn get_cached_header(&self, height: u32) -> Option<block::Header> {
+        self.headers.get(&height).copied()
+    }
+    fn shutdown(&mut self) {
+        self.shut_down.store(true, Ordering::SeqCst);
+    }
+    fn cached_header_heights(&self) -> Vec<u32> {
+        let mut heights: Vec<u32> = self.headers.keys().copied().collect();
+        heights.sort_unstable();
+        heights
+    }
+    fn get_block_header(&mut self, height: u32) -> anyhow::Result<block::Header> {
+        self.header_requests.lock().unwrap().push(height);
+        self.archived_headers.get(&height).copied().ok_or_else(|| anyhow::anyhow!("no header at {}", height))
+    }
+    fn request_block_header(&mut self, height: u32) -> bool {
+        if self.requestable_headers {
+            self.header_requests.lock().unwrap().push(height);
         }
-        fn fetch_history_txs(&mut self, _hash: sha256::Hash) -> Option<Vec<Transaction>> {
-            vec![].into() // Return empty for simplicity
+        self.requestable_headers
+    }
+    fn take_block_header(&mut self, height: u32) -> Option<anyhow::Result<block::Header>> {
+        self.archived_headers.get(&height).copied().map(Ok)
+    }
+    fn pending_work(&self) -> PendingWork {
+        self.pending
+    }
+    fn request_transaction(&mut self, txid: Txid) -> bool {
+        self.tx_requests.lock().unwrap().push(txid);
+        true
+    }
+    fn take_transaction(&mut self, txid: &Txid) -> Option<Option<Transaction>> {
+        Some(self.available_txs.get(txid).cloned())
+    }
+    fn cached_transaction(&self, txid: &Txid) -> Option<Transaction> {
+        self.cached_txs.get(txid).cloned()
+    }
+    fn get_balances(&mut self, hashes: &[sha256::Hash]) -> anyhow::Result<Vec<ScriptBalance>> {
+        Ok(hashes.iter().map(|h| self.balances.get(h).copied().unwrap_or_default()).collect())
+    }
+    fn request_balances(&mut self, _hashes: &[sha256::Hash]) -> bool {
+        true
+    }
+    fn take_balances(&mut self, hashes: &[sha256::Hash]) -> Option<anyhow::Result<Vec<ScriptBalance>>> {
+        (!self.balances_held).then(|| self.get_balances(hashes))
+    }
+    fn connection_epoch(&self) -> u64 {
+        self.epoch
+    }
+    fn chain_tip(&self) -> Option<(u32, block::Header)> {
+        self.tip
+    }
+    fn terminal_error(&self) -> Option<String> {
+        (self.stop_when_drained && self.drained).then(|| "drained".to_string())
+    }
+}
+
+type TestWallet = Arc<Mutex<PersistedWallet<Store<ChangeSet>>>>;
+
+// Global counter to ensure unique paths
+static TEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn dummy_wallet() -> Arc<Mutex<PersistedWallet<Store<ChangeSet>>>> {
+    dummy_wallet_with_store().0
+}
+
+/// Like `dummy_wallet`, also returning its store and the store's path.
+fn dummy_wallet_with_store() -> (TestWallet, Store<ChangeSet>, std::path::PathBuf) {
+    let mut temp_dir = std::env::temp_dir();
+    let count = TEST_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+    
+    // 1. Create a unique directory for this test
+    temp_dir.push(format!("bdk_test_driver_{}_{}", millis, count));
+    std::fs::create_dir_all(&temp_dir).expect("failed to create temp dir");
+
+    // 2. Create the DB file path INSIDE that directory
+    let mut db_path = temp_dir.clone();
+    db_path.push("bdk_store.db");
+
+    // 3. Create the store
+    let mut db = Store::<ChangeSet>::create(b"test", &db_path)
+        .expect("failed to create store");
+
+    let external = Descriptor::from_str(EXTERNAL_DESC).unwrap();
+    let internal = Descriptor::from_str(INTERNAL_DESC).unwrap();
+
+    let wallet = Wallet::create(external, internal)
+        .network(Network::Testnet)
+        .create_wallet(&mut db)
+        .expect("failed to create wallet");
+
+    (Arc::new(Mutex::new(wallet)), db, db_path)
+}
+
+fn mock_api() -> MockApi {
+    MockApi {
+        registered: Arc::new(Mutex::new(vec![])),
+        history_requests: Arc::new(Mutex::new(vec![])),
+        notifications: VecDeque::new(),
+        available_txs: HashMap::new(),
+        tx_requests: Arc::new(Mutex::new(vec![])),
+        pending: PendingWork::default(),
+        headers: HashMap::new(),
+        archived_headers: HashMap::new(),
+        requestable_headers: false,
+        header_requests: Arc::new(Mutex::new(vec![])),
+        cached_txs: HashMap::new(),
+        balances: HashMap::new(),
+        balances_held: false,
+        incomplete: HashSet::new(),
+        history_misses: false,
+        epoch: 0,
+        tip: None,
+        histories: HashMap::new(),
+        stop_when_drained: false,
+        drained: false,
+        shut_down: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+/// An engine tracking the same two keychains as `dummy_wallet`.
+fn wallet_engine() -> SyncEngine<KeychainKind> {
+    let mut tracker = DerivedSpkTracker::new(2);
+    tracker.insert_descriptor(KeychainKind::External, Descriptor::from_str(EXTERNAL_DESC).unwrap(), 0);
+    tracker.insert_descriptor(KeychainKind::Internal, Descriptor::from_str(INTERNAL_DESC).unwrap(), 0);
+    SyncEngine::new(tracker)
+}
+
+fn tx(inputs: Vec<OutPoint>, outputs: Vec<(ScriptBuf, u64)>) -> Transaction {
+    Transaction {
+        version: Version(2),
+        lock_time: LockTime::ZERO,
+        input: inputs
+            .into_iter()
+            .map(|previous_output| TxIn { previous_output, ..Default::default() })
+            .collect(),
+        output: outputs
+            .into_iter()
+            .map(|(script_pubkey, sats)| TxOut { value: Amount::from_sat(sats), script_pubkey })
+            .collect(),
+    }
+}
+
+fn unconfirmed(tx: &Transaction) -> HistoryTx {
+    HistoryTx { tx: tx.clone(), height: 0, verified: true }
+}
+
+fn spk_hash(script: &ScriptBuf) -> sha256::Hash {
+    sha256::Hash::hash(script.as_bytes())
+}
+
+/// A payment of 100k sats to external/0, and a tx spending it: 60k to a foreign
+/// script and 30k change to internal/0.
+fn fund_and_spend(
+    wallet: &Arc<Mutex<PersistedWallet<Store<ChangeSet>>>>,
+) -> (ScriptBuf, Transaction, ScriptBuf, Transaction) {
+    let w = wallet.lock().unwrap();
+    let receive = w.peek_address(KeychainKind::External, 0).script_pubkey();
+    let change = w.peek_address(KeychainKind::Internal, 0).script_pubkey();
+    drop(w);
+
+    let foreign_in = OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 };
+    let fund = tx(vec![foreign_in], vec![(receive.clone(), 100_000)]);
+    let spend = tx(
+        vec![OutPoint { txid: fund.compute_txid(), vout: 0 }],
+        vec![(ScriptBuf::new_op_return([0u8; 4]), 60_000), (change.clone(), 30_000)],
+    );
+    (receive, fund, change, spend)
+}
+
+// --- Tests ---
+
+#[test]
+fn driver_initial_bootstrap_subscribes() {
+    let tracker = DerivedSpkTracker::<String>::new(2);
+    let engine = SyncEngine::new(tracker);
+    
+    let api = MockApi {
+        registered: Arc::new(Mutex::new(vec![])),
+        history_requests: Arc::new(Mutex::new(vec![])),
+        notifications: VecDeque::new(),
+        available_txs: HashMap::new(),
+        tx_requests: Arc::new(Mutex::new(vec![])),
+        pending: PendingWork::default(),
+        headers: HashMap::new(),
+        archived_headers: HashMap::new(),
+        requestable_headers: false,
+        header_requests: Arc::new(Mutex::new(vec![])),
+        cached_txs: HashMap::new(),
+        balances: HashMap::new(),
+        balances_held: false,
+        incomplete: HashSet::new(),
+        history_misses: false,
+        epoch: 0,
+        tip: None,
+        histories: HashMap::new(),
+        stop_when_drained: false,
+        drained: false,
+        shut_down: Arc::new(AtomicBool::new(false)),
+    };
+    let registered_clone = api.registered.clone();
+
+    let mut driver = SyncOrchestrator::new(engine, api, dummy_wallet());
+
+    driver.process_engine(EngineEvent::Connected);
+
+    assert!(registered_clone.lock().unwrap().is_empty());
+}
+
+#[test]
+fn driver_processes_history_event() {
+    let tracker = DerivedSpkTracker::<String>::new(2);
+    let engine = SyncEngine::new(tracker);
+    
+    let mut api = MockApi {
+        registered: Arc::new(Mutex::new(vec![])),
+        history_requests: Arc::new(Mutex::new(vec![])),
+        notifications: VecDeque::new(),
+        available_txs: HashMap::new(),
+        tx_requests: Arc::new(Mutex::new(vec![])),
+        pending: PendingWork::default(),
+        headers: HashMap::new(),
+        archived_headers: HashMap::new(),
+        requestable_headers: false,
+        header_requests: Arc::new(Mutex::new(vec![])),
+        cached_txs: HashMap::new(),
+        balances: HashMap::new(),
+        balances_held: false,
+        incomplete: HashSet::new(),
+        history_misses: false,
+        epoch: 0,
+        tip: None,
+        histories: HashMap::new(),
+        stop_when_drained: false,
+        drained: false,
+        shut_down: Arc::new(AtomicBool::new(false)),
+    };
+    
+    let dummy_hash = sha256::Hash::all_zeros();
+    api.notifications.push_back(dummy_hash);
+    // The notification arrives before its history (Option B's cache miss).
+    api.history_misses = true;
+
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(engine, api, dummy_wallet());
+
+    driver.run_until_idle();
+
+    // We assert that the driver successfully delegated this request to the API.
+    assert!(!history_requests.lock().unwrap().is_empty(), "Driver should process the notification and request history");
+}
+
+#[test]
+fn per_apply_notifies_transient_balances() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, spend) = fund_and_spend(&wallet);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet)
+        .with_balance_change_notifier({
+            let seen = seen.clone();
+            move |b| seen.lock().unwrap().push(b.total().to_sat())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: vec![unconfirmed(&fund)] });
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&change), txs: vec![unconfirmed(&spend)] });
+    driver.run_until_idle();
+
+    assert_eq!(*seen.lock().unwrap(), vec![100_000, 30_000]);
+}
+
+#[test]
+fn after_batch_notifies_single_coherent_balance() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, spend) = fund_and_spend(&wallet);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet)
+        .with_balance_notify_mode(BalanceNotifyMode::AfterBatch)
+        .with_balance_change_notifier({
+            let seen = seen.clone();
+            move |b| seen.lock().unwrap().push(b.total().to_sat())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: vec![unconfirmed(&fund)] });
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&change), txs: vec![unconfirmed(&spend)] });
+    driver.run_until_idle();
+
+    // Only the final, consistent state is observed.
+    assert_eq!(*seen.lock().unwrap(), vec![30_000]);
+}
+
+#[test]
+fn balance_notifier_skips_applies_that_leave_the_balance_unchanged() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, _) = fund_and_spend(&wallet);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet)
+        .with_balance_change_notifier({
+            let seen = seen.clone();
+            move |b| seen.lock().unwrap().push(b.total().to_sat())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&change), txs: vec![] });
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: vec![unconfirmed(&fund)] });
+    driver.run_until_idle();
+    // The same history again is a no-op for the wallet.
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: vec![unconfirmed(&fund)] });
+    driver.run_until_idle();
+
+    assert_eq!(*seen.lock().unwrap(), vec![100_000]);
+}
+
+#[test]
+fn reveal_next_address_subscribes_new_script() {
+    let wallet = dummy_wallet();
+    // The engine watches 0..=2; reveal past that window.
+    let _ = wallet.lock().unwrap().reveal_addresses_to(KeychainKind::External, 4);
+
+    let api = mock_api();
+    let registered = api.registered.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    let handle = driver.handle();
+
+    let address = handle.reveal_next_address(KeychainKind::External).unwrap();
+    driver.run_until_idle();
+
+    assert_eq!(address, wallet.lock().unwrap().peek_address(KeychainKind::External, 5).address);
+    let registered = registered.lock().unwrap();
+    assert!(registered.contains(&spk_hash(&address.script_pubkey())));
+    // ...with a full lookahead window beyond it.
+    let seven = wallet.lock().unwrap().peek_address(KeychainKind::External, 7).script_pubkey();
+    assert!(registered.contains(&spk_hash(&seven)));
+}
+
+#[test]
+fn advance_derivation_reconverges_after_a_crash_before_the_engine_caught_up() {
+    let (wallet, store, db_path) = dummy_wallet_with_store();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone()).with_store(store);
+    let handle = driver.handle();
+
+    assert_eq!(handle.advance_derivation(KeychainKind::External, 30).unwrap(), 30);
+    // Crash: the driver never processes the advance.
+    assert!(driver.engine_mut().tracker().max_derived_index(&KeychainKind::External) < Some(30));
+    drop(driver);
+    drop(handle);
+    drop(wallet);
+
+    // Restart: the tracker is re-derived from the persisted wallet.
+    let (mut store, _) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+    let reloaded = Wallet::load().load_wallet(&mut store).unwrap().unwrap();
+    assert_eq!(reloaded.derivation_index(KeychainKind::External), Some(30));
+    let tracker = crate::persistence::tracker_for_wallet(&reloaded, 2);
+    let last = spk_hash(&reloaded.peek_address(KeychainKind::External, 30).script_pubkey());
+    let beyond = spk_hash(&reloaded.peek_address(KeychainKind::External, 32).script_pubkey());
+
+    let api = mock_api();
+    let registered = api.registered.clone();
+    let wallet = Arc::new(Mutex::new(reloaded));
+    let mut driver = SyncOrchestrator::new(SyncEngine::new(tracker), api, wallet.clone()).with_store(store);
+    driver.process_engine(EngineEvent::Connected);
+    driver.run_until_idle();
+    assert!(registered.lock().unwrap().contains(&last));
+    assert!(registered.lock().unwrap().contains(&beyond));
+
+    // Advancing to an index already revealed changes nothing.
+    assert_eq!(driver.handle().advance_derivation(KeychainKind::External, 10).unwrap(), 30);
+    driver.run_until_idle();
+    assert_eq!(driver.handle().derivation_index(KeychainKind::External), Some(30));
+}
+
+#[test]
+fn unknown_parent_of_mempool_tx_is_fetched_and_applied() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let foreign = ScriptBuf::new_op_return([1u8; 4]);
+    let parent = tx(vec![OutPoint { txid: Txid::from_byte_array([9; 32]), vout: 0 }], vec![(foreign, 60_000)]);
+    let child = tx(vec![OutPoint { txid: parent.compute_txid(), vout: 0 }], vec![(receive.clone(), 50_000)]);
+
+    let mut api = mock_api();
+    api.available_txs.insert(parent.compute_txid(), parent.clone());
+    let tx_requests = api.tx_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+
+    driver.process_engine(EngineEvent::Connected);
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: vec![unconfirmed(&child)] });
+
+    // The child is held back until its parent is in hand.
+    assert_eq!(*tx_requests.lock().unwrap(), vec![parent.compute_txid()]);
+    assert!(wallet.lock().unwrap().tx_graph().get_tx(child.compute_txid()).is_none());
+
+    driver.run_until_idle();
+
+    let w = wallet.lock().unwrap();
+    assert!(w.tx_graph().get_tx(parent.compute_txid()).is_some());
+    assert!(w.tx_graph().get_tx(child.compute_txid()).is_some());
+    assert_eq!(w.balance().total().to_sat(), 50_000);
+    assert_eq!(w.calculate_fee(&child).unwrap().to_sat(), 10_000);
+}
+
+#[test]
+fn persisted_tip_gives_confirmations_before_any_network_event() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let payment = tx(vec![OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 }], vec![(receive, 10_000)]);
+
+    // Confirmed at height 100 during a previous run.
+    let mut block = bitcoin::constants::genesis_block(Network::Testnet).header;
+    block.nonce = 100;
+    let anchor = bdk_wallet::chain::ConfirmationBlockTime {
+        block_id: bdk_wallet::chain::BlockId { height: 100, hash: block.block_hash() },
+        confirmation_time: block.time as u64,
+    };
+    {
+        let mut w = wallet.lock().unwrap();
+        let mut update = bdk_wallet::Update::default();
+        update.tx_update.txs.push(Arc::new(payment.clone()));
+        update.tx_update.anchors.insert((anchor, payment.compute_txid()));
+        update.chain = Some(w.latest_checkpoint().insert(anchor.block_id));
+        w.apply_update(update).unwrap();
+    }
+
+    let mut tip_header = block;
+    tip_header.nonce = 105;
+    let tip_path = std::env::temp_dir().join(format!(
+        "bdk_test_tip_{}_{}",
+        std::process::id(),
+        TEST_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    save_tip(&tip_path, &ChainTip { height: 105, header: tip_header }).unwrap();
+
+    let driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet).with_persisted_tip(&tip_path);
+    let handle = driver.handle();
+
+    assert_eq!(handle.tip().map(|t| t.height), Some(105));
+    assert_eq!(handle.confirmations(payment.compute_txid()), Some(6));
+    let _ = std::fs::remove_file(tip_path);
+}
+
+#[test]
+fn bulk_initial_apply_applies_bootstrap_once() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, spend) = fund_and_spend(&wallet);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    let api = mock_api();
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone())
+        .with_bulk_initial_apply(true)
+        .with_balance_change_notifier({
+            let seen = seen.clone();
+            move |b| seen.lock().unwrap().push(b.total().to_sat())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+
+    // Answer every bootstrap request, including ones for newly derived scripts.
+    loop {
+        let pending: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+        if pending.is_empty() {
+            break;
         }
-        fn poll_scripthash_changed(&mut self) -> Option<sha256::Hash> {
-            self.notifications.pop_front()
+        for hash in pending {
+            let txs = if hash == spk_hash(&receive) {
+                vec![unconfirmed(&fund)]
+            } else if hash == spk_hash(&change) {
+                vec![unconfirmed(&spend)]
+            } else {
+                vec![]
+            };
+            assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 0, "applied mid-bootstrap");
+            driver.handle_history(hash, txs);
         }
     }
+    // Let the held-back updates give up on their (foreign) parents.
+    driver.run_until_idle();
+
+    // One apply (and so one notification) with the final state.
+    assert_eq!(*seen.lock().unwrap(), vec![30_000]);
 
-    // Global counter to ensure unique paths
-    static TEST_COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // After bootstrap, events are applied as they come.
+    let late = tx(vec![OutPoint { txid: Txid::from_byte_array([8; 32]), vout: 0 }], vec![(receive.clone(), 5_000)]);
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&fund), unconfirmed(&late)]);
+    driver.run_until_idle();
+    assert_eq!(*seen.lock().unwrap(), vec![30_000, 35_000]);
+}
 
-    fn dummy_wallet() -> Arc<Mutex<PersistedWallet<Store<ChangeSet>>>> {
-        let mut temp_dir = std::env::temp_dir();
-        let count = TEST_COUNTER.fetch_add(1, Ordering::Relaxed);
-        let millis = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
-        
-        // 1. Create a unique directory for this test
-        temp_dir.push(format!("bdk_test_driver_{}_{}", millis, count));
-        std::fs::create_dir_all(&temp_dir).expect("failed to create temp dir");
+#[test]
+fn sync_status_reports_most_fundamental_pending_work() {
+    let mut api = mock_api();
+    api.pending = PendingWork { histories: 0, txs: 3, headers: 2 };
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, dummy_wallet());
+    let handle = driver.handle();
 
-        // 2. Create the DB file path INSIDE that directory
-        let mut db_path = temp_dir.clone();
-        db_path.push("bdk_store.db");
+    driver.run_until_idle();
+    assert_eq!(handle.sync_status(), SyncStatus::DownloadingTxs(3));
+    assert!(!handle.is_caught_up());
 
-        // 3. Create the store
-        let mut db = Store::<ChangeSet>::create(b"test", &db_path)
-            .expect("failed to create store");
+    driver.client_mut().pending.txs = 0;
+    driver.run_until_idle();
+    assert_eq!(handle.sync_status(), SyncStatus::FetchingHeaders(2));
 
-        let external = Descriptor::from_str("wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/0/*)").unwrap();
-        let internal = Descriptor::from_str("wpkh([73c5da0a/84h/1h/0h]tpubDC8msFGeGuwnKG9Upg7DM2b4DaRqg3CUZa5g8v2SRQ6K4NSkxUgd7HsL2XVWbVm39yBA4LAxysQAm397zwQSQoQgewGiYZqrA9DsP4zbQ1M/1/*)").unwrap();
+    driver.client_mut().pending = PendingWork { histories: 4, txs: 1, headers: 2 };
+    driver.run_until_idle();
+    assert_eq!(handle.sync_status(), SyncStatus::FetchingHistories(4));
 
-        let wallet = Wallet::create(external, internal)
-            .network(Network::Testnet)
-            .create_wallet(&mut db)
-            .expect("failed to create wallet");
+    driver.client_mut().pending = PendingWork::default();
+    driver.run_until_idle();
+    assert!(handle.is_caught_up());
+}
 
-        Arc::new(Mutex::new(wallet))
+#[test]
+fn interrupted_bootstrap_resumes_with_remaining_scripts() {
+    let (wallet, store, db_path) = dummy_wallet_with_store();
+    let progress = db_path.with_file_name("bootstrap.dat");
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let fund = tx(vec![OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+    let block = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let history = |hash: sha256::Hash| {
+        let confirmed = HistoryTx { tx: fund.clone(), height: 100, verified: true };
+        if hash == spk_hash(&receive) { vec![confirmed] } else { vec![] }
+    };
+
+    // First run: the funded script and half of the others arrive, then the
+    // process dies.
+    let mut api = mock_api();
+    api.headers.insert(100, block);
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone())
+        .with_store(store)
+        .with_bootstrap_progress(&progress);
+    driver.process_engine(EngineEvent::Connected);
+
+    let mut all: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+    all.sort();
+    let others: Vec<sha256::Hash> = all.iter().copied().filter(|h| *h != spk_hash(&receive)).collect();
+    let mut done = vec![spk_hash(&receive)];
+    done.extend(&others[..others.len() / 2]);
+    for hash in &done {
+        driver.handle_history(*hash, history(*hash));
     }
+    drop(driver);
+    assert_eq!(load_bootstrap_progress(&progress).unwrap().len(), done.len());
 
-    // --- Tests ---
+    // Restart: only the other half is fetched, plus the scripts the payment
+    // derives; the engine learns the payment from the wallet.
+    let (store, _) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+    let mut api = mock_api();
+    api.headers.insert(100, block);
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone())
+        .with_store(store)
+        .with_bootstrap_progress(&progress);
+    driver.process_engine(EngineEvent::Connected);
 
-    #[test]
-    fn driver_initial_bootstrap_subscribes() {
-        let tracker = DerivedSpkTracker::<String>::new(2);
-        let engine = SyncEngine::new(tracker);
-        
-        let api = MockApi {
-            registered: Arc::new(Mutex::new(vec![])),
-            history_requests: Arc::new(Mutex::new(vec![])),
-            notifications: VecDeque::new(),
-        };
-        let registered_clone = api.registered.clone();
+    let fetched: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+    let (known, derived): (Vec<sha256::Hash>, Vec<sha256::Hash>) = fetched.iter().partition(|h| all.contains(h));
+    let mut known = known;
+    known.sort();
+    assert_eq!(known, others[others.len() / 2..]);
+    assert!(!derived.is_empty(), "the payment extends the gap");
+    assert!(driver.engine_mut().relevance(&fund.compute_txid()).is_some());
+    assert_eq!(driver.handle().stats().txs_applied, 0, "resumed script was reapplied");
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 20_000);
 
-        let mut driver = SyncOrchestrator::new(engine, api, dummy_wallet());
+    // Finishing the bootstrap forgets the progress.
+    for hash in fetched {
+        driver.handle_history(hash, vec![]);
+    }
+    assert!(!progress.exists());
+}
 
-        driver.process_engine(EngineEvent::Connected);
+#[test]
+fn announced_chain_tip_extends_the_wallet_chain() {
+    let wallet = dummy_wallet();
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header_at = |height: u32| block::Header { nonce: height, ..genesis };
+
+    let mut api = mock_api();
+    api.tip = Some((100, header_at(100)));
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.run_until_idle();
+    assert_eq!(driver.handle().tip().map(|t| t.height), Some(100));
+    let checkpoint = wallet.lock().unwrap().latest_checkpoint();
+    assert_eq!((checkpoint.height(), checkpoint.hash()), (100, header_at(100).block_hash()));
+
+    // The next block moves both along.
+    driver.client_mut().tip = Some((101, header_at(101)));
+    driver.run_until_idle();
+    assert_eq!(driver.handle().tip().map(|t| t.height), Some(101));
+    assert_eq!(wallet.lock().unwrap().latest_checkpoint().height(), 101);
+}
+
+#[test]
+fn resync_after_a_reorg_moves_the_anchor_to_the_new_block() {
+    let wallet = dummy_wallet();
+    let (receive, fund, _, _) = fund_and_spend(&wallet);
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let (old_block, new_block) = (block::Header { nonce: 1, ..genesis }, block::Header { nonce: 2, ..genesis });
+    let confirmed = HistoryTx { tx: fund.clone(), height: 100, verified: true };
+
+    let mut api = mock_api();
+    api.headers.insert(100, old_block);
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(spk_hash(&receive), vec![confirmed.clone()]);
+
+    // The client evicted the old header and re-synced the scripthash.
+    driver.client_mut().headers.insert(100, new_block);
+    driver.handle_history(spk_hash(&receive), vec![confirmed]);
+
+    let w = wallet.lock().unwrap();
+    match w.get_tx(fund.compute_txid()).unwrap().chain_position {
+        bdk_wallet::chain::ChainPosition::Confirmed { anchor, .. } => {
+            assert_eq!(anchor.block_id.hash, new_block.block_hash());
+        }
+        other => panic!("expected confirmed, got {:?}", other),
+    }
+    assert_eq!(w.local_chain().get(100).map(|cp| cp.hash()), Some(new_block.block_hash()));
+}
+
+#[test]
+fn spendable_balance_counts_only_sufficiently_confirmed_utxos() {
+    let wallet = dummy_wallet();
+    let (recent, old) = {
+        let w = wallet.lock().unwrap();
+        (w.peek_address(KeychainKind::External, 0).script_pubkey(), w.peek_address(KeychainKind::External, 1).script_pubkey())
+    };
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header_at = |height: u32| block::Header { nonce: height, ..genesis };
+
+    // 6 confirmations at tip 100, and 1.
+    {
+        let mut w = wallet.lock().unwrap();
+        let mut update = bdk_wallet::Update::default();
+        let mut chain = w.latest_checkpoint();
+        for (i, (script, height, sats)) in [(old, 95, 60_000), (recent, 100, 1_000)].into_iter().enumerate() {
+            let payment = tx(vec![OutPoint { txid: Txid::from_byte_array([i as u8 + 1; 32]), vout: 0 }], vec![(script, sats)]);
+            let header = header_at(height);
+            let anchor = bdk_wallet::chain::ConfirmationBlockTime {
+                block_id: bdk_wallet::chain::BlockId { height, hash: header.block_hash() },
+                confirmation_time: header.time as u64,
+            };
+            update.tx_update.anchors.insert((anchor, payment.compute_txid()));
+            update.tx_update.txs.push(Arc::new(payment));
+            chain = chain.insert(anchor.block_id);
+        }
+        update.chain = Some(chain);
+        w.apply_update(update).unwrap();
+    }
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet);
+    driver.observe_tip(100, header_at(100));
+    let handle = driver.handle();
 
-        assert!(registered_clone.lock().unwrap().is_empty());
+    assert_eq!(handle.spendable_balance(3), Amount::from_sat(60_000));
+    assert_eq!(handle.spendable_balance(1), Amount::from_sat(61_000));
+    assert_eq!(handle.spendable_balance(7), Amount::ZERO);
+}
+
+#[test]
+fn coinbase_is_immature_until_one_hundred_confirmations() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let coinbase = |height: u32, sats: u64| {
+        let mut cb = tx(vec![OutPoint::null()], vec![(receive.clone(), sats)]);
+        cb.lock_time = LockTime::from_height(height).unwrap();
+        cb
+    };
+    let (young, old) = (coinbase(100, 50_000), coinbase(40, 7_000));
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header_at = |height: u32| block::Header { nonce: height, ..genesis };
+    let mut api = mock_api();
+    api.headers.insert(100, header_at(100));
+    api.headers.insert(40, header_at(40));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.observe_tip(149, header_at(149));
+    let handle = driver.handle();
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(
+        spk_hash(&receive),
+        vec![HistoryTx { tx: old, height: 40, verified: true }, HistoryTx { tx: young, height: 100, verified: true }],
+    );
+
+    // 50 confirmations: immature; 110: spendable.
+    let balance = wallet.lock().unwrap().balance();
+    assert_eq!(balance.immature.to_sat(), 50_000);
+    assert_eq!(balance.confirmed.to_sat(), 7_000);
+    assert_eq!(handle.spendable_balance(1), Amount::from_sat(7_000));
+}
+
+#[test]
+fn confirmed_tx_connects_its_anchor_block_to_the_local_chain() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let paid = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header = block::Header { nonce: 100, ..genesis };
+    let mut api = mock_api();
+    api.headers.insert(100, header);
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    // No tip was ever announced: only the anchor can put block 100 in the chain.
+    driver.handle_history(spk_hash(&receive), vec![HistoryTx { tx: paid.clone(), height: 100, verified: true }]);
+
+    let wallet = wallet.lock().unwrap();
+    assert_eq!(wallet.local_chain().get(100).map(|cp| cp.hash()), Some(header.block_hash()));
+    match wallet.get_tx(paid.compute_txid()).unwrap().chain_position {
+        bdk_wallet::chain::ChainPosition::Confirmed { anchor, .. } => assert_eq!(anchor.block_id.height, 100),
+        other => panic!("not confirmed: {:?}", other),
     }
+    assert_eq!(wallet.balance().confirmed.to_sat(), 20_000);
+}
+
+#[test]
+fn tx_confirmed_far_below_the_tip_is_anchored_by_a_fetched_header() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let change = wallet.lock().unwrap().peek_address(KeychainKind::Internal, 0).script_pubkey();
+    let recent = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+    let ancient = tx(vec![OutPoint { txid: Txid::from_byte_array([2; 32]), vout: 0 }], vec![(change.clone(), 5_000)]);
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header_at = |height: u32| block::Header { nonce: height, ..genesis };
+    let mut api = mock_api();
+    api.headers.insert(800_000, header_at(800_000));
+    // The old block is only available on request, like a height the
+    // adapter's history flow never cached.
+    api.archived_headers.insert(1_000, header_at(1_000));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(spk_hash(&receive), vec![HistoryTx { tx: recent, height: 800_000, verified: true }]);
+    driver.handle_history(spk_hash(&change), vec![HistoryTx { tx: ancient.clone(), height: 1_000, verified: true }]);
+
+    let wallet = wallet.lock().unwrap();
+    let old_block = wallet.local_chain().get(1_000).expect("historical block connected");
+    assert_eq!(old_block.hash(), header_at(1_000).block_hash());
+    assert_eq!(wallet.latest_checkpoint().height(), 800_000);
+    let canonical = wallet.get_tx(ancient.compute_txid()).unwrap();
+    assert!(canonical.chain_position.is_confirmed());
+    assert_eq!(wallet.balance().confirmed.to_sat(), 25_000);
+}
+
+#[test]
+fn unreachable_header_is_looked_up_once_per_update() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let first = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+    let second = tx(vec![OutPoint { txid: Txid::from_byte_array([2; 32]), vout: 0 }], vec![(receive.clone(), 5_000)]);
+
+    let api = mock_api();
+    let header_requests = api.header_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(
+        spk_hash(&receive),
+        vec![
+            HistoryTx { tx: first.clone(), height: 1_000, verified: true },
+            HistoryTx { tx: second.clone(), height: 1_000, verified: true },
+        ],
+    );
+
+    // Let the (now unconfirmed) txs give up on their foreign parents.
+    driver.run_until_idle();
 
-    #[test]
-    fn driver_processes_history_event() {
-        let tracker = DerivedSpkTracker::<String>::new(2);
-        let engine = SyncEngine::new(tracker);
-        
-        let mut api = MockApi {
-            registered: Arc::new(Mutex::new(vec![])),
-            history_requests: Arc::new(Mutex::new(vec![])),
-            notifications: VecDeque::new(),
+    assert_eq!(*header_requests.lock().unwrap(), vec![1_000]);
+    // Both still counted, unconfirmed.
+    let wallet = wallet.lock().unwrap();
+    assert!(!wallet.get_tx(first.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert!(!wallet.get_tx(second.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert_eq!(wallet.balance().total().to_sat(), 25_000);
+}
+
+#[test]
+fn update_is_parked_until_a_requested_header_arrives() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let first = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+    let second = tx(vec![OutPoint { txid: Txid::from_byte_array([2; 32]), vout: 0 }], vec![(receive.clone(), 5_000)]);
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header = block::Header { nonce: 1_000, ..genesis };
+    let mut api = mock_api();
+    api.requestable_headers = true;
+    let header_requests = api.header_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(
+        spk_hash(&receive),
+        vec![
+            HistoryTx { tx: first.clone(), height: 1_000, verified: true },
+            HistoryTx { tx: second.clone(), height: 1_000, verified: true },
+        ],
+    );
+
+    // One request for the height, and nothing applied while it's out.
+    assert_eq!(*header_requests.lock().unwrap(), vec![1_000]);
+    assert!(wallet.lock().unwrap().get_tx(first.compute_txid()).is_none());
+
+    driver.client_mut().archived_headers.insert(1_000, header);
+    driver.run_until_idle();
+
+    let wallet = wallet.lock().unwrap();
+    assert_eq!(wallet.local_chain().get(1_000).map(|cp| cp.hash()), Some(header.block_hash()));
+    assert!(wallet.get_tx(first.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert!(wallet.get_tx(second.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert_eq!(wallet.balance().confirmed.to_sat(), 25_000);
+    assert_eq!(*header_requests.lock().unwrap(), vec![1_000]);
+}
+
+#[test]
+fn unverified_confirmation_is_not_anchored() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let proven = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+    // Spends `proven`, so as a mempool tx its parent is already known.
+    let unproven = tx(vec![OutPoint { txid: proven.compute_txid(), vout: 0 }], vec![(receive.clone(), 15_000)]);
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let mut api = mock_api();
+    api.headers.insert(100, block::Header { nonce: 100, ..genesis });
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(
+        spk_hash(&receive),
+        vec![
+            HistoryTx { tx: proven.clone(), height: 100, verified: true },
+            HistoryTx { tx: unproven.clone(), height: 100, verified: false },
+        ],
+    );
+
+    let wallet = wallet.lock().unwrap();
+    assert!(wallet.get_tx(proven.compute_txid()).unwrap().chain_position.is_confirmed());
+    // Still applied, but only as unconfirmed.
+    assert!(!wallet.get_tx(unproven.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert_eq!(wallet.balance().confirmed.to_sat(), 0);
+    assert_eq!(wallet.balance().total().to_sat(), 15_000);
+}
+
+/// Records every payment it is told about.
+struct StubNotifier(Arc<Mutex<Vec<(Txid, i64, u32)>>>);
+
+impl PaymentNotifier for StubNotifier {
+    fn on_payment(&self, tx: &Transaction, amount: SignedAmount, confs: u32) {
+        self.0.lock().unwrap().push((tx.compute_txid(), amount.to_sat(), confs));
+    }
+}
+
+#[test]
+fn payment_notifier_fires_only_above_threshold() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let big = tx(vec![OutPoint { txid: Txid::from_byte_array([1; 32]), vout: 0 }], vec![(receive.clone(), 100_000)]);
+    let dust = tx(vec![OutPoint { txid: Txid::from_byte_array([2; 32]), vout: 0 }], vec![(receive.clone(), 500)]);
+
+    let payments = Arc::new(Mutex::new(Vec::new()));
+    let policy = PaymentAlertPolicy { min_amount: Amount::from_sat(1_000), notify_unconfirmed: true };
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet)
+        .with_payment_notifier(StubNotifier(payments.clone()), policy);
+    driver.process_engine(EngineEvent::Connected);
+
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&big), unconfirmed(&dust)]);
+    driver.run_until_idle();
+    assert_eq!(*payments.lock().unwrap(), vec![(big.compute_txid(), 100_000, 0)]);
+
+    // Seeing the same history again doesn't repeat the alert.
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&big), unconfirmed(&dust)]);
+    assert_eq!(payments.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn spend_applied_before_its_parent_uses_cached_prevout() {
+    let wallet = dummy_wallet();
+    let (_, fund, change, spend) = fund_and_spend(&wallet);
+    let header = block::Header { nonce: 100, ..bitcoin::constants::genesis_block(Network::Testnet).header };
+
+    // The funding tx was downloaded but its history hasn't been applied yet.
+    let mut api = mock_api();
+    api.headers.insert(100, header);
+    api.cached_txs.insert(fund.compute_txid(), fund.clone());
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+
+    driver.handle_history(spk_hash(&change), vec![HistoryTx { tx: spend.clone(), height: 100, verified: true }]);
+
+    let w = wallet.lock().unwrap();
+    assert!(w.tx_graph().get_tx(fund.compute_txid()).is_none());
+    let (sent, received) = w.sent_and_received(&spend);
+    assert_eq!((sent.to_sat(), received.to_sat()), (100_000, 30_000));
+    assert_eq!(w.calculate_fee(&spend).unwrap().to_sat(), 10_000);
+}
+
+#[test]
+fn rejected_batch_is_retried_per_tx_and_only_the_bad_one_reported() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let block_id = |height: u32, nonce: u32| bdk_wallet::chain::BlockId {
+        height,
+        hash: block::Header { nonce, ..genesis }.block_hash(),
+    };
+    let anchor = |block_id| bdk_wallet::chain::ConfirmationBlockTime { block_id, confirmation_time: 0 };
+
+    // The wallet already knows blocks 80 and 100.
+    let genesis_cp = wallet.lock().unwrap().latest_checkpoint();
+    {
+        let chain = genesis_cp.clone().insert(block_id(80, 80)).insert(block_id(100, 100));
+        let update = bdk_wallet::Update { chain: Some(chain), ..Default::default() };
+        wallet.lock().unwrap().apply_update(update).unwrap();
+    }
+
+    // A batch built on a stale view of the chain (no block 80), whose middle
+    // tx claims a different block at height 100.
+    let payments: Vec<Transaction> = (1..=3u8)
+        .map(|i| tx(vec![OutPoint { txid: Txid::from_byte_array([i; 32]), vout: 0 }], vec![(receive.clone(), 1_000 * i as u64)]))
+        .collect();
+    let blocks = [block_id(90, 90), block_id(100, 999), block_id(110, 110)];
+    let mut batch = bdk_wallet::Update::default();
+    let mut chain = genesis_cp;
+    for (payment, block) in payments.iter().zip(blocks) {
+        batch.tx_update.txs.push(Arc::new(payment.clone()));
+        batch.tx_update.anchors.insert((anchor(block), payment.compute_txid()));
+        chain = chain.insert(block);
+    }
+    batch.chain = Some(chain);
+    let mut local_chain = wallet.lock().unwrap().local_chain().clone();
+    assert!(local_chain.apply_update(batch.chain.clone().unwrap()).is_err(), "the batch must fail as a whole");
+
+    let rejected = Arc::new(Mutex::new(Vec::new()));
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone())
+        .with_apply_error_notifier({
+            let rejected = rejected.clone();
+            move |txid, _reason| rejected.lock().unwrap().push(txid)
+        });
+    driver.apply_wallet_update(batch);
+
+    let w = wallet.lock().unwrap();
+    assert!(w.get_tx(payments[0].compute_txid()).is_some());
+    assert!(w.get_tx(payments[1].compute_txid()).is_none());
+    assert!(w.get_tx(payments[2].compute_txid()).is_some());
+    assert_eq!(*rejected.lock().unwrap(), vec![payments[1].compute_txid()]);
+    assert_eq!(w.balance().confirmed.to_sat(), 4_000);
+}
+
+#[test]
+fn rbf_replacement_becomes_canonical_and_is_reported() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let foreign_in = OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 };
+    let original = tx(vec![foreign_in], vec![(receive.clone(), 50_000)]);
+    // The payer bumped the fee out of the payment.
+    let replacement = tx(vec![foreign_in], vec![(receive.clone(), 44_000)]);
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    let handle = driver.handle();
+    driver.process_engine(EngineEvent::Connected);
+
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&original)]);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 50_000);
+
+    // While the replacement propagates the server lists both.
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&original), unconfirmed(&replacement)]);
+    driver.run_until_idle();
+
+    {
+        let w = wallet.lock().unwrap();
+        assert_eq!(w.balance().total().to_sat(), 44_000);
+        let canonical: Vec<Txid> = w.transactions().map(|t| t.tx_node.txid).collect();
+        assert_eq!(canonical, vec![replacement.compute_txid()]);
+        // Both are kept, the replacement seen last.
+        let last_seen = |txid| w.tx_graph().get_tx_node(txid).unwrap().last_seen.unwrap();
+        assert!(last_seen(replacement.compute_txid()) > last_seen(original.compute_txid()));
+    }
+    assert_eq!(
+        handle.replacements(),
+        [(original.compute_txid(), replacement.compute_txid())].into_iter().collect()
+    );
+    assert_eq!(handle.stats().replacements, 1);
+
+    // Once the replacement confirms, the conflict is settled and forgotten.
+    let block = bitcoin::constants::genesis_block(Network::Testnet).header;
+    driver.client_mut().headers.insert(100, block);
+    let confirmed = HistoryTx { tx: replacement.clone(), height: 100, verified: true };
+    driver.handle_history(spk_hash(&receive), vec![confirmed]);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().confirmed.to_sat(), 44_000);
+    assert!(handle.replacements().is_empty());
+    assert_eq!(handle.stats().replacements, 0);
+}
+
+#[test]
+fn unconfirmed_tx_replaced_out_of_its_history_is_evicted_and_reported() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let foreign_in = OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 };
+    let original = tx(vec![foreign_in], vec![(receive.clone(), 50_000)]);
+    let replacement = tx(vec![foreign_in], vec![(receive.clone(), 44_000)]);
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    let handle = driver.handle();
+    driver.process_engine(EngineEvent::Connected);
+
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&original)]);
+    driver.run_until_idle();
+
+    // The original is already gone from the server's mempool when we next look.
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&replacement)]);
+    driver.run_until_idle();
+
+    let w = wallet.lock().unwrap();
+    assert_eq!(w.balance().total().to_sat(), 44_000);
+    let canonical: Vec<Txid> = w.transactions().map(|t| t.tx_node.txid).collect();
+    assert_eq!(canonical, vec![replacement.compute_txid()]);
+    assert_eq!(
+        handle.replacements(),
+        [(original.compute_txid(), replacement.compute_txid())].into_iter().collect()
+    );
+}
+
+#[test]
+fn later_of_two_conflicting_unconfirmed_txs_wins_within_the_same_second() {
+    let foreign_in = OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 };
+    // Conflicting payments to two different scripts, so no single history
+    // lists both; either order must leave the later one canonical.
+    for later_first in [false, true] {
+        let wallet = dummy_wallet();
+        let (a, b) = {
+            let w = wallet.lock().unwrap();
+            (w.peek_address(KeychainKind::External, 0).script_pubkey(), w.peek_address(KeychainKind::External, 1).script_pubkey())
         };
-        
-        let dummy_hash = sha256::Hash::all_zeros();
-        api.notifications.push_back(dummy_hash);
+        let mut payments = [tx(vec![foreign_in], vec![(a.clone(), 50_000)]), tx(vec![foreign_in], vec![(b.clone(), 40_000)])];
+        if later_first {
+            payments.reverse();
+        }
+        let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+        driver.process_engine(EngineEvent::Connected);
 
-        let history_requests = api.history_requests.clone();
-        let mut driver = SyncOrchestrator::new(engine, api, dummy_wallet());
+        for payment in &payments {
+            let paid = &payment.output[0].script_pubkey;
+            driver.handle_history(spk_hash(paid), vec![unconfirmed(payment)]);
+            driver.run_until_idle();
+        }
+
+        let w = wallet.lock().unwrap();
+        let canonical: Vec<Txid> = w.transactions().map(|t| t.tx_node.txid).collect();
+        assert_eq!(canonical, vec![payments[1].compute_txid()]);
+    }
+}
+
+#[test]
+fn txs_dropped_from_a_history_leave_the_balance() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let first = tx(vec![OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 }], vec![(receive.clone(), 50_000)]);
+    let second = tx(vec![OutPoint { txid: Txid::from_byte_array([6; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&first), unconfirmed(&second)]);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 70_000);
+
+    // Both fell out of the mempool (e.g. expired) within the same second.
+    driver.handle_history(spk_hash(&receive), vec![]);
+    driver.run_until_idle();
+
+    let w = wallet.lock().unwrap();
+    assert_eq!(w.balance().total().to_sat(), 0);
+    assert_eq!(w.transactions().count(), 0);
+}
+
+#[test]
+fn incomplete_refetch_keeps_the_txs_it_lacks_in_the_balance() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let hash = spk_hash(&receive);
+    let first = tx(vec![OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 }], vec![(receive.clone(), 50_000)]);
+    let second = tx(vec![OutPoint { txid: Txid::from_byte_array([6; 32]), vout: 0 }], vec![(receive.clone(), 20_000)]);
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    driver.client_mut().histories.insert(hash, vec![unconfirmed(&first), unconfirmed(&second)]);
+    driver.on_scripthash_changed(hash);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 70_000);
+
+    // The refetch couldn't download `second`: the client flags the history.
+    driver.client_mut().histories.insert(hash, vec![unconfirmed(&first)]);
+    driver.client_mut().incomplete.insert(hash);
+    driver.on_scripthash_changed(hash);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 70_000);
+
+    // A complete history without it does drop it.
+    driver.client_mut().incomplete.clear();
+    driver.on_scripthash_changed(hash);
+    driver.run_until_idle();
+    assert_eq!(wallet.lock().unwrap().balance().total().to_sat(), 50_000);
+}
+
+#[test]
+fn stats_count_subscriptions_histories_and_applied_txs() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, spend) = fund_and_spend(&wallet);
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    let handle = driver.handle();
+    driver.process_engine(EngineEvent::Connected);
+    // Lookahead 2 on both keychains.
+    assert_eq!(handle.stats().scripts_subscribed, 6);
+    assert_eq!(handle.stats().first_history_latency, None);
+
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&fund)]);
+    driver.handle_history(spk_hash(&change), vec![unconfirmed(&fund), unconfirmed(&spend)]);
+    let empty = wallet.lock().unwrap().peek_address(KeychainKind::External, 1).script_pubkey();
+    driver.handle_history(spk_hash(&empty), vec![]);
+
+    let stats = handle.stats();
+    assert_eq!(stats.histories_processed, 3);
+    // `fund` is in both histories but applied once.
+    assert_eq!(stats.txs_applied, 2);
+    // Each used index extends its keychain's window by one.
+    assert_eq!(stats.scripts_subscribed, 8);
+    assert!(stats.first_history_latency.is_some());
+}
+
+#[test]
+fn applied_txs_are_durable_before_observers_hear_of_them() {
+    let (wallet, store, db_path) = dummy_wallet_with_store();
+    let (receive, fund, _, _) = fund_and_spend(&wallet);
+    let fund_txid = fund.compute_txid();
+    let durable_at_notify = Arc::new(Mutex::new(Vec::new()));
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone())
+        .with_store(store)
+        .with_balance_change_notifier({
+            let durable_at_notify = durable_at_notify.clone();
+            let db_path = db_path.clone();
+            move |_| {
+                let (_, changeset) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+                let durable = changeset.unwrap().tx_graph.txs.iter().any(|tx| tx.compute_txid() == fund_txid);
+                durable_at_notify.lock().unwrap().push(durable);
+            }
+        });
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&fund)]);
+    driver.run_until_idle();
+    assert_eq!(*durable_at_notify.lock().unwrap(), vec![true]);
+
+    // Crash: nothing persists the wallet again.
+    drop(driver);
+    drop(wallet);
 
+    let (mut store, _) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+    let reloaded = Wallet::load().load_wallet(&mut store).unwrap().unwrap();
+    assert!(reloaded.get_tx(fund_txid).is_some());
+    assert_eq!(reloaded.balance().total().to_sat(), 100_000);
+}
+
+#[test]
+fn applies_that_failed_to_persist_are_announced_after_the_next_write() {
+    let (wallet, store, db_path) = dummy_wallet_with_store();
+    let (receive, fund, _, _) = fund_and_spend(&wallet);
+    let more = tx(vec![OutPoint { txid: Txid::from_byte_array([9; 32]), vout: 0 }], vec![(receive.clone(), 5_000)]);
+    let stored = || {
+        let (_, changeset) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+        changeset.unwrap().tx_graph.txs.iter().map(|tx| tx.compute_txid()).collect::<Vec<_>>()
+    };
+    let balances = Arc::new(Mutex::new(Vec::new()));
+    let payments = Arc::new(Mutex::new(Vec::new()));
+    let policy = PaymentAlertPolicy { min_amount: Amount::from_sat(1_000), notify_unconfirmed: true };
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone())
+        .with_store(store)
+        .with_balance_notify_mode(BalanceNotifyMode::AfterBatch)
+        .with_balance_change_notifier({
+            let balances = balances.clone();
+            move |b| balances.lock().unwrap().push(b.total().to_sat())
+        })
+        .with_payment_notifier(StubNotifier(payments.clone()), policy);
+    driver.process_engine(EngineEvent::Connected);
+
+    // The write fails: the apply stands, but nobody hears of it.
+    driver.fail_next_persists(1);
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&fund)]);
+    driver.run_until_idle();
+    assert!(wallet.lock().unwrap().get_tx(fund.compute_txid()).is_some());
+    assert!(!stored().contains(&fund.compute_txid()));
+    assert!(balances.lock().unwrap().is_empty());
+    assert!(payments.lock().unwrap().is_empty());
+
+    // The next apply is written along with it, and both are announced.
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&fund), unconfirmed(&more)]);
+    driver.run_until_idle();
+    assert!(stored().contains(&fund.compute_txid()) && stored().contains(&more.compute_txid()));
+    assert_eq!(*balances.lock().unwrap(), vec![105_000]);
+    assert_eq!(
+        *payments.lock().unwrap(),
+        vec![(fund.compute_txid(), 100_000, 0), (more.compute_txid(), 5_000, 0)]
+    );
+}
+
+#[test]
+fn state_dump_captures_engine_client_and_wallet_state() {
+    let wallet = dummy_wallet();
+    let (receive, fund, _, _) = fund_and_spend(&wallet);
+    let header = block::Header { nonce: 100, ..bitcoin::constants::genesis_block(Network::Testnet).header };
+
+    let mut api = mock_api();
+    api.headers.insert(100, header);
+    api.pending = PendingWork { histories: 0, txs: 0, headers: 1 };
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet);
+    let handle = driver.handle();
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(spk_hash(&receive), vec![HistoryTx { tx: fund.clone(), height: 100, verified: true }]);
+
+    // The dump is answered by the driver loop.
+    let dumper = std::thread::spawn(move || handle.dump_state());
+    while !dumper.is_finished() {
         driver.run_until_idle();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+    let dump = dumper.join().unwrap().unwrap();
 
-        // FIX: The current engine implementation is greedy and requests history for any notification.
-        // We assert that the driver successfully delegated this request to the API.
-        assert!(!history_requests.lock().unwrap().is_empty(), "Driver should process the notification and request history");
+    let receive_wire = crate::streaming::util::scripthash_to_wire(&spk_hash(&receive));
+    // external/0 used: 0..=3 external, 0..=2 internal.
+    assert_eq!(dump.scripts.len(), 7);
+    let first = &dump.scripts[0];
+    assert_eq!((first.keychain.as_str(), first.index), ("External", 0));
+    assert_eq!(first.address, "tb1q6rz28mcfaxtmd6v789l9rrlrusdprr9pqcpvkl");
+    assert_eq!(first.scripthash, receive_wire);
+    assert_eq!(dump.subscribed.len(), 7);
+    assert_eq!(dump.histories[&receive_wire], vec![fund.compute_txid().to_string()]);
+    assert_eq!(dump.cached_headers, vec![100]);
+    assert_eq!(dump.tip.as_ref().map(|t| t.height), Some(100));
+    assert_eq!(dump.wallet_tip_height, 100);
+    assert_eq!(dump.balance_sat, 100_000);
+    assert_eq!(dump.status, SyncStatus::FetchingHeaders(1));
+    assert_eq!(dump.pending.client.headers, 1);
+
+    let json = serde_json::to_value(&dump).unwrap();
+    for field in ["scripts", "subscribed", "histories", "pending", "cached_headers", "tip", "status", "latency"] {
+        assert!(json.get(field).is_some(), "missing {}", field);
+    }
+}
+
+#[test]
+fn balance_verification_reports_scripts_the_wallet_disagrees_on() {
+    let wallet = dummy_wallet();
+    let (receive, fund, change, _) = fund_and_spend(&wallet);
+    let mut api = mock_api();
+    api.balances.insert(spk_hash(&receive), ScriptBalance { confirmed: 0, unconfirmed: 100_000 });
+    // The server knows of 25k on the change script that the wallet never saw.
+    api.balances.insert(spk_hash(&change), ScriptBalance { confirmed: 25_000, unconfirmed: 0 });
+    api.balances_held = true;
+    let history_requests = api.history_requests.clone();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone())
+        .with_balance_verification({
+            let events = events.clone();
+            move |check| events.lock().unwrap().push(format!("{}", check.unwrap()))
+        })
+        .with_initial_sync_notifier({
+            let events = events.clone();
+            move || events.lock().unwrap().push("synced".to_string())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+    loop {
+        let pending: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+        if pending.is_empty() {
+            break;
+        }
+        for hash in pending {
+            let txs = if hash == spk_hash(&receive) { vec![unconfirmed(&fund)] } else { vec![] };
+            driver.handle_history(hash, txs);
+        }
     }
-}
\ No newline at end of file
+    // The check waits for the funding tx, which is held back for its parent.
+    assert!(events.lock().unwrap().is_empty());
+    driver.run_until_idle();
+    // The balances are requested without blocking; the driver keeps polling until they arrive.
+    assert!(events.lock().unwrap().is_empty());
+    driver.client_mut().balances_held = false;
+    driver.run_until_idle();
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[1], "synced");
+    let report = &events[0];
+    assert!(report.starts_with("server 125000 sat / wallet 100000 sat (1 scripts differ)"), "{}", report);
+    assert!(report.contains(&crate::streaming::util::scripthash_to_wire(&spk_hash(&change))));
+    assert!(!report.contains(&crate::streaming::util::scripthash_to_wire(&spk_hash(&receive))));
+}
+
+/// Replays one canned full scan, like an Electrum server would answer it.
+struct CannedScan(Mutex<Option<bdk_wallet::chain::spk_client::FullScanResponse<KeychainKind>>>);
+
+impl crate::polling::FullScanClient for CannedScan {
+    fn full_scan(
+        &self,
+        _request: bdk_wallet::chain::spk_client::FullScanRequest<KeychainKind>,
+        _stop_gap: usize,
+        _batch_size: usize,
+    ) -> anyhow::Result<bdk_wallet::chain::spk_client::FullScanResponse<KeychainKind>> {
+        Ok(self.0.lock().unwrap().take().unwrap_or_default())
+    }
+}
+
+#[test]
+fn streaming_matches_polling_balance_after_self_sends_with_change() {
+    let streaming_wallet = dummy_wallet();
+    let polling_wallet = dummy_wallet();
+    let w = streaming_wallet.lock().unwrap();
+    let (ext0, ext1) = (w.peek_address(KeychainKind::External, 0).script_pubkey(), w.peek_address(KeychainKind::External, 1).script_pubkey());
+    let (int0, int1) = (w.peek_address(KeychainKind::Internal, 0).script_pubkey(), w.peek_address(KeychainKind::Internal, 1).script_pubkey());
+    drop(w);
+
+    // 100k in; a self-send of 40k with 59k change; then the change pays 30k
+    // out with 28k change. The last tx only touches change scripts.
+    let fund = tx(vec![OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 }], vec![(ext0.clone(), 100_000)]);
+    let self_send = tx(
+        vec![OutPoint { txid: fund.compute_txid(), vout: 0 }],
+        vec![(ext1.clone(), 40_000), (int0.clone(), 59_000)],
+    );
+    let spend_change = tx(
+        vec![OutPoint { txid: self_send.compute_txid(), vout: 1 }],
+        vec![(ScriptBuf::new_op_return([0u8; 4]), 30_000), (int1.clone(), 28_000)],
+    );
+
+    // Streaming, with the tracker `run_streaming` builds from the wallet.
+    let tracker = crate::persistence::tracker_for_wallet(&streaming_wallet.lock().unwrap(), crate::persistence::LOOKAHEAD);
+    let api = mock_api();
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(SyncEngine::new(tracker), api, streaming_wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    loop {
+        let pending: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+        if pending.is_empty() {
+            break;
+        }
+        for hash in pending {
+            let txs: Vec<&Transaction> = match hash {
+                h if h == spk_hash(&ext0) => vec![&fund, &self_send],
+                h if h == spk_hash(&ext1) => vec![&self_send],
+                h if h == spk_hash(&int0) => vec![&self_send, &spend_change],
+                h if h == spk_hash(&int1) => vec![&spend_change],
+                _ => vec![],
+            };
+            driver.handle_history(hash, txs.into_iter().map(unconfirmed).collect());
+        }
+    }
+    driver.run_until_idle();
+
+    // Polling: one full scan finding the same txs.
+    let mut scan = bdk_wallet::chain::spk_client::FullScanResponse::<KeychainKind>::default();
+    for t in [&fund, &self_send, &spend_change] {
+        scan.tx_update.txs.push(Arc::new(t.clone()));
+        scan.tx_update.seen_ats.insert((t.compute_txid(), 1));
+    }
+    scan.last_active_indices = [(KeychainKind::External, 1), (KeychainKind::Internal, 1)].into();
+    let client = CannedScan(Mutex::new(Some(scan)));
+    crate::polling::cold_start_sync(&mut polling_wallet.lock().unwrap(), &client, 1).unwrap();
+
+    let streaming = streaming_wallet.lock().unwrap().balance().total();
+    let polling = polling_wallet.lock().unwrap().balance().total();
+    assert_eq!(polling, Amount::from_sat(68_000));
+    assert_eq!(streaming, polling);
+}
+
+#[test]
+fn streamed_txs_are_applied_to_a_bare_tx_graph_sink() {
+    let receive = Descriptor::from_str(EXTERNAL_DESC).unwrap().at_derivation_index(0).unwrap().script_pubkey();
+    let change = Descriptor::from_str(INTERNAL_DESC).unwrap().at_derivation_index(0).unwrap().script_pubkey();
+    let fund = tx(vec![OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 }], vec![(receive.clone(), 100_000)]);
+    let spend = tx(vec![OutPoint { txid: fund.compute_txid(), vout: 0 }], vec![(change.clone(), 90_000)]);
+
+    let header = block::Header { nonce: 100, ..bitcoin::constants::genesis_block(Network::Testnet).header };
+    let mut api = mock_api();
+    api.headers.insert(100, header);
+    let sink = Arc::new(Mutex::new(TxGraphSink::new(Network::Testnet)));
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, sink.clone());
+
+    driver.process_engine(EngineEvent::Connected);
+    driver.process_engine(EngineEvent::ScriptHashHistory {
+        hash: spk_hash(&receive),
+        txs: vec![HistoryTx { tx: fund.clone(), height: 100, verified: true }],
+    });
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&change), txs: vec![unconfirmed(&spend)] });
+    driver.run_until_idle();
+
+    let sink = sink.lock().unwrap();
+    let graph = sink.graph();
+    assert!(graph.get_tx(fund.compute_txid()).is_some());
+    assert!(graph.get_tx(spend.compute_txid()).is_some());
+    // The confirmed tx is anchored to its block, which the sink's chain has.
+    let anchor = graph.all_anchors().get(&fund.compute_txid()).and_then(|a| a.iter().next()).unwrap();
+    assert_eq!(anchor.block_id.hash, header.block_hash());
+    assert_eq!(sink.chain().get(100).map(|cp| cp.hash()), Some(header.block_hash()));
+}
+
+#[test]
+fn notification_burst_for_one_hash_is_fetched_once() {
+    let hash = sha256::Hash::hash(b"busy address");
+    let mut api = mock_api();
+    api.history_misses = true;
+    let history_requests = api.history_requests.clone();
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, dummy_wallet())
+        .with_notification_debounce(std::time::Duration::from_millis(50));
+
+    for _ in 0..5 {
+        driver.on_scripthash_changed(hash);
+    }
+    driver.run_until_idle();
+    assert!(history_requests.lock().unwrap().is_empty(), "fetched before the window closed");
+
+    std::thread::sleep(std::time::Duration::from_millis(60));
+    driver.run_until_idle();
+    assert_eq!(*history_requests.lock().unwrap(), vec![hash]);
+}
+
+#[test]
+fn unconfirmed_self_spend_chain_stays_canonical_in_any_history_order() {
+    let wallet = dummy_wallet();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let fund = tx(vec![OutPoint { txid: Txid::from_byte_array([7; 32]), vout: 0 }], vec![(receive.clone(), 100_000)]);
+    let mut chain = vec![fund];
+    for sats in [90_000, 80_000, 70_000] {
+        let parent = chain.last().unwrap().compute_txid();
+        chain.push(tx(vec![OutPoint { txid: parent, vout: 0 }], vec![(receive.clone(), sats)]));
+    }
+
+    let mut driver = SyncOrchestrator::new(wallet_engine(), mock_api(), wallet.clone());
+    driver.process_engine(EngineEvent::Connected);
+    // The server lists mempool txs in no particular order.
+    let listed = [3, 1, 0, 2].iter().map(|i| unconfirmed(&chain[*i])).collect();
+    driver.process_engine(EngineEvent::ScriptHashHistory { hash: spk_hash(&receive), txs: listed });
+    driver.run_until_idle();
+
+    let w = wallet.lock().unwrap();
+    let canonical: Vec<Txid> = w.transactions().map(|t| t.tx_node.txid).collect();
+    for tx in &chain {
+        assert!(canonical.contains(&tx.compute_txid()), "{} dropped", tx.compute_txid());
+    }
+    assert_eq!(w.balance().total(), Amount::from_sat(70_000));
+    // Seen in dependency order, whatever order the server listed them in.
+    let seen: Vec<Option<u64>> =
+        chain.iter().map(|tx| w.tx_graph().get_tx_node(tx.compute_txid()).unwrap().last_seen).collect();
+    assert!(seen.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seen);
+}
+
+#[test]
+fn new_connection_epoch_resubscribes_every_script() {
+    let api = mock_api();
+    let (registered, history_requests) = (api.registered.clone(), api.history_requests.clone());
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, dummy_wallet());
+    driver.process_engine(EngineEvent::Connected);
+    driver.run_until_idle();
+    let scripts = registered.lock().unwrap().len();
+    let bootstrap_fetches = history_requests.lock().unwrap().len();
+    assert!(scripts > 0);
+
+    // Same connection: nothing happens.
+    driver.run_until_idle();
+    assert_eq!(registered.lock().unwrap().len(), scripts);
+
+    // Reconnected: every script is subscribed again, histories aren't refetched.
+    driver.client_mut().epoch += 1;
+    driver.run_until_idle();
+    let registered = registered.lock().unwrap();
+    assert_eq!(registered.len(), 2 * scripts);
+    let (first, again): (BTreeSet<_>, BTreeSet<_>) =
+        (registered[..scripts].iter().collect(), registered[scripts..].iter().collect());
+    assert_eq!(first, again);
+    assert_eq!(history_requests.lock().unwrap().len(), bootstrap_fetches);
+}
+
+#[test]
+fn run_returns_once_shutdown_is_set_and_shuts_the_client_down() {
+    let api = mock_api();
+    let client_shut_down = api.shut_down.clone();
+    let driver = SyncOrchestrator::new(wallet_engine(), api, dummy_wallet());
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let thread = std::thread::spawn({
+        let shutdown = shutdown.clone();
+        move || done_tx.send(driver.run(shutdown)).unwrap()
+    });
+
+    assert!(done_rx.recv_timeout(std::time::Duration::from_millis(200)).is_err(), "runs until told to stop");
+    shutdown.store(true, Ordering::SeqCst);
+    let result = done_rx.recv_timeout(std::time::Duration::from_secs(2)).expect("stops within the timeout");
+    thread.join().unwrap();
+    assert!(result.is_ok());
+    assert!(client_shut_down.load(Ordering::SeqCst));
+}
+
+#[test]
+fn run_until_idle_takes_the_same_path_as_run_forever() {
+    let scenario = |stop_when_drained: bool| {
+        let wallet = dummy_wallet();
+        let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+        let funding = tx(vec![OutPoint { txid: Txid::from_byte_array([3; 32]), vout: 0 }], vec![(receive.clone(), 42_000)]);
+        let mut api = mock_api();
+        api.histories.insert(spk_hash(&receive), vec![unconfirmed(&funding)]);
+        api.notifications.push_back(spk_hash(&receive));
+        api.stop_when_drained = stop_when_drained;
+        let history_requests = api.history_requests.clone();
+        (SyncOrchestrator::new(wallet_engine(), api, wallet.clone()), wallet, history_requests)
+    };
+
+    let (mut driver, idle_wallet, idle_requests) = scenario(false);
+    driver.process_engine(EngineEvent::Connected);
+    driver.run_until_idle();
+
+    let (driver, forever_wallet, forever_requests) = scenario(true);
+    assert!(driver.run_forever().is_err(), "stops once the mock is drained");
+
+    // The notified history is fetched from the client and applied, not
+    // turned into a fresh request.
+    let balance = |wallet: &TestWallet| wallet.lock().unwrap().balance().total();
+    assert_eq!(balance(&idle_wallet), Amount::from_sat(42_000));
+    assert_eq!(balance(&idle_wallet), balance(&forever_wallet));
+    assert_eq!(*idle_requests.lock().unwrap(), *forever_requests.lock().unwrap());
+}
+
+#[test]
+fn seeded_txs_count_before_sync_and_are_not_reapplied() {
+    let (wallet, store, db_path) = dummy_wallet_with_store();
+    let receive = wallet.lock().unwrap().peek_address(KeychainKind::External, 0).script_pubkey();
+    let change = wallet.lock().unwrap().peek_address(KeychainKind::Internal, 0).script_pubkey();
+    let pending = tx(vec![OutPoint { txid: Txid::from_byte_array([4; 32]), vout: 0 }], vec![(receive.clone(), 30_000)]);
+    let settled = tx(vec![OutPoint { txid: Txid::from_byte_array([5; 32]), vout: 0 }], vec![(change.clone(), 12_000)]);
+
+    let path = std::env::temp_dir().join(format!("bdk_test_seed_{}_{}.txt", std::process::id(), TEST_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    std::fs::write(
+        &path,
+        format!("# exported\n{} 0\n{} 500\n", serialize_hex(&pending), serialize_hex(&settled)),
+    )
+    .unwrap();
+
+    let genesis = bitcoin::constants::genesis_block(Network::Testnet).header;
+    let header = block::Header { nonce: 500, ..genesis };
+    let mut api = mock_api();
+    api.headers.insert(500, header);
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, wallet.clone())
+        .with_store(store)
+        .with_seed_transactions(&path)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Before any server response, and already on disk.
+    assert_eq!(wallet.lock().unwrap().balance().total(), Amount::from_sat(42_000));
+    let (_, changeset) = Store::<ChangeSet>::load(b"test", &db_path).unwrap();
+    let stored: Vec<Txid> = changeset.unwrap().tx_graph.txs.iter().map(|tx| tx.compute_txid()).collect();
+    assert!(stored.contains(&pending.compute_txid()) && stored.contains(&settled.compute_txid()));
+
+    // The server reports both: the pending one isn't re-applied, the settled
+    // one gets its confirmation.
+    driver.process_engine(EngineEvent::Connected);
+    driver.handle_history(spk_hash(&receive), vec![unconfirmed(&pending)]);
+    driver.handle_history(spk_hash(&change), vec![HistoryTx { tx: settled.clone(), height: 500, verified: true }]);
+
+    assert_eq!(driver.handle().stats().txs_applied, 1);
+    let wallet = wallet.lock().unwrap();
+    assert!(wallet.get_tx(settled.compute_txid()).unwrap().chain_position.is_confirmed());
+    assert_eq!(wallet.balance().total(), Amount::from_sat(42_000));
+}
+
+#[test]
+fn unreadable_seed_file_is_an_error() {
+    let missing = std::env::temp_dir().join(format!("bdk_test_seed_missing_{}", std::process::id()));
+    let result = SyncOrchestrator::new(wallet_engine(), mock_api(), dummy_wallet()).with_seed_transactions(&missing);
+    assert!(result.is_err());
+}
+
+#[test]
+fn progress_notifier_counts_bootstrap_histories_up_to_the_total() {
+    let api = mock_api();
+    let history_requests = api.history_requests.clone();
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let synced_at = Arc::new(Mutex::new(None));
+    let mut driver = SyncOrchestrator::new(wallet_engine(), api, dummy_wallet())
+        .with_progress_notifier({
+            let progress = progress.clone();
+            move |done, total| progress.lock().unwrap().push((done, total))
+        })
+        .with_initial_sync_notifier({
+            let (progress, synced_at) = (progress.clone(), synced_at.clone());
+            move || *synced_at.lock().unwrap() = Some(progress.lock().unwrap().len())
+        });
+
+    driver.process_engine(EngineEvent::Connected);
+    let pending: Vec<sha256::Hash> = history_requests.lock().unwrap().drain(..).collect();
+    let n = pending.len();
+    assert!(n > 1);
+    for hash in pending {
+        driver.handle_history(hash, vec![]);
+    }
+
+    let progress = progress.lock().unwrap();
+    let expected: Vec<(usize, usize)> = (1..=n).map(|done| (done, n)).collect();
+    assert_eq!(*progress, expected);
+    // The final (n, n) came right before the initial sync notifier.
+    assert_eq!(*synced_at.lock().unwrap(), Some(n));
+}
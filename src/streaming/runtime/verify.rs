@@ -0,0 +1,73 @@
+use crate::streaming::electrum::api::ScriptBalance;
+use crate::streaming::util::scripthash_to_wire;
+
+use bitcoin::hashes::sha256;
+use bitcoin::{Amount, SignedAmount};
+use std::collections::HashMap;
+use std::fmt;
+
+/// The server's balance of every tracked script compared against the
+/// wallet's unspent outputs, after the initial sync (see
+/// `SyncOrchestrator::with_balance_verification`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceCheck {
+    /// Sum of `blockchain.scripthash.get_balance` over the tracked scripts.
+    pub server_total: SignedAmount,
+    /// `wallet.balance().total()`.
+    pub wallet_total: Amount,
+    /// Scripts whose balances differ, in the order they were queried.
+    pub mismatches: Vec<ScriptMismatch>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptMismatch {
+    pub hash: sha256::Hash,
+    pub server: SignedAmount,
+    /// Value of the wallet's unspent outputs locked to the script.
+    pub wallet: Amount,
+}
+
+impl BalanceCheck {
+    /// Compares `server` (per scripthash) against the wallet's unspent value
+    /// per scripthash.
+    pub(crate) fn compare(
+        server: &[(sha256::Hash, ScriptBalance)],
+        wallet_by_script: &HashMap<sha256::Hash, Amount>,
+        wallet_total: Amount,
+    ) -> Self {
+        let mut server_total = SignedAmount::ZERO;
+        let mut mismatches = Vec::new();
+        for (hash, balance) in server {
+            server_total += balance.total();
+            let wallet = wallet_by_script.get(hash).copied().unwrap_or(Amount::ZERO);
+            if wallet.to_signed().ok() != Some(balance.total()) {
+                mismatches.push(ScriptMismatch { hash: *hash, server: balance.total(), wallet });
+            }
+        }
+        Self { server_total, wallet_total, mismatches }
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.mismatches.is_empty() && self.wallet_total.to_signed().ok() == Some(self.server_total)
+    }
+}
+
+impl fmt::Display for BalanceCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server {} sat / wallet {} sat", self.server_total.to_sat(), self.wallet_total.to_sat())?;
+        if self.is_consistent() {
+            return write!(f, " (consistent)");
+        }
+        write!(f, " ({} scripts differ)", self.mismatches.len())?;
+        for m in &self.mismatches {
+            write!(
+                f,
+                "\n  {}: server {} sat / wallet {} sat",
+                scripthash_to_wire(&m.hash),
+                m.server.to_sat(),
+                m.wallet.to_sat()
+            )?;
+        }
+        Ok(())
+    }
+}